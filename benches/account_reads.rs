@@ -0,0 +1,85 @@
+//! Count `get_account` calls on a few hot accounts while a shard keeps applying deposits to
+//! them, like a balance query endpoint next to a busy feed. Reads lock the account by
+//! default and read its published copy with the `lock-free-reads` feature.
+//!
+//! Run with `cargo bench --bench account_reads`, then again with
+//! `--features lock-free-reads` to compare.
+
+use payments_engine::engine::ShardedEngine;
+use payments_engine::models::{ClientId, Transaction};
+use rust_decimal_macros::dec;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CLIENTS: ClientId = 4;
+const DEPOSITS: u32 = 400_000;
+
+fn run(readers: usize) -> (f64, f64) {
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start the runtime");
+    let mut engine = runtime.block_on(async { ShardedEngine::new(1) });
+    for (tx_id, client) in (1..).zip(0..CLIENTS) {
+        engine
+            .route_transaction(Transaction::deposit(client, tx_id, dec!(1.0)))
+            .expect("Failed to route");
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let started = Instant::now();
+    let handles: Vec<_> = (0..readers)
+        .map(|reader| {
+            let engine = engine.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut reads = 0u64;
+                let mut client = reader as ClientId % CLIENTS;
+                while !done.load(Ordering::Relaxed) {
+                    let account = futures::executor::block_on(engine.get_account(client));
+                    assert!(account.is_some());
+                    client = (client + 1) % CLIENTS;
+                    reads += 1;
+                }
+                reads
+            })
+        })
+        .collect();
+
+    let deposits = (0..DEPOSITS)
+        .zip((0..CLIENTS).cycle())
+        .map(|(tx_id, client)| Transaction::deposit(client, tx_id + 100, dec!(0.0001)))
+        .collect();
+    engine.route_batch(deposits).expect("Failed to route");
+    // The readers' clones keep the shard channel open, so wait for the last deposit instead
+    // of the shutdown
+    let applied = DEPOSITS as usize + CLIENTS as usize;
+    while runtime.block_on(engine.run_summary()).deposits < applied {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let elapsed = started.elapsed();
+    done.store(true, Ordering::Relaxed);
+
+    let reads: u64 = handles
+        .into_iter()
+        .map(|handle| handle.join().expect("Reader panicked"))
+        .sum();
+    engine.shutdown();
+    runtime.block_on(engine.wait_for_completion());
+    let seconds = elapsed.max(Duration::from_millis(1)).as_secs_f64();
+    (reads as f64 / seconds, f64::from(DEPOSITS) / seconds)
+}
+
+fn main() {
+    let path = if cfg!(feature = "lock-free-reads") {
+        "lock-free"
+    } else {
+        "mutex"
+    };
+    for readers in [1, 2, 4] {
+        let (reads, deposits) = run(readers);
+        println!(
+            "{} reads, {} readers: {:>12.0} reads/s, {:>10.0} deposits/s",
+            path, readers, reads, deposits
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! Time a sharded engine whose observer callback blocks, with one and with several lanes
+//! per shard (`EngineOptions::lanes_per_shard`). With one lane a slow callback holds up
+//! every client of its shard; with more, only the clients of its lane.
+//!
+//! Run with `cargo bench --bench contention`.
+
+use payments_engine::engine::{EngineOptions, ShardedEngine};
+use payments_engine::models::{ClientAccount, ClientId, Transaction};
+use payments_engine::observer::TransactionObserver;
+use rust_decimal_macros::dec;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const SHARDS: usize = 2;
+const CLIENTS: u32 = 64;
+const DEPOSITS_PER_CLIENT: u32 = 50;
+/// Time the callback blocks for every deposit, like a synchronous call to another service
+const CALLBACK_LATENCY: Duration = Duration::from_micros(50);
+
+struct SlowObserver;
+
+impl TransactionObserver for SlowObserver {
+    fn on_deposit(&self, _transaction: &Transaction, _account: &ClientAccount) {
+        std::thread::sleep(CALLBACK_LATENCY);
+    }
+}
+
+fn run(lanes_per_shard: usize) -> Duration {
+    // A thread for every lane, so blocked callbacks never wait for a free worker thread
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(SHARDS * lanes_per_shard + 1)
+        .enable_all()
+        .build()
+        .expect("Failed to start the runtime");
+    runtime.block_on(async {
+        let options = EngineOptions {
+            lanes_per_shard,
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(SHARDS, Arc::new(SlowObserver), options);
+        let deposits = (0..DEPOSITS_PER_CLIENT)
+            .flat_map(|round| {
+                (0..CLIENTS).map(move |client| {
+                    let tx_id = round * CLIENTS + client + 1;
+                    Transaction::deposit(client as ClientId, tx_id, dec!(1.0))
+                })
+            })
+            .collect();
+
+        let started = Instant::now();
+        engine.route_batch(deposits).expect("Failed to route");
+        engine.shutdown();
+        engine.wait_for_completion().await;
+        started.elapsed()
+    })
+}
+
+fn main() {
+    let deposits = f64::from(CLIENTS * DEPOSITS_PER_CLIENT);
+    for lanes_per_shard in [1, 2, 4, 8] {
+        let elapsed = run(lanes_per_shard);
+        println!(
+            "{} shards x {} lanes: {:>8.1} ms, {:>8.0} deposits/s",
+            SHARDS,
+            lanes_per_shard,
+            elapsed.as_secs_f64() * 1000.0,
+            deposits / elapsed.as_secs_f64()
+        );
+    }
+}
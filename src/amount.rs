@@ -0,0 +1,225 @@
+use crate::models::MAX_DISPLAY_PRECISION;
+use rust_decimal::Decimal;
+use std::fmt;
+
+/// Number type of account balances. `ClientAccount` is generic over it: the engine keeps
+/// balances as `Decimal`, `MinorUnits` is an integer alternative for callers applying
+/// `AccountOp`s in their own hot loops.
+pub trait Amount: Copy + PartialOrd + fmt::Debug + fmt::Display {
+    /// Balance of a new account
+    fn zero() -> Self;
+
+    /// `self + other`, or `None` when the sum does not fit the type
+    fn checked_add(self, other: Self) -> Option<Self>;
+
+    /// `self - other`, or `None` when the difference does not fit the type
+    fn checked_sub(self, other: Self) -> Option<Self>;
+
+    /// Value reported in errors such as `InsufficientFunds`
+    fn to_decimal(self) -> Decimal;
+}
+
+impl Amount for Decimal {
+    fn zero() -> Self {
+        Decimal::new(0, MAX_DISPLAY_PRECISION)
+    }
+
+    fn checked_add(self, other: Self) -> Option<Self> {
+        Decimal::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        Decimal::checked_sub(self, other)
+    }
+
+    fn to_decimal(self) -> Decimal {
+        self
+    }
+}
+
+pub use minor_units::MinorUnits;
+
+mod minor_units {
+    use super::Amount;
+    use crate::errors::EngineError;
+    use crate::models::MAX_DISPLAY_PRECISION;
+    use rust_decimal::Decimal;
+    use std::fmt;
+    use std::iter;
+    use std::str::FromStr;
+
+    /// Minor units in one unit of currency, at the precision the engine keeps
+    const UNITS: u64 = 10u64.pow(MAX_DISPLAY_PRECISION);
+
+    /// Amount held as an `i64` count of ten-thousandths, the smallest fraction the engine
+    /// keeps (`MAX_DISPLAY_PRECISION`), e.g. `MinorUnits(15_000)` is `1.5`.
+    ///
+    /// Arithmetic is checked: a balance update past about ±922 billion fails with
+    /// `AmountOverflow` and leaves the account unchanged.
+    #[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct MinorUnits(pub i64);
+
+    impl Amount for MinorUnits {
+        fn zero() -> Self {
+            MinorUnits(0)
+        }
+
+        fn checked_add(self, other: Self) -> Option<Self> {
+            self.0.checked_add(other.0).map(MinorUnits)
+        }
+
+        fn checked_sub(self, other: Self) -> Option<Self> {
+            self.0.checked_sub(other.0).map(MinorUnits)
+        }
+
+        fn to_decimal(self) -> Decimal {
+            Decimal::new(self.0, MAX_DISPLAY_PRECISION)
+        }
+    }
+
+    /// Always four decimal places, like the balance of a new account (`0.0000`)
+    impl fmt::Display for MinorUnits {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let sign = if self.0 < 0 { "-" } else { "" };
+            let units = self.0.unsigned_abs();
+            write!(f, "{}{}.{:04}", sign, units / UNITS, units % UNITS)
+        }
+    }
+
+    /// Parse a decimal amount such as `-12.5`. Digits past the fourth decimal place are
+    /// truncated, like amounts read by the engine.
+    impl FromStr for MinorUnits {
+        type Err = EngineError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let invalid = || EngineError::TransactionError(format!("Invalid amount: {}", s));
+
+            let (negative, digits) = match s.strip_prefix('-') {
+                Some(digits) => (true, digits),
+                None => (false, s.strip_prefix('+').unwrap_or(s)),
+            };
+            let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+            let is_numeric = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+            if (integer.is_empty() && fraction.is_empty())
+                || !is_numeric(integer)
+                || !is_numeric(fraction)
+            {
+                return Err(invalid());
+            }
+
+            let scale = MAX_DISPLAY_PRECISION as usize;
+            let fraction = &fraction[..fraction.len().min(scale)];
+            let padding = iter::repeat_n(b'0', scale - fraction.len());
+            let mut units: i64 = 0;
+            for digit in integer.bytes().chain(fraction.bytes()).chain(padding) {
+                units = units
+                    .checked_mul(10)
+                    .and_then(|units| units.checked_add(i64::from(digit - b'0')))
+                    .ok_or_else(invalid)?;
+            }
+            Ok(MinorUnits(if negative { -units } else { units }))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::models::{AccountOp, ClientAccount};
+
+        fn amount(value: &str) -> MinorUnits {
+            value.parse().unwrap()
+        }
+
+        #[test]
+        fn test_parse_and_display() {
+            assert_eq!(amount("1.5"), MinorUnits(15_000));
+            assert_eq!(amount("-0.25"), MinorUnits(-2_500));
+            assert_eq!(amount("+3"), MinorUnits(30_000));
+            assert_eq!(amount(".5"), MinorUnits(5_000));
+            // Truncated to four decimal places
+            assert_eq!(amount("2.123456"), MinorUnits(21_234));
+
+            assert_eq!(MinorUnits(15_000).to_string(), "1.5000");
+            assert_eq!(MinorUnits(-2_500).to_string(), "-0.2500");
+            assert_eq!(MinorUnits(0).to_string(), "0.0000");
+            assert_eq!(MinorUnits(-2_500).to_decimal(), Decimal::new(-2_500, 4));
+
+            for invalid in ["", ".", "1.2.3", "abc", "1e5", "--1", "99999999999999999"] {
+                assert!(invalid.parse::<MinorUnits>().is_err(), "{}", invalid);
+            }
+        }
+
+        #[test]
+        fn test_account_lifecycle_with_minor_units() {
+            let mut account = ClientAccount::<MinorUnits>::new();
+            assert_eq!(account.total, MinorUnits(0));
+
+            account.apply(AccountOp::Credit(amount("100.0"))).unwrap();
+            account.apply(AccountOp::Debit(amount("30.25"))).unwrap();
+            assert_eq!(account.available, amount("69.75"));
+
+            let result = account.apply(AccountOp::Debit(amount("70")));
+            assert!(matches!(
+                result,
+                Err(EngineError::InsufficientFunds { available, requested, .. })
+                    if available == Decimal::new(6_975, 2) && requested == Decimal::new(70, 0)
+            ));
+
+            account.apply(AccountOp::Charge(amount("0.75"))).unwrap();
+            account.apply(AccountOp::Hold(amount("40"))).unwrap();
+            assert_eq!(
+                (account.available, account.held, account.total),
+                (amount("29"), amount("40"), amount("69"))
+            );
+
+            account.apply(AccountOp::Release(amount("10"))).unwrap();
+            assert!(matches!(
+                account.apply(AccountOp::Reverse(amount("31"))),
+                Err(EngineError::InsufficientHeldFunds { .. })
+            ));
+            account.apply(AccountOp::Reverse(amount("30"))).unwrap();
+            assert_eq!(
+                (account.available, account.held, account.total),
+                (amount("39"), amount("0"), amount("39"))
+            );
+            assert!(account.locked);
+
+            assert!(matches!(
+                account.apply(AccountOp::Credit(amount("1"))),
+                Err(EngineError::AccountLocked(_))
+            ));
+            assert_eq!(account.total.to_string(), "39.0000");
+        }
+
+        #[test]
+        fn test_overflow_is_reported() {
+            let mut account = ClientAccount::<MinorUnits>::new();
+            account
+                .apply(AccountOp::Credit(MinorUnits(i64::MAX)))
+                .unwrap();
+
+            let result = account.apply(AccountOp::Credit(MinorUnits(1)));
+            assert!(matches!(
+                result,
+                Err(EngineError::AmountOverflow { requested, .. })
+                    if requested == Decimal::new(1, 4)
+            ));
+            // A fee may take the balance below zero, not below `i64::MIN`
+            account
+                .apply(AccountOp::Charge(MinorUnits(i64::MAX)))
+                .unwrap();
+            account
+                .apply(AccountOp::Charge(MinorUnits(i64::MAX)))
+                .unwrap();
+            assert!(matches!(
+                account.apply(AccountOp::Charge(MinorUnits(2))),
+                Err(EngineError::AmountOverflow { .. })
+            ));
+            // Nothing was applied by the failed operations
+            assert_eq!(
+                (account.available, account.total),
+                (MinorUnits(-i64::MAX), MinorUnits(-i64::MAX))
+            );
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::models::ClientAccount;
+use rust_decimal::Decimal;
+use std::hint;
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, Ordering};
+
+/// Copy of a `ClientAccount` readable without taking a lock, published by the engine after
+/// every change to the account (the `lock-free-reads` feature).
+///
+/// A sequence lock: the writer makes the sequence odd, stores the fields and makes it even
+/// again; a reader copies the fields and keeps the copy only if the sequence was the same
+/// even number before and after. A read overlapping a write retries, so a read is never torn
+/// and never waits for a lock, while a write costs a few more atomic stores. Every field is
+/// an atomic, each `Decimal` split in two words.
+///
+/// Only one thread may `store` at a time: the engine stores under the account's own lock.
+#[derive(Debug, Default)]
+pub struct AtomicAccount {
+    sequence: AtomicU64,
+    /// `available`, `held` and `total`, two words each
+    words: [AtomicU64; 6],
+    locked: AtomicBool,
+}
+
+impl AtomicAccount {
+    pub fn new(account: &ClientAccount) -> Self {
+        let published = Self::default();
+        published.store(account);
+        published
+    }
+
+    /// Publish `account`, the only writer at this time
+    pub fn store(&self, account: &ClientAccount) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Relaxed);
+        // Readers seeing any of the stores below also see the odd sequence
+        fence(Ordering::Release);
+
+        let amounts = [account.available, account.held, account.total];
+        for (words, amount) in self.words.chunks_exact(2).zip(amounts) {
+            let bytes = amount.serialize();
+            words[0].store(word(&bytes[..8]), Ordering::Relaxed);
+            words[1].store(word(&bytes[8..]), Ordering::Relaxed);
+        }
+        self.locked.store(account.locked, Ordering::Relaxed);
+
+        self.sequence
+            .store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    /// The account as of the last complete `store`
+    pub fn load(&self) -> ClientAccount {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+
+            let mut amounts = [Decimal::ZERO; 3];
+            for (amount, words) in amounts.iter_mut().zip(self.words.chunks_exact(2)) {
+                let mut bytes = [0; 16];
+                bytes[..8].copy_from_slice(&words[0].load(Ordering::Relaxed).to_le_bytes());
+                bytes[8..].copy_from_slice(&words[1].load(Ordering::Relaxed).to_le_bytes());
+                *amount = Decimal::deserialize(bytes);
+            }
+            let locked = self.locked.load(Ordering::Relaxed);
+
+            // The loads above happen before the sequence is read again
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                let [available, held, total] = amounts;
+                return ClientAccount {
+                    available,
+                    held,
+                    total,
+                    locked,
+                };
+            }
+        }
+    }
+}
+
+fn word(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().expect("8 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_load_returns_stored_account() {
+        let account = ClientAccount {
+            available: dec!(-1.2345),
+            held: dec!(79228162514264337593543950335),
+            total: dec!(0.0001),
+            locked: true,
+        };
+
+        assert_eq!(AtomicAccount::new(&account).load(), account);
+    }
+
+    #[test]
+    fn test_concurrent_reads_never_torn() {
+        let published = Arc::new(AtomicAccount::new(&ClientAccount::new()));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let published = Arc::clone(&published);
+                let done = Arc::clone(&done);
+                thread::spawn(move || {
+                    let mut last = Decimal::ZERO;
+                    let mut reads = 0;
+                    while !done.load(Ordering::Relaxed) || reads == 0 {
+                        let account = published.load();
+                        // Every write keeps these, a torn read breaks them
+                        assert_eq!(account.available + account.held, account.total);
+                        assert_eq!(account.held, account.available * dec!(2));
+                        assert_eq!(account.locked, account.available % dec!(2) == dec!(1));
+                        // Writes are seen in order
+                        assert!(account.available >= last);
+                        last = account.available;
+                        reads += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for step in 1..=100_000u32 {
+            let available = Decimal::from(step);
+            published.store(&ClientAccount {
+                available,
+                held: available * dec!(2),
+                total: available * dec!(3),
+                locked: step % 2 == 1,
+            });
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(published.load().total, dec!(300000));
+    }
+}
@@ -0,0 +1,97 @@
+use crate::models::{output_amount, ClientAccount, ClientId};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// Canonical form of an account set: one `client,available,held,total,locked` line per
+/// client in client order, amounts cut to the output precision and normalized so `10.5000`
+/// and `10.5` read the same
+pub fn canonical_accounts(accounts: &[(ClientId, ClientAccount)]) -> String {
+    let mut sorted: Vec<_> = accounts.iter().collect();
+    sorted.sort_by_key(|(client, _)| *client);
+
+    let mut canonical = String::new();
+    for (client, account) in sorted {
+        // Writing into a `String` never fails
+        let _ = writeln!(
+            canonical,
+            "{},{},{},{},{}",
+            client,
+            output_amount(account.available).normalize(),
+            output_amount(account.held).normalize(),
+            output_amount(account.total).normalize(),
+            account.locked
+        );
+    }
+    canonical
+}
+
+/// SHA-256 of `canonical_accounts`, as lowercase hex. Two runs share a checksum exactly
+/// when they end with the same balances, whatever the shard count or output order.
+pub fn accounts_checksum(accounts: &[(ClientId, ClientAccount)]) -> String {
+    Sha256::digest(canonical_accounts(accounts).as_bytes())
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{PaymentEngine, ShardedEngine};
+    use crate::models::Transaction;
+    use rust_decimal_macros::dec;
+
+    async fn run(num_shards: usize, transactions: Vec<Transaction>) -> String {
+        let mut engine = ShardedEngine::new(num_shards);
+        engine.route_batch(transactions).unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+        engine.checksum().await
+    }
+
+    fn input() -> Vec<Transaction> {
+        vec![
+            Transaction::deposit(1, 1, dec!(10.5)),
+            Transaction::deposit(2, 2, dec!(7.0)),
+            Transaction::deposit(3, 3, dec!(3.25)),
+            Transaction::withdrawal(1, 4, dec!(0.5)),
+            Transaction::dispute(3, 3),
+            Transaction::chargeback(3, 3),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_same_input_same_checksum() {
+        let first = run(4, input()).await;
+        assert_eq!(first.len(), 64);
+        assert_eq!(run(4, input()).await, first);
+        // Another shard count changes the output order, not the checksum
+        assert_eq!(run(1, input()).await, first);
+    }
+
+    #[tokio::test]
+    async fn test_different_input_different_checksum() {
+        let mut other = input();
+        other[1] = Transaction::deposit(2, 2, dec!(7.01));
+        assert_ne!(run(4, other).await, run(4, input()).await);
+    }
+
+    #[test]
+    fn test_checksum_ignores_decimal_scale() {
+        let account = |amount| {
+            let mut account = ClientAccount::new();
+            account.deposit(amount).unwrap();
+            account
+        };
+        assert_eq!(
+            accounts_checksum(&[(1, account(dec!(10.5000)))]),
+            accounts_checksum(&[(1, account(dec!(10.5)))])
+        );
+        assert_eq!(
+            canonical_accounts(&[(2, account(dec!(1.0))), (1, account(dec!(2.50)))]),
+            "1,2.5,0,2.5,false\n2,1,0,1,false\n"
+        );
+    }
+}
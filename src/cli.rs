@@ -0,0 +1,1079 @@
+use crate::errors::EngineError;
+use crate::io::{HeaderMode, InputFormat};
+use crate::logging::LogFormat;
+use crate::models::{
+    DisputePolicy, FeePolicy, LockPolicy, MAX_DISPLAY_PRECISION, MAX_HOLD_PRECISION,
+};
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+pub const USAGE: &str = concat!(
+    "Usage: payments_engine <input_file>... [--concurrent-inputs|--merge-by-tx-id]",
+    " [--log-format text|json]",
+    " [--shards <n>|auto] [--sqlite <path>|--output-dir <dir>] [--dead-letter-retries <n>]",
+    " [--thousands-sep <char>] [--max-rows <n>] [--max-bytes <n>] [--rate <tx/s>]",
+    " [--stats-output <path>] [--mmap]",
+    " [--fee-policy reject|allow-negative] [--global-unique-tx] [--summary-only]",
+    " [--report-shards] [--check-balance-nonnegative] [--flexible] [--comment-char <char>]",
+    " [--explain <tx_id>] [--has-header true|false|auto] [--accounts-filter <file>]",
+    " [--checksum-output <path>|-] [--single-thread] [--hold-precision <places>]",
+    " [--max-error-rate <0.0..1.0>] [--check-aggregate] [--input-format csv|json]",
+    " [--dispute-policy deposits-only|deposits-and-withdrawals]",
+    " [--lock-policy freeze-all|settle-disputes] [--output-precision <places>] [--profile]",
+    " [--allow-zero-amount] [--rejects-output <path>] [--lanes-per-shard <n>]",
+    " [--strict-ordering] [--auto-freeze-above <amount>] [--lenient-resolve]",
+    " [--max-clients <n>] [--validate-schema-only] [--metrics-addr <host:port>]",
+    " [--no-auto-create-on-withdrawal] [--dispute-amounts]\n",
+    "       payments_engine verify <expected_output> <actual_output>"
+);
+
+/// Number of shards to start the engine with
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ShardCount {
+    Fixed(usize),
+    /// Pick a count from a sample of the input, see `runner::auto_shard_count`
+    Auto,
+}
+
+impl Default for ShardCount {
+    fn default() -> Self {
+        ShardCount::Fixed(4)
+    }
+}
+
+impl FromStr for ShardCount {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(ShardCount::Auto);
+        }
+        match s.parse::<usize>() {
+            Ok(count) if count > 0 => Ok(ShardCount::Fixed(count)),
+            _ => Err(EngineError::ConfigError(format!(
+                "Invalid shard count: {} (expected a positive number or auto)",
+                s
+            ))),
+        }
+    }
+}
+
+/// What the binary was asked to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Process the input files and write the account balances
+    Run(Box<Config>),
+    /// Compare two account output files logically, see `verify::verify_files`
+    Verify { expected: String, actual: String },
+}
+
+impl Command {
+    /// Parse the arguments following the program name
+    pub fn from_args<I>(args: I) -> Result<Self, EngineError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter().peekable();
+        if args.peek().map(String::as_str) != Some("verify") {
+            return Config::from_args(args).map(|config| Command::Run(Box::new(config)));
+        }
+
+        let flag = args.next().unwrap_or_default();
+        let expected = next_value(&mut args, &flag)?;
+        let actual = next_value(&mut args, &flag)?;
+        if let Some(extra) = args.next() {
+            return Err(EngineError::ConfigError(format!(
+                "Unexpected argument for verify: {}",
+                extra
+            )));
+        }
+        Ok(Command::Verify { expected, actual })
+    }
+}
+
+/// Options for a single run of the engine, parsed from the command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub input_files: Vec<String>,
+    pub concurrent_inputs: bool,
+    /// Read the input files as one stream ordered by tx id, see `runner::route_files_merged`
+    pub merge_by_tx_id: bool,
+    pub log_format: LogFormat,
+    pub shards: ShardCount,
+    /// Write the final balances to this SQLite database instead of CSV on stdout
+    pub sqlite_path: Option<String>,
+    /// Write one `accounts-<n>.csv` per shard into this directory instead of CSV on stdout
+    pub output_dir: Option<String>,
+    /// Write the run summary to this path, as JSON for a `.json` path and CSV otherwise
+    pub stats_output: Option<String>,
+    /// Retry passes over transactions that failed with a retryable error, 0 to disable
+    pub dead_letter_retries: u32,
+    /// Thousands separator stripped from amounts before parsing
+    pub thousands_sep: Option<char>,
+    /// Abort when an input file has more data rows than this
+    pub max_rows: Option<u64>,
+    /// Abort when more bytes than this are read from an input file
+    pub max_bytes: Option<u64>,
+    /// Maximum number of transactions routed per second, across all input files
+    pub rate: Option<u32>,
+    /// Read input files through a memory mapping
+    pub mmap: bool,
+    /// Whether a fee larger than the available funds is rejected or overdraws the account
+    pub fee_policy: FeePolicy,
+    /// Whether withdrawals can be disputed as well as deposits
+    pub dispute_policy: DisputePolicy,
+    /// Which transactions an account locked by a chargeback still accepts
+    pub lock_policy: LockPolicy,
+    /// Reject transaction ids already used by any client, not only within a shard
+    pub global_unique_tx: bool,
+    /// Reject disputes, resolves and chargebacks referencing an id past the latest deposit or
+    /// withdrawal
+    pub strict_ordering: bool,
+    /// Accept a resolve of a transaction not under dispute as a no-op
+    pub lenient_resolve: bool,
+    /// Hold the amount given on a dispute row instead of the whole transaction
+    pub dispute_amounts: bool,
+    /// Open no account for a client whose first transaction is rejected
+    pub no_auto_create_on_withdrawal: bool,
+    /// Print only the run summary instead of the accounts
+    pub summary_only: bool,
+    /// Print the number of clients and transactions of each shard to stderr after the run
+    pub report_shards: bool,
+    /// Print the time spent parsing, routing, processing and writing to stderr after the run
+    pub profile: bool,
+    /// Fail the run when an account ends with a negative available or total balance
+    pub check_balance_nonnegative: bool,
+    /// Fail the run when the overflow-checked aggregate of the flows differs from the sum of
+    /// the account totals. Always on in debug builds.
+    pub check_aggregate: bool,
+    /// Accept input rows with more or fewer cells than the header
+    pub flexible: bool,
+    /// Accept deposits and withdrawals of zero
+    pub allow_zero_amount: bool,
+    /// Skip input lines starting with this character
+    pub comment_char: Option<u8>,
+    /// Print what this transaction did to stderr after the run
+    pub explain: Option<u32>,
+    /// Whether input files start with a header row
+    pub has_header: HeaderMode,
+    /// Format of the input files, CSV unless `--input-format json`
+    pub input_format: InputFormat,
+    /// File listing the only clients to process, one id per line
+    pub accounts_filter: Option<String>,
+    /// Write the SHA-256 of the final balances to this path, or to stderr for `-`
+    pub checksum_output: Option<String>,
+    /// Write the rejected rows with their reason to this CSV file
+    pub rejects_output: Option<String>,
+    /// Apply transactions inline on a single thread instead of in shard workers
+    pub single_thread: bool,
+    /// Tasks applying the transactions of each shard in parallel, by client. 0 or 1 keeps
+    /// one per shard.
+    pub lanes_per_shard: usize,
+    /// Decimal places kept in balances, `None` keeps the output precision
+    pub hold_precision: Option<u32>,
+    /// Decimal places of the amounts in the accounts CSV, `None` keeps 4
+    pub output_precision: Option<u32>,
+    /// Abort when more than this share of the rows (0.0 to 1.0) is rejected
+    pub max_error_rate: Option<f64>,
+    /// Lock an account once a deposit takes its available funds above this amount
+    pub auto_freeze_above: Option<Decimal>,
+    /// Reject transactions of new clients once this many client accounts are open
+    pub max_clients: Option<usize>,
+    /// Only parse the input and count valid and invalid rows, without applying anything
+    pub validate_schema_only: bool,
+    /// Serve Prometheus metrics on this address during the run
+    pub metrics_addr: Option<String>,
+}
+
+impl Config {
+    /// Parse the arguments following the program name
+    pub fn from_args<I>(args: I) -> Result<Self, EngineError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut input_files = Vec::new();
+        let mut concurrent_inputs = false;
+        let mut merge_by_tx_id = false;
+        let mut log_format = LogFormat::default();
+        let mut shards = ShardCount::default();
+        let mut sqlite_path = None;
+        let mut output_dir = None;
+        let mut stats_output = None;
+        let mut dead_letter_retries = 0;
+        let mut thousands_sep = None;
+        let mut max_rows = None;
+        let mut max_bytes = None;
+        let mut rate = None;
+        let mut mmap = false;
+        let mut fee_policy = FeePolicy::default();
+        let mut dispute_policy = DisputePolicy::default();
+        let mut lock_policy = LockPolicy::default();
+        let mut global_unique_tx = false;
+        let mut strict_ordering = false;
+        let mut lenient_resolve = false;
+        let mut dispute_amounts = false;
+        let mut no_auto_create_on_withdrawal = false;
+        let mut validate_schema_only = false;
+        let mut summary_only = false;
+        let mut report_shards = false;
+        let mut profile = false;
+        let mut check_balance_nonnegative = false;
+        let mut check_aggregate = false;
+        let mut flexible = false;
+        let mut allow_zero_amount = false;
+        let mut comment_char = None;
+        let mut explain = None;
+        let mut has_header = HeaderMode::default();
+        let mut input_format = InputFormat::default();
+        let mut accounts_filter = None;
+        let mut checksum_output = None;
+        let mut rejects_output = None;
+        let mut single_thread = false;
+        let mut lanes_per_shard = 0;
+        let mut hold_precision = None;
+        let mut output_precision = None;
+        let mut max_error_rate = None;
+        let mut auto_freeze_above = None;
+        let mut max_clients = None;
+        let mut metrics_addr = None;
+        let mut shards_given = false;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--concurrent-inputs" => concurrent_inputs = true,
+                "--merge-by-tx-id" => merge_by_tx_id = true,
+                "--global-unique-tx" => global_unique_tx = true,
+                "--strict-ordering" => strict_ordering = true,
+                "--lenient-resolve" => lenient_resolve = true,
+                "--dispute-amounts" => dispute_amounts = true,
+                "--no-auto-create-on-withdrawal" => no_auto_create_on_withdrawal = true,
+                "--validate-schema-only" => validate_schema_only = true,
+                "--summary-only" => summary_only = true,
+                "--report-shards" => report_shards = true,
+                "--profile" => profile = true,
+                "--check-balance-nonnegative" => check_balance_nonnegative = true,
+                "--check-aggregate" => check_aggregate = true,
+                "--flexible" => flexible = true,
+                "--allow-zero-amount" => allow_zero_amount = true,
+                "--single-thread" => single_thread = true,
+                "--mmap" => {
+                    if !cfg!(feature = "mmap") {
+                        return Err(EngineError::ConfigError(
+                            "--mmap requires building with the `mmap` feature".into(),
+                        ));
+                    }
+                    mmap = true;
+                }
+                "--log-format" => {
+                    log_format = next_value(&mut args, &arg)?.parse()?;
+                }
+                "--shards" => {
+                    shards = next_value(&mut args, &arg)?.parse()?;
+                    shards_given = true;
+                }
+                "--fee-policy" => fee_policy = next_value(&mut args, &arg)?.parse()?,
+                "--dispute-policy" => dispute_policy = next_value(&mut args, &arg)?.parse()?,
+                "--lock-policy" => lock_policy = next_value(&mut args, &arg)?.parse()?,
+                "--sqlite" => {
+                    if !cfg!(feature = "sqlite") {
+                        return Err(EngineError::ConfigError(
+                            "--sqlite requires building with the `sqlite` feature".into(),
+                        ));
+                    }
+                    sqlite_path = Some(next_value(&mut args, &arg)?);
+                }
+                "--metrics-addr" => {
+                    if !cfg!(feature = "prometheus") {
+                        return Err(EngineError::ConfigError(
+                            "--metrics-addr requires building with the `prometheus` feature".into(),
+                        ));
+                    }
+                    metrics_addr = Some(next_value(&mut args, &arg)?);
+                }
+                "--output-dir" => output_dir = Some(next_value(&mut args, &arg)?),
+                "--stats-output" => stats_output = Some(next_value(&mut args, &arg)?),
+                "--rejects-output" => rejects_output = Some(next_value(&mut args, &arg)?),
+                "--lanes-per-shard" => {
+                    let value = next_value(&mut args, &arg)?;
+                    lanes_per_shard = value.parse().map_err(|_| {
+                        EngineError::ConfigError(format!("Invalid lane count: {}", value))
+                    })?;
+                }
+                "--dead-letter-retries" => {
+                    let value = next_value(&mut args, &arg)?;
+                    dead_letter_retries = value.parse().map_err(|_| {
+                        EngineError::ConfigError(format!("Invalid retry count: {}", value))
+                    })?;
+                }
+                "--max-rows" => max_rows = Some(parse_limit(&mut args, &arg)?),
+                "--max-bytes" => max_bytes = Some(parse_limit(&mut args, &arg)?),
+                "--has-header" => has_header = next_value(&mut args, &arg)?.parse()?,
+                "--input-format" => input_format = next_value(&mut args, &arg)?.parse()?,
+                "--accounts-filter" => accounts_filter = Some(next_value(&mut args, &arg)?),
+                "--checksum-output" => checksum_output = Some(next_value(&mut args, &arg)?),
+                "--explain" => {
+                    let value = next_value(&mut args, &arg)?;
+                    explain = Some(value.parse().map_err(|_| {
+                        EngineError::ConfigError(format!("Invalid transaction id: {}", value))
+                    })?);
+                }
+                "--hold-precision" => {
+                    let value = next_value(&mut args, &arg)?;
+                    match value.parse::<u32>() {
+                        Ok(places)
+                            if (MAX_DISPLAY_PRECISION..=MAX_HOLD_PRECISION).contains(&places) =>
+                        {
+                            hold_precision = Some(places)
+                        }
+                        _ => {
+                            return Err(EngineError::ConfigError(format!(
+                                "Invalid hold precision: {} (expected {} to {} decimal places)",
+                                value, MAX_DISPLAY_PRECISION, MAX_HOLD_PRECISION
+                            )));
+                        }
+                    }
+                }
+                "--output-precision" => {
+                    let value = next_value(&mut args, &arg)?;
+                    match value.parse::<u32>() {
+                        Ok(places) if places <= MAX_DISPLAY_PRECISION => {
+                            output_precision = Some(places)
+                        }
+                        _ => {
+                            return Err(EngineError::ConfigError(format!(
+                                "Invalid output precision: {} (expected 0 to {} decimal places)",
+                                value, MAX_DISPLAY_PRECISION
+                            )));
+                        }
+                    }
+                }
+                "--max-error-rate" => {
+                    let value = next_value(&mut args, &arg)?;
+                    match value.parse::<f64>() {
+                        Ok(rate) if (0.0..=1.0).contains(&rate) => max_error_rate = Some(rate),
+                        _ => {
+                            return Err(EngineError::ConfigError(format!(
+                                "Invalid error rate: {} (expected 0.0 to 1.0)",
+                                value
+                            )));
+                        }
+                    }
+                }
+                "--max-clients" => {
+                    let value = next_value(&mut args, &arg)?;
+                    match value.parse::<usize>() {
+                        Ok(count) if count >= 1 => max_clients = Some(count),
+                        _ => {
+                            return Err(EngineError::ConfigError(format!(
+                                "Invalid client limit: {} (expected at least 1)",
+                                value
+                            )));
+                        }
+                    }
+                }
+                "--auto-freeze-above" => {
+                    let value = next_value(&mut args, &arg)?;
+                    match Decimal::from_str(&value) {
+                        Ok(amount) if !amount.is_sign_negative() => {
+                            auto_freeze_above = Some(amount)
+                        }
+                        _ => {
+                            return Err(EngineError::ConfigError(format!(
+                                "Invalid freeze threshold: {} (expected a non-negative amount)",
+                                value
+                            )));
+                        }
+                    }
+                }
+                "--rate" => {
+                    let value = next_value(&mut args, &arg)?;
+                    match value.parse::<u32>() {
+                        Ok(per_second) if per_second > 0 => rate = Some(per_second),
+                        _ => {
+                            return Err(EngineError::ConfigError(format!(
+                                "Invalid rate: {} (expected transactions per second)",
+                                value
+                            )));
+                        }
+                    }
+                }
+                "--thousands-sep" => {
+                    let value = next_value(&mut args, &arg)?;
+                    let mut chars = value.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(sep), None) if sep != '.' && !sep.is_ascii_digit() => {
+                            thousands_sep = Some(sep)
+                        }
+                        _ => {
+                            return Err(EngineError::ConfigError(format!(
+                                "Invalid thousands separator: {}",
+                                value
+                            )));
+                        }
+                    }
+                }
+                "--comment-char" => {
+                    let value = next_value(&mut args, &arg)?;
+                    match value.as_bytes() {
+                        [comment] if comment.is_ascii_graphic() && !b",\"".contains(comment) => {
+                            comment_char = Some(*comment)
+                        }
+                        _ => {
+                            return Err(EngineError::ConfigError(format!(
+                                "Invalid comment character: {}",
+                                value
+                            )));
+                        }
+                    }
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(EngineError::ConfigError(format!(
+                        "Unknown option: {}",
+                        flag
+                    )));
+                }
+                _ => input_files.push(arg),
+            }
+        }
+
+        if input_files.is_empty() {
+            return Err(EngineError::ConfigError("Missing input file".into()));
+        }
+        if concurrent_inputs && merge_by_tx_id {
+            return Err(EngineError::ConfigError(
+                "--concurrent-inputs and --merge-by-tx-id cannot be combined".into(),
+            ));
+        }
+        if sqlite_path.is_some() && output_dir.is_some() {
+            return Err(EngineError::ConfigError(
+                "--sqlite and --output-dir cannot be combined".into(),
+            ));
+        }
+        if single_thread
+            && (shards_given
+                || concurrent_inputs
+                || report_shards
+                || lanes_per_shard > 1
+                || metrics_addr.is_some())
+        {
+            return Err(EngineError::ConfigError(
+                "--single-thread cannot be combined with --shards, --concurrent-inputs, --report-shards, --lanes-per-shard or --metrics-addr"
+                    .into(),
+            ));
+        }
+        if mmap && input_format != InputFormat::Csv {
+            return Err(EngineError::ConfigError(
+                "--mmap only reads CSV input".into(),
+            ));
+        }
+        if summary_only && (sqlite_path.is_some() || output_dir.is_some()) {
+            return Err(EngineError::ConfigError(
+                "--summary-only cannot be combined with --sqlite or --output-dir".into(),
+            ));
+        }
+
+        Ok(Config {
+            input_files,
+            concurrent_inputs,
+            merge_by_tx_id,
+            log_format,
+            shards,
+            sqlite_path,
+            output_dir,
+            stats_output,
+            dead_letter_retries,
+            thousands_sep,
+            max_rows,
+            max_bytes,
+            rate,
+            mmap,
+            fee_policy,
+            dispute_policy,
+            lock_policy,
+            global_unique_tx,
+            strict_ordering,
+            lenient_resolve,
+            dispute_amounts,
+            no_auto_create_on_withdrawal,
+            summary_only,
+            report_shards,
+            profile,
+            check_balance_nonnegative,
+            check_aggregate,
+            flexible,
+            allow_zero_amount,
+            comment_char,
+            explain,
+            has_header,
+            input_format,
+            accounts_filter,
+            checksum_output,
+            rejects_output,
+            single_thread,
+            lanes_per_shard,
+            hold_precision,
+            output_precision,
+            max_error_rate,
+            auto_freeze_above,
+            max_clients,
+            validate_schema_only,
+            metrics_addr,
+        })
+    }
+
+    /// Whether the run may use the single-threaded engine: the options asking for shards,
+    /// lanes or concurrent readers need the sharded one
+    pub fn allows_single_thread(&self) -> bool {
+        !self.concurrent_inputs
+            && !self.report_shards
+            && self.lanes_per_shard <= 1
+            && self.metrics_addr.is_none()
+    }
+
+    /// Whether `--shards auto` may pick the single-threaded engine for a small input. Its
+    /// single transaction log rejects an id reused by another client, which the sharded
+    /// engine only does with `--global-unique-tx`, so the choice must not depend on the size
+    /// of the input otherwise.
+    pub fn allows_auto_single_thread(&self) -> bool {
+        self.allows_single_thread() && self.global_unique_tx
+    }
+}
+
+fn parse_limit<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<u64, EngineError> {
+    let value = next_value(args, flag)?;
+    value
+        .parse()
+        .map_err(|_| EngineError::ConfigError(format!("Invalid value for {}: {}", flag, value)))
+}
+
+fn next_value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, EngineError> {
+    args.next()
+        .ok_or_else(|| EngineError::ConfigError(format!("Missing value for {}", flag)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_defaults_to_text_logging() {
+        let config = Config::from_args(args(&["input.csv"])).unwrap();
+
+        assert_eq!(config.input_files, vec!["input.csv"]);
+        assert!(!config.concurrent_inputs);
+        assert!(!config.merge_by_tx_id);
+        assert_eq!(config.log_format, LogFormat::Text);
+        assert_eq!(config.shards, ShardCount::Fixed(4));
+        assert_eq!(config.sqlite_path, None);
+        assert_eq!(config.output_dir, None);
+        assert_eq!(config.stats_output, None);
+        assert_eq!(config.dead_letter_retries, 0);
+        assert_eq!(config.thousands_sep, None);
+        assert_eq!(config.max_rows, None);
+        assert_eq!(config.max_bytes, None);
+        assert_eq!(config.rate, None);
+        assert!(!config.mmap);
+        assert_eq!(config.fee_policy, FeePolicy::Reject);
+        assert!(!config.global_unique_tx);
+        assert!(!config.strict_ordering);
+        assert!(!config.lenient_resolve);
+        assert!(!config.dispute_amounts);
+        assert!(!config.no_auto_create_on_withdrawal);
+        assert!(!config.summary_only);
+        assert!(!config.report_shards);
+        assert!(!config.profile);
+        assert!(!config.check_balance_nonnegative);
+        assert!(!config.check_aggregate);
+        assert!(!config.flexible);
+        assert!(!config.allow_zero_amount);
+        assert_eq!(config.comment_char, None);
+        assert_eq!(config.explain, None);
+        assert_eq!(config.has_header, HeaderMode::Present);
+        assert_eq!(config.input_format, InputFormat::Csv);
+        assert_eq!(config.accounts_filter, None);
+        assert_eq!(config.checksum_output, None);
+        assert_eq!(config.rejects_output, None);
+        assert!(!config.single_thread);
+        assert_eq!(config.lanes_per_shard, 0);
+        assert_eq!(config.hold_precision, None);
+        assert_eq!(config.output_precision, None);
+        assert_eq!(config.max_error_rate, None);
+        assert_eq!(config.auto_freeze_above, None);
+        assert_eq!(config.max_clients, None);
+        assert!(!config.validate_schema_only);
+        assert_eq!(config.metrics_addr, None);
+    }
+
+    #[test]
+    fn test_log_format_json() {
+        let config = Config::from_args(args(&["--log-format", "json", "input.csv"])).unwrap();
+
+        assert_eq!(config.input_files, vec!["input.csv"]);
+        assert_eq!(config.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn test_multiple_input_files() {
+        let config = Config::from_args(args(&[
+            "a.csv",
+            "--concurrent-inputs",
+            "b.csv",
+            "--global-unique-tx",
+            "--strict-ordering",
+            "--lenient-resolve",
+            "--dispute-amounts",
+            "--no-auto-create-on-withdrawal",
+            "--check-balance-nonnegative",
+            "--check-aggregate",
+            "--flexible",
+            "--allow-zero-amount",
+        ]))
+        .unwrap();
+
+        assert_eq!(config.input_files, vec!["a.csv", "b.csv"]);
+        assert!(config.concurrent_inputs);
+        assert!(config.global_unique_tx);
+        assert!(config.strict_ordering);
+        assert!(config.lenient_resolve);
+        assert!(config.dispute_amounts);
+        assert!(config.no_auto_create_on_withdrawal);
+        assert!(config.check_balance_nonnegative);
+        assert!(config.check_aggregate);
+        assert!(config.flexible);
+        assert!(config.allow_zero_amount);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_output() {
+        let config = Config::from_args(args(&["input.csv", "--sqlite", "out.db"])).unwrap();
+
+        assert_eq!(config.sqlite_path.as_deref(), Some("out.db"));
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    #[test]
+    fn test_sqlite_output_requires_feature() {
+        let result = Config::from_args(args(&["input.csv", "--sqlite", "out.db"]));
+
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_metrics_addr() {
+        let config =
+            Config::from_args(args(&["input.csv", "--metrics-addr", "0.0.0.0:9100"])).unwrap();
+        assert_eq!(config.metrics_addr.as_deref(), Some("0.0.0.0:9100"));
+        // The metrics are read from the shards
+        assert!(!config.allows_single_thread());
+        let result = Config::from_args(args(&[
+            "input.csv",
+            "--single-thread",
+            "--metrics-addr",
+            "0.0.0.0:9100",
+        ]));
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+    }
+
+    #[cfg(not(feature = "prometheus"))]
+    #[test]
+    fn test_metrics_addr_requires_feature() {
+        let result = Config::from_args(args(&["input.csv", "--metrics-addr", "0.0.0.0:9100"]));
+
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_shards() {
+        let config = Config::from_args(args(&["input.csv", "--shards", "8"])).unwrap();
+        assert_eq!(config.shards, ShardCount::Fixed(8));
+
+        let config = Config::from_args(args(&["input.csv", "--shards", "auto"])).unwrap();
+        assert_eq!(config.shards, ShardCount::Auto);
+
+        assert!(Config::from_args(args(&["input.csv", "--shards", "0"])).is_err());
+        assert!(Config::from_args(args(&["input.csv", "--shards", "many"])).is_err());
+    }
+
+    #[test]
+    fn test_output_dir() {
+        let config = Config::from_args(args(&["input.csv", "--output-dir", "out"])).unwrap();
+        assert_eq!(config.output_dir.as_deref(), Some("out"));
+
+        assert!(Config::from_args(args(&["input.csv", "--output-dir"])).is_err());
+    }
+
+    #[test]
+    fn test_stats_output() {
+        let config =
+            Config::from_args(args(&["input.csv", "--stats-output", "stats.json"])).unwrap();
+
+        assert_eq!(config.stats_output.as_deref(), Some("stats.json"));
+    }
+
+    #[test]
+    fn test_rejects_output() {
+        let config =
+            Config::from_args(args(&["input.csv", "--rejects-output", "rejects.csv"])).unwrap();
+
+        assert_eq!(config.rejects_output.as_deref(), Some("rejects.csv"));
+        assert!(Config::from_args(args(&["input.csv", "--rejects-output"])).is_err());
+    }
+
+    #[test]
+    fn test_summary_only() {
+        let config =
+            Config::from_args(args(&["input.csv", "--summary-only", "--report-shards"])).unwrap();
+        assert!(config.summary_only);
+        assert!(config.report_shards);
+
+        let result = Config::from_args(args(&[
+            "input.csv",
+            "--summary-only",
+            "--output-dir",
+            "out",
+        ]));
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_merge_by_tx_id() {
+        let config = Config::from_args(args(&["a.csv", "b.csv", "--merge-by-tx-id"])).unwrap();
+        assert!(config.merge_by_tx_id);
+
+        let result = Config::from_args(args(&[
+            "a.csv",
+            "b.csv",
+            "--merge-by-tx-id",
+            "--concurrent-inputs",
+        ]));
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_and_output_dir_are_exclusive() {
+        let result = Config::from_args(args(&[
+            "input.csv",
+            "--sqlite",
+            "out.db",
+            "--output-dir",
+            "out",
+        ]));
+
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_dead_letter_retries() {
+        let config = Config::from_args(args(&["input.csv", "--dead-letter-retries", "3"])).unwrap();
+
+        assert_eq!(config.dead_letter_retries, 3);
+    }
+
+    #[test]
+    fn test_thousands_sep() {
+        let config = Config::from_args(args(&["input.csv", "--thousands-sep", ","])).unwrap();
+        assert_eq!(config.thousands_sep, Some(','));
+
+        assert!(Config::from_args(args(&["input.csv", "--thousands-sep", "."])).is_err());
+        assert!(Config::from_args(args(&["input.csv", "--thousands-sep", ",,"])).is_err());
+    }
+
+    #[test]
+    fn test_comment_char() {
+        let config = Config::from_args(args(&["input.csv", "--comment-char", "#"])).unwrap();
+        assert_eq!(config.comment_char, Some(b'#'));
+
+        assert!(Config::from_args(args(&["input.csv", "--comment-char", ","])).is_err());
+        assert!(Config::from_args(args(&["input.csv", "--comment-char", "//"])).is_err());
+    }
+
+    #[test]
+    fn test_explain() {
+        let config = Config::from_args(args(&["input.csv", "--explain", "12345"])).unwrap();
+        assert_eq!(config.explain, Some(12345));
+
+        assert!(Config::from_args(args(&["input.csv", "--explain", "tx1"])).is_err());
+        assert!(Config::from_args(args(&["input.csv", "--explain"])).is_err());
+    }
+
+    #[test]
+    fn test_has_header() {
+        let config = Config::from_args(args(&["input.csv", "--has-header", "auto"])).unwrap();
+        assert_eq!(config.has_header, HeaderMode::Auto);
+
+        let result = Config::from_args(args(&["input.csv", "--has-header", "maybe"]));
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_accounts_filter() {
+        let config =
+            Config::from_args(args(&["input.csv", "--accounts-filter", "clients.txt"])).unwrap();
+        assert_eq!(config.accounts_filter.as_deref(), Some("clients.txt"));
+        assert!(Config::from_args(args(&["input.csv", "--accounts-filter"])).is_err());
+    }
+
+    #[test]
+    fn test_single_thread() {
+        let config = Config::from_args(args(&["input.csv", "--single-thread"])).unwrap();
+        assert!(config.single_thread);
+        assert!(config.allows_single_thread());
+
+        for conflicting in ["--concurrent-inputs", "--report-shards"] {
+            let config = Config::from_args(args(&["input.csv", conflicting])).unwrap();
+            assert!(!config.allows_single_thread());
+            let result = Config::from_args(args(&["input.csv", "--single-thread", conflicting]));
+            assert!(matches!(result, Err(EngineError::ConfigError(_))));
+        }
+        let result = Config::from_args(args(&["input.csv", "--single-thread", "--shards", "2"]));
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+
+        // Automatically only where both engines treat reused ids alike
+        let config = Config::from_args(args(&["input.csv", "--shards", "auto"])).unwrap();
+        assert!(!config.allows_auto_single_thread());
+        let config = Config::from_args(args(&[
+            "input.csv",
+            "--shards",
+            "auto",
+            "--global-unique-tx",
+        ]))
+        .unwrap();
+        assert!(config.allows_auto_single_thread());
+    }
+
+    #[test]
+    fn test_lanes_per_shard() {
+        let config = Config::from_args(args(&["input.csv", "--lanes-per-shard", "4"])).unwrap();
+        assert_eq!(config.lanes_per_shard, 4);
+        // Lanes only exist within shards
+        assert!(!config.allows_single_thread());
+        let result = Config::from_args(args(&[
+            "input.csv",
+            "--single-thread",
+            "--lanes-per-shard",
+            "4",
+        ]));
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+
+        let result = Config::from_args(args(&["input.csv", "--lanes-per-shard", "-1"]));
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_checksum_output() {
+        let config = Config::from_args(args(&["input.csv", "--checksum-output", "-"])).unwrap();
+        assert_eq!(config.checksum_output.as_deref(), Some("-"));
+    }
+
+    #[test]
+    fn test_profile() {
+        let config = Config::from_args(args(&["input.csv", "--profile"])).unwrap();
+        assert!(config.profile);
+    }
+
+    #[test]
+    fn test_output_precision() {
+        let config = Config::from_args(args(&["input.csv", "--output-precision", "2"])).unwrap();
+        assert_eq!(config.output_precision, Some(2));
+
+        for invalid in ["5", "-1", "x"] {
+            let result = Config::from_args(args(&["input.csv", "--output-precision", invalid]));
+            assert!(
+                matches!(result, Err(EngineError::ConfigError(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_hold_precision() {
+        let config = Config::from_args(args(&["input.csv", "--hold-precision", "8"])).unwrap();
+        assert_eq!(config.hold_precision, Some(8));
+
+        for invalid in ["3", "29", "-1", "x"] {
+            let result = Config::from_args(args(&["input.csv", "--hold-precision", invalid]));
+            assert!(
+                matches!(result, Err(EngineError::ConfigError(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_input_format() {
+        let config = Config::from_args(args(&["input.jsonl", "--input-format", "json"])).unwrap();
+        assert_eq!(config.input_format, InputFormat::Json);
+
+        let result = Config::from_args(args(&["input.xml", "--input-format", "xml"]));
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_max_error_rate() {
+        let config = Config::from_args(args(&["input.csv", "--max-error-rate", "0.05"])).unwrap();
+        assert_eq!(config.max_error_rate, Some(0.05));
+
+        for invalid in ["1.5", "-0.1", "NaN", "5%"] {
+            let result = Config::from_args(args(&["input.csv", "--max-error-rate", invalid]));
+            assert!(
+                matches!(result, Err(EngineError::ConfigError(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_schema_only() {
+        let config = Config::from_args(args(&["input.csv", "--validate-schema-only"])).unwrap();
+        assert!(config.validate_schema_only);
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let config = Config::from_args(args(&["input.csv", "--max-clients", "100"])).unwrap();
+        assert_eq!(config.max_clients, Some(100));
+
+        for invalid in ["0", "-1", "lots"] {
+            let result = Config::from_args(args(&["input.csv", "--max-clients", invalid]));
+            assert!(
+                matches!(result, Err(EngineError::ConfigError(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_auto_freeze_above() {
+        let config =
+            Config::from_args(args(&["input.csv", "--auto-freeze-above", "10000.50"])).unwrap();
+        assert_eq!(config.auto_freeze_above, Some(Decimal::new(1000050, 2)));
+
+        for invalid in ["-1", "lots", ""] {
+            let result = Config::from_args(args(&["input.csv", "--auto-freeze-above", invalid]));
+            assert!(
+                matches!(result, Err(EngineError::ConfigError(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_command() {
+        let command = Command::from_args(args(&["verify", "golden.csv", "out.csv"])).unwrap();
+        assert_eq!(
+            command,
+            Command::Verify {
+                expected: "golden.csv".into(),
+                actual: "out.csv".into(),
+            }
+        );
+
+        let command = Command::from_args(args(&["input.csv"])).unwrap();
+        assert!(matches!(command, Command::Run(_)));
+
+        assert!(Command::from_args(args(&["verify", "golden.csv"])).is_err());
+        assert!(Command::from_args(args(&["verify", "a.csv", "b.csv", "c.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_input_limits() {
+        let config = Config::from_args(args(&[
+            "input.csv",
+            "--max-rows",
+            "1000",
+            "--max-bytes",
+            "65536",
+        ]))
+        .unwrap();
+        assert_eq!(config.max_rows, Some(1000));
+        assert_eq!(config.max_bytes, Some(65536));
+
+        assert!(Config::from_args(args(&["input.csv", "--max-rows", "lots"])).is_err());
+        assert!(Config::from_args(args(&["input.csv", "--max-bytes"])).is_err());
+    }
+
+    #[test]
+    fn test_rate() {
+        let config = Config::from_args(args(&["input.csv", "--rate", "500"])).unwrap();
+        assert_eq!(config.rate, Some(500));
+
+        assert!(Config::from_args(args(&["input.csv", "--rate", "0"])).is_err());
+        assert!(Config::from_args(args(&["input.csv", "--rate", "fast"])).is_err());
+    }
+
+    #[test]
+    fn test_fee_policy() {
+        let config =
+            Config::from_args(args(&["input.csv", "--fee-policy", "allow-negative"])).unwrap();
+        assert_eq!(config.fee_policy, FeePolicy::AllowNegative);
+
+        let config = Config::from_args(args(&["input.csv", "--fee-policy", "reject"])).unwrap();
+        assert_eq!(config.fee_policy, FeePolicy::Reject);
+
+        assert!(Config::from_args(args(&["input.csv", "--fee-policy", "waive"])).is_err());
+        assert!(Config::from_args(args(&["input.csv", "--fee-policy"])).is_err());
+    }
+
+    #[test]
+    fn test_dispute_policy() {
+        let config = Config::from_args(args(&["input.csv"])).unwrap();
+        assert_eq!(config.dispute_policy, DisputePolicy::DepositsAndWithdrawals);
+
+        let config =
+            Config::from_args(args(&["input.csv", "--dispute-policy", "deposits-only"])).unwrap();
+        assert_eq!(config.dispute_policy, DisputePolicy::DepositsOnly);
+
+        assert!(Config::from_args(args(&["input.csv", "--dispute-policy", "none"])).is_err());
+    }
+
+    #[test]
+    fn test_lock_policy() {
+        let config = Config::from_args(args(&["input.csv"])).unwrap();
+        assert_eq!(config.lock_policy, LockPolicy::FreezeAll);
+
+        let config =
+            Config::from_args(args(&["input.csv", "--lock-policy", "settle-disputes"])).unwrap();
+        assert_eq!(config.lock_policy, LockPolicy::SettleDisputes);
+
+        assert!(Config::from_args(args(&["input.csv", "--lock-policy", "thaw"])).is_err());
+    }
+
+    #[test]
+    fn test_mmap() {
+        let result = Config::from_args(args(&["input.csv", "--mmap"]));
+
+        if cfg!(feature = "mmap") {
+            assert!(result.unwrap().mmap);
+        } else {
+            assert!(matches!(result, Err(EngineError::ConfigError(_))));
+        }
+    }
+
+    #[test]
+    fn test_missing_input_file() {
+        let result = Config::from_args(args(&["--log-format", "json"]));
+
+        assert!(matches!(result, Err(EngineError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_invalid_and_missing_option_values() {
+        assert!(Config::from_args(args(&["input.csv", "--log-format", "xml"])).is_err());
+        assert!(Config::from_args(args(&["input.csv", "--log-format"])).is_err());
+        assert!(Config::from_args(args(&["input.csv", "--unknown"])).is_err());
+        assert!(Config::from_args(args(&["input.csv", "--dead-letter-retries", "-1"])).is_err());
+    }
+}
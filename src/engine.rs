@@ -1,13 +1,100 @@
+#[cfg(feature = "lock-free-reads")]
+use crate::atomic_account::AtomicAccount;
+use crate::checksum;
 use crate::errors::EngineError;
-use crate::models::{ClientAccount, Transaction, TransactionType};
+use crate::models::{
+    check_output_amount, display_amount, display_amount_at, AccountOp, ClientAccount, ClientId,
+    DisputePolicy, FeePolicy, LockPolicy, Transaction, TransactionType, MAX_DISPLAY_PRECISION,
+};
+use crate::observer::{self, NoopObserver, TransactionEvent, TransactionObserver};
+use crate::profile::{self, Phase, Profile};
+use crate::reconciliation::{AggregateTotal, FlowTotals, ReconciliationReport};
+use crate::rejects::RejectLog;
+use crate::snapshot::{AccountSnapshot, Snapshot, TransactionSnapshot};
+use crate::statistics::RunSummary;
+use crate::verify::{self, AccountDiff, AccountRow};
 use async_std::io::stdout;
-use std::collections::{HashMap, HashSet};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex, Notify};
+use dashmap::mapref::entry::Entry;
+use dashmap::{DashMap, DashSet};
+use futures::io::{AsyncWrite, BufWriter};
+use futures::stream::{Stream, StreamExt};
+use rust_decimal::Decimal;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::hash::BuildHasherDefault;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio_util::sync::CancellationToken;
 
-type ClientShard = Arc<Mutex<ShardState>>;
+/// Bytes of account output gathered before each write to stdout or a file
+pub const OUTPUT_BUFFER_SIZE: usize = 256 * 1024;
+
+type ClientShard = Arc<ShardState>;
 type TxChannel = mpsc::UnboundedSender<Transaction>;
+type AccountLock = Arc<AccountEntry>;
+
+/// An account of a shard, behind its own lock
+struct AccountEntry {
+    account: Mutex<ClientAccount>,
+    /// Copy of `account` republished after every change, read by `get_account` without
+    /// taking the lock
+    #[cfg(feature = "lock-free-reads")]
+    published: AtomicAccount,
+}
+
+impl AccountEntry {
+    fn new(account: ClientAccount) -> Self {
+        Self {
+            #[cfg(feature = "lock-free-reads")]
+            published: AtomicAccount::new(&account),
+            account: Mutex::new(account),
+        }
+    }
+}
+
+/// Count of the accounts opened by all shards of an engine, capped by
+/// `EngineOptions::max_clients`. Shared by the shards, so a client is only admitted while the
+/// engine as a whole has room for it.
+#[derive(Debug)]
+struct ClientCap {
+    max: usize,
+    opened: AtomicUsize,
+}
+
+impl ClientCap {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            opened: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count a new account for `client`, or refuse it once `max` are open
+    fn admit(&self, client: ClientId) -> Result<(), EngineError> {
+        self.opened
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |opened| {
+                (opened < self.max).then_some(opened + 1)
+            })
+            .map(|_| ())
+            .map_err(|_| EngineError::ClientLimitExceeded {
+                client,
+                max: self.max,
+            })
+    }
+}
+
+/// Hasher of the maps of a shard. `DefaultHasher::new` always uses the same keys, unlike the
+/// `RandomState` of a default `DashMap`, so iterating a shard's accounts (and therefore the
+/// order of the output rows) is the same on every run over the same input.
+type FixedState = BuildHasherDefault<DefaultHasher>;
 
 #[derive(Clone)]
 pub struct ShardedEngine {
@@ -16,51 +103,971 @@ pub struct ShardedEngine {
     notify: Arc<Notify>,
     completed_shards: Arc<AtomicUsize>,
     shutdown: Arc<AtomicBool>,
+    cancel: CancellationToken,
+    /// Transactions sent to a shard so far
+    routed: Arc<AtomicUsize>,
+    /// Client of every recorded transaction id routed so far, across shards (opt-in)
+    seen_tx_ids: Option<Arc<DashMap<u32, ClientId>>>,
+    /// Events of applied transactions, shared by all shards (opt-in)
+    events: Option<broadcast::Sender<TransactionEvent>>,
+    /// Clients whose transactions are routed, all others are dropped (opt-in)
+    client_filter: Option<Arc<HashSet<ClientId>>>,
+    /// Decimal places of the amounts in the accounts CSV
+    output_precision: u32,
 }
 
 /// ShardState holds both the accounts and the transaction log for a shard.
+///
+/// Nothing locks the shard as a whole: every account has its own lock and the transaction
+/// log is a concurrent map, so transactions of different clients can be applied in parallel
+/// (see `EngineOptions::lanes_per_shard`) and reading an account never waits for another
+/// client. The transactions of one client must be applied one at a time, in order.
 pub struct ShardState {
-    accounts: HashMap<u16, ClientAccount>,
-    transactions: HashMap<u32, Transaction>,
-    processed_transactions: HashSet<Transaction>,
+    accounts: DashMap<ClientId, AccountLock, FixedState>,
+    transactions: DashMap<u32, Transaction, FixedState>,
+    processed_transactions: DashSet<Transaction, FixedState>,
+    unseen_references: DashSet<u32, FixedState>,
+    /// Amount held by each open dispute naming its own amount, with `dispute_amounts`
+    disputed_amounts: DashMap<u32, Decimal, FixedState>,
+    /// Latest timestamp received for each client, only tracked for timestamped input
+    last_timestamps: DashMap<ClientId, u64, FixedState>,
+    /// Highest sequence number received for each client, only tracked for numbered input
+    last_seqs: DashMap<ClientId, u64, FixedState>,
+    tally: Mutex<ShardTally>,
+    /// Held for reading while a transaction is applied, and for writing to pause the shard
+    /// for a consistent copy, see `ShardedEngine::snapshot`
+    gate: RwLock<()>,
+    /// Transactions finally rejected, readable while the shard runs without locking the tally
+    rejected: AtomicUsize,
+    /// Highest id of a deposit or withdrawal received, only checked with `strict_ordering`
+    latest_tx_id: AtomicU32,
+    /// Set when the worker of the shard stopped before the end of its input
+    failed: AtomicBool,
+    strict_ordering: bool,
+    precision: u32,
+    retry_limit: u32,
+    fee_policy: FeePolicy,
+    dispute_policy: DisputePolicy,
+    lock_policy: LockPolicy,
+    auto_freeze_above: Option<Decimal>,
+    lenient_resolve: bool,
+    dispute_amounts: bool,
+    no_auto_create_on_withdrawal: bool,
+    /// Shared with the other shards of the engine, only set with `EngineOptions::max_clients`
+    client_cap: Option<Arc<ClientCap>>,
+    profile: Option<Arc<Profile>>,
+    rejects: Option<Arc<RejectLog>>,
+    observer: Arc<dyn TransactionObserver>,
+    events: Option<broadcast::Sender<TransactionEvent>>,
+}
+
+/// Counters and queues of a shard updated by every transaction, whatever its client. Only
+/// locked for the update itself.
+#[derive(Default)]
+struct ShardTally {
+    flows: FlowTotals,
+    /// Overflow-checked net of the flows, only kept with `EngineOptions::check_aggregate`
+    aggregate: Option<AggregateTotal>,
+    dead_letters: Vec<(Transaction, EngineError)>,
+    out_of_order: usize,
+    /// Transactions stamped earlier than one already received for the same client
+    timestamp_regressions: usize,
+    /// Transactions numbered past the next sequence number of their client
+    seq_gaps: usize,
+    /// Transactions received from the router, applied or not
+    received: usize,
+    summary: RunSummary,
+}
+
+/// Lock a mutex of the shard state. It is only poisoned if a transaction panicked midway,
+/// the state is then unusable anyway.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect("shard state poisoned")
+}
+
+impl ShardState {
+    pub fn new(observer: Arc<dyn TransactionObserver>) -> Self {
+        Self {
+            accounts: DashMap::default(),
+            transactions: DashMap::default(),
+            processed_transactions: DashSet::default(),
+            unseen_references: DashSet::default(),
+            disputed_amounts: DashMap::default(),
+            last_timestamps: DashMap::default(),
+            last_seqs: DashMap::default(),
+            tally: Mutex::new(ShardTally::default()),
+            gate: RwLock::new(()),
+            rejected: AtomicUsize::new(0),
+            latest_tx_id: AtomicU32::new(0),
+            failed: AtomicBool::new(false),
+            strict_ordering: false,
+            precision: MAX_DISPLAY_PRECISION,
+            retry_limit: 0,
+            fee_policy: FeePolicy::default(),
+            dispute_policy: DisputePolicy::default(),
+            lock_policy: LockPolicy::default(),
+            auto_freeze_above: None,
+            lenient_resolve: false,
+            dispute_amounts: false,
+            no_auto_create_on_withdrawal: false,
+            client_cap: None,
+            profile: None,
+            rejects: None,
+            observer,
+            events: None,
+        }
+    }
+
+    /// Change the number of decimal places amounts are applied with, e.g. after reloading
+    /// transactions recorded under a different precision
+    pub fn set_precision(&mut self, precision: u32) {
+        self.precision = precision;
+    }
+
+    /// Take the settings of `options` that apply within a shard
+    pub(crate) fn configure(&mut self, options: &EngineOptions) {
+        self.retry_limit = options.dead_letter_retries;
+        self.fee_policy = options.fee_policy;
+        self.dispute_policy = options.dispute_policy;
+        self.lock_policy = options.lock_policy;
+        self.strict_ordering = options.strict_ordering;
+        self.auto_freeze_above = options.auto_freeze_above;
+        self.lenient_resolve = options.lenient_resolve;
+        self.dispute_amounts = options.dispute_amounts;
+        self.no_auto_create_on_withdrawal = options.no_auto_create_on_withdrawal;
+        self.client_cap = options.max_clients.map(|max| Arc::new(ClientCap::new(max)));
+        self.profile = options.profile.clone();
+        self.rejects = options.rejects.clone();
+        self.precision = options.hold_precision.unwrap_or(MAX_DISPLAY_PRECISION);
+        if options.check_aggregate {
+            lock(&self.tally).aggregate = Some(AggregateTotal::new(self.precision));
+        }
+    }
+
+    /// Apply a transaction handed to this shard, recording the outcome in the run summary,
+    /// or in the dead-letter queue for a retryable failure
+    pub(crate) fn receive(&self, transaction: Transaction) {
+        let _applying = self.gate.read().expect("shard state poisoned");
+        lock(&self.tally).received += 1;
+        self.check_timestamp(&transaction);
+        self.check_seq(&transaction);
+        let new_client = self.is_discardable_client(transaction.client);
+        let result = profile::time(self.profile.as_deref(), Phase::Process, || {
+            ShardedEngine::process_transaction_in_shard(self, transaction)
+        });
+        match result {
+            Ok(()) => lock(&self.tally)
+                .summary
+                .record_applied(transaction.tx_type),
+            Err(e) => {
+                if new_client {
+                    self.discard_account(transaction.client);
+                }
+                self.record_failure(transaction, e)
+            }
+        }
+    }
+
+    /// Amount held by the open dispute of `tx`: the amount named by the dispute, or the
+    /// whole transaction
+    fn held_amount(&self, tx: &Transaction) -> Option<Decimal> {
+        self.disputed_amounts
+            .get(&tx.tx_id)
+            .map(|held| *held)
+            .or(tx.amount)
+    }
+
+    /// Whether the account of `client` would be opened by its next transaction and should be
+    /// dropped again if that transaction fails, see `EngineOptions::no_auto_create_on_withdrawal`
+    fn is_discardable_client(&self, client: ClientId) -> bool {
+        self.no_auto_create_on_withdrawal && !self.accounts.contains_key(&client)
+    }
+
+    /// Drop the account opened for a client whose first transaction was rejected, as long as
+    /// nothing changed it, giving its place under `client_cap` back
+    fn discard_account(&self, client: ClientId) {
+        let pristine = ClientAccount::new();
+        let discarded = self
+            .accounts
+            .remove_if(&client, |_, entry| *lock(&entry.account) == pristine);
+        if let (Some(_), Some(cap)) = (discarded, &self.client_cap) {
+            cap.opened.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The lock of `client`'s account, opening the account if the client is new and the
+    /// `client_cap` leaves room for it
+    fn account_lock(&self, client: ClientId) -> Result<AccountLock, EngineError> {
+        if let Some(account) = self.accounts.get(&client) {
+            return Ok(Arc::clone(&account));
+        }
+        let account = match self.accounts.entry(client) {
+            Entry::Occupied(entry) => Arc::clone(entry.get()),
+            Entry::Vacant(entry) => {
+                if let Some(cap) = &self.client_cap {
+                    cap.admit(client)?;
+                }
+                Arc::clone(&entry.insert(Arc::new(AccountEntry::new(ClientAccount::new()))))
+            }
+        };
+        Ok(account)
+    }
+
+    /// A copy of `client`'s account, if the client has been seen. With the `lock-free-reads`
+    /// feature the copy is read from its published copy, without waiting for the account lock.
+    pub(crate) fn account(&self, client: ClientId) -> Option<ClientAccount> {
+        let entry = self
+            .accounts
+            .get(&client)
+            .map(|account| Arc::clone(&account))?;
+        #[cfg(feature = "lock-free-reads")]
+        let account = entry.published.load();
+        #[cfg(not(feature = "lock-free-reads"))]
+        let account = lock(&entry.account).clone();
+        Some(account)
+    }
+
+    /// A copy of every account, each taken under its own lock, in no particular order
+    pub(crate) fn accounts(&self) -> Vec<(ClientId, ClientAccount)> {
+        self.accounts
+            .iter()
+            .map(|entry| (*entry.key(), lock(&entry.account).clone()))
+            .collect()
+    }
+
+    /// Money flows applied to the accounts of this shard
+    pub(crate) fn flows(&self) -> FlowTotals {
+        lock(&self.tally).flows
+    }
+
+    pub(crate) fn aggregate(&self) -> Option<AggregateTotal> {
+        lock(&self.tally).aggregate
+    }
+
+    /// Summary of the transactions received by this shard, with its locked accounts
+    pub(crate) fn run_summary(&self) -> RunSummary {
+        let mut summary = lock(&self.tally).summary.clone();
+        summary.locked_accounts = self
+            .accounts
+            .iter()
+            .filter(|entry| lock(&entry.account).locked)
+            .count();
+        summary
+    }
+
+    /// Number of transactions this shard finally rejected so far
+    pub(crate) fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// The transaction `tx_id` recorded by this shard, with the balances of its client
+    pub(crate) fn explain(&self, tx_id: u32) -> Option<Explanation> {
+        let transaction = *self.transactions.get(&tx_id)?;
+        let account = self.account(transaction.client)?;
+        Some(Explanation {
+            transaction,
+            account,
+        })
+    }
+
+    /// Count and log a transaction stamped earlier than one already received for its client.
+    /// The transaction is still applied in arrival order.
+    fn check_timestamp(&self, transaction: &Transaction) {
+        let Some(timestamp) = transaction.timestamp else {
+            return;
+        };
+        let mut latest = self
+            .last_timestamps
+            .entry(transaction.client)
+            .or_insert(timestamp);
+        if timestamp < *latest {
+            lock(&self.tally).timestamp_regressions += 1;
+            log::warn!(
+                tx_id = transaction.tx_id,
+                client = transaction.client;
+                "Transaction {} is stamped {}, before {} already received for the client, input is likely out of order",
+                transaction.tx_id,
+                timestamp,
+                *latest
+            );
+        } else {
+            *latest = timestamp;
+        }
+    }
+
+    /// Count a transaction skipping sequence numbers of its client, whose transactions were
+    /// likely dropped upstream. The first number received for a client is taken as is, the
+    /// input may start midway through the producer's numbering.
+    fn check_seq(&self, transaction: &Transaction) {
+        let Some(seq) = transaction.seq else {
+            return;
+        };
+        let mut latest = self.last_seqs.entry(transaction.client).or_insert(seq);
+        if seq > latest.saturating_add(1) {
+            lock(&self.tally).seq_gaps += 1;
+            log::warn!(
+                tx_id = transaction.tx_id,
+                client = transaction.client;
+                "Transaction {} is number {} of its client, after {}: {} transactions are missing",
+                transaction.tx_id,
+                seq,
+                *latest,
+                seq - *latest - 1
+            );
+        }
+        *latest = seq.max(*latest);
+    }
+
+    /// Keep a failed transaction for a later retry if its error may be transient,
+    /// otherwise log and drop it
+    fn record_failure(&self, transaction: Transaction, err: EngineError) {
+        if self.retry_limit > 0 && err.is_retryable() {
+            log::debug!(
+                tx_id = transaction.tx_id,
+                client = transaction.client;
+                "Dead-lettered for retry: {}", err
+            );
+            lock(&self.tally).dead_letters.push((transaction, err));
+        } else {
+            self.reject(&transaction, &err);
+        }
+    }
+
+    /// Count and log a transaction that will not be applied
+    fn reject(&self, transaction: &Transaction, err: &EngineError) {
+        let unfunded = self.is_unfunded_withdrawal(transaction, err);
+        let mut tally = lock(&self.tally);
+        tally.summary.record_rejection(err);
+        if unfunded {
+            tally.summary.unfunded_withdrawals += 1;
+        }
+        drop(tally);
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        if let Some(rejects) = &self.rejects {
+            rejects.record_transaction(transaction, err);
+        }
+        log::error!(
+            tx_id = transaction.tx_id,
+            client = transaction.client;
+            "{}", err
+        );
+    }
+
+    /// Whether `err` rejects a withdrawal for insufficient funds on an account holding no funds
+    /// at all, most likely a withdrawal arriving before the deposit funding it
+    fn is_unfunded_withdrawal(&self, transaction: &Transaction, err: &EngineError) -> bool {
+        transaction.tx_type == TransactionType::Withdrawal
+            && matches!(err, EngineError::InsufficientFunds { .. })
+            && self
+                .account(transaction.client)
+                .is_none_or(|account| account.total.is_zero() && account.held.is_zero())
+    }
+
+    /// Give dead-lettered transactions up to `retry_limit` more passes, now that the rest of
+    /// the input has been applied. Transactions still failing afterwards are logged and dropped.
+    pub(crate) fn retry_dead_letters(&self) {
+        let _applying = self.gate.read().expect("shard state poisoned");
+        for _ in 0..self.retry_limit {
+            let dead_letters = std::mem::take(&mut lock(&self.tally).dead_letters);
+            if dead_letters.is_empty() {
+                break;
+            }
+            for (transaction, _) in dead_letters {
+                // The failed attempt was recorded as processed and would be seen as a duplicate
+                self.processed_transactions.remove(&transaction);
+                let new_client = self.is_discardable_client(transaction.client);
+                let result = ShardedEngine::process_transaction_in_shard(self, transaction);
+                if result.is_err() && new_client {
+                    self.discard_account(transaction.client);
+                }
+                match result {
+                    Ok(()) => lock(&self.tally)
+                        .summary
+                        .record_applied(transaction.tx_type),
+                    Err(err) if err.is_retryable() => {
+                        lock(&self.tally).dead_letters.push((transaction, err))
+                    }
+                    Err(err) => self.reject(&transaction, &err),
+                }
+            }
+        }
+        let dead_letters = std::mem::take(&mut lock(&self.tally).dead_letters);
+        for (transaction, err) in dead_letters {
+            self.reject(&transaction, &err);
+        }
+    }
+}
+
+/// Writer counting the complete lines accepted by the writer it wraps, so a failed output
+/// can tell how far it got despite the buffers in front of it
+struct LineCounter<W> {
+    inner: W,
+    lines: usize,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for LineCounter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.lines += buf[..written].iter().filter(|byte| **byte == b'\n').count();
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Apply the transactions of one lane of a shard (see `EngineOptions::lanes_per_shard`) in the
+/// order received, until the shard worker drops the sender. On cancellation the transaction
+/// in progress completes, queued ones are discarded.
+async fn run_lane(
+    shard: ClientShard,
+    mut rx: mpsc::UnboundedReceiver<Transaction>,
+    cancel: CancellationToken,
+) {
+    let mut guard = TaskGuard {
+        shard: Arc::clone(&shard),
+        completion: None,
+        finished: false,
+    };
+    loop {
+        let transaction = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => break,
+            received = rx.recv() => match received {
+                Some(transaction) => transaction,
+                None => break,
+            },
+        };
+        shard.receive(transaction);
+    }
+    guard.finished = true;
+}
+
+/// Held by the worker and the lane tasks of a shard. Dropped before `finished` is set, i.e.
+/// when its task panicked, it marks the shard as failed. The worker's guard also counts it as
+/// completed, whether it reached the end of its input or not, so
+/// `ShardedEngine::wait_for_completion` never waits for a dead worker.
+struct TaskGuard {
+    shard: ClientShard,
+    /// Count of completed workers and its notify, for the worker's guard
+    completion: Option<(Arc<AtomicUsize>, Arc<Notify>)>,
+    finished: bool,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.shard.failed.store(true, Ordering::SeqCst);
+        }
+        if let Some((completed_shards, notify)) = &self.completion {
+            completed_shards.fetch_add(1, Ordering::SeqCst);
+            notify.notify_one();
+        }
+    }
+}
+
+/// Counters of a `ShardedEngine` readable while it runs and after, see `ShardedEngine::stats`
+#[derive(Clone)]
+pub struct EngineStats {
+    shards: Vec<ClientShard>,
+    routed: Arc<AtomicUsize>,
+}
+
+impl EngineStats {
+    /// Same as `ShardedEngine::run_summary`
+    pub fn run_summary(&self) -> RunSummary {
+        let mut summary = RunSummary::default();
+        for shard in &self.shards {
+            summary.merge(&shard.run_summary());
+        }
+        summary
+    }
+
+    /// Number of client accounts across all shards
+    pub fn account_count(&self) -> usize {
+        self.shards.iter().map(|shard| shard.accounts.len()).sum()
+    }
+
+    /// Transactions routed and not yet taken by their shard. Those still queued when the
+    /// engine is cancelled are never taken and stay counted.
+    pub fn in_flight(&self) -> usize {
+        let received: usize = self
+            .shards
+            .iter()
+            .map(|shard| lock(&shard.tally).received)
+            .sum();
+        self.routed.load(Ordering::Relaxed).saturating_sub(received)
+    }
+}
+
+/// Transaction ids referenced by a dispute, resolve or chargeback before being recorded
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OrderingReport {
+    /// Referenced ids whose deposit or withdrawal arrived afterwards: out-of-order input
+    pub out_of_order: usize,
+    /// Referenced ids that never appeared in the input
+    pub never_seen: usize,
+    /// Transactions whose timestamp is earlier than one already received for the same client
+    pub timestamp_regressions: usize,
+    /// Transactions whose sequence number skips numbers of their client, each gap pointing to
+    /// transactions dropped upstream
+    pub seq_gaps: usize,
+}
+
+/// A recorded transaction and the current account of its client, see `ShardedEngine::explain`
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    pub transaction: Transaction,
+    pub account: ClientAccount,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let transaction = &self.transaction;
+        writeln!(
+            f,
+            "tx {}: {} of {} for client {}, {}",
+            transaction.tx_id,
+            transaction.tx_type,
+            transaction.amount.map_or_else(String::new, display_amount),
+            transaction.client,
+            if transaction.under_dispute {
+                "under dispute"
+            } else {
+                "not disputed"
+            }
+        )?;
+        write!(
+            f,
+            "client {}: available {}, held {}, total {}, locked {}",
+            transaction.client,
+            display_amount(self.account.available),
+            display_amount(self.account.held),
+            display_amount(self.account.total),
+            self.account.locked
+        )
+    }
+}
+
+/// Optional behaviour of a `ShardedEngine`
+#[derive(Debug, Clone, Default)]
+pub struct EngineOptions {
+    /// Number of retry passes over the dead-letter queue once the input is exhausted.
+    /// Only retryable failures (e.g. a dispute arriving before its deposit) are queued;
+    /// 0 disables the queue and every failure is logged and dropped immediately.
+    pub dead_letter_retries: u32,
+    /// Whether a fee larger than the available funds is rejected or overdraws the account
+    pub fee_policy: FeePolicy,
+    /// Whether withdrawals can be disputed as well as deposits
+    pub dispute_policy: DisputePolicy,
+    /// Which transactions an account locked by a chargeback still accepts
+    pub lock_policy: LockPolicy,
+    /// Lock an account, pending review, once a deposit takes its available funds above this
+    /// amount. The account then rejects transactions like one locked by a chargeback.
+    pub auto_freeze_above: Option<Decimal>,
+    /// Accept a resolve of a recorded transaction that is not under dispute as a no-op,
+    /// counted in `RunSummary::redundant_resolves`, instead of rejecting it
+    pub lenient_resolve: bool,
+    /// Hold the amount given on a dispute, up to the amount of the disputed transaction,
+    /// instead of the whole amount; its resolve or chargeback then settles that amount. A
+    /// dispute without an amount still holds the whole transaction.
+    pub dispute_amounts: bool,
+    /// Leave no account behind for a client whose first transaction is rejected, e.g. a
+    /// withdrawal or dispute of a client never seen before, instead of a zero balance row
+    pub no_auto_create_on_withdrawal: bool,
+    /// Most client accounts the engine opens, across all shards. A transaction of a new
+    /// client past the cap is rejected with `EngineError::ClientLimitExceeded` and the client
+    /// gets no account; clients restored from a snapshot count towards the cap.
+    pub max_clients: Option<usize>,
+    /// Reject a deposit, withdrawal, fee or adjustment reusing the id of one already routed,
+    /// even for another client. Without it ids are only unique within a shard.
+    pub global_unique_tx: bool,
+    /// Reject a dispute, resolve or chargeback referencing an id higher than any deposit or
+    /// withdrawal received so far by its shard with `EngineError::FutureReference`, instead
+    /// of waiting for the transaction (see `dead_letter_retries`)
+    pub strict_ordering: bool,
+    /// Capacity of the channel behind `ShardedEngine::subscribe`, `None` disables events.
+    /// A subscriber falling further behind than this loses the oldest events.
+    pub event_capacity: Option<usize>,
+    /// Only route transactions of these clients. Transactions of other clients, including
+    /// disputes, resolves and chargebacks, are dropped before reaching a shard, so those
+    /// clients never get an account.
+    pub client_filter: Option<HashSet<ClientId>>,
+    /// Decimal places kept in balances and disputed amounts, `None` keeps
+    /// `MAX_DISPLAY_PRECISION`. Output is always cut to `MAX_DISPLAY_PRECISION`, so a higher
+    /// value only avoids rounding losses piling up over many small amounts.
+    pub hold_precision: Option<u32>,
+    /// Decimal places of the amounts written by `write_accounts_to` and the other CSV
+    /// outputs, `None` keeps `MAX_DISPLAY_PRECISION`, which is also the most allowed.
+    /// Amounts are truncated like input amounts, independently of `hold_precision`.
+    pub output_precision: Option<u32>,
+    /// Tasks applying the transactions of each shard, each one taking a fixed subset of the
+    /// shard's clients, so a slow transaction (e.g. a slow observer callback) only holds up
+    /// the clients of its lane. The transactions of a client are still applied one at a time,
+    /// in order. 0 and 1 both apply a shard's transactions on its worker task.
+    pub lanes_per_shard: usize,
+    /// Keep an overflow-checked `AggregateTotal` of every flow next to the `Decimal` ones,
+    /// compared with the account totals by `PaymentEngine::check_aggregate`
+    pub check_aggregate: bool,
+    /// Time spent applying transactions is added to this profile (`--profile`)
+    pub profile: Option<Arc<Profile>>,
+    /// Transactions finally rejected are recorded here (`--rejects-output`)
+    pub rejects: Option<Arc<RejectLog>>,
+}
+
+impl EngineOptions {
+    /// Decimal places of output amounts, at most `MAX_DISPLAY_PRECISION`
+    pub(crate) fn output_precision(&self) -> u32 {
+        self.output_precision
+            .map_or(MAX_DISPLAY_PRECISION, |places| {
+                places.min(MAX_DISPLAY_PRECISION)
+            })
+    }
+}
+
+/// What a consumer needs from an engine, so it can be written (and tested) against any
+/// implementation: `ShardedEngine` for large inputs, `SingleThreadedEngine` for small ones
+/// that do not benefit from the sharding overhead.
+///
+/// Transactions are routed, then `finish` is awaited once before the accounts are read.
+pub trait PaymentEngine: Sync {
+    /// Hand a transaction to the engine. Failures to apply it are logged and counted by the
+    /// engine, only a transaction that cannot be accepted at all returns an error.
+    fn route_transaction(&self, transaction: Transaction) -> Result<(), EngineError>;
+
+    /// Route several transactions in order
+    fn route_batch(&self, transactions: Vec<Transaction>) -> Result<(), EngineError> {
+        transactions
+            .into_iter()
+            .try_for_each(|transaction| self.route_transaction(transaction))
+    }
+
+    /// Token stopping input reading and processing when cancelled, from any task
+    fn cancellation_token(&self) -> CancellationToken;
+
+    /// Stop accepting transactions and wait until every routed one is applied, including
+    /// the retries of the dead-letter queue
+    fn finish(&mut self) -> impl Future<Output = ()> + Send;
+
+    /// Return a copy of a client's account, if the client has been seen
+    fn get_account(&self, client: ClientId) -> impl Future<Output = Option<ClientAccount>> + Send;
+
+    /// Return a copy of every account, ordered by client id
+    fn accounts(&self) -> impl Future<Output = Vec<(ClientId, ClientAccount)>> + Send;
+
+    /// Counts of applied and rejected transactions, and of locked accounts
+    fn run_summary(&self) -> impl Future<Output = RunSummary> + Send;
+
+    /// Number of transactions rejected so far, cheap enough to poll while routing. Rejections
+    /// of transactions still queued in a shard are not counted yet.
+    fn rejected_count(&self) -> usize;
+
+    /// The recorded transaction `tx_id` with its dispute state and the current balances of
+    /// its client, see `ShardedEngine::explain`
+    fn explain(&self, tx_id: u32) -> impl Future<Output = Vec<Explanation>> + Send;
+
+    /// Clients whose `available` or `total` balance is negative, in client order
+    fn negative_balances(&self) -> impl Future<Output = Vec<ClientId>> + Send {
+        async move {
+            self.accounts()
+                .await
+                .into_iter()
+                .filter(|(_, account)| {
+                    account.available < Decimal::ZERO || account.total < Decimal::ZERO
+                })
+                .map(|(client, _)| client)
+                .collect()
+        }
+    }
+
+    /// Compare the accounts with an expected account output file, e.g. the `accounts.csv` of
+    /// an external ledger, and return the clients that differ, see `verify::diff_accounts`.
+    /// Balances are compared as written, cut to `MAX_DISPLAY_PRECISION` places.
+    fn diff_against(
+        &self,
+        path: &str,
+    ) -> impl Future<Output = Result<Vec<AccountDiff>, EngineError>> + Send {
+        async move {
+            let expected = verify::read_accounts(path).await?;
+            let actual = self
+                .accounts()
+                .await
+                .iter()
+                .map(|(client, account)| (*client, AccountRow::from(account)))
+                .collect();
+            Ok(verify::diff_accounts(&expected, &actual))
+        }
+    }
+
+    /// Clients whose account is locked by a chargeback, in client order. Their number is the
+    /// `locked_accounts` of the run summary.
+    fn locked_accounts(&self) -> impl Future<Output = Vec<ClientId>> + Send {
+        async move {
+            self.accounts()
+                .await
+                .into_iter()
+                .filter(|(_, account)| account.locked)
+                .map(|(client, _)| client)
+                .collect()
+        }
+    }
+
+    /// Compare the aggregate kept with `EngineOptions::check_aggregate` against the sum of the
+    /// account totals, failing with `AggregateMismatch` if they differ. Without the option
+    /// there is nothing to compare and the check passes.
+    fn check_aggregate(&self) -> impl Future<Output = Result<(), EngineError>> + Send;
+
+    /// SHA-256 of the final balances in canonical form, see `checksum::accounts_checksum`
+    fn checksum(&self) -> impl Future<Output = String> + Send {
+        async move { checksum::accounts_checksum(&self.accounts().await) }
+    }
+
+    /// Write the state of all client accounts as CSV, with a header
+    fn write_accounts_to<W>(&self, writer: W) -> impl Future<Output = Result<(), EngineError>>
+    where
+        W: AsyncWrite + Unpin;
+
+    /// Same as `write_accounts_to`, but a reader closing the pipe early (e.g. `| head`) ends
+    /// the output cleanly instead of failing, like any other Unix tool
+    fn write_accounts_to_pipe<W>(&self, writer: W) -> impl Future<Output = Result<(), EngineError>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        async move {
+            match self.write_accounts_to(writer).await {
+                Err(err) if err.is_broken_pipe() => {
+                    log::debug!("Output closed by the reader: {}", err);
+                    Ok(())
+                }
+                result => result,
+            }
+        }
+    }
+
+    /// Write the accounts of each shard to its own `accounts-<n>.csv` in `dir`, see
+    /// `ShardedEngine::write_accounts_to_dir`. An engine without shards writes all of them
+    /// to `accounts-0.csv`.
+    fn write_accounts_to_dir(
+        &self,
+        dir: &Path,
+    ) -> impl Future<Output = Result<Vec<PathBuf>, EngineError>> {
+        async move {
+            async_std::fs::create_dir_all(dir).await?;
+            let path = dir.join("accounts-0.csv");
+            let file = async_std::fs::File::create(&path).await?;
+            self.write_accounts_to(file).await?;
+            Ok(vec![path])
+        }
+    }
+
+    /// End a run in one call: `finish`, then write the accounts to `writer` and return the
+    /// run summary. Nothing can be written before every routed transaction is applied.
+    fn finalize<W>(&mut self, writer: W) -> impl Future<Output = Result<RunSummary, EngineError>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        async move {
+            self.finish().await;
+            self.write_accounts_to(writer).await?;
+            Ok(self.run_summary().await)
+        }
+    }
+}
+
+impl PaymentEngine for ShardedEngine {
+    fn route_transaction(&self, transaction: Transaction) -> Result<(), EngineError> {
+        ShardedEngine::route_transaction(self, transaction)
+    }
+
+    fn route_batch(&self, transactions: Vec<Transaction>) -> Result<(), EngineError> {
+        ShardedEngine::route_batch(self, transactions)
+    }
+
+    fn cancellation_token(&self) -> CancellationToken {
+        ShardedEngine::cancellation_token(self)
+    }
+
+    async fn finish(&mut self) {
+        self.shutdown();
+        self.wait_for_completion().await;
+    }
+
+    async fn get_account(&self, client: ClientId) -> Option<ClientAccount> {
+        ShardedEngine::get_account(self, client).await
+    }
+
+    async fn accounts(&self) -> Vec<(ClientId, ClientAccount)> {
+        ShardedEngine::accounts(self).await
+    }
+
+    async fn run_summary(&self) -> RunSummary {
+        ShardedEngine::run_summary(self).await
+    }
+
+    fn rejected_count(&self) -> usize {
+        ShardedEngine::rejected_count(self)
+    }
+
+    async fn check_aggregate(&self) -> Result<(), EngineError> {
+        ShardedEngine::check_aggregate(self).await
+    }
+
+    async fn explain(&self, tx_id: u32) -> Vec<Explanation> {
+        ShardedEngine::explain(self, tx_id).await
+    }
+
+    async fn negative_balances(&self) -> Vec<ClientId> {
+        ShardedEngine::negative_balances(self).await
+    }
+
+    async fn locked_accounts(&self) -> Vec<ClientId> {
+        ShardedEngine::locked_accounts(self).await
+    }
+
+    async fn write_accounts_to<W>(&self, writer: W) -> Result<(), EngineError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        ShardedEngine::write_accounts_to(self, writer).await
+    }
+
+    async fn write_accounts_to_dir(&self, dir: &Path) -> Result<Vec<PathBuf>, EngineError> {
+        ShardedEngine::write_accounts_to_dir(self, dir).await
+    }
+}
+
+impl Default for ShardState {
+    fn default() -> Self {
+        Self::new(Arc::new(NoopObserver))
+    }
 }
 
 impl ShardedEngine {
     pub fn new(num_shards: usize) -> Self {
+        Self::with_observer(num_shards, Arc::new(NoopObserver))
+    }
+
+    /// Create an engine whose shards report every successfully processed transaction
+    pub fn with_observer(num_shards: usize, observer: Arc<dyn TransactionObserver>) -> Self {
+        Self::with_options(num_shards, observer, EngineOptions::default())
+    }
+
+    /// Create an engine with an observer and non-default options.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `num_shards` is 0, as no client could be routed to a shard.
+    pub fn with_options(
+        num_shards: usize,
+        observer: Arc<dyn TransactionObserver>,
+        options: EngineOptions,
+    ) -> Self {
+        assert!(num_shards >= 1, "ShardedEngine requires at least one shard");
+
         let mut shards: Vec<ClientShard> = Vec::with_capacity(num_shards);
         let mut tx_channels: Vec<TxChannel> = Vec::with_capacity(num_shards);
         let notify = Arc::new(Notify::new());
         let completed_shards = Arc::new(AtomicUsize::new(0));
         let shutdown = Arc::new(AtomicBool::new(false));
+        let cancel = CancellationToken::new();
+        let events = options
+            .event_capacity
+            .map(|capacity| broadcast::channel(capacity).0);
+        // One count for all shards, a client is only ever opened by its own shard
+        let client_cap = options.max_clients.map(|max| Arc::new(ClientCap::new(max)));
 
         for _ in 0..num_shards {
             let (tx, mut rx) = mpsc::unbounded_channel();
-            let shard: ClientShard = Arc::new(Mutex::new(ShardState {
-                accounts: HashMap::new(),
-                transactions: HashMap::new(),
-                processed_transactions: HashSet::new(),
-            }));
+            let mut shard_state = ShardState::new(Arc::clone(&observer));
+            shard_state.configure(&options);
+            shard_state.client_cap = client_cap.clone();
+            shard_state.events = events.clone();
+            let shard: ClientShard = Arc::new(shard_state);
 
             let shard_clone: ClientShard = Arc::clone(&shard);
             let notify_clone = Arc::clone(&notify);
             let completed_shards_clone = Arc::clone(&completed_shards);
             let shutdown_clone = Arc::clone(&shutdown);
+            let cancel_clone = cancel.clone();
 
+            // With a single lane the worker applies the transactions itself
+            let lane_count = match options.lanes_per_shard {
+                0 | 1 => 0,
+                lanes => lanes,
+            };
             tokio::spawn(async move {
-                while let Some(transaction) = rx.recv().await {
+                let mut guard = TaskGuard {
+                    shard: Arc::clone(&shard_clone),
+                    completion: Some((completed_shards_clone, notify_clone)),
+                    finished: false,
+                };
+                let (lanes, lane_tasks): (Vec<TxChannel>, Vec<_>) = (0..lane_count)
+                    .map(|_| {
+                        let (lane, lane_rx) = mpsc::unbounded_channel();
+                        let task = tokio::spawn(run_lane(
+                            Arc::clone(&shard_clone),
+                            lane_rx,
+                            cancel_clone.clone(),
+                        ));
+                        (lane, task)
+                    })
+                    .unzip();
+
+                let mut cancelled = false;
+                loop {
+                    // On cancellation the transaction in progress completes, queued ones are discarded
+                    let transaction = tokio::select! {
+                        biased;
+                        _ = cancel_clone.cancelled() => {
+                            cancelled = true;
+                            break;
+                        }
+                        received = rx.recv() => match received {
+                            Some(transaction) => transaction,
+                            None => break,
+                        },
+                    };
                     if shutdown_clone.load(Ordering::SeqCst) {
                         break;
                     }
 
-                    let mut shard_state = shard_clone.lock().await;
-                    if let Err(e) =
-                        Self::process_transaction_in_shard(&mut shard_state, transaction)
-                    {
-                        log::error!("{}", e);
+                    if lanes.is_empty() {
+                        shard_clone.receive(transaction);
+                    } else {
+                        // Clients of this shard are `index + k * num_shards`, spread by `k`
+                        let lane = (transaction.client as usize / num_shards) % lanes.len();
+                        // A lane stops early on cancellation, or when it panicked and its
+                        // guard marked the shard as failed
+                        if lanes[lane].send(transaction).is_err() {
+                            break;
+                        }
                     }
                 }
-                completed_shards_clone.fetch_add(1, Ordering::SeqCst);
-                notify_clone.notify_one();
+                drop(lanes);
+                let lanes_failed = futures::future::join_all(lane_tasks)
+                    .await
+                    .iter()
+                    .any(Result::is_err);
+                if lanes_failed {
+                    // The guard marks the shard as failed
+                    return;
+                }
+                // A lane may have seen the cancellation after the worker was done
+                if !cancelled && !cancel_clone.is_cancelled() {
+                    shard_clone.retry_dead_letters();
+                }
+                guard.finished = true;
             });
 
             shards.push(shard);
@@ -73,150 +1080,998 @@ impl ShardedEngine {
             notify,
             completed_shards,
             shutdown,
+            cancel,
+            routed: Arc::new(AtomicUsize::new(0)),
+            seen_tx_ids: options.global_unique_tx.then(|| Arc::new(DashMap::new())),
+            events,
+            output_precision: options.output_precision(),
+            client_filter: options.client_filter.map(Arc::new),
         }
     }
 
-    pub fn route_transaction(&self, transaction: Transaction) -> Result<(), EngineError> {
-        if self.shutdown.load(Ordering::SeqCst) {
-            return Err(EngineError::ShutDownError(
-                "Engine is shutting down, no new transactions accepted.".into(),
-            ));
-        }
-        let shard_index = (transaction.client as usize) % self.shards.len();
-        self.tx_channels[shard_index].send(transaction)?;
+    /// Receive an event for every transaction applied from now on, or `None` if the engine
+    /// was created without `EngineOptions::event_capacity`.
+    ///
+    /// Events of different shards interleave in no particular order; those of one client
+    /// arrive in the order they were applied. The channel keeps the last `event_capacity`
+    /// events: a subscriber further behind loses the oldest ones, and its next `recv`
+    /// returns `RecvError::Lagged` with the number skipped before resuming with the oldest
+    /// event still kept. Shards never wait for slow subscribers.
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<TransactionEvent>> {
+        self.events.as_ref().map(broadcast::Sender::subscribe)
+    }
 
-        Ok(())
+    /// Token stopping input reading and shard processing when cancelled, from any task.
+    ///
+    /// Cancellation is not a graceful shutdown: each shard finishes the transaction it is
+    /// applying and discards whatever is still queued, so the resulting state is partial but
+    /// consistent, and `wait_for_completion` returns promptly.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
     }
 
-    pub fn shutdown(&mut self) {
-        //TODO graceful shutdown
-        // self.shutdown.store(true, Ordering::SeqCst);
-        self.tx_channels.clear();
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
     }
 
-    pub async fn wait_for_completion(&self) {
-        while self.completed_shards.load(Ordering::SeqCst) < self.shards.len() {
-            self.notify.notified().await;
+    /// Send a transaction to the shard of its client. A transaction of a client left out by
+    /// `EngineOptions::client_filter` is dropped and reported as routed.
+    pub fn route_transaction(&self, transaction: Transaction) -> Result<(), EngineError> {
+        self.check_accepting()?;
+        if !self.is_routed(transaction.client) {
+            return Ok(());
         }
+        self.claim_tx_id(&transaction)?;
+        self.send_to_shard(self.shard_index(transaction.client), transaction)?;
+        self.routed.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
     }
 
-    pub fn process_transaction_in_shard(
-        shard_state: &mut ShardState,
+    /// Send a transaction to the worker of a shard. Once the shard failed, e.g. a lane of it
+    /// panicked while the worker still receives, this fails like a closed channel: the
+    /// transaction would never be applied.
+    fn send_to_shard(
+        &self,
+        shard_index: usize,
         transaction: Transaction,
     ) -> Result<(), EngineError> {
-        let account = shard_state
-            .accounts
-            .entry(transaction.client)
-            .or_insert_with(ClientAccount::new);
+        if self.shards[shard_index].failed.load(Ordering::SeqCst) {
+            return Err(SendError(transaction).into());
+        }
+        self.tx_channels[shard_index].send(transaction)?;
+        Ok(())
+    }
 
-        if shard_state.processed_transactions.contains(&transaction) {
-            return Err(EngineError::TransactionError(
-                "Duplicate transaction".into(),
-            ));
-        } else {
-            shard_state.processed_transactions.insert(transaction);
-        }
-
-        match transaction.tx_type {
-            TransactionType::Deposit => {
-                if let Some(amount) = transaction.amount {
-                    account.deposit(amount)?;
-                    shard_state.transactions.insert(
-                        transaction.tx_id,
-                        Transaction {
-                            under_dispute: false,
-                            ..transaction.clone()
-                        },
-                    );
-                }
-            }
+    /// Route every transaction of `stream` and finalize the engine, for sources other than
+    /// CSV files (a message queue consumer, a generator).
+    ///
+    /// Items that failed upstream are logged and skipped, like rows failing to parse, as are
+    /// transactions failing to route. Stops early when the engine is cancelled, and with the
+    /// routing error when a shard worker is gone. Once the stream is exhausted the engine is
+    /// shut down and the summary of the run is returned; the accounts remain readable.
+    pub async fn run_stream<S>(&mut self, stream: S) -> Result<RunSummary, EngineError>
+    where
+        S: Stream<Item = Result<Transaction, EngineError>>,
+    {
+        let mut stream = pin!(stream);
+        let cancel = self.cancellation_token();
 
-            TransactionType::Withdrawal => {
-                if let Some(amount) = transaction.amount {
-                    account.withdraw(amount)?;
-                    shard_state.transactions.insert(
-                        transaction.tx_id,
-                        Transaction {
-                            under_dispute: false,
-                            ..transaction.clone()
-                        },
-                    );
+        loop {
+            let item = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => {
+                    log::info!("Processing of the transaction stream cancelled");
+                    break;
                 }
+                next = stream.next() => match next {
+                    Some(item) => item,
+                    None => break,
+                },
+            };
+
+            let transaction = match item {
+                Ok(transaction) => transaction,
+                Err(err) => {
+                    log::error!("{}", err);
+                    continue;
+                }
+            };
+            match self.route_transaction(transaction) {
+                Err(err) if err.is_fatal() => return Err(err),
+                Err(err) => {
+                    log::error!(
+                        tx_id = transaction.tx_id,
+                        client = transaction.client;
+                        "Failed to route transaction: {}", err
+                    );
+                }
+                Ok(()) => {}
+            }
+        }
+
+        self.shutdown();
+        self.wait_for_completion().await;
+        self.check_workers()?;
+        Ok(self.run_summary().await)
+    }
+
+    /// Route many transactions at once, bucketing them by shard first so the shutdown check
+    /// runs once per batch. The order of transactions of a same client is preserved.
+    /// With `global_unique_tx`, transactions reusing an id are logged and left out of the batch.
+    pub fn route_batch(&self, transactions: Vec<Transaction>) -> Result<(), EngineError> {
+        self.check_accepting()?;
+
+        let mut buckets: Vec<Vec<Transaction>> = vec![Vec::new(); self.shards.len()];
+        for transaction in transactions {
+            if !self.is_routed(transaction.client) {
+                continue;
+            }
+            if let Err(err) = self.claim_tx_id(&transaction) {
+                log::error!(
+                    tx_id = transaction.tx_id,
+                    client = transaction.client;
+                    "Failed to route transaction: {}", err
+                );
+                continue;
+            }
+            buckets[self.shard_index(transaction.client)].push(transaction);
+        }
+
+        for (shard_index, bucket) in buckets.into_iter().enumerate() {
+            for transaction in bucket {
+                self.send_to_shard(shard_index, transaction)?;
+                self.routed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deterministic stand-in for routing from concurrent tasks, one per lane, for tests: the
+    /// lane of each next transaction is picked by a xorshift generator seeded with `seed`,
+    /// and every lane keeps its own order like the transactions of one task. Returns the
+    /// transactions in the order they were routed. On a single-threaded runtime the shards
+    /// receive them in that order, so the same seed replays the same run through the real
+    /// shards, e.g. an interleaving that made a concurrent test fail.
+    pub fn route_interleaved(
+        &self,
+        lanes: Vec<Vec<Transaction>>,
+        seed: u64,
+    ) -> Result<Vec<Transaction>, EngineError> {
+        let mut state = seed | 1;
+        let mut lanes: Vec<_> = lanes.into_iter().map(Vec::into_iter).collect();
+        let mut routed = Vec::new();
+        while !lanes.is_empty() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let lane = (state % lanes.len() as u64) as usize;
+            match lanes[lane].next() {
+                Some(transaction) => {
+                    self.route_transaction(transaction)?;
+                    routed.push(transaction);
+                }
+                None => {
+                    lanes.swap_remove(lane);
+                }
+            }
+        }
+        Ok(routed)
+    }
+
+    /// Whether transactions of `client` pass `EngineOptions::client_filter`
+    fn is_routed(&self, client: ClientId) -> bool {
+        self.client_filter
+            .as_ref()
+            .is_none_or(|clients| clients.contains(&client))
+    }
+
+    /// Record the id of a deposit, withdrawal, fee or adjustment in the global index,
+    /// failing if another transaction already used it. A dispute, resolve or chargeback of an
+    /// indexed id fails unless its client owns the id: sent to its own client's shard, it
+    /// would not find the transaction when the owner lives on another shard.
+    fn claim_tx_id(&self, transaction: &Transaction) -> Result<(), EngineError> {
+        let Some(seen) = &self.seen_tx_ids else {
+            return Ok(());
+        };
+        if !transaction.tx_type.is_recorded() {
+            return match seen.get(&transaction.tx_id) {
+                Some(owner) if *owner != transaction.client => {
+                    Err(EngineError::DisputeClientMismatch {
+                        tx_id: transaction.tx_id,
+                        expected: *owner,
+                        got: transaction.client,
+                    })
+                }
+                _ => Ok(()),
+            };
+        }
+        match seen.entry(transaction.tx_id) {
+            Entry::Occupied(_) => Err(EngineError::TransactionError(format!(
+                "Transaction id {} already used",
+                transaction.tx_id
+            ))),
+            Entry::Vacant(entry) => {
+                entry.insert(transaction.client);
+                Ok(())
             }
+        }
+    }
+
+    fn check_accepting(&self) -> Result<(), EngineError> {
+        if self.shutdown.load(Ordering::SeqCst) {
+            return Err(EngineError::ShutDownError(
+                "Engine is shutting down, no new transactions accepted.".into(),
+            ));
+        }
+        if self.is_cancelled() {
+            return Err(EngineError::ShutDownError(
+                "Engine processing was cancelled, no new transactions accepted.".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn shard_index(&self, client: ClientId) -> usize {
+        (client as usize) % self.shards.len()
+    }
+
+    pub fn shutdown(&mut self) {
+        //TODO graceful shutdown
+        // self.shutdown.store(true, Ordering::SeqCst);
+        self.tx_channels.clear();
+    }
+
+    /// Wait until the worker of every shard has stopped, after `shutdown` or on cancellation.
+    /// A worker that panicked counts as stopped, see `check_workers`.
+    pub async fn wait_for_completion(&self) {
+        while self.completed_shards.load(Ordering::SeqCst) < self.shards.len() {
+            self.notify.notified().await;
+        }
+    }
+
+    /// Fail with `EngineError::WorkerFailed` if the worker of a shard stopped before the end
+    /// of its input, e.g. because an observer panicked: the transactions it had not applied
+    /// are lost, even when routing them succeeded. Checked after `wait_for_completion`.
+    pub fn check_workers(&self) -> Result<(), EngineError> {
+        match self
+            .shards
+            .iter()
+            .position(|shard| shard.failed.load(Ordering::SeqCst))
+        {
+            Some(shard) => Err(EngineError::WorkerFailed(shard)),
+            None => Ok(()),
+        }
+    }
+
+    /// Return a copy of a client's account, if the client has been seen
+    pub async fn get_account(&self, client: ClientId) -> Option<ClientAccount> {
+        self.shards[self.shard_index(client)].account(client)
+    }
+
+    /// Look up the recorded transaction `tx_id` (a deposit, withdrawal, fee or adjustment)
+    /// with its dispute state and the current balances of its client. Without
+    /// `global_unique_tx` several shards may have recorded the id, all are returned.
+    pub async fn explain(&self, tx_id: u32) -> Vec<Explanation> {
+        let mut explanations = Vec::new();
+        for shard in &self.shards {
+            explanations.extend(shard.explain(tx_id));
+        }
+        explanations
+    }
+
+    /// Return a copy of every account, ordered by client id
+    pub async fn accounts(&self) -> Vec<(ClientId, ClientAccount)> {
+        let mut accounts = Vec::new();
+        for shard in &self.shards {
+            accounts.extend(shard.accounts());
+        }
+        accounts.sort_by_key(|(client, _)| *client);
+        accounts
+    }
+
+    /// Check that the sum of all account totals matches the net of the applied flows
+    /// (deposits - withdrawals - chargebacks), which catches arithmetic bugs in the accounts
+    pub async fn reconcile(&self) -> ReconciliationReport {
+        let mut total_balance = Decimal::ZERO;
+        let mut flows = FlowTotals::default();
+
+        for shard in &self.shards {
+            total_balance += shard
+                .accounts()
+                .iter()
+                .map(|(_, account)| account.total)
+                .sum::<Decimal>();
+            flows.merge(&shard.flows());
+        }
+
+        let report = ReconciliationReport::new(total_balance, flows);
+        if !report.is_balanced() {
+            log::warn!(
+                "Reconciliation discrepancy of {}: accounts total {}, expected {}",
+                report.discrepancy,
+                report.total_balance,
+                report.flows.net()
+            );
+        }
+        report
+    }
+
+    /// Check the overflow-checked aggregate of the flows of all shards against the account
+    /// totals, see `PaymentEngine::check_aggregate`. Unlike `reconcile`, the sums are kept in
+    /// an `i128`, so they cannot overflow however many accounts near the `Decimal` limit
+    /// there are.
+    pub async fn check_aggregate(&self) -> Result<(), EngineError> {
+        let mut aggregates = self.shards.iter().filter_map(|shard| shard.aggregate());
+        let Some(mut aggregate) = aggregates.next() else {
+            return Ok(());
+        };
+        for other in aggregates {
+            aggregate.merge(&other);
+        }
+        let totals = self
+            .shards
+            .iter()
+            .flat_map(|shard| shard.accounts())
+            .map(|(_, account)| account.total);
+        aggregate.verify(totals)
+    }
 
-            TransactionType::Dispute => {
-                match shard_state.transactions.get_mut(&transaction.tx_id) {
-                    Some(tx) => {
-                        if let Some(amount) = tx.amount {
-                            account.dispute(amount)?;
-                            tx.under_dispute = true;
+    /// Copy the accounts, recorded transactions (with their dispute state) and flows of
+    /// every shard, to be restored into a later engine.
+    ///
+    /// Safe to call while transactions are processed: the copy is taken with every shard
+    /// paused, see `consistent_accounts`. The pause then lasts for the copy of all
+    /// transactions too, which makes this the wrong call for frequent monitoring.
+    pub async fn snapshot(&self) -> Snapshot {
+        self.paused(|shards| {
+            let mut snapshot = Snapshot::default();
+            for shard in shards {
+                snapshot.accounts.extend(
+                    shard
+                        .accounts()
+                        .iter()
+                        .map(|(client, account)| AccountSnapshot::new(*client, account)),
+                );
+                snapshot
+                    .transactions
+                    .extend(shard.transactions.iter().map(|transaction| {
+                        TransactionSnapshot {
+                            held: shard
+                                .disputed_amounts
+                                .get(transaction.key())
+                                .map(|held| *held),
+                            ..TransactionSnapshot::from(transaction.value())
                         }
-                    }
-                    None => {
-                        return Err(EngineError::TransactionNotFound(transaction.tx_id));
-                    }
+                    }));
+                snapshot.flows.merge(&shard.flows());
+            }
+            // Chronological when the input was timestamped, by id otherwise
+            snapshot
+                .transactions
+                .sort_by_key(|transaction| (transaction.timestamp, transaction.tx_id));
+            snapshot
+        })
+        .await
+    }
+
+    /// Copy every account as of a single point in time, ordered by client id, while
+    /// transactions keep being routed, e.g. for a monitoring endpoint.
+    ///
+    /// `accounts` copies one shard after the other while they keep running, so each shard
+    /// is caught at a different point of its input. Here all shards are paused first: the
+    /// call waits for the transaction each shard is applying, holds back the next ones
+    /// while the accounts are copied, then lets the shards resume. Transactions routed in
+    /// the meantime wait in the shard queues, none is lost.
+    ///
+    /// The latency cost falls on processing: every shard stalls for the slowest transaction
+    /// in flight (including its observer callback) plus the copy of all accounts, roughly
+    /// linear in their number. Must not be called from an observer callback, which runs
+    /// while its shard applies a transaction and would wait for itself.
+    pub async fn consistent_accounts(&self) -> Vec<(ClientId, ClientAccount)> {
+        self.paused(|shards| {
+            let mut accounts: Vec<_> = shards.iter().flat_map(|shard| shard.accounts()).collect();
+            accounts.sort_by_key(|(client, _)| *client);
+            accounts
+        })
+        .await
+    }
+
+    /// Run `copy` with no transaction being applied by any shard. Waiting for the shards
+    /// blocks, so it happens off the runtime workers.
+    async fn paused<T, F>(&self, copy: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&[ClientShard]) -> T + Send + 'static,
+    {
+        let shards = self.shards.clone();
+        tokio::task::spawn_blocking(move || {
+            // Always in shard order, so two concurrent copies cannot deadlock
+            let _paused: Vec<_> = shards
+                .iter()
+                .map(|shard| shard.gate.write().expect("shard state poisoned"))
+                .collect();
+            copy(&shards)
+        })
+        .await
+        .expect("copy of the paused shards panicked")
+    }
+
+    /// Load a snapshot taken by `snapshot`, before routing any transaction. The engine may
+    /// have a different shard count: accounts and transactions go to the shard of their client.
+    pub async fn restore(&self, snapshot: Snapshot) {
+        if let Some(cap) = &self.shards[0].client_cap {
+            cap.opened
+                .fetch_add(snapshot.accounts.len(), Ordering::Relaxed);
+        }
+        for account in &snapshot.accounts {
+            self.shards[self.shard_index(account.client)]
+                .accounts
+                .insert(
+                    account.client,
+                    Arc::new(AccountEntry::new(account.to_account())),
+                );
+        }
+        for transaction in &snapshot.transactions {
+            let shard = &self.shards[self.shard_index(transaction.client)];
+            if transaction.tx_type.is_disputable() {
+                shard
+                    .latest_tx_id
+                    .fetch_max(transaction.tx_id, Ordering::Relaxed);
+            }
+            if let Some(held) = transaction.held {
+                shard.disputed_amounts.insert(transaction.tx_id, held);
+            }
+            shard
+                .transactions
+                .insert(transaction.tx_id, Transaction::from(transaction));
+        }
+        // Only the sum of the flows is meaningful, see `reconcile`
+        let tally = &mut *lock(&self.shards[0].tally);
+        tally.flows.merge(&snapshot.flows);
+        if let Some(aggregate) = &mut tally.aggregate {
+            for account in &snapshot.accounts {
+                aggregate.add(account.total);
+            }
+        }
+    }
+
+    /// Clients whose `available` or `total` balance is negative, in client order. Besides
+    /// logic bugs, disputing a deposit whose funds were already withdrawn and fees under
+    /// `FeePolicy::AllowNegative` drive a balance negative.
+    pub async fn negative_balances(&self) -> Vec<ClientId> {
+        let mut clients = Vec::new();
+        for shard in &self.shards {
+            clients.extend(
+                shard
+                    .accounts()
+                    .into_iter()
+                    .filter(|(_, account)| {
+                        account.available < Decimal::ZERO || account.total < Decimal::ZERO
+                    })
+                    .map(|(client, _)| client),
+            );
+        }
+        clients.sort_unstable();
+        clients
+    }
+
+    /// Clients whose account is locked by a chargeback, in client order, e.g. the frozen
+    /// clients to hand to compliance. Their number is the `locked_accounts` of `run_summary`.
+    pub async fn locked_accounts(&self) -> Vec<ClientId> {
+        let mut clients = Vec::new();
+        for shard in &self.shards {
+            clients.extend(
+                shard
+                    .accounts()
+                    .into_iter()
+                    .filter(|(_, account)| account.locked)
+                    .map(|(client, _)| client),
+            );
+        }
+        clients.sort_unstable();
+        clients
+    }
+
+    /// `(shard index, client count, transaction count)` of every shard, to diagnose skew.
+    /// Clients are assigned by `client % shards`; transactions count everything a shard
+    /// received, including rejected ones.
+    pub async fn shard_distribution(&self) -> Vec<(usize, usize, usize)> {
+        let mut distribution = Vec::with_capacity(self.shards.len());
+        for (index, shard) in self.shards.iter().enumerate() {
+            let received = lock(&shard.tally).received;
+            distribution.push((index, shard.accounts.len(), received));
+        }
+        distribution
+    }
+
+    /// Merge the per-shard counters of applied and rejected transactions, and count the
+    /// locked accounts
+    pub async fn run_summary(&self) -> RunSummary {
+        let mut summary = RunSummary::default();
+        for shard in &self.shards {
+            summary.merge(&shard.run_summary());
+        }
+        summary
+    }
+
+    /// Read-only view of the counters of the engine, e.g. for a metrics endpoint. Unlike a
+    /// clone of the engine it routes nothing, so it does not keep the shards running after
+    /// `shutdown`.
+    pub fn stats(&self) -> EngineStats {
+        EngineStats {
+            shards: self.shards.clone(),
+            routed: Arc::clone(&self.routed),
+        }
+    }
+
+    /// Number of transactions rejected so far by all shards, without waiting for them
+    pub fn rejected_count(&self) -> usize {
+        self.shards.iter().map(|shard| shard.rejected()).sum()
+    }
+
+    /// Count the transaction ids that were referenced before being recorded, separating
+    /// out-of-order input from references to ids that never appeared. Meaningful once the
+    /// input is fully processed.
+    pub async fn ordering_report(&self) -> OrderingReport {
+        let mut report = OrderingReport::default();
+        for shard in &self.shards {
+            let tally = lock(&shard.tally);
+            report.out_of_order += tally.out_of_order;
+            report.never_seen += shard.unseen_references.len();
+            report.timestamp_regressions += tally.timestamp_regressions;
+            report.seq_gaps += tally.seq_gaps;
+        }
+
+        if report.out_of_order > 0 {
+            log::warn!(
+                "{} transactions were referenced before they arrived, input is likely out of order",
+                report.out_of_order
+            );
+        }
+        if report.never_seen > 0 {
+            log::warn!(
+                "{} referenced transactions never appeared in the input",
+                report.never_seen
+            );
+        }
+        if report.timestamp_regressions > 0 {
+            log::warn!(
+                "{} transactions were stamped earlier than a previous one of their client",
+                report.timestamp_regressions
+            );
+        }
+        if report.seq_gaps > 0 {
+            log::warn!(
+                "{} gaps in the sequence numbers of clients, transactions were likely dropped",
+                report.seq_gaps
+            );
+        }
+        report
+    }
+
+    /// Apply a transaction to the account of its client in `shard_state`. Transactions of
+    /// different clients may be applied concurrently, those of one client must not be.
+    pub fn process_transaction_in_shard(
+        shard_state: &ShardState,
+        transaction: Transaction,
+    ) -> Result<(), EngineError> {
+        let account_lock = shard_state.account_lock(transaction.client)?;
+
+        // A resolve moves funds only while its transaction is under dispute, so a repeated
+        // one is left to the `Resolve` arm, which rejects it as not disputed
+        if transaction.tx_type != TransactionType::Resolve
+            && !shard_state.processed_transactions.insert(transaction)
+        {
+            return Err(EngineError::TransactionError(
+                "Duplicate transaction".into(),
+            ));
+        }
+
+        // A new deposit, withdrawal, fee or adjustment must not reuse the id of a recorded transaction,
+        // otherwise later disputes would reference the wrong amount. Its vacant entry is held
+        // until the transaction is recorded or rejected, so another client of the shard cannot
+        // claim the id meanwhile.
+        let record = if transaction.tx_type.is_recorded() {
+            match shard_state.transactions.entry(transaction.tx_id) {
+                Entry::Occupied(_) => {
+                    return Err(EngineError::TransactionError(format!(
+                        "Transaction id {} already used",
+                        transaction.tx_id
+                    )));
                 }
+                Entry::Vacant(entry) => Some(entry),
+            }
+        } else {
+            None
+        };
+
+        // Ids grow with the input, so a reference past the latest disputable transaction of
+        // the shard cannot be to one already received. A client's own transactions are
+        // applied in order, so lanes do not make this check reject a valid reference.
+        if transaction.tx_type.is_disputable() {
+            shard_state
+                .latest_tx_id
+                .fetch_max(transaction.tx_id, Ordering::Relaxed);
+        } else if shard_state.strict_ordering && !transaction.tx_type.is_recorded() {
+            let latest = shard_state.latest_tx_id.load(Ordering::Relaxed);
+            if transaction.tx_id > latest {
+                return Err(EngineError::FutureReference {
+                    tx_id: transaction.tx_id,
+                    latest,
+                });
             }
+        }
+
+        // A transaction that a dispute, resolve or chargeback already referenced came late
+        if matches!(
+            transaction.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) && shard_state
+            .unseen_references
+            .remove(&transaction.tx_id)
+            .is_some()
+        {
+            lock(&shard_state.tally).out_of_order += 1;
+            log::warn!(
+                tx_id = transaction.tx_id,
+                client = transaction.client;
+                "Transaction {} arrived after a transaction referencing it, input is likely out of order",
+                transaction.tx_id
+            );
+        }
+
+        let precision = shard_state.precision;
+        let op = match transaction.tx_type {
+            TransactionType::Deposit => transaction.amount.map(AccountOp::Credit),
+            TransactionType::Withdrawal => transaction.amount.map(AccountOp::Debit),
+            TransactionType::Fee => transaction.amount.map(match shard_state.fee_policy {
+                FeePolicy::Reject => AccountOp::Debit,
+                FeePolicy::AllowNegative => AccountOp::Charge,
+            }),
+            TransactionType::Adjustment => transaction.amount.map(|amount| {
+                if amount.is_sign_negative() {
+                    AccountOp::Debit(-amount)
+                } else {
+                    AccountOp::Credit(amount)
+                }
+            }),
 
-            TransactionType::Resolve => {
-                match shard_state.transactions.get_mut(&transaction.tx_id) {
-                    Some(tx) if tx.under_dispute => {
-                        if let Some(amount) = tx.amount {
-                            account.resolve(amount)?;
+            TransactionType::Dispute => match shard_state.transactions.get(&transaction.tx_id) {
+                Some(tx) if tx.client != transaction.client => {
+                    return Err(EngineError::DisputeClientMismatch {
+                        tx_id: transaction.tx_id,
+                        expected: tx.client,
+                        got: transaction.client,
+                    });
+                }
+                Some(tx) if !tx.tx_type.is_disputable() => {
+                    return Err(EngineError::InvalidOperation(format!(
+                        "Dispute attempted on a non-disputable {:?} transaction",
+                        tx.tx_type
+                    )));
+                }
+                Some(tx) if !shard_state.dispute_policy.allows(tx.tx_type) => {
+                    return Err(EngineError::InvalidOperation(format!(
+                        "Dispute attempted on a {} under {:?}, only deposits can be disputed",
+                        tx.tx_type, shard_state.dispute_policy
+                    )));
+                }
+                // Holding the amount a second time would leave it held after the resolve
+                Some(tx) if tx.under_dispute => {
+                    return Err(EngineError::InvalidOperation(
+                        "Dispute attempted on a transaction already under dispute".into(),
+                    ));
+                }
+                Some(tx) => match (tx.amount, transaction.amount) {
+                    (Some(amount), Some(disputed)) if shard_state.dispute_amounts => {
+                        if disputed > amount {
+                            return Err(EngineError::InvalidOperation(format!(
+                                "Disputed amount {} exceeds the amount {} of transaction {}",
+                                disputed, amount, transaction.tx_id
+                            )));
                         }
+                        Some(AccountOp::Hold(disputed.trunc_with_scale(precision)))
                     }
-                    Some(_) => {
-                        return Err(EngineError::InvalidOperation(
-                            "Resolve attempted on a non-disputed transaction".into(),
-                        ));
-                    }
-                    None => {
-                        return Err(EngineError::TransactionNotFound(transaction.tx_id));
+                    (amount, _) => {
+                        amount.map(|amount| AccountOp::Hold(amount.trunc_with_scale(precision)))
                     }
+                },
+                None => {
+                    shard_state.unseen_references.insert(transaction.tx_id);
+                    return Err(EngineError::TransactionNotFound(transaction.tx_id));
+                }
+            },
+
+            TransactionType::Resolve => match shard_state.transactions.get(&transaction.tx_id) {
+                Some(tx) if tx.client != transaction.client => {
+                    return Err(EngineError::DisputeClientMismatch {
+                        tx_id: transaction.tx_id,
+                        expected: tx.client,
+                        got: transaction.client,
+                    });
+                }
+                Some(tx) if tx.under_dispute => shard_state
+                    .held_amount(&tx)
+                    .map(|amount| AccountOp::Release(amount.trunc_with_scale(precision))),
+                // Some feeds repeat resolves, the first one already settled the dispute
+                Some(_) if shard_state.lenient_resolve => {
+                    lock(&shard_state.tally).summary.redundant_resolves += 1;
+                    return Ok(());
+                }
+                Some(_) => {
+                    return Err(EngineError::InvalidOperation(
+                        "Resolve attempted on a non-disputed transaction".into(),
+                    ));
+                }
+                None => {
+                    shard_state.unseen_references.insert(transaction.tx_id);
+                    return Err(EngineError::TransactionNotFound(transaction.tx_id));
+                }
+            },
+
+            TransactionType::Chargeback => match shard_state.transactions.get(&transaction.tx_id) {
+                Some(tx) if tx.client != transaction.client => {
+                    return Err(EngineError::DisputeClientMismatch {
+                        tx_id: transaction.tx_id,
+                        expected: tx.client,
+                        got: transaction.client,
+                    });
+                }
+                Some(tx) if tx.under_dispute => shard_state
+                    .held_amount(&tx)
+                    .map(|amount| AccountOp::Reverse(amount.trunc_with_scale(precision))),
+                Some(_) => {
+                    return Err(EngineError::InvalidOperation(
+                        "Chargeback attempted on a non-disputed transaction".into(),
+                    ));
+                }
+                None => {
+                    shard_state.unseen_references.insert(transaction.tx_id);
+                    return Err(EngineError::TransactionNotFound(transaction.tx_id));
                 }
+            },
+        };
+
+        // The account lock is only held for the operation itself, never while waiting on
+        // the transaction log, so it cannot deadlock with a transaction of another client
+        let mut frozen = false;
+        let account = {
+            let mut account = lock(&account_lock.account);
+            if let Some(op) = op {
+                account
+                    .apply_with(op, shard_state.lock_policy)
+                    .map_err(|err| err.with_client(transaction.client))?;
+                if let Some(threshold) = shard_state.auto_freeze_above {
+                    frozen = transaction.tx_type == TransactionType::Deposit
+                        && !account.locked
+                        && account.available > threshold;
+                    account.locked |= frozen;
+                }
+                #[cfg(feature = "lock-free-reads")]
+                account_lock.published.store(&account);
             }
+            account.clone()
+        };
 
-            TransactionType::Chargeback => {
-                match shard_state.transactions.get_mut(&transaction.tx_id) {
-                    Some(tx) if tx.under_dispute => {
-                        if let Some(amount) = tx.amount {
-                            account.chargeback(amount)?;
-                        }
-                    }
-                    Some(_) => {
-                        return Err(EngineError::InvalidOperation(
-                            "Chargeback attempted on a non-disputed transaction".into(),
-                        ));
+        if let Some(op) = op {
+            // Bookkeeping happens only once the account accepted the operation
+            let amount = op.amount();
+            {
+                let tally = &mut *lock(&shard_state.tally);
+                let flows = &mut tally.flows;
+                match transaction.tx_type {
+                    TransactionType::Deposit => flows.deposits += amount,
+                    TransactionType::Withdrawal => flows.withdrawals += amount,
+                    TransactionType::Fee => flows.fees += amount,
+                    TransactionType::Adjustment => match op {
+                        AccountOp::Debit(_) => flows.adjustments -= amount,
+                        _ => flows.adjustments += amount,
+                    },
+                    TransactionType::Dispute | TransactionType::Resolve => {}
+                    TransactionType::Chargeback => flows.chargebacks += amount,
+                }
+                let signed = match op {
+                    AccountOp::Debit(_) if transaction.tx_type == TransactionType::Adjustment => {
+                        -amount
                     }
-                    None => {
-                        return Err(EngineError::TransactionNotFound(transaction.tx_id));
+                    _ => amount,
+                };
+                tally.summary.amounts.add(transaction.tx_type, signed);
+                if let Some(aggregate) = &mut tally.aggregate {
+                    match op {
+                        AccountOp::Credit(_) => aggregate.add(amount),
+                        AccountOp::Debit(_) | AccountOp::Charge(_) | AccountOp::Reverse(_) => {
+                            aggregate.sub(amount)
+                        }
+                        AccountOp::Hold(_) | AccountOp::Release(_) => {}
                     }
                 }
             }
+            if !transaction.tx_type.is_recorded() {
+                // Settling the dispute clears the flag, so a second resolve or chargeback
+                // of the same transaction cannot release or remove the amount again
+                if let Some(mut tx) = shard_state.transactions.get_mut(&transaction.tx_id) {
+                    tx.under_dispute = transaction.tx_type == TransactionType::Dispute;
+                }
+                if transaction.tx_type == TransactionType::Dispute
+                    && shard_state.dispute_amounts
+                    && transaction.amount.is_some()
+                {
+                    shard_state
+                        .disputed_amounts
+                        .insert(transaction.tx_id, amount);
+                } else {
+                    shard_state.disputed_amounts.remove(&transaction.tx_id);
+                }
+            }
+            if let Some(record) = record {
+                record.insert(Transaction {
+                    under_dispute: false,
+                    ..transaction
+                });
+            }
+        }
+
+        observer::notify(shard_state.observer.as_ref(), &transaction, &account);
+        if frozen {
+            log::warn!(
+                tx_id = transaction.tx_id,
+                client = transaction.client;
+                "Account of client {} frozen for review, deposit {} took its available funds to {}",
+                transaction.client,
+                transaction.tx_id,
+                account.available
+            );
+            shard_state.observer.on_auto_freeze(&transaction, &account);
+        }
+        if let Some(events) = &shard_state.events {
+            // Fails only when nobody is subscribed
+            let _ = events.send(TransactionEvent {
+                client: transaction.client,
+                tx_id: transaction.tx_id,
+                tx_type: transaction.tx_type,
+                account,
+            });
         }
         Ok(())
     }
 
     pub async fn write_accounts(&self) -> Result<(), EngineError> {
-        let mut wtr = csv_async::AsyncWriter::from_writer(stdout());
+        self.write_accounts_to_pipe(stdout()).await
+    }
 
-        wtr.write_record(&["client", "available", "held", "total", "locked"])
-            .await?;
-        // TODO we can improve for more Concurrent Shard Processing
-        for shard in &self.shards {
-            let shard_state = shard.lock().await;
-            for (client_id, account) in shard_state.accounts.iter() {
-                wtr.write_record([
-                    client_id.to_string(),
-                    account.available.to_string(),
-                    account.held.to_string(),
-                    account.total.to_string(),
-                    account.locked.to_string(),
-                ])
+    /// Same as `write_accounts_to`, but a reader closing the pipe early (e.g. `| head`) ends
+    /// the output cleanly instead of failing, like any other Unix tool
+    pub async fn write_accounts_to_pipe<W>(&self, writer: W) -> Result<(), EngineError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        PaymentEngine::write_accounts_to_pipe(self, writer).await
+    }
+
+    /// Shut down, wait for completion, write the accounts to `writer` and return the run
+    /// summary, see `PaymentEngine::finalize`
+    pub async fn finalize<W>(&mut self, writer: W) -> Result<RunSummary, EngineError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        PaymentEngine::finalize(self, writer).await
+    }
+
+    /// Write the state of all client accounts as CSV to the given writer.
+    ///
+    /// The accounts are copied one at a time (see `copy_accounts_by_shard`) and
+    /// serialized afterwards, so a slow writer never stalls the shards, which may keep
+    /// processing in the meantime. Output goes through a buffer of `OUTPUT_BUFFER_SIZE`
+    /// bytes, so a large account set reaches the writer in a few large writes rather than
+    /// many small ones.
+    ///
+    /// A failing writer (e.g. a full disk) yields `EngineError::PartialOutput` with the number
+    /// of account rows it accepted before the failure. A balance too large to be plausible
+    /// (see `models::check_output_amount`) fails with `ImplausibleAmount` before anything
+    /// is written.
+    pub async fn write_accounts_to<W>(&self, writer: W) -> Result<(), EngineError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let shards = self.copy_accounts_by_shard();
+        Self::write_accounts_csv(writer, &shards, self.output_precision).await
+    }
+
+    /// Write the accounts of all groups as CSV in client order, with a header, through a
+    /// buffer of `OUTPUT_BUFFER_SIZE` bytes. Sorting makes the output independent of how the
+    /// accounts were grouped, so any shard count and `SingleThreadedEngine` write the same
+    /// bytes. On failure, the error is wrapped in a `PartialOutput` counting the complete rows
+    /// the writer accepted.
+    pub(crate) async fn write_accounts_csv<W>(
+        writer: W,
+        groups: &[Vec<(ClientId, ClientAccount)>],
+        places: u32,
+    ) -> Result<(), EngineError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        // Checked up front, so an implausible balance fails the output before anything is
+        // written rather than leave it partial
+        for (client, account) in groups.iter().flatten() {
+            for amount in [account.available, account.held, account.total] {
+                check_output_amount(*client, amount)?;
+            }
+        }
+        let mut accounts: Vec<_> = groups.iter().flatten().collect();
+        accounts.sort_unstable_by_key(|(client, _)| *client);
+        let mut counter = LineCounter {
+            inner: writer,
+            lines: 0,
+        };
+        let result = async {
+            let writer = BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, &mut counter);
+            let mut wtr = csv_async::AsyncWriter::from_writer(writer);
+            wtr.write_record(&["client", "available", "held", "total", "locked"])
                 .await?;
+            Self::write_account_rows(&mut wtr, accounts, places).await?;
+            wtr.flush().await?;
+            Ok(())
+        }
+        .await;
+
+        result.map_err(|source| EngineError::PartialOutput {
+            // The first line is the header
+            rows_written: counter.lines.saturating_sub(1),
+            source: Box::new(source),
+        })
+    }
+
+    /// Copy the accounts of every shard. Each account is copied under its own lock, so the
+    /// copy of an account is consistent even while transactions are being processed, and a
+    /// transaction is only held up while its own account is copied. The locks are only held
+    /// for the copy, at the cost of a second copy of every account in memory.
+    fn copy_accounts_by_shard(&self) -> Vec<Vec<(ClientId, ClientAccount)>> {
+        self.shards.iter().map(|shard| shard.accounts()).collect()
+    }
+
+    /// Write each shard's accounts to its own `accounts-<n>.csv` in `dir`, with a header.
+    ///
+    /// Shards are written concurrently and independently, without a cross-shard merge.
+    /// Clients are partitioned by `client % shards`, so client `c` is always in
+    /// `accounts-<c % shards>.csv`. Returns the paths of the written files, by shard index.
+    pub async fn write_accounts_to_dir(&self, dir: &Path) -> Result<Vec<PathBuf>, EngineError> {
+        async_std::fs::create_dir_all(dir).await?;
+        let shards = self.copy_accounts_by_shard();
+        let places = self.output_precision;
+
+        let writes = shards.iter().enumerate().map(|(index, accounts)| {
+            let path = dir.join(format!("accounts-{}.csv", index));
+            async move {
+                let file = async_std::fs::File::create(&path).await?;
+                Self::write_accounts_csv(file, std::slice::from_ref(accounts), places).await?;
+                Ok::<_, EngineError>(path)
             }
+        });
+
+        futures::future::try_join_all(writes).await
+    }
+
+    pub(crate) async fn write_account_rows<'a, W>(
+        wtr: &mut csv_async::AsyncWriter<W>,
+        accounts: impl IntoIterator<Item = &'a (ClientId, ClientAccount)>,
+        places: u32,
+    ) -> Result<(), EngineError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        for (client_id, account) in accounts {
+            wtr.write_record([
+                client_id.to_string(),
+                display_amount_at(account.available, places),
+                display_amount_at(account.held, places),
+                display_amount_at(account.total, places),
+                account.locked.to_string(),
+            ])
+            .await?;
         }
-        wtr.flush().await?;
         Ok(())
     }
 }
@@ -224,322 +2079,2598 @@ impl ShardedEngine {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::output_amount;
     use rust_decimal_macros::dec;
-    use std::collections::HashMap;
+    use std::sync::Mutex as StdMutex;
+
+    #[tokio::test]
+    async fn test_process_deposit() {
+        let shard_state = ShardState::default();
+
+        let transaction = Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx_id: 1,
+            amount: Some(dec!(1000.0)),
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, transaction).unwrap();
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(1000.0));
+        assert_eq!(account.total, dec!(1000.0));
+        assert_eq!(account.held, dec!(0.0));
+
+        let tx = shard_state.transactions.get(&1).unwrap();
+        assert_eq!(tx.tx_type, TransactionType::Deposit);
+        assert_eq!(tx.amount, Some(dec!(1000.0)));
+    }
+
+    #[tokio::test]
+    async fn test_process_withdrawal() {
+        let shard_state = ShardState::default();
+
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx_id: 1,
+            amount: Some(dec!(1000.0)),
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, deposit).unwrap();
+
+        let withdrawal = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client: 1,
+            tx_id: 2,
+            amount: Some(dec!(500.0)),
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, withdrawal).unwrap();
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(500.0));
+        assert_eq!(account.total, dec!(500.0));
+        assert_eq!(account.held, dec!(0.0));
+
+        let tx = shard_state.transactions.get(&2).unwrap();
+        assert_eq!(tx.tx_type, TransactionType::Withdrawal);
+        assert_eq!(tx.amount, Some(dec!(500.0)));
+    }
+
+    #[tokio::test]
+    async fn test_process_dispute() {
+        let shard_state = ShardState::default();
+
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx_id: 1,
+            amount: Some(dec!(1000.0)),
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, deposit).unwrap();
+
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            client: 1,
+            tx_id: 1,
+            amount: None,
+            under_dispute: true,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, dispute).unwrap();
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(0.0));
+        assert_eq!(account.held, dec!(1000.0));
+        assert_eq!(account.total, dec!(1000.0));
+
+        let tx = shard_state.transactions.get(&1).unwrap();
+        assert!(tx.under_dispute);
+    }
+
+    #[tokio::test]
+    async fn test_process_resolve() {
+        let shard_state = ShardState::default();
+
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx_id: 1,
+            amount: Some(dec!(1000.0)),
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, deposit).unwrap();
+
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            client: 1,
+            tx_id: 1,
+            amount: None,
+            under_dispute: true,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, dispute).unwrap();
+
+        let resolve = Transaction {
+            tx_type: TransactionType::Resolve,
+            client: 1,
+            tx_id: 1,
+            amount: None,
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, resolve).unwrap();
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(1000.0));
+        assert_eq!(account.held, dec!(0.0));
+        assert_eq!(account.total, dec!(1000.0));
+
+        let tx = shard_state.transactions.get(&1).unwrap();
+        assert!(!tx.under_dispute);
+    }
+
+    #[tokio::test]
+    async fn test_process_chargeback() {
+        let shard_state = ShardState::default();
+
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx_id: 1,
+            amount: Some(dec!(1000.0)),
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, deposit).unwrap();
+
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            client: 1,
+            tx_id: 1,
+            amount: None,
+            under_dispute: true,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, dispute).unwrap();
+
+        let chargeback = Transaction {
+            tx_type: TransactionType::Chargeback,
+            client: 1,
+            tx_id: 1,
+            amount: None,
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, chargeback).unwrap();
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(0.0));
+        assert_eq!(account.held, dec!(0.0));
+        assert_eq!(account.total, dec!(0.0));
+        assert!(account.locked);
+
+        let tx = shard_state.transactions.get(&1).unwrap();
+        assert!(!tx.under_dispute);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_rescales_to_current_precision() {
+        let mut shard_state = ShardState::default();
+
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx_id: 1,
+            amount: Some(dec!(10.1299)),
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+        ShardedEngine::process_transaction_in_shard(&shard_state, deposit).unwrap();
+
+        // Transactions recorded at precision 4, disputes now applied at precision 2
+        shard_state.set_precision(2);
+
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            client: 1,
+            tx_id: 1,
+            amount: None,
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+        ShardedEngine::process_transaction_in_shard(&shard_state, dispute).unwrap();
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.held, dec!(10.12));
+        assert_eq!(account.held.scale(), 2);
+        assert_eq!(account.available, dec!(0.0099));
+        assert_eq!(account.total, dec!(10.1299));
+
+        let resolve = Transaction {
+            tx_type: TransactionType::Resolve,
+            ..dispute
+        };
+        ShardedEngine::process_transaction_in_shard(&shard_state, resolve).unwrap();
+
+        // Resolve releases exactly what the dispute held
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.held, dec!(0));
+        assert_eq!(account.available, dec!(10.1299));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_on_locked_account_changes_nothing() {
+        let shard_state = ShardState::default();
+
+        for transaction in [
+            Transaction::deposit(1, 1, dec!(100.0)),
+            Transaction::deposit(1, 2, dec!(30.0)),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+        ] {
+            ShardedEngine::process_transaction_in_shard(&shard_state, transaction).unwrap();
+        }
+        assert!(shard_state.account(1).unwrap().locked);
+
+        let result =
+            ShardedEngine::process_transaction_in_shard(&shard_state, Transaction::dispute(1, 2));
+        assert!(matches!(result, Err(EngineError::AccountLocked(_))));
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(30.0));
+        assert_eq!(account.held, dec!(0.0));
+        assert_eq!(account.total, dec!(30.0));
+        assert!(!shard_state.transactions.get(&2).unwrap().under_dispute);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_amount_holds_only_that_part() {
+        let options = EngineOptions {
+            dispute_amounts: true,
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+        let partial = |client, tx_id, amount| Transaction {
+            amount: Some(amount),
+            ..Transaction::dispute(client, tx_id)
+        };
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.0)),
+                partial(1, 1, dec!(4.0)),
+                Transaction::deposit(2, 2, dec!(10.0)),
+                partial(2, 2, dec!(3.0)),
+                Transaction::chargeback(2, 2),
+                // More than the deposit
+                Transaction::deposit(3, 3, dec!(10.0)),
+                partial(3, 3, dec!(10.5)),
+                // Without an amount the whole deposit is held
+                Transaction::dispute(3, 3),
+            ])
+            .unwrap();
+
+        engine.finish().await;
+        let snapshot = engine.snapshot().await;
+
+        // Only the disputed part moves to held
+        let account = engine.get_account(1).await.unwrap();
+        assert_eq!((account.available, account.held), (dec!(6.0), dec!(4.0)));
+        let account = engine.get_account(3).await.unwrap();
+        assert_eq!((account.available, account.held), (dec!(0), dec!(10.0)));
+        // The chargeback reverses what the dispute held
+        let account = engine.get_account(2).await.unwrap();
+        assert_eq!((account.total, account.held), (dec!(7.0), dec!(0)));
+        assert!(account.locked);
+        assert_eq!(
+            engine
+                .run_summary()
+                .await
+                .rejections
+                .get("InvalidOperation"),
+            Some(&1)
+        );
+
+        // An open partial dispute survives a snapshot and releases what it held
+        let mut restored = ShardedEngine::with_options(
+            3,
+            Arc::new(NoopObserver),
+            EngineOptions {
+                dispute_amounts: true,
+                ..EngineOptions::default()
+            },
+        );
+        restored.restore(snapshot).await;
+        restored
+            .route_transaction(Transaction::resolve(1, 1))
+            .unwrap();
+        restored.finish().await;
+        let account = restored.get_account(1).await.unwrap();
+        assert_eq!((account.available, account.held), (dec!(10.0), dec!(0)));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_after_resolve_rejected_as_not_disputed() {
+        let shard_state = ShardState::default();
+        for transaction in [
+            Transaction::deposit(1, 1, dec!(10.0)),
+            Transaction::dispute(1, 1),
+            Transaction::resolve(1, 1),
+        ] {
+            ShardedEngine::process_transaction_in_shard(&shard_state, transaction).unwrap();
+        }
+
+        let err =
+            ShardedEngine::process_transaction_in_shard(&shard_state, Transaction::resolve(1, 1))
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "InvalidOperation: Resolve attempted on a non-disputed transaction"
+        );
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(10.0));
+        assert_eq!(account.held, dec!(0.0));
+        assert_eq!(account.total, dec!(10.0));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_first_transaction_leaves_no_account() {
+        for no_auto_create_on_withdrawal in [false, true] {
+            let options = EngineOptions {
+                no_auto_create_on_withdrawal,
+                dead_letter_retries: 2,
+                ..EngineOptions::default()
+            };
+            let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+            engine
+                .route_batch(vec![
+                    Transaction::deposit(1, 1, dec!(10.0)),
+                    Transaction::withdrawal(7, 2, dec!(5.0)),
+                    Transaction::dispute(8, 1),
+                    // Known clients keep their account after a rejection
+                    Transaction::withdrawal(1, 3, dec!(50.0)),
+                ])
+                .unwrap();
+            engine.finish().await;
+
+            let mut output = Vec::new();
+            engine.write_accounts_to(&mut output).await.unwrap();
+            let output = String::from_utf8(output).unwrap();
+            let mut rows: Vec<_> = output.lines().skip(1).collect();
+            rows.sort_unstable();
+            if no_auto_create_on_withdrawal {
+                assert_eq!(rows, vec!["1,10.0,0.0000,10.0,false"]);
+            } else {
+                assert_eq!(
+                    rows,
+                    vec![
+                        "1,10.0,0.0000,10.0,false",
+                        "7,0.0000,0.0000,0.0000,false",
+                        "8,0.0000,0.0000,0.0000,false"
+                    ]
+                );
+            }
+            assert_eq!(engine.run_summary().await.rejections.len(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_clients_rejects_new_clients_past_cap() {
+        let options = EngineOptions {
+            max_clients: Some(2),
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+        // Clients 1 and 2 live on different shards, both count towards the cap
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.0)),
+                Transaction::deposit(2, 2, dec!(20.0)),
+            ])
+            .unwrap();
+        while engine.run_summary().await.deposits < 2 {
+            tokio::task::yield_now().await;
+        }
+        engine
+            .route_batch(vec![
+                Transaction::deposit(3, 3, dec!(30.0)),
+                Transaction::dispute(3, 3),
+                Transaction::deposit(1, 4, dec!(5.0)),
+            ])
+            .unwrap();
+        engine.finish().await;
+
+        assert_eq!(engine.get_account(3).await, None);
+        assert_eq!(engine.get_account(1).await.unwrap().available, dec!(15.0));
+        assert_eq!(engine.accounts().await.len(), 2);
+        let summary = engine.run_summary().await;
+        assert_eq!(summary.rejections.get("ClientLimitExceeded"), Some(&2));
+        assert_eq!(summary.deposits, 3);
+    }
+
+    #[tokio::test]
+    async fn test_redundant_resolve_follows_lenient_resolve() {
+        for lenient_resolve in [false, true] {
+            let options = EngineOptions {
+                lenient_resolve,
+                ..EngineOptions::default()
+            };
+            let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+            engine
+                .route_batch(vec![
+                    Transaction::deposit(1, 1, dec!(10.0)),
+                    Transaction::dispute(1, 1),
+                    Transaction::resolve(1, 1),
+                    Transaction::resolve(1, 1),
+                    // Never disputed at all
+                    Transaction::deposit(1, 2, dec!(5.0)),
+                    Transaction::resolve(1, 2),
+                    // Still rejected: nothing to resolve
+                    Transaction::resolve(1, 99),
+                ])
+                .unwrap();
+            engine.finish().await;
+
+            let account = engine.get_account(1).await.unwrap();
+            assert_eq!(account.available, dec!(15.0));
+            assert_eq!(account.held, dec!(0.0));
+            let summary = engine.run_summary().await;
+            assert_eq!(summary.rejections.get("TransactionNotFound"), Some(&1));
+            if lenient_resolve {
+                assert_eq!(summary.redundant_resolves, 2);
+                assert_eq!(summary.rejections.get("InvalidOperation"), None);
+            } else {
+                assert_eq!(summary.redundant_resolves, 0);
+                assert_eq!(summary.rejections.get("InvalidOperation"), Some(&2));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_settled_dispute_not_settled_again() {
+        let shard_state = ShardState::default();
+        for transaction in [
+            Transaction::deposit(1, 1, dec!(10.0)),
+            Transaction::deposit(1, 2, dec!(5.0)),
+            Transaction::dispute(1, 1),
+            Transaction::dispute(1, 2),
+            Transaction::resolve(1, 1),
+        ] {
+            ShardedEngine::process_transaction_in_shard(&shard_state, transaction).unwrap();
+        }
+
+        // Charging back the resolved deposit would take the funds held for the other one
+        let result = ShardedEngine::process_transaction_in_shard(
+            &shard_state,
+            Transaction::chargeback(1, 1),
+        );
+        assert!(matches!(result, Err(EngineError::InvalidOperation(_))));
+        // A second dispute with a different row (here with an amount cell) holds nothing more
+        let result = ShardedEngine::process_transaction_in_shard(
+            &shard_state,
+            Transaction {
+                amount: Some(dec!(5.0)),
+                ..Transaction::dispute(1, 2)
+            },
+        );
+        assert!(matches!(result, Err(EngineError::InvalidOperation(_))));
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(10.0));
+        assert_eq!(account.held, dec!(5.0));
+        assert!(!account.locked);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_client_mismatch() {
+        let shard_state = ShardState::default();
+
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx_id: 7,
+            amount: Some(dec!(25.0)),
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+        ShardedEngine::process_transaction_in_shard(&shard_state, deposit).unwrap();
+
+        for tx_type in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            let foreign = Transaction {
+                tx_type,
+                client: 3,
+                tx_id: 7,
+                amount: None,
+                under_dispute: false,
+                timestamp: None,
+                seq: None,
+            };
+            let err =
+                ShardedEngine::process_transaction_in_shard(&shard_state, foreign).unwrap_err();
+            assert!(matches!(
+                err,
+                EngineError::DisputeClientMismatch {
+                    tx_id: 7,
+                    expected: 1,
+                    got: 3,
+                }
+            ));
+            assert_eq!(
+                err.to_string(),
+                "DisputeClientMismatch: transaction 7 belongs to client 1, not client 3"
+            );
+        }
+
+        // Neither the owner nor the other client were touched
+        let owner = shard_state.account(1).unwrap();
+        assert_eq!(owner.available, dec!(25.0));
+        assert_eq!(owner.held, dec!(0));
+        let other = shard_state.account(3).unwrap();
+        assert_eq!(other.available, dec!(0));
+        assert_eq!(other.held, dec!(0));
+        assert!(!shard_state.transactions.get(&7).unwrap().under_dispute);
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order_reference_detected() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                // Dispute before its deposit: out of order
+                Transaction::dispute(1, 1),
+                Transaction::deposit(1, 1, dec!(10.0)),
+                // Chargeback for a transaction that never comes
+                Transaction::chargeback(2, 99),
+                Transaction::deposit(2, 2, dec!(5.0)),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        assert_eq!(
+            engine.ordering_report().await,
+            OrderingReport {
+                out_of_order: 1,
+                never_seen: 1,
+                timestamp_regressions: 0,
+                seq_gaps: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_regression_detected() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.0)).at(100),
+                Transaction::deposit(2, 2, dec!(10.0)).at(50),
+                // Earlier than client 1's deposit, but still applied
+                Transaction::withdrawal(1, 3, dec!(4.0)).at(90),
+                Transaction::withdrawal(1, 4, dec!(1.0)).at(100),
+                // No timestamp, nothing to compare
+                Transaction::deposit(1, 5, dec!(1.0)),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        assert_eq!(engine.ordering_report().await.timestamp_regressions, 1);
+        assert_eq!(engine.get_account(1).await.unwrap().available, dec!(6.0));
+    }
+
+    #[tokio::test]
+    async fn test_seq_gap_detected() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.0)).with_seq(1),
+                // Client 2 is numbered on its own, starting midway
+                Transaction::deposit(2, 2, dec!(10.0)).with_seq(7),
+                Transaction::withdrawal(1, 3, dec!(4.0)).with_seq(2),
+                Transaction::deposit(2, 4, dec!(1.0)).with_seq(8),
+                // Number 3 of client 1 never arrived, the transaction is still applied
+                Transaction::deposit(1, 5, dec!(1.0)).with_seq(4),
+                // Not numbered, nothing to compare
+                Transaction::deposit(1, 6, dec!(1.0)),
+            ])
+            .unwrap();
+        engine.finish().await;
+
+        assert_eq!(engine.ordering_report().await.seq_gaps, 1);
+        assert_eq!(engine.get_account(1).await.unwrap().available, dec!(8.0));
+    }
+
+    #[tokio::test]
+    async fn test_insufficient_funds_withdrawal() {
+        let shard_state = ShardState::default();
+
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx_id: 1,
+            amount: Some(dec!(500.0)),
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        ShardedEngine::process_transaction_in_shard(&shard_state, deposit).unwrap();
+
+        let withdrawal = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client: 1,
+            tx_id: 2,
+            amount: Some(dec!(1000.0)),
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        let result = ShardedEngine::process_transaction_in_shard(&shard_state, withdrawal);
+        match result {
+            Err(EngineError::InsufficientFunds {
+                client,
+                available,
+                requested,
+            }) => {
+                assert_eq!(client, 1);
+                assert_eq!(available, dec!(500.0));
+                assert_eq!(requested, dec!(1000.0));
+            }
+            other => panic!("Expected InsufficientFunds, got {:?}", other),
+        }
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(500.0));
+        assert_eq!(account.total, dec!(500.0));
+        assert_eq!(account.held, dec!(0.0));
+
+        let tx = shard_state.transactions.get(&2);
+        assert!(tx.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fee_overdraw_rejected() {
+        let shard_state = ShardState::default();
+        ShardedEngine::process_transaction_in_shard(
+            &shard_state,
+            Transaction::deposit(1, 1, dec!(5.0)),
+        )
+        .unwrap();
+
+        let result = ShardedEngine::process_transaction_in_shard(
+            &shard_state,
+            Transaction::fee(1, 2, dec!(7.5)),
+        );
+        assert!(matches!(
+            result,
+            Err(EngineError::InsufficientFunds { client: 1, .. })
+        ));
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(5.0));
+        assert_eq!(account.total, dec!(5.0));
+        assert!(!shard_state.transactions.contains_key(&2));
+        assert_eq!(shard_state.flows().fees, dec!(0));
+    }
+
+    #[tokio::test]
+    async fn test_fee_overdraw_allowed_negative() {
+        let shard_state = ShardState {
+            fee_policy: FeePolicy::AllowNegative,
+            ..ShardState::default()
+        };
+        ShardedEngine::process_transaction_in_shard(
+            &shard_state,
+            Transaction::deposit(1, 1, dec!(5.0)),
+        )
+        .unwrap();
+
+        ShardedEngine::process_transaction_in_shard(
+            &shard_state,
+            Transaction::fee(1, 2, dec!(7.5)),
+        )
+        .unwrap();
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(-2.5));
+        assert_eq!(account.total, dec!(-2.5));
+        assert_eq!(account.held, dec!(0.0));
+        assert_eq!(
+            shard_state.transactions.get(&2).unwrap().tx_type,
+            TransactionType::Fee
+        );
+        assert_eq!(shard_state.flows().fees, dec!(7.5));
+        assert_eq!(shard_state.flows().net(), dec!(-2.5));
+    }
+
+    #[tokio::test]
+    async fn test_fee_not_disputable() {
+        let shard_state = ShardState::default();
+        ShardedEngine::process_transaction_in_shard(
+            &shard_state,
+            Transaction::deposit(1, 1, dec!(10.0)),
+        )
+        .unwrap();
+        ShardedEngine::process_transaction_in_shard(
+            &shard_state,
+            Transaction::fee(1, 2, dec!(1.0)),
+        )
+        .unwrap();
+
+        let result =
+            ShardedEngine::process_transaction_in_shard(&shard_state, Transaction::dispute(1, 2));
+        assert!(matches!(result, Err(EngineError::InvalidOperation(_))));
+
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(9.0));
+        assert_eq!(account.held, dec!(0.0));
+        assert!(!shard_state.transactions.get(&2).unwrap().under_dispute);
+    }
+
+    #[tokio::test]
+    async fn test_locked_account_follows_lock_policy() {
+        // Client 1 locked by the chargeback of tx 1, with tx 2 still under dispute
+        let locked_shard = |lock_policy| {
+            let shard_state = ShardState {
+                lock_policy,
+                ..ShardState::default()
+            };
+            for transaction in [
+                Transaction::deposit(1, 1, dec!(10.0)),
+                Transaction::deposit(1, 2, dec!(5.0)),
+                Transaction::withdrawal(1, 3, dec!(1.0)),
+                Transaction::dispute(1, 1),
+                Transaction::dispute(1, 2),
+                Transaction::chargeback(1, 1),
+            ] {
+                ShardedEngine::process_transaction_in_shard(&shard_state, transaction).unwrap();
+            }
+            assert!(shard_state.account(1).unwrap().locked);
+            shard_state
+        };
+        let transactions = [
+            (Transaction::deposit(1, 10, dec!(1.0)), false),
+            (Transaction::withdrawal(1, 10, dec!(1.0)), false),
+            (Transaction::fee(1, 10, dec!(0.5)), false),
+            (Transaction::adjustment(1, 10, dec!(1.0)), false),
+            (Transaction::dispute(1, 3), true),
+            (Transaction::resolve(1, 2), true),
+            (Transaction::chargeback(1, 2), true),
+        ];
+
+        for (transaction, settles) in transactions {
+            for lock_policy in [LockPolicy::FreezeAll, LockPolicy::SettleDisputes] {
+                let shard_state = locked_shard(lock_policy);
+                let before = shard_state.account(1).unwrap();
+                let result = ShardedEngine::process_transaction_in_shard(&shard_state, transaction);
+
+                let allowed = settles && lock_policy == LockPolicy::SettleDisputes;
+                let after = shard_state.account(1).unwrap();
+                if allowed {
+                    assert!(result.is_ok(), "{:?}: {:?}", transaction, result);
+                    assert_ne!(after, before, "{:?}", transaction);
+                } else {
+                    assert!(
+                        matches!(result, Err(EngineError::AccountLocked(_))),
+                        "{:?} under {:?}: {:?}",
+                        transaction,
+                        lock_policy,
+                        result
+                    );
+                    assert_eq!(after, before, "{:?}", transaction);
+                    assert!(!shard_state.transactions.contains_key(&10));
+                }
+                assert!(after.locked);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_dispute_follows_dispute_policy() {
+        for (dispute_policy, disputable) in [
+            (DisputePolicy::DepositsAndWithdrawals, true),
+            (DisputePolicy::DepositsOnly, false),
+        ] {
+            let shard_state = ShardState {
+                dispute_policy,
+                ..ShardState::default()
+            };
+            ShardedEngine::process_transaction_in_shard(
+                &shard_state,
+                Transaction::deposit(1, 1, dec!(10.0)),
+            )
+            .unwrap();
+            ShardedEngine::process_transaction_in_shard(
+                &shard_state,
+                Transaction::withdrawal(1, 2, dec!(4.0)),
+            )
+            .unwrap();
+
+            let result = ShardedEngine::process_transaction_in_shard(
+                &shard_state,
+                Transaction::dispute(1, 2),
+            );
+            let account = shard_state.account(1).unwrap();
+            if disputable {
+                assert!(result.is_ok(), "{:?}", result);
+                assert_eq!(account.available, dec!(2.0));
+                assert_eq!(account.held, dec!(4.0));
+            } else {
+                assert!(
+                    matches!(&result, Err(EngineError::InvalidOperation(message))
+                        if message.contains("withdrawal under DepositsOnly")),
+                    "{:?}",
+                    result
+                );
+                assert_eq!(account.available, dec!(6.0));
+                assert_eq!(account.held, dec!(0.0));
+            }
+            assert_eq!(
+                shard_state.transactions.get(&2).unwrap().under_dispute,
+                disputable
+            );
+
+            // Deposits stay disputable under both policies
+            ShardedEngine::process_transaction_in_shard(&shard_state, Transaction::dispute(1, 1))
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_positive_and_negative_adjustments() {
+        let shard_state = ShardState::default();
+        ShardedEngine::process_transaction_in_shard(
+            &shard_state,
+            Transaction::deposit(1, 1, dec!(100.0)),
+        )
+        .unwrap();
+
+        ShardedEngine::process_transaction_in_shard(
+            &shard_state,
+            Transaction::adjustment(1, 2, dec!(1.25)),
+        )
+        .unwrap();
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(101.25));
+        assert_eq!(account.total, dec!(101.25));
+
+        ShardedEngine::process_transaction_in_shard(
+            &shard_state,
+            Transaction::adjustment(1, 3, dec!(-0.5)),
+        )
+        .unwrap();
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(100.75));
+        assert_eq!(account.total, dec!(100.75));
+        assert_eq!(account.held, dec!(0.0));
+
+        assert_eq!(shard_state.flows().adjustments, dec!(0.75));
+        assert_eq!(shard_state.flows().net(), dec!(100.75));
+
+        let result =
+            ShardedEngine::process_transaction_in_shard(&shard_state, Transaction::dispute(1, 2));
+        assert!(matches!(result, Err(EngineError::InvalidOperation(_))));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_transactions() {
+        let mut engine = ShardedEngine::new(4);
+
+        // One lane per transaction, as if each was routed by its own task
+        let mut lanes = vec![];
+        for i in 0..100u32 {
+            let transaction = Transaction {
+                tx_type: if i % 2 == 0 {
+                    TransactionType::Deposit
+                } else {
+                    TransactionType::Withdrawal
+                },
+                client: (i % 10) as ClientId,
+                tx_id: i,
+                amount: Some(dec!(1000.0)),
+                under_dispute: false,
+                timestamp: None,
+                seq: None,
+            };
+
+            lanes.push(vec![transaction]);
+        }
+        engine.route_interleaved(lanes, 42).unwrap();
+
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        for shard in &engine.shards {
+            for (_, account) in shard.accounts() {
+                // Ensure the account is consistent.
+                assert!(account.available >= dec!(0.0));
+                assert!(account.total >= account.available);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_routing_replays_dispute_before_deposit() {
+        let lanes = || {
+            vec![
+                (0..8)
+                    .map(|tx_id| Transaction::deposit(1, tx_id, dec!(10.0)))
+                    .collect(),
+                vec![Transaction::dispute(1, 5)],
+                (8..16)
+                    .map(|tx_id| Transaction::deposit(2, tx_id, dec!(1.0)))
+                    .collect(),
+            ]
+        };
+        let dispute_first = |routed: &[Transaction]| {
+            let position = |tx_type| {
+                routed
+                    .iter()
+                    .position(|tx| tx.tx_id == 5 && tx.tx_type == tx_type)
+                    .unwrap()
+            };
+            position(TransactionType::Dispute) < position(TransactionType::Deposit)
+        };
+
+        // Seed 14 routes the deposit first, seed 3 the dispute, which is replayed twice
+        let engine = ShardedEngine::new(4);
+        assert!(!dispute_first(
+            &engine.route_interleaved(lanes(), 14).unwrap()
+        ));
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let mut engine = ShardedEngine::new(4);
+            let routed = engine.route_interleaved(lanes(), 3).unwrap();
+            engine.shutdown();
+            engine.wait_for_completion().await;
+
+            assert!(dispute_first(&routed));
+            assert_eq!(engine.ordering_report().await.out_of_order, 1);
+            let summary = engine.run_summary().await;
+            assert_eq!(summary.rejections.get("TransactionNotFound"), Some(&1));
+            let account = engine.get_account(1).await.unwrap();
+            assert_eq!(account.held, dec!(0.0));
+            assert_eq!(account.available, dec!(80.0));
+            runs.push((routed, summary));
+        }
+        assert_eq!(runs[0], runs[1]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_dispute_lifecycle_invariants() {
+        let mut engine = ShardedEngine::new(4);
+
+        // The resolve of every disputed deposit and a withdrawal race the deposit, its
+        // dispute and (for some) its chargeback from separate tasks, so they reach the shard
+        // in any order, interleaved with the other clients
+        let mut handles = vec![];
+        for tx_id in 1..=400u32 {
+            let client = (tx_id % 16) as ClientId;
+            let mut lifecycle = vec![
+                Transaction::deposit(client, tx_id, Decimal::from(tx_id % 7 + 1)),
+                Transaction::dispute(client, tx_id),
+            ];
+            if tx_id % 5 == 0 {
+                lifecycle.push(Transaction::chargeback(client, tx_id));
+            }
+            let mut sequences = vec![
+                lifecycle,
+                vec![Transaction::withdrawal(client, tx_id + 1000, dec!(2.0))],
+            ];
+            // Left unresolved, so some accounts are certainly locked
+            if tx_id % 80 != 0 {
+                sequences.push(vec![Transaction::resolve(client, tx_id)]);
+            }
+            for sequence in sequences {
+                let engine = engine.clone();
+                handles.push(tokio::spawn(async move {
+                    for transaction in sequence {
+                        tokio::task::yield_now().await;
+                        engine.route_transaction(transaction).unwrap();
+                    }
+                }));
+            }
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let mut locked = 0;
+        for shard_state in &engine.shards {
+            for (client, account) in shard_state.accounts() {
+                assert_eq!(account.total, account.available + account.held);
+                assert!(account.held >= dec!(0.0), "client {}", client);
+
+                // Held funds are exactly the amounts still under dispute
+                let disputed: Decimal = shard_state
+                    .transactions
+                    .iter()
+                    .filter(|tx| tx.client == client && tx.under_dispute)
+                    .filter_map(|tx| tx.amount)
+                    .sum();
+                assert_eq!(account.held, disputed, "client {}", client);
+
+                if account.locked {
+                    locked += 1;
+                    let result = ShardedEngine::process_transaction_in_shard(
+                        shard_state,
+                        Transaction::deposit(client, 5000, dec!(1.0)),
+                    );
+                    assert!(matches!(result, Err(EngineError::AccountLocked(_))));
+                    assert_eq!(shard_state.account(client).unwrap().total, account.total);
+                }
+            }
+        }
+        assert!(locked > 0);
+        assert!(engine.reconcile().await.is_balanced());
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one shard")]
+    fn test_zero_shards_rejected() {
+        ShardedEngine::new(0);
+    }
+
+    #[tokio::test]
+    async fn test_global_unique_tx_across_shards() {
+        let options = EngineOptions {
+            global_unique_tx: true,
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+
+        // Clients 1 and 2 land in different shards
+        engine
+            .route_transaction(Transaction::deposit(1, 7, dec!(10.0)))
+            .unwrap();
+        let result = engine.route_transaction(Transaction::deposit(2, 7, dec!(20.0)));
+        assert!(matches!(result, Err(EngineError::TransactionError(_))));
+
+        // Disputes reference an existing id and are not claims
+        engine
+            .route_transaction(Transaction::dispute(1, 7))
+            .unwrap();
+
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let account = engine.get_account(1).await.unwrap();
+        assert_eq!(account.held, dec!(10.0));
+        assert!(engine.get_account(2).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dispute_routed_to_shard_of_its_client() {
+        // Clients 1 and 2 land in different shards
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 7, dec!(10.0)),
+                // Routed to client 2's shard, which never recorded tx 7
+                Transaction::dispute(2, 7),
+                // The client of the deposit finds it
+                Transaction::dispute(1, 7),
+            ])
+            .unwrap();
+        engine.finish().await;
+
+        assert_eq!(engine.get_account(1).await.unwrap().held, dec!(10.0));
+        assert_eq!(engine.ordering_report().await.never_seen, 1);
+        let summary = engine.run_summary().await;
+        assert_eq!(summary.disputes, 1);
+        assert_eq!(summary.rejections.get("TransactionNotFound"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_global_unique_tx_rejects_dispute_of_other_shard_client() {
+        let options = EngineOptions {
+            global_unique_tx: true,
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+        engine
+            .route_transaction(Transaction::deposit(1, 7, dec!(10.0)))
+            .unwrap();
+
+        // Same error as for a client of the same shard, whatever the shard count
+        let result = engine.route_transaction(Transaction::dispute(2, 7));
+        assert!(matches!(
+            result,
+            Err(EngineError::DisputeClientMismatch {
+                tx_id: 7,
+                expected: 1,
+                got: 2,
+            })
+        ));
+        // An id not recorded yet is left to the shard, it may still arrive
+        engine
+            .route_transaction(Transaction::dispute(2, 8))
+            .unwrap();
+        engine
+            .route_transaction(Transaction::dispute(1, 7))
+            .unwrap();
+        engine.finish().await;
+
+        assert_eq!(engine.get_account(1).await.unwrap().held, dec!(10.0));
+        assert_eq!(engine.run_summary().await.disputes, 1);
+    }
+
+    #[tokio::test]
+    async fn test_same_tx_id_for_two_clients_allowed_by_default() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 7, dec!(10.0)),
+                Transaction::deposit(2, 7, dec!(20.0)),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        assert_eq!(engine.get_account(1).await.unwrap().total, dec!(10.0));
+        assert_eq!(engine.get_account(2).await.unwrap().total, dec!(20.0));
+    }
+
+    #[tokio::test]
+    async fn test_run_stream_routes_and_finalizes() {
+        let mut engine = ShardedEngine::new(2);
+        let items = vec![
+            Ok(Transaction::deposit(1, 1, dec!(100.0))),
+            Ok(Transaction::deposit(2, 2, dec!(40.0))),
+            Err(EngineError::TransactionError(
+                "Invalid transaction type".into(),
+            )),
+            Ok(Transaction::withdrawal(1, 3, dec!(25.0))),
+            Ok(Transaction::dispute(2, 2)),
+            // Insufficient funds
+            Ok(Transaction::withdrawal(2, 4, dec!(10.0))),
+        ];
+
+        let summary = engine
+            .run_stream(futures::stream::iter(items))
+            .await
+            .unwrap();
+
+        assert_eq!(summary.deposits, 2);
+        assert_eq!(summary.withdrawals, 1);
+        assert_eq!(summary.disputes, 1);
+        assert_eq!(summary.rejections.get("InsufficientFunds"), Some(&1));
+
+        let first = engine.get_account(1).await.unwrap();
+        assert_eq!(first.available, dec!(75.0));
+        let second = engine.get_account(2).await.unwrap();
+        assert_eq!(second.available, dec!(0.0));
+        assert_eq!(second.held, dec!(40.0));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_receives_applied_transactions() {
+        let options = EngineOptions {
+            event_capacity: Some(16),
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+        let mut events = engine.subscribe().unwrap();
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.0)),
+                // Rejected, no event
+                Transaction::withdrawal(1, 2, dec!(50.0)),
+                Transaction::withdrawal(1, 3, dec!(4.0)),
+                Transaction::dispute(1, 1),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            assert_eq!(event.client, 1);
+            received.push((
+                event.tx_type,
+                event.tx_id,
+                event.account.available,
+                event.account.held,
+            ));
+        }
+        assert_eq!(
+            received,
+            vec![
+                (TransactionType::Deposit, 1, dec!(10.0), dec!(0)),
+                (TransactionType::Withdrawal, 3, dec!(6.0), dec!(0)),
+                (TransactionType::Dispute, 1, dec!(-4.0), dec!(10.0)),
+            ]
+        );
+        assert!(ShardedEngine::new(1).subscribe().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_loses_oldest_events() {
+        let options = EngineOptions {
+            event_capacity: Some(2),
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(1, Arc::new(NoopObserver), options);
+        let mut events = engine.subscribe().unwrap();
+        let transactions = (1..=5)
+            .map(|tx_id| Transaction::deposit(1, tx_id, dec!(1.0)))
+            .collect();
+        engine.route_batch(transactions).unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        assert!(matches!(
+            events.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(3))
+        ));
+        assert_eq!(events.try_recv().unwrap().tx_id, 4);
+        assert_eq!(events.try_recv().unwrap().tx_id, 5);
+    }
+
+    #[tokio::test]
+    async fn test_explain_recorded_transaction() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 12345, dec!(10.5)),
+                Transaction::deposit(1, 2, dec!(4.0)),
+                Transaction::dispute(1, 12345),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let explanations = engine.explain(12345).await;
+        assert_eq!(explanations.len(), 1);
+        assert_eq!(
+            explanations[0].to_string(),
+            "tx 12345: deposit of 10.5 for client 1, under dispute\n\
+             client 1: available 4.0000, held 10.5000, total 14.5, locked false"
+        );
+        assert!(engine.explain(3).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_negative_balances_flag_dispute_after_withdrawal() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(100.0)),
+                Transaction::withdrawal(1, 2, dec!(80.0)),
+                // Holds 100 with only 20 available
+                Transaction::dispute(1, 1),
+                Transaction::deposit(2, 3, dec!(5.0)),
+                Transaction::deposit(3, 4, dec!(5.0)),
+                Transaction::withdrawal(3, 5, dec!(5.0)),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        assert_eq!(engine.get_account(1).await.unwrap().available, dec!(-80.0));
+        assert_eq!(engine.negative_balances().await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_locked_accounts_lists_charged_back_clients() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.0)),
+                Transaction::deposit(2, 2, dec!(10.0)),
+                Transaction::deposit(4, 3, dec!(10.0)),
+                Transaction::deposit(7, 4, dec!(10.0)),
+                Transaction::dispute(7, 4),
+                Transaction::chargeback(7, 4),
+                Transaction::dispute(2, 2),
+                Transaction::chargeback(2, 2),
+                // Disputed and resolved, not locked
+                Transaction::dispute(4, 3),
+                Transaction::resolve(4, 3),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        assert_eq!(engine.locked_accounts().await, vec![2, 7]);
+        assert_eq!(engine.run_summary().await.locked_accounts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_shard_distribution_follows_modulo() {
+        let mut engine = ShardedEngine::new(3);
+        let mut transactions = Vec::new();
+        // Clients 0..7: shard 0 gets 0, 3, 6; shard 1 gets 1, 4; shard 2 gets 2, 5
+        for (client, tx_id) in (0..7).zip(1..) {
+            transactions.push(Transaction::deposit(client, tx_id, dec!(1.0)));
+        }
+        // Two more transactions for client 4 (shard 1), one of them rejected
+        transactions.push(Transaction::withdrawal(4, 100, dec!(0.5)));
+        transactions.push(Transaction::withdrawal(4, 101, dec!(5.0)));
+        engine.route_batch(transactions).unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        assert_eq!(
+            engine.shard_distribution().await,
+            vec![(0, 3, 3), (1, 2, 4), (2, 2, 2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_same_input_gives_same_distribution_and_output() {
+        async fn run(transactions: &[Transaction]) -> (Vec<(usize, usize, usize)>, Vec<u8>) {
+            let mut engine = ShardedEngine::new(4);
+            engine.route_batch(transactions.to_vec()).unwrap();
+            engine.shutdown();
+            engine.wait_for_completion().await;
+
+            let mut output = Vec::new();
+            engine.write_accounts_to(&mut output).await.unwrap();
+            (engine.shard_distribution().await, output)
+        }
+
+        let transactions: Vec<_> = (0..500u32)
+            .map(|tx_id| Transaction::deposit((tx_id * 7919 % 1000) as ClientId, tx_id, dec!(1.5)))
+            .collect();
+        let (distribution, output) = run(&transactions).await;
+        assert_eq!(run(&transactions).await, (distribution, output));
+    }
+
+    #[tokio::test]
+    async fn test_route_batch_reaches_correct_shards() {
+        let num_shards = 3;
+        let mut engine = ShardedEngine::new(num_shards);
+
+        let transactions: Vec<Transaction> = (0..30u32)
+            .map(|i| Transaction {
+                tx_type: TransactionType::Deposit,
+                client: (i % 7) as ClientId,
+                tx_id: i,
+                amount: Some(dec!(1.0)),
+                under_dispute: false,
+                timestamp: None,
+                seq: None,
+            })
+            .collect();
+        engine.route_batch(transactions).unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let mut seen_clients = 0;
+        for (shard_index, shard_state) in engine.shards.iter().enumerate() {
+            for (client, account) in shard_state.accounts() {
+                assert_eq!(client as usize % num_shards, shard_index);
+                let expected = (0..30u32).filter(|i| (i % 7) as ClientId == client).count();
+                assert_eq!(account.total, Decimal::from(expected));
+                seen_clients += 1;
+            }
+            for transaction in shard_state.transactions.iter() {
+                assert_eq!(transaction.client as usize % num_shards, shard_index);
+            }
+        }
+        assert_eq!(seen_clients, 7);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_retry_out_of_order_dispute() {
+        let options = EngineOptions {
+            dead_letter_retries: 2,
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+
+        let transactions = [
+            (TransactionType::Dispute, 1, 1, None),
+            (TransactionType::Deposit, 1, 1, Some(dec!(40.0))),
+            // Permanent failure: insufficient funds is not retried, even once funds arrive
+            (TransactionType::Withdrawal, 2, 2, Some(dec!(50.0))),
+            (TransactionType::Deposit, 2, 3, Some(dec!(100.0))),
+        ];
+        for (tx_type, client, tx_id, amount) in transactions {
+            engine
+                .route_transaction(Transaction {
+                    tx_type,
+                    client,
+                    tx_id,
+                    amount,
+                    under_dispute: false,
+                    timestamp: None,
+                    seq: None,
+                })
+                .unwrap();
+        }
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let client_1 = engine.get_account(1).await.unwrap();
+        assert_eq!(client_1.available, dec!(0.0));
+        assert_eq!(client_1.held, dec!(40.0));
+        assert_eq!(client_1.total, dec!(40.0));
+
+        let client_2 = engine.get_account(2).await.unwrap();
+        assert_eq!(client_2.available, dec!(100.0));
+        assert_eq!(client_2.total, dec!(100.0));
+    }
+
+    #[tokio::test]
+    async fn test_strict_ordering_rejects_future_reference() {
+        for strict_ordering in [false, true] {
+            let options = EngineOptions {
+                dead_letter_retries: 1,
+                strict_ordering,
+                ..EngineOptions::default()
+            };
+            let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+            engine
+                .route_batch(vec![
+                    Transaction::deposit(1, 1, dec!(10.0)),
+                    Transaction::dispute(1, 1),
+                    // No deposit or withdrawal with an id this high has been seen yet
+                    Transaction::dispute(1, 5),
+                    Transaction::deposit(1, 5, dec!(3.0)),
+                ])
+                .unwrap();
+            engine.finish().await;
+
+            let account = engine.get_account(1).await.unwrap();
+            let rejections = engine.run_summary().await.rejections;
+            if strict_ordering {
+                assert_eq!(account.held, dec!(10.0));
+                assert_eq!(rejections.get("FutureReference"), Some(&1));
+            } else {
+                // The dead-letter retry applies the dispute once its deposit arrived
+                assert_eq!(account.held, dec!(13.0));
+                assert!(rejections.is_empty(), "{:?}", rejections);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters_disabled_by_default() {
+        let mut engine = ShardedEngine::new(1);
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            client: 1,
+            tx_id: 1,
+            amount: None,
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            amount: Some(dec!(40.0)),
+            ..dispute
+        };
+        engine.route_batch(vec![dispute, deposit]).unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let client_1 = engine.get_account(1).await.unwrap();
+        assert_eq!(client_1.available, dec!(40.0));
+        assert_eq!(client_1.held, dec!(0));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_transaction() {
+        let shard_state = ShardState::default();
+
+        // Create a deposit transaction
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx_id: 1,
+            amount: Some(dec!(1000.0)),
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        // Process the transaction once
+        let result = ShardedEngine::process_transaction_in_shard(&shard_state, deposit);
+        assert!(result.is_ok());
+
+        // Attempt to process the same transaction again
+        let duplicate_result = ShardedEngine::process_transaction_in_shard(&shard_state, deposit);
+        assert!(duplicate_result.is_err());
+
+        // Ensure the account was only updated once
+        let account = shard_state.account(1).unwrap();
+        assert_eq!(account.available, dec!(1000.0));
+        assert_eq!(account.total, dec!(1000.0));
+        assert_eq!(account.held, dec!(0.0));
+
+        // Ensure that only one transaction is recorded
+        assert_eq!(shard_state.transactions.len(), 1);
+    }
+
+    /// Observer recording every callback as (callback name, tx id)
+    #[derive(Default)]
+    struct RecordingObserver {
+        calls: StdMutex<Vec<(&'static str, u32)>>,
+    }
+
+    impl RecordingObserver {
+        fn record(&self, name: &'static str, transaction: &Transaction) {
+            self.calls.lock().unwrap().push((name, transaction.tx_id));
+        }
+    }
+
+    impl TransactionObserver for RecordingObserver {
+        fn on_deposit(&self, transaction: &Transaction, _account: &ClientAccount) {
+            self.record("deposit", transaction);
+        }
+        fn on_withdrawal(&self, transaction: &Transaction, _account: &ClientAccount) {
+            self.record("withdrawal", transaction);
+        }
+        fn on_dispute(&self, transaction: &Transaction, _account: &ClientAccount) {
+            self.record("dispute", transaction);
+        }
+        fn on_resolve(&self, transaction: &Transaction, _account: &ClientAccount) {
+            self.record("resolve", transaction);
+        }
+        fn on_chargeback(&self, transaction: &Transaction, account: &ClientAccount) {
+            assert!(account.locked);
+            self.record("chargeback", transaction);
+        }
+        fn on_auto_freeze(&self, transaction: &Transaction, account: &ClientAccount) {
+            assert!(account.locked);
+            self.record("auto_freeze", transaction);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_observer_callbacks_in_order() {
+        let observer = Arc::new(RecordingObserver::default());
+        let mut engine = ShardedEngine::with_observer(2, observer.clone());
+
+        let transactions = [
+            (TransactionType::Deposit, 1, Some(dec!(100.0))),
+            (TransactionType::Deposit, 2, Some(dec!(50.0))),
+            (TransactionType::Withdrawal, 3, Some(dec!(500.0))), // rejected, not observed
+            (TransactionType::Withdrawal, 4, Some(dec!(20.0))),
+            (TransactionType::Dispute, 1, None),
+            (TransactionType::Resolve, 1, None),
+            (TransactionType::Dispute, 2, None),
+            (TransactionType::Chargeback, 2, None),
+        ];
+        for (tx_type, tx_id, amount) in transactions {
+            engine
+                .route_transaction(Transaction {
+                    tx_type,
+                    client: 1,
+                    tx_id,
+                    amount,
+                    under_dispute: false,
+                    timestamp: None,
+                    seq: None,
+                })
+                .unwrap();
+        }
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        assert_eq!(
+            *observer.calls.lock().unwrap(),
+            vec![
+                ("deposit", 1),
+                ("deposit", 2),
+                ("withdrawal", 4),
+                ("dispute", 1),
+                ("resolve", 1),
+                ("dispute", 2),
+                ("chargeback", 2),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auto_freeze_above_locks_crossing_deposit() {
+        let observer = Arc::new(RecordingObserver::default());
+        let options = EngineOptions {
+            auto_freeze_above: Some(dec!(100.0)),
+            ..EngineOptions::default()
+        };
+        // A single shard keeps the callbacks of both clients in order
+        let mut engine = ShardedEngine::with_options(1, observer.clone(), options);
+        engine
+            .route_batch(vec![
+                // Reaching the threshold is not crossing it
+                Transaction::deposit(1, 1, dec!(60.0)),
+                Transaction::deposit(1, 2, dec!(40.0)),
+                Transaction::withdrawal(1, 3, dec!(10.0)),
+                Transaction::deposit(1, 4, dec!(50.0)),
+                // Rejected by the frozen account
+                Transaction::deposit(1, 5, dec!(1.0)),
+                Transaction::withdrawal(1, 6, dec!(1.0)),
+                // Other clients are unaffected
+                Transaction::deposit(2, 7, dec!(100.0)),
+            ])
+            .unwrap();
+        engine.finish().await;
+
+        let account = engine.get_account(1).await.unwrap();
+        assert!(account.locked);
+        assert_eq!(account.available, dec!(140.0));
+        assert!(!engine.get_account(2).await.unwrap().locked);
+        assert_eq!(
+            engine.run_summary().await.rejections.get("AccountLocked"),
+            Some(&2)
+        );
+        assert_eq!(
+            *observer.calls.lock().unwrap(),
+            vec![
+                ("deposit", 1),
+                ("deposit", 2),
+                ("withdrawal", 3),
+                ("deposit", 4),
+                ("auto_freeze", 4),
+                ("deposit", 7),
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_snapshots_during_processing_are_consistent() {
+        let options = EngineOptions {
+            lanes_per_shard: 2,
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(4, Arc::new(NoopObserver), options);
+
+        // Deposits, withdrawals and disputes keep every balance moving while snapshots run
+        let router = engine.clone();
+        let routing = tokio::spawn(async move {
+            for round in 0..600u32 {
+                let batch = (1..=16u32)
+                    .flat_map(|n| {
+                        let tx_id = (round * 16 + n) * 2;
+                        let client = n as ClientId;
+                        [
+                            Transaction::deposit(client, tx_id, dec!(3.5)),
+                            match round % 3 {
+                                0 => Transaction::withdrawal(client, tx_id + 1, dec!(1.25)),
+                                1 => Transaction::dispute(client, tx_id),
+                                _ => Transaction::resolve(client, tx_id - 32),
+                            },
+                        ]
+                    })
+                    .collect();
+                router.route_batch(batch).unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let mut snapshots = 0;
+        while !routing.is_finished() || snapshots < 10 {
+            for (client, account) in engine.consistent_accounts().await {
+                assert_eq!(
+                    account.total,
+                    account.available + account.held,
+                    "client {}",
+                    client
+                );
+            }
+            // A copy taken shard by shard while they run would catch some accounts before
+            // and the flows after a transaction
+            let snapshot = engine.snapshot().await;
+            let total: Decimal = snapshot.accounts.iter().map(|account| account.total).sum();
+            assert_eq!(total, snapshot.flows.net());
+            snapshots += 1;
+        }
+        routing.await.unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        assert!(engine.reconcile().await.is_balanced());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_lanes_preserve_per_client_order() {
+        let observer = Arc::new(RecordingObserver::default());
+        let options = EngineOptions {
+            lanes_per_shard: 4,
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(2, observer.clone(), options);
+
+        // Every step only succeeds after the previous one of its client, and the clients of
+        // a step are interleaved so each lane receives several clients at once
+        // Transaction ids are `client * 10 + step`
+        let clients = 1..=32u32;
+        let steps: [&dyn Fn(u32) -> Transaction; 6] = [
+            &|n| Transaction::deposit(n as ClientId, n * 10 + 1, dec!(10.0)),
+            &|n| Transaction::withdrawal(n as ClientId, n * 10 + 2, dec!(10.0)),
+            &|n| Transaction::deposit(n as ClientId, n * 10 + 3, dec!(5.0)),
+            &|n| Transaction::dispute(n as ClientId, n * 10 + 3),
+            &|n| Transaction::resolve(n as ClientId, n * 10 + 3),
+            &|n| Transaction::withdrawal(n as ClientId, n * 10 + 4, dec!(5.0)),
+        ];
+        for step in steps {
+            engine
+                .route_batch(clients.clone().map(step).collect())
+                .unwrap();
+        }
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let summary = engine.run_summary().await;
+        assert!(summary.rejections.is_empty(), "{:?}", summary.rejections);
+        let calls = observer.calls.lock().unwrap().clone();
+        assert_eq!(calls.len(), 32 * 6);
+        for n in clients {
+            let order: Vec<_> = calls
+                .iter()
+                .filter(|(_, tx_id)| tx_id / 10 == n)
+                .map(|(name, tx_id)| (*name, tx_id % 10))
+                .collect();
+            assert_eq!(
+                order,
+                vec![
+                    ("deposit", 1),
+                    ("withdrawal", 2),
+                    ("deposit", 3),
+                    ("dispute", 3),
+                    ("resolve", 3),
+                    ("withdrawal", 4)
+                ],
+                "client {}",
+                n
+            );
+            let account = engine.get_account(n as ClientId).await.unwrap();
+            assert_eq!(account.total, dec!(0.0));
+        }
+        assert!(engine.reconcile().await.is_balanced());
+    }
+
+    /// Observer blocking in the deposit callback of client 1 until released
+    struct BlockingObserver {
+        release: StdMutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    impl TransactionObserver for BlockingObserver {
+        fn on_deposit(&self, transaction: &Transaction, _account: &ClientAccount) {
+            if transaction.client == 1 {
+                self.release.lock().unwrap().recv().unwrap();
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_slow_client_does_not_block_its_shard() {
+        let (release, blocked) = std::sync::mpsc::channel();
+        let observer = Arc::new(BlockingObserver {
+            release: StdMutex::new(blocked),
+        });
+        let options = EngineOptions {
+            lanes_per_shard: 2,
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(1, observer, options);
+
+        // Clients 1 and 2 share the shard but not the lane
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.0)),
+                Transaction::deposit(1, 2, dec!(1.0)),
+                Transaction::deposit(2, 3, dec!(5.0)),
+                Transaction::withdrawal(2, 4, dec!(2.0)),
+            ])
+            .unwrap();
+
+        // Client 2 is processed while client 1 is stuck in its callback, whose account was
+        // already updated and can be read
+        wait_for_available(&engine, 2, dec!(3.0)).await;
+        wait_for_available(&engine, 1, dec!(10.0)).await;
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+        assert_eq!(engine.get_account(1).await.unwrap().available, dec!(11.0));
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_known_sequence() {
+        let mut engine = ShardedEngine::new(3);
+
+        let transactions = [
+            (TransactionType::Deposit, 1, 1, Some(dec!(100.0))),
+            (TransactionType::Deposit, 2, 2, Some(dec!(200.0))),
+            (TransactionType::Deposit, 3, 3, Some(dec!(50.1234))),
+            (TransactionType::Withdrawal, 1, 4, Some(dec!(40.0))),
+            (TransactionType::Withdrawal, 3, 5, Some(dec!(100.0))), // insufficient funds
+            (TransactionType::Dispute, 2, 2, None),
+            (TransactionType::Chargeback, 2, 2, None),
+            (TransactionType::Dispute, 1, 1, None),
+            (TransactionType::Resolve, 1, 1, None),
+        ];
+        for (tx_type, client, tx_id, amount) in transactions {
+            engine
+                .route_transaction(Transaction {
+                    tx_type,
+                    client,
+                    tx_id,
+                    amount,
+                    under_dispute: false,
+                    timestamp: None,
+                    seq: None,
+                })
+                .unwrap();
+        }
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let report = engine.reconcile().await;
+        assert_eq!(report.flows.deposits, dec!(350.1234));
+        assert_eq!(report.flows.withdrawals, dec!(40.0));
+        assert_eq!(report.flows.chargebacks, dec!(200.0));
+        assert_eq!(report.total_balance, dec!(110.1234));
+        assert_eq!(report.discrepancy, dec!(0));
+        assert!(report.is_balanced());
+    }
+
+    #[tokio::test]
+    async fn test_check_aggregate_beyond_decimal_limit() {
+        let options = EngineOptions {
+            check_aggregate: true,
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(4, Arc::new(NoopObserver), options);
+        let near_limit = dec!(79_000_000_000_000_000_000_000_000_000);
+        // One client per shard, together holding several times `Decimal::MAX`
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, near_limit),
+                Transaction::deposit(2, 2, near_limit),
+                Transaction::deposit(3, 3, near_limit),
+                Transaction::deposit(4, 4, dec!(50_000_000_000_000_000_000_000_000_000)),
+                Transaction::deposit(4, 5, dec!(20_000_000_000_000_000_000_000_000_000)),
+                Transaction::withdrawal(1, 6, dec!(1)),
+                Transaction::fee(1, 7, dec!(2)),
+                Transaction::adjustment(1, 8, dec!(-3)),
+                Transaction::adjustment(2, 9, dec!(1)),
+                Transaction::dispute(4, 5),
+                Transaction::chargeback(4, 5),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        assert!(engine.run_summary().await.rejections.is_empty());
+        engine.check_aggregate().await.unwrap();
+
+        // A balance changed behind the engine's back breaks the check
+        lock(&engine.shards[1].account_lock(1).unwrap().account).total += dec!(1);
+        let result = engine.check_aggregate().await;
+        let expected = format!(
+            "accounts total {}.0000, flows add up to {}.0000",
+            "286999999999999999999999999996", "286999999999999999999999999995"
+        );
+        assert!(
+            matches!(&result, Err(EngineError::AggregateMismatch(msg)) if *msg == expected),
+            "{:?}",
+            result
+        );
+    }
 
     #[tokio::test]
-    async fn test_process_deposit() {
-        let mut shard_state = ShardState {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
-            processed_transactions: HashSet::new(),
-        };
+    async fn test_cancel_midway_leaves_partial_consistent_state() {
+        let engine = ShardedEngine::new(4);
+        let routed = 20_000u32;
 
-        let transaction = Transaction {
+        for tx_id in 0..routed {
+            engine
+                .route_transaction(Transaction {
+                    tx_type: TransactionType::Deposit,
+                    client: (tx_id % 8) as ClientId,
+                    tx_id,
+                    amount: Some(dec!(1.0)),
+                    under_dispute: false,
+                    timestamp: None,
+                    seq: None,
+                })
+                .unwrap();
+        }
+
+        // Let the shard workers start draining their queues, then cancel
+        tokio::task::yield_now().await;
+        engine.cancellation_token().cancel();
+
+        // Workers exit even though the engine still holds its senders
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            engine.wait_for_completion(),
+        )
+        .await
+        .expect("Engine did not stop after cancellation");
+
+        let report = engine.reconcile().await;
+        assert!(report.is_balanced());
+        assert!(report.flows.deposits < Decimal::from(routed));
+
+        let late = Transaction {
             tx_type: TransactionType::Deposit,
             client: 1,
-            tx_id: 1,
-            amount: Some(dec!(1000.0)),
+            tx_id: routed,
+            amount: Some(dec!(1.0)),
             under_dispute: false,
+            timestamp: None,
+            seq: None,
         };
+        assert!(matches!(
+            engine.route_transaction(late),
+            Err(EngineError::ShutDownError(_))
+        ));
+    }
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, transaction).unwrap();
+    #[tokio::test]
+    async fn test_write_accounts_to() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_transaction(Transaction {
+                tx_type: TransactionType::Deposit,
+                client: 1,
+                tx_id: 1,
+                amount: Some(dec!(10.5)),
+                under_dispute: false,
+                timestamp: None,
+                seq: None,
+            })
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
 
-        let account = shard_state.accounts.get(&1).unwrap();
-        assert_eq!(account.available, dec!(1000.0));
-        assert_eq!(account.total, dec!(1000.0));
-        assert_eq!(account.held, dec!(0.0));
+        let mut output = Vec::new();
+        engine.write_accounts_to(&mut output).await.unwrap();
 
-        let tx = shard_state.transactions.get(&1).unwrap();
-        assert_eq!(tx.tx_type, TransactionType::Deposit);
-        assert_eq!(tx.amount, Some(dec!(1000.0)));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,10.5,0.0000,10.5,false\n"
+        );
     }
 
     #[tokio::test]
-    async fn test_process_withdrawal() {
-        let mut shard_state = ShardState {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
-            processed_transactions: HashSet::new(),
-        };
-
-        let deposit = Transaction {
-            tx_type: TransactionType::Deposit,
-            client: 1,
-            tx_id: 1,
-            amount: Some(dec!(1000.0)),
-            under_dispute: false,
-        };
+    async fn test_finalize_writes_accounts_and_returns_summary() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.5)),
+                Transaction::withdrawal(1, 2, dec!(3.0)),
+                Transaction::deposit(2, 3, dec!(5.0)),
+                Transaction::withdrawal(2, 4, dec!(9.0)),
+            ])
+            .unwrap();
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, deposit).unwrap();
+        let mut output = Vec::new();
+        let summary = engine.finalize(&mut output).await.unwrap();
 
-        let withdrawal = Transaction {
-            tx_type: TransactionType::Withdrawal,
-            client: 1,
-            tx_id: 2,
-            amount: Some(dec!(500.0)),
-            under_dispute: false,
-        };
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,7.5,0.0000,7.5,false\n\
+             2,5.0,0.0000,5.0,false\n"
+        );
+        assert_eq!(summary.deposits, 2);
+        assert_eq!(summary.withdrawals, 1);
+        assert_eq!(summary.rejections.get("InsufficientFunds"), Some(&1));
+    }
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, withdrawal).unwrap();
+    #[tokio::test]
+    async fn test_write_accounts_net_zero_matches_new_account() {
+        let mut engine = ShardedEngine::new(1);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.5)),
+                Transaction::withdrawal(1, 2, dec!(10.50)),
+                // Rejected for insufficient funds, leaves a brand-new account behind
+                Transaction::withdrawal(2, 3, dec!(1.0)),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
 
-        let account = shard_state.accounts.get(&1).unwrap();
-        assert_eq!(account.available, dec!(500.0));
-        assert_eq!(account.total, dec!(500.0));
-        assert_eq!(account.held, dec!(0.0));
+        let mut output = Vec::new();
+        engine.write_accounts_to(&mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut rows: Vec<&str> = output.lines().skip(1).collect();
+        rows.sort();
 
-        let tx = shard_state.transactions.get(&2).unwrap();
-        assert_eq!(tx.tx_type, TransactionType::Withdrawal);
-        assert_eq!(tx.amount, Some(dec!(500.0)));
+        assert_eq!(
+            rows,
+            vec![
+                "1,0.0000,0.0000,0.0000,false",
+                "2,0.0000,0.0000,0.0000,false"
+            ]
+        );
     }
 
     #[tokio::test]
-    async fn test_process_dispute() {
-        let mut shard_state = ShardState {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
-            processed_transactions: HashSet::new(),
+    async fn test_write_accounts_negative_zero_has_no_sign() {
+        let options = EngineOptions {
+            fee_policy: FeePolicy::AllowNegative,
+            hold_precision: Some(8),
+            ..EngineOptions::default()
         };
+        let mut engine = ShardedEngine::with_options(1, Arc::new(NoopObserver), options);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(1.0)),
+                Transaction::fee(1, 2, dec!(1.00001)),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
 
-        let deposit = Transaction {
-            tx_type: TransactionType::Deposit,
-            client: 1,
-            tx_id: 1,
-            amount: Some(dec!(1000.0)),
-            under_dispute: false,
-        };
+        // -0.00001 is cut to -0.0000 at the display precision
+        let (_, account) = engine.accounts().await.remove(0);
+        assert_eq!(account.available, dec!(-0.00001));
+        assert!(output_amount(account.total).is_sign_negative());
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, deposit).unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts_to(&mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
 
-        let dispute = Transaction {
-            tx_type: TransactionType::Dispute,
-            client: 1,
-            tx_id: 1,
-            amount: None,
-            under_dispute: true,
-        };
+        assert_eq!(output.lines().nth(1), Some("1,0.0000,0.0000,0.0000,false"));
+    }
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, dispute).unwrap();
+    #[tokio::test]
+    async fn test_implausible_balance_fails_output() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(999999999999999.9999)),
+                Transaction::deposit(2, 2, dec!(100000000000000000000)),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
 
-        let account = shard_state.accounts.get(&1).unwrap();
-        assert_eq!(account.available, dec!(0.0));
-        assert_eq!(account.held, dec!(1000.0));
-        assert_eq!(account.total, dec!(1000.0));
+        let mut output = Vec::new();
+        let result = engine.write_accounts_to(&mut output).await;
+        assert!(matches!(
+            result,
+            Err(EngineError::ImplausibleAmount { client: 2, amount })
+                if amount == dec!(100000000000000000000)
+        ));
+        assert!(output.is_empty());
 
-        let tx = shard_state.transactions.get(&1).unwrap();
-        assert!(tx.under_dispute);
+        // The largest plausible balance is written in full
+        assert!(check_output_amount(1, dec!(-999999999999999.9999)).is_ok());
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_transaction(Transaction::deposit(1, 1, dec!(999999999999999.9999)))
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+        let mut output = Vec::new();
+        engine.write_accounts_to(&mut output).await.unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("\n1,999999999999999.9999,0.0000,999999999999999.9999,false\n"));
     }
 
     #[tokio::test]
-    async fn test_process_resolve() {
-        let mut shard_state = ShardState {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
-            processed_transactions: HashSet::new(),
+    async fn test_output_precision_independent_of_hold_precision() {
+        let options = EngineOptions {
+            fee_policy: FeePolicy::AllowNegative,
+            output_precision: Some(2),
+            ..EngineOptions::default()
         };
+        let mut engine = ShardedEngine::with_options(1, Arc::new(NoopObserver), options);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(1.2399)),
+                Transaction::deposit(2, 2, dec!(10.5)),
+                Transaction::deposit(2, 3, dec!(0.0099)),
+                Transaction::dispute(2, 3),
+                Transaction::deposit(3, 4, dec!(1.0)),
+                Transaction::fee(3, 5, dec!(1.004)),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
 
-        let deposit = Transaction {
-            tx_type: TransactionType::Deposit,
-            client: 1,
-            tx_id: 1,
-            amount: Some(dec!(1000.0)),
-            under_dispute: false,
-        };
+        // Balances are still kept at 4 places
+        assert_eq!(engine.get_account(1).await.unwrap().total, dec!(1.2399));
+        assert_eq!(engine.get_account(2).await.unwrap().held, dec!(0.0099));
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, deposit).unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts_to(&mut output).await.unwrap();
+        let mut rows: Vec<_> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(str::to_string)
+            .collect();
+        rows.sort();
 
-        let dispute = Transaction {
-            tx_type: TransactionType::Dispute,
-            client: 1,
-            tx_id: 1,
-            amount: None,
-            under_dispute: true,
-        };
+        assert_eq!(
+            rows,
+            vec![
+                "1,1.23,0.00,1.23,false",
+                "2,10.50,0.00,10.50,false",
+                "3,0.00,0.00,0.00,false",
+            ]
+        );
+    }
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, dispute).unwrap();
+    /// Writer recording the number of writes it receives
+    struct CountingWriter {
+        bytes: Vec<u8>,
+        writes: usize,
+    }
 
-        let resolve = Transaction {
-            tx_type: TransactionType::Resolve,
-            client: 1,
-            tx_id: 1,
-            amount: None,
-            under_dispute: false,
-        };
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.writes += 1;
+            self.bytes.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, resolve).unwrap();
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
 
-        let account = shard_state.accounts.get(&1).unwrap();
-        assert_eq!(account.available, dec!(1000.0));
-        assert_eq!(account.held, dec!(0.0));
-        assert_eq!(account.total, dec!(1000.0));
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
 
-        let tx = shard_state.transactions.get(&1).unwrap();
-        assert!(tx.under_dispute);
+    /// Writer taking `lines` complete lines, then failing like a full disk
+    struct FailingWriter {
+        bytes: Vec<u8>,
+        lines: usize,
     }
 
-    #[tokio::test]
-    async fn test_process_chargeback() {
-        let mut shard_state = ShardState {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
-            processed_transactions: HashSet::new(),
-        };
+    impl AsyncWrite for FailingWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            if self.lines == 0 {
+                return std::task::Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    "No space left on device",
+                )));
+            }
+            // A short write ending with the last line that fits
+            let accepted = buf
+                .iter()
+                .enumerate()
+                .filter(|(_, byte)| **byte == b'\n')
+                .nth(self.lines - 1)
+                .map_or(buf.len(), |(index, _)| index + 1);
+            let lines = buf[..accepted]
+                .iter()
+                .filter(|byte| **byte == b'\n')
+                .count();
+            self.lines -= lines;
+            self.bytes.extend_from_slice(&buf[..accepted]);
+            std::task::Poll::Ready(Ok(accepted))
+        }
 
-        let deposit = Transaction {
-            tx_type: TransactionType::Deposit,
-            client: 1,
-            tx_id: 1,
-            amount: Some(dec!(1000.0)),
-            under_dispute: false,
-        };
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, deposit).unwrap();
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
 
-        let dispute = Transaction {
-            tx_type: TransactionType::Dispute,
-            client: 1,
-            tx_id: 1,
-            amount: None,
-            under_dispute: true,
+    #[tokio::test]
+    async fn test_failed_output_reports_rows_written() {
+        let mut engine = ShardedEngine::new(2);
+        let transactions = (1..=10)
+            .map(|client| Transaction::deposit(client, 100, dec!(1.0)))
+            .collect();
+        engine.route_batch(transactions).unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        // The header and 4 accounts fit, the disk is full from the fifth on
+        let mut writer = FailingWriter {
+            bytes: Vec::new(),
+            lines: 5,
         };
+        let result = engine.write_accounts_to(&mut writer).await;
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, dispute).unwrap();
+        match result {
+            Err(EngineError::PartialOutput {
+                rows_written,
+                source,
+            }) => {
+                assert_eq!(rows_written, 4);
+                assert!(source.to_string().contains("No space left on device"));
+            }
+            other => panic!("expected a partial output, got {:?}", other),
+        }
+        assert_eq!(String::from_utf8(writer.bytes).unwrap().lines().count(), 5);
 
-        let chargeback = Transaction {
-            tx_type: TransactionType::Chargeback,
-            client: 1,
-            tx_id: 1,
-            amount: None,
-            under_dispute: false,
+        // Nothing written at all
+        let mut writer = FailingWriter {
+            bytes: Vec::new(),
+            lines: 0,
         };
+        let err = engine.write_accounts_to(&mut writer).await.unwrap_err();
+        assert!(matches!(
+            err,
+            EngineError::PartialOutput {
+                rows_written: 0,
+                ..
+            }
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("PartialOutput: output stopped after 0 account rows"));
+    }
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, chargeback).unwrap();
+    /// Gate stalling a `GatedWriter` until released, like a stdout nobody reads
+    #[derive(Default)]
+    struct Gate {
+        reached: Notify,
+        released: AtomicBool,
+        waker: futures::task::AtomicWaker,
+    }
 
-        let account = shard_state.accounts.get(&1).unwrap();
-        assert_eq!(account.available, dec!(0.0));
-        assert_eq!(account.held, dec!(0.0));
-        assert_eq!(account.total, dec!(0.0));
-        assert!(account.locked);
+    impl Gate {
+        fn release(&self) {
+            self.released.store(true, Ordering::SeqCst);
+            self.waker.wake();
+        }
+    }
 
-        let tx = shard_state.transactions.get(&1).unwrap();
-        assert!(tx.under_dispute);
+    struct GatedWriter {
+        bytes: Vec<u8>,
+        gate: Arc<Gate>,
     }
 
-    #[tokio::test]
-    async fn test_insufficient_funds_withdrawal() {
-        let mut shard_state = ShardState {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
-            processed_transactions: HashSet::new(),
-        };
+    impl AsyncWrite for GatedWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            if !self.gate.released.load(Ordering::SeqCst) {
+                self.gate.waker.register(cx.waker());
+                self.gate.reached.notify_one();
+                if !self.gate.released.load(Ordering::SeqCst) {
+                    return std::task::Poll::Pending;
+                }
+            }
+            self.bytes.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
 
-        let deposit = Transaction {
-            tx_type: TransactionType::Deposit,
-            client: 1,
-            tx_id: 1,
-            amount: Some(dec!(500.0)),
-            under_dispute: false,
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn wait_for_available(engine: &ShardedEngine, client: ClientId, expected: Decimal) {
+        let applied = async {
+            while engine
+                .get_account(client)
+                .await
+                .map(|account| account.available)
+                != Some(expected)
+            {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
         };
+        tokio::time::timeout(std::time::Duration::from_secs(10), applied)
+            .await
+            .expect("shards stalled");
+    }
 
-        ShardedEngine::process_transaction_in_shard(&mut shard_state, deposit).unwrap();
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_output_during_processing_does_not_block_shards() {
+        let mut engine = ShardedEngine::new(2);
+        // Enough rows to overflow the output buffer, so the writer is reached mid-output
+        let transactions = (1..=20_000)
+            .zip(1..)
+            .map(|(client, tx_id)| Transaction::deposit(client, tx_id, dec!(1.0)))
+            .collect();
+        engine.route_batch(transactions).unwrap();
+        wait_for_available(&engine, 19_999, dec!(1.0)).await;
+        wait_for_available(&engine, 20_000, dec!(1.0)).await;
 
-        let withdrawal = Transaction {
-            tx_type: TransactionType::Withdrawal,
-            client: 1,
-            tx_id: 2,
-            amount: Some(dec!(1000.0)),
-            under_dispute: false,
+        let gate = Arc::new(Gate::default());
+        let mut writer = GatedWriter {
+            bytes: Vec::new(),
+            gate: Arc::clone(&gate),
         };
+        let output_engine = engine.clone();
+        let output = tokio::spawn(async move {
+            output_engine.write_accounts_to(&mut writer).await.unwrap();
+            writer.bytes
+        });
 
-        let result = ShardedEngine::process_transaction_in_shard(&mut shard_state, withdrawal);
-        assert!(result.is_err());
+        // The writer is stalled, yet both shards keep applying transactions
+        gate.reached.notified().await;
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 100_001, dec!(5.0)),
+                Transaction::deposit(2, 100_002, dec!(5.0)),
+            ])
+            .unwrap();
+        wait_for_available(&engine, 1, dec!(6.0)).await;
+        wait_for_available(&engine, 2, dec!(6.0)).await;
 
-        let account = shard_state.accounts.get(&1).unwrap();
-        assert_eq!(account.available, dec!(500.0));
-        assert_eq!(account.total, dec!(500.0));
-        assert_eq!(account.held, dec!(0.0));
+        gate.release();
+        let output = String::from_utf8(output.await.unwrap()).unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
 
-        let tx = shard_state.transactions.get(&2);
-        assert!(tx.is_none());
+        // The output is the state from before the stall
+        assert_eq!(output.lines().count(), 20_001);
+        assert!(output.lines().any(|line| line == "1,1.0,0.0000,1.0,false"));
+        assert!(output.lines().any(|line| line == "2,1.0,0.0000,1.0,false"));
     }
 
     #[tokio::test]
-    async fn test_concurrent_transactions() {
+    async fn test_buffered_output_bytes_unchanged() {
         let mut engine = ShardedEngine::new(4);
+        let transactions = (0..20_000)
+            .zip(1..)
+            .map(|(client, tx_id)| Transaction::deposit(client, tx_id, dec!(1234.5678)))
+            .collect();
+        engine.route_batch(transactions).unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
 
-        let mut handles = vec![];
+        // Reference output written without the buffer, in the same client order
+        let mut reference = csv_async::AsyncWriter::from_writer(Vec::new());
+        reference
+            .write_record(&["client", "available", "held", "total", "locked"])
+            .await
+            .unwrap();
+        let mut accounts: Vec<_> = engine
+            .shards
+            .iter()
+            .flat_map(|shard| shard.accounts())
+            .collect();
+        accounts.sort_unstable_by_key(|(client, _)| *client);
+        ShardedEngine::write_account_rows(&mut reference, &accounts, MAX_DISPLAY_PRECISION)
+            .await
+            .unwrap();
+        let reference = reference.into_inner().await.unwrap();
 
-        for i in 0..100 {
-            let engine = engine.clone();
-            let transaction = Transaction {
-                tx_type: if i % 2 == 0 {
-                    TransactionType::Deposit
-                } else {
-                    TransactionType::Withdrawal
-                },
-                client: i % 10,
-                tx_id: i as u32,
-                amount: Some(dec!(1000.0)),
-                under_dispute: false,
-            };
+        let mut output = CountingWriter {
+            bytes: Vec::new(),
+            writes: 0,
+        };
+        engine.write_accounts_to(&mut output).await.unwrap();
 
-            let handle = tokio::spawn(async move {
-                engine.route_transaction(transaction).unwrap();
-            });
+        assert_eq!(output.bytes, reference);
+        // About 800 KB of rows reach the writer in a handful of writes
+        assert!(output.writes <= reference.len() / OUTPUT_BUFFER_SIZE + 2);
+    }
+
+    #[tokio::test]
+    async fn test_write_accounts_to_dir_partitions_by_shard() {
+        let num_shards = 3;
+        let mut engine = ShardedEngine::new(num_shards);
+        let deposits = (1..=10u32)
+            .map(|i| Transaction::deposit(i as ClientId, i, dec!(2.5)))
+            .collect();
+        engine.route_batch(deposits).unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
 
-            handles.push(handle);
+        let dir = Path::new("res/test_write_accounts_to_dir");
+        let paths = engine.write_accounts_to_dir(dir).await.unwrap();
+        assert_eq!(paths.len(), num_shards);
+
+        for (index, path) in paths.iter().enumerate() {
+            assert_eq!(path, &dir.join(format!("accounts-{}.csv", index)));
+            let content = std::fs::read_to_string(path).unwrap();
+            let mut lines = content.lines();
+            assert_eq!(lines.next(), Some("client,available,held,total,locked"));
+
+            let mut clients: Vec<u32> = lines
+                .map(|line| line.split(',').next().unwrap().parse().unwrap())
+                .collect();
+            clients.sort();
+            let expected: Vec<u32> = (1..=10u32)
+                .filter(|client| *client as usize % num_shards == index)
+                .collect();
+            assert_eq!(clients, expected);
         }
 
-        for handle in handles {
-            handle.await.unwrap();
+        std::fs::remove_dir_all(dir).expect("Failed to delete test directory");
+    }
+
+    /// Writer accepting `limit` bytes, then failing like a pipe whose reader went away
+    struct ClosedPipe {
+        accepted: usize,
+        limit: usize,
+    }
+
+    impl AsyncWrite for ClosedPipe {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            if self.accepted >= self.limit {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+            }
+            let written = buf.len().min(self.limit - self.accepted);
+            self.accepted += written;
+            std::task::Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
         }
+    }
 
+    #[tokio::test]
+    async fn test_write_accounts_to_closed_pipe() {
+        let mut engine = ShardedEngine::new(2);
+        let deposits = (1..=20u32)
+            .map(|i| Transaction::deposit(i as ClientId, i, dec!(1.0)))
+            .collect();
+        engine.route_batch(deposits).unwrap();
         engine.shutdown();
         engine.wait_for_completion().await;
 
-        for shard in &engine.shards {
-            let shard_state = shard.lock().await;
-            for account in shard_state.accounts.values() {
-                // Ensure the account is consistent.
-                assert!(account.available >= dec!(0.0));
-                assert!(account.total >= account.available);
-            }
-        }
+        // The reader goes away after the header and a few rows
+        let err = engine
+            .write_accounts_to(ClosedPipe {
+                accepted: 0,
+                limit: 64,
+            })
+            .await
+            .unwrap_err();
+        assert!(err.is_broken_pipe());
+
+        let result = engine
+            .write_accounts_to_pipe(ClosedPipe {
+                accepted: 0,
+                limit: 64,
+            })
+            .await;
+        assert!(result.is_ok());
     }
 
+    #[cfg(feature = "wide-client-id")]
     #[tokio::test]
-    async fn test_duplicate_transaction() {
-        let mut shard_state = ShardState {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
-            processed_transactions: HashSet::new(),
-        };
+    async fn test_wide_client_id_routing_and_output() {
+        let client: ClientId = 70_001;
+        let mut engine = ShardedEngine::new(4);
+        engine
+            .route_transaction(Transaction {
+                tx_type: TransactionType::Deposit,
+                client,
+                tx_id: 1,
+                amount: Some(dec!(25.0)),
+                under_dispute: false,
+                timestamp: None,
+                seq: None,
+            })
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        // 70_001 % 4 == 1, and the id must not have been truncated to u16
+        assert!(engine.shards[1].accounts.contains_key(&client));
+
+        let mut output = Vec::new();
+        engine.write_accounts_to(&mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\n70001,25.0,0.0000,25.0,false\n"));
+    }
+
+    #[tokio::test]
+    async fn test_reused_transaction_id_rejected() {
+        let shard_state = ShardState::default();
 
-        // Create a deposit transaction
         let deposit = Transaction {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx_id: 1,
             amount: Some(dec!(1000.0)),
             under_dispute: false,
+            timestamp: None,
+            seq: None,
         };
+        ShardedEngine::process_transaction_in_shard(&shard_state, deposit).unwrap();
 
-        // Process the transaction once
-        let result = ShardedEngine::process_transaction_in_shard(&mut shard_state, deposit.clone());
-        assert!(result.is_ok());
-
-        // Attempt to process the same transaction again
-        let duplicate_result =
-            ShardedEngine::process_transaction_in_shard(&mut shard_state, deposit);
-        assert!(duplicate_result.is_err());
+        // Same id, different amount, e.g. coming from another input file
+        let reused = Transaction {
+            amount: Some(dec!(5.0)),
+            ..deposit
+        };
+        let result = ShardedEngine::process_transaction_in_shard(&shard_state, reused);
+        assert!(result.is_err());
 
-        // Ensure the account was only updated once
-        let account = shard_state.accounts.get(&1).unwrap();
+        let account = shard_state.account(1).unwrap();
         assert_eq!(account.available, dec!(1000.0));
-        assert_eq!(account.total, dec!(1000.0));
-        assert_eq!(account.held, dec!(0.0));
-
-        // Ensure that only one transaction is recorded
-        assert_eq!(shard_state.transactions.len(), 1);
+        assert_eq!(
+            shard_state.transactions.get(&1).unwrap().amount,
+            Some(dec!(1000.0))
+        );
     }
 }
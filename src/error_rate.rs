@@ -0,0 +1,98 @@
+use crate::errors::EngineError;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Rows a run must have read before `ErrorRateLimit` can abort it, so a bad row near the
+/// start of the input does not stand for the whole file
+pub const MIN_ERROR_RATE_SAMPLE: u64 = 100;
+
+/// Aborts a run once more than `max_rate` of its rows were rejected, shared by every reader.
+///
+/// A row is rejected when it fails to parse or route, or when the engine refuses to apply
+/// its transaction. The engine counts the latter itself and may still be working through
+/// earlier rows, so its count is passed to `check` and may lag behind the reader.
+#[derive(Debug)]
+pub struct ErrorRateLimit {
+    max_rate: f64,
+    min_rows: u64,
+    rows: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl ErrorRateLimit {
+    pub fn new(max_rate: f64) -> Self {
+        Self::with_min_rows(max_rate, MIN_ERROR_RATE_SAMPLE)
+    }
+
+    /// Limit only enforced once `min_rows` rows have been read
+    pub fn with_min_rows(max_rate: f64, min_rows: u64) -> Self {
+        Self {
+            max_rate,
+            min_rows,
+            rows: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a data row, `rejected` if it failed to parse or route
+    pub fn record_row(&self, rejected: bool) {
+        self.rows.fetch_add(1, Ordering::Relaxed);
+        if rejected {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Fail with `ErrorRateExceeded` when enough rows have been read and the share of them
+    /// rejected by the reader, or by the engine (`engine_rejected`), is above the limit
+    pub fn check(&self, engine_rejected: usize) -> Result<(), EngineError> {
+        let rows = self.rows.load(Ordering::Relaxed);
+        if rows == 0 || rows < self.min_rows {
+            return Ok(());
+        }
+        let rejected = self.rejected.load(Ordering::Relaxed) + engine_rejected as u64;
+        let rate = rejected as f64 / rows as f64;
+        if rate > self.max_rate {
+            return Err(EngineError::ErrorRateExceeded(format!(
+                "{} of {} rows rejected ({:.1}%), above the limit of {:.1}%",
+                rejected,
+                rows,
+                rate * 100.0,
+                self.max_rate * 100.0
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_waits_for_min_rows() {
+        let limit = ErrorRateLimit::with_min_rows(0.5, 4);
+        for _ in 0..3 {
+            limit.record_row(true);
+            assert!(limit.check(0).is_ok());
+        }
+
+        limit.record_row(false);
+        let result = limit.check(0);
+        assert!(
+            matches!(&result, Err(EngineError::ErrorRateExceeded(message))
+                if message == "3 of 4 rows rejected (75.0%), above the limit of 50.0%"),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_check_adds_engine_rejections() {
+        let limit = ErrorRateLimit::with_min_rows(0.5, 1);
+        for rejected in [true, false, false, false] {
+            limit.record_row(rejected);
+        }
+
+        assert!(limit.check(1).is_ok());
+        assert!(limit.check(2).is_err());
+    }
+}
@@ -1,33 +1,295 @@
-use crate::models::Transaction;
+use crate::models::{ClientId, Transaction, MAX_OUTPUT_DIGITS};
 use csv_async::Error as AsyncCsvError;
+use rust_decimal::{Decimal, Error as DecimalError};
+use std::num::ParseIntError;
 use std::{fmt, io};
 use tokio::sync::mpsc::error::SendError;
 /// Custom error type for the transaction processing engine
 #[derive(Debug)]
 pub enum EngineError {
     IoError(io::Error),
+    /// An input file could not be opened, e.g. because it does not exist
+    FileOpenError {
+        path: String,
+        source: io::Error,
+    },
     AsyncCsvError(AsyncCsvError),
     TransactionError(String),
     TransactionNotFound(u32),
     InvalidOperation(String),
+    AccountLocked(String),
+    /// A withdrawal (or a fee under `FeePolicy::Reject`) larger than the available funds.
+    /// `ClientAccount` does not know its client and reports 0, the engine fills it in.
+    InsufficientFunds {
+        client: ClientId,
+        available: Decimal,
+        requested: Decimal,
+    },
+    /// A chargeback larger than the funds held for the client, which would drive held
+    /// funds negative. Filled in by the engine like `InsufficientFunds`.
+    InsufficientHeldFunds {
+        client: ClientId,
+        held: Decimal,
+        requested: Decimal,
+    },
+    /// A balance update whose result does not fit the `Amount` type of the account, e.g.
+    /// past about ±922 billion for `MinorUnits`. Filled in by the engine like
+    /// `InsufficientFunds`.
+    AmountOverflow {
+        client: ClientId,
+        requested: Decimal,
+    },
+    DisputeClientMismatch {
+        tx_id: u32,
+        expected: ClientId,
+        got: ClientId,
+    },
     SendError(SendError<Transaction>),
+    /// The worker of this shard stopped before the end of its input, e.g. because an
+    /// observer panicked, see `ShardedEngine::check_workers`
+    WorkerFailed(usize),
     ShutDownError(String),
+    ConfigError(String),
+    HeaderError(String),
+    InputLimitExceeded(String),
+    /// Too large a share of the input rows was rejected, see `ErrorRateLimit`
+    ErrorRateExceeded(String),
+    /// The overflow-checked aggregate of all flows disagrees with the account totals, or could
+    /// not be kept exactly, see `AggregateTotal`
+    AggregateMismatch(String),
+    /// Clients whose account ended the run with a negative available or total balance
+    NegativeBalance(Vec<ClientId>),
+    /// A balance too large to be written, see `models::check_output_amount`. No real
+    /// balance gets there, it points to a bug.
+    ImplausibleAmount {
+        client: ClientId,
+        amount: Decimal,
+    },
+    /// A dispute, resolve or chargeback referencing an id higher than any deposit or
+    /// withdrawal received, under `EngineOptions::strict_ordering`
+    FutureReference {
+        tx_id: u32,
+        latest: u32,
+    },
+    /// A transaction of a new client once `EngineOptions::max_clients` accounts are open
+    ClientLimitExceeded {
+        client: ClientId,
+        max: usize,
+    },
+    ParseIntError(ParseIntError),
+    ParseDecimalError(DecimalError),
+    ParseField {
+        field: &'static str,
+        source: Box<EngineError>,
+    },
+    /// Writing the accounts failed after `rows_written` complete account rows (not counting
+    /// the header) had reached the writer: the output is partial
+    PartialOutput {
+        rows_written: usize,
+        source: Box<EngineError>,
+    },
+    #[cfg(feature = "sqlite")]
+    SqliteError(rusqlite::Error),
 }
 
 impl fmt::Display for EngineError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             EngineError::IoError(err) => write!(f, "IoError: {}", err),
+            EngineError::FileOpenError { path, source } => {
+                write!(f, "FileOpenError: cannot open {}: {}", path, source)
+            }
             EngineError::TransactionError(err) => write!(f, "TransactionError: {}", err),
             EngineError::TransactionNotFound(tx_id) => {
                 write!(f, "TransactionNotFound: {}", tx_id)
             }
             EngineError::InvalidOperation(err) => write!(f, "InvalidOperation: {}", err),
+            EngineError::AccountLocked(err) => write!(f, "AccountLocked: {}", err),
+            EngineError::InsufficientFunds {
+                client,
+                available,
+                requested,
+            } => write!(
+                f,
+                "InsufficientFunds: client {} has {} available, {} requested",
+                client, available, requested
+            ),
+            EngineError::InsufficientHeldFunds {
+                client,
+                held,
+                requested,
+            } => write!(
+                f,
+                "InsufficientHeldFunds: client {} has {} held, {} requested",
+                client, held, requested
+            ),
+            EngineError::AmountOverflow { client, requested } => write!(
+                f,
+                "AmountOverflow: a balance of client {} overflows when applying {}",
+                client, requested
+            ),
+            EngineError::DisputeClientMismatch {
+                tx_id,
+                expected,
+                got,
+            } => write!(
+                f,
+                "DisputeClientMismatch: transaction {} belongs to client {}, not client {}",
+                tx_id, expected, got
+            ),
             EngineError::SendError(err) => write!(f, "SendError: {}", err),
+            EngineError::WorkerFailed(shard) => write!(
+                f,
+                "WorkerFailed: the worker of shard {} stopped before the end of its input",
+                shard
+            ),
             EngineError::ShutDownError(err) => write!(f, "ShutDownError: {}", err),
             EngineError::AsyncCsvError(err) => write!(f, "AsyncCsvError: {}", err),
+            EngineError::ConfigError(err) => write!(f, "ConfigError: {}", err),
+            EngineError::HeaderError(err) => write!(f, "HeaderError: {}", err),
+            EngineError::InputLimitExceeded(err) => write!(f, "InputLimitExceeded: {}", err),
+            EngineError::ErrorRateExceeded(err) => write!(f, "ErrorRateExceeded: {}", err),
+            EngineError::AggregateMismatch(err) => write!(f, "AggregateMismatch: {}", err),
+            EngineError::NegativeBalance(clients) => write!(
+                f,
+                "NegativeBalance: clients {} have a negative available or total balance",
+                clients
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            EngineError::ImplausibleAmount { client, amount } => write!(
+                f,
+                "ImplausibleAmount: client {} has a balance of {}, more than {} integer digits",
+                client, amount, MAX_OUTPUT_DIGITS
+            ),
+            EngineError::FutureReference { tx_id, latest } => write!(
+                f,
+                "FutureReference: tx {} is past the latest deposit or withdrawal received (tx {})",
+                tx_id, latest
+            ),
+            EngineError::ClientLimitExceeded { client, max } => write!(
+                f,
+                "ClientLimitExceeded: client {} is new but {} client accounts are already open",
+                client, max
+            ),
+            EngineError::ParseIntError(err) => write!(f, "ParseIntError: {}", err),
+            EngineError::ParseDecimalError(err) => write!(f, "ParseDecimalError: {}", err),
+            EngineError::ParseField { field, source } => {
+                write!(f, "ParseField: Invalid {} ({})", field, source)
+            }
+            EngineError::PartialOutput {
+                rows_written,
+                source,
+            } => write!(
+                f,
+                "PartialOutput: output stopped after {} account rows ({})",
+                rows_written, source
+            ),
+            #[cfg(feature = "sqlite")]
+            EngineError::SqliteError(err) => write!(f, "SqliteError: {}", err),
+        }
+    }
+}
+
+impl EngineError {
+    /// Whether the failure may succeed once later input has been applied, e.g. a dispute
+    /// referencing a deposit that had not arrived yet. Business rule violations such as
+    /// insufficient funds are permanent.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, EngineError::TransactionNotFound(_))
+    }
+
+    /// Name of the variant, used to group errors in statistics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EngineError::IoError(_) => "IoError",
+            EngineError::FileOpenError { .. } => "FileOpenError",
+            EngineError::AsyncCsvError(_) => "AsyncCsvError",
+            EngineError::TransactionError(_) => "TransactionError",
+            EngineError::TransactionNotFound(_) => "TransactionNotFound",
+            EngineError::InvalidOperation(_) => "InvalidOperation",
+            EngineError::AccountLocked(_) => "AccountLocked",
+            EngineError::InsufficientFunds { .. } => "InsufficientFunds",
+            EngineError::InsufficientHeldFunds { .. } => "InsufficientHeldFunds",
+            EngineError::AmountOverflow { .. } => "AmountOverflow",
+            EngineError::DisputeClientMismatch { .. } => "DisputeClientMismatch",
+            EngineError::SendError(_) => "SendError",
+            EngineError::WorkerFailed(_) => "WorkerFailed",
+            EngineError::ShutDownError(_) => "ShutDownError",
+            EngineError::ConfigError(_) => "ConfigError",
+            EngineError::HeaderError(_) => "HeaderError",
+            EngineError::InputLimitExceeded(_) => "InputLimitExceeded",
+            EngineError::ErrorRateExceeded(_) => "ErrorRateExceeded",
+            EngineError::AggregateMismatch(_) => "AggregateMismatch",
+            EngineError::NegativeBalance(_) => "NegativeBalance",
+            EngineError::ImplausibleAmount { .. } => "ImplausibleAmount",
+            EngineError::FutureReference { .. } => "FutureReference",
+            EngineError::ClientLimitExceeded { .. } => "ClientLimitExceeded",
+            EngineError::ParseIntError(_) => "ParseIntError",
+            EngineError::ParseDecimalError(_) => "ParseDecimalError",
+            EngineError::ParseField { .. } => "ParseField",
+            EngineError::PartialOutput { .. } => "PartialOutput",
+            #[cfg(feature = "sqlite")]
+            EngineError::SqliteError(_) => "SqliteError",
+        }
+    }
+
+    /// `FileOpenError` for a failure to open `path`
+    pub fn file_open(path: &str, source: io::Error) -> Self {
+        EngineError::FileOpenError {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    /// Whether the output was closed by its reader, e.g. stdout piped into `head`
+    pub fn is_broken_pipe(&self) -> bool {
+        match self {
+            EngineError::IoError(err) => err.kind() == io::ErrorKind::BrokenPipe,
+            EngineError::AsyncCsvError(err) => matches!(
+                err.kind(),
+                csv_async::ErrorKind::Io(err) if err.kind() == io::ErrorKind::BrokenPipe
+            ),
+            EngineError::PartialOutput { source, .. } => source.is_broken_pipe(),
+            _ => false,
+        }
+    }
+
+    /// Attach the client of the transaction that failed, for errors raised by a
+    /// `ClientAccount` which does not know its client
+    pub fn with_client(self, client: ClientId) -> Self {
+        match self {
+            EngineError::InsufficientFunds {
+                available,
+                requested,
+                ..
+            } => EngineError::InsufficientFunds {
+                client,
+                available,
+                requested,
+            },
+            EngineError::InsufficientHeldFunds {
+                held, requested, ..
+            } => EngineError::InsufficientHeldFunds {
+                client,
+                held,
+                requested,
+            },
+            EngineError::AmountOverflow { requested, .. } => {
+                EngineError::AmountOverflow { client, requested }
+            }
+            err => err,
         }
     }
+
+    /// Whether routing must stop: a shard whose receiver is gone (its worker stopped) would
+    /// silently drop every later transaction of its clients. Other routing failures, such as
+    /// a rejected duplicate id, only concern the transaction at hand.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, EngineError::SendError(_))
+    }
 }
 
 impl From<io::Error> for EngineError {
@@ -41,12 +303,31 @@ impl From<AsyncCsvError> for EngineError {
     }
 }
 
+impl From<ParseIntError> for EngineError {
+    fn from(err: ParseIntError) -> Self {
+        EngineError::ParseIntError(err)
+    }
+}
+
+impl From<DecimalError> for EngineError {
+    fn from(err: DecimalError) -> Self {
+        EngineError::ParseDecimalError(err)
+    }
+}
+
 impl From<SendError<Transaction>> for EngineError {
     fn from(err: SendError<Transaction>) -> Self {
         EngineError::SendError(err)
     }
 }
 
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for EngineError {
+    fn from(err: rusqlite::Error) -> Self {
+        EngineError::SqliteError(err)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -56,11 +337,49 @@ mod tests {
 
     #[test]
     fn test_io_error_display() {
-        let io_err = io::Error::new(io::ErrorKind::Other, "some io error");
+        let io_err = io::Error::other("some io error");
         let engine_error = EngineError::from(io_err);
         assert_eq!(format!("{}", engine_error), "IoError: some io error");
     }
 
+    #[test]
+    fn test_kind_matches_display_prefix() {
+        let errors = [
+            EngineError::TransactionNotFound(1),
+            EngineError::AccountLocked("locked".into()),
+            EngineError::InsufficientFunds {
+                client: 1,
+                available: Decimal::ONE,
+                requested: Decimal::TWO,
+            },
+            EngineError::DisputeClientMismatch {
+                tx_id: 1,
+                expected: 1,
+                got: 2,
+            },
+            EngineError::FutureReference {
+                tx_id: 5,
+                latest: 1,
+            },
+            EngineError::ClientLimitExceeded { client: 3, max: 2 },
+            EngineError::from("x".parse::<u32>().unwrap_err()),
+            EngineError::file_open("input.csv", io::Error::from(io::ErrorKind::NotFound)),
+        ];
+        for err in errors {
+            assert!(err.to_string().starts_with(&format!("{}: ", err.kind())));
+        }
+    }
+
+    #[test]
+    fn test_is_broken_pipe() {
+        let broken_pipe = EngineError::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(broken_pipe.is_broken_pipe());
+
+        let not_found = EngineError::from(io::Error::from(io::ErrorKind::NotFound));
+        assert!(!not_found.is_broken_pipe());
+        assert!(!EngineError::TransactionNotFound(1).is_broken_pipe());
+    }
+
     #[test]
     fn test_transaction_error_display() {
         let engine_error = EngineError::TransactionError("invalid transaction".into());
@@ -76,6 +395,18 @@ mod tests {
         assert_eq!(format!("{}", engine_error), "TransactionNotFound: 42");
     }
 
+    #[test]
+    fn test_is_retryable() {
+        assert!(EngineError::TransactionNotFound(42).is_retryable());
+        assert!(!EngineError::InsufficientFunds {
+            client: 1,
+            available: Decimal::ZERO,
+            requested: Decimal::ONE,
+        }
+        .is_retryable());
+        assert!(!EngineError::TransactionError("Duplicate transaction".into()).is_retryable());
+    }
+
     #[test]
     fn test_invalid_operation_display() {
         let engine_error = EngineError::InvalidOperation("invalid operation".into());
@@ -85,6 +416,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_account_locked_display() {
+        let engine_error =
+            EngineError::AccountLocked("Attempted to process dispute on a locked account".into());
+        assert_eq!(
+            format!("{}", engine_error),
+            "AccountLocked: Attempted to process dispute on a locked account"
+        );
+    }
+
+    #[test]
+    fn test_insufficient_funds_display() {
+        let engine_error = EngineError::InsufficientFunds {
+            client: 0,
+            available: Decimal::new(105, 1),
+            requested: Decimal::new(20, 0),
+        }
+        .with_client(3);
+        assert_eq!(
+            format!("{}", engine_error),
+            "InsufficientFunds: client 3 has 10.5 available, 20 requested"
+        );
+    }
+
+    #[test]
+    fn test_insufficient_held_funds_display() {
+        let engine_error = EngineError::InsufficientHeldFunds {
+            client: 0,
+            held: Decimal::new(4, 0),
+            requested: Decimal::new(10, 0),
+        }
+        .with_client(2);
+        assert_eq!(
+            format!("{}", engine_error),
+            "InsufficientHeldFunds: client 2 has 4 held, 10 requested"
+        );
+    }
+
+    #[test]
+    fn test_dispute_client_mismatch_display() {
+        let engine_error = EngineError::DisputeClientMismatch {
+            tx_id: 7,
+            expected: 1,
+            got: 3,
+        };
+        assert_eq!(
+            format!("{}", engine_error),
+            "DisputeClientMismatch: transaction 7 belongs to client 1, not client 3"
+        );
+    }
+
     #[test]
     fn test_send_error_display() {
         let (_tx, _rx) = mpsc::channel::<Transaction>(1);
@@ -94,11 +476,15 @@ mod tests {
             tx_id: 0,
             amount: None,
             under_dispute: false,
+            timestamp: None,
+            seq: None,
         };
 
         let send_error: Result<(), SendError<Transaction>> = Err(SendError(transaction));
         let engine_error = EngineError::from(send_error.err().unwrap());
         assert!(format!("{}", engine_error).contains("SendError"));
+        assert!(engine_error.is_fatal());
+        assert!(!EngineError::TransactionError("Transaction id 1 already used".into()).is_fatal());
     }
 
     #[test]
@@ -109,4 +495,81 @@ mod tests {
             "ShutDownError: shutdown failed"
         );
     }
+
+    #[test]
+    fn test_config_error_display() {
+        let engine_error = EngineError::ConfigError("Missing input file".into());
+        assert_eq!(
+            format!("{}", engine_error),
+            "ConfigError: Missing input file"
+        );
+    }
+
+    #[test]
+    fn test_header_error_display() {
+        let engine_error = EngineError::HeaderError("Missing required columns: amount".into());
+        assert_eq!(
+            format!("{}", engine_error),
+            "HeaderError: Missing required columns: amount"
+        );
+    }
+
+    #[test]
+    fn test_input_limit_exceeded_display() {
+        let engine_error =
+            EngineError::InputLimitExceeded("input.csv has more than 10 rows".into());
+        assert_eq!(
+            format!("{}", engine_error),
+            "InputLimitExceeded: input.csv has more than 10 rows"
+        );
+    }
+
+    #[test]
+    fn test_negative_balance_display() {
+        let engine_error = EngineError::NegativeBalance(vec![1, 7]);
+        assert_eq!(
+            format!("{}", engine_error),
+            "NegativeBalance: clients 1, 7 have a negative available or total balance"
+        );
+    }
+
+    #[test]
+    fn test_parse_int_error_display() {
+        let parse_err = "abc".parse::<u16>().unwrap_err();
+        let engine_error = EngineError::from(parse_err);
+        assert_eq!(
+            format!("{}", engine_error),
+            "ParseIntError: invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn test_parse_decimal_error_display() {
+        let parse_err = "abc".parse::<rust_decimal::Decimal>().unwrap_err();
+        let engine_error = EngineError::from(parse_err);
+        assert!(format!("{}", engine_error).starts_with("ParseDecimalError: "));
+    }
+
+    #[test]
+    fn test_parse_field_display() {
+        let parse_err = "-1".parse::<u32>().unwrap_err();
+        let engine_error = EngineError::ParseField {
+            field: "transaction ID",
+            source: Box::new(EngineError::from(parse_err)),
+        };
+        assert_eq!(
+            format!("{}", engine_error),
+            "ParseField: Invalid transaction ID (ParseIntError: invalid digit found in string)"
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_error_display() {
+        let engine_error = EngineError::from(rusqlite::Error::QueryReturnedNoRows);
+        assert_eq!(
+            format!("{}", engine_error),
+            "SqliteError: Query returned no rows"
+        );
+    }
 }
@@ -1,28 +1,670 @@
+use crate::error_rate::ErrorRateLimit;
 use crate::errors::EngineError;
-use crate::models::{Transaction, TransactionType, MAX_DISPLAY_PRECISION};
+use crate::models::{ClientId, Transaction, TransactionType, MAX_DISPLAY_PRECISION};
+use crate::profile::Profile;
+use crate::rate_limit::RateLimiter;
+use crate::rejects::RejectLog;
+use async_compression::futures::bufread::GzipDecoder;
 use async_std::fs::File;
-use csv_async::{AsyncReaderBuilder, StringRecord, StringRecordsIntoStream, Trim};
+use csv_async::{AsyncReaderBuilder, StringRecord, Trim};
+use futures::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use futures::stream::{BoxStream, StreamExt};
 use rust_decimal::Decimal;
+use std::collections::HashSet;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::time::Sleep;
+use tokio_util::compat::TokioAsyncReadCompatExt;
 
-/// Stream transactions from a CSV file without loading the entire file into memory
-pub async fn stream_transactions(
+/// Columns every transaction file must provide, in the order the parser reads them
+pub const EXPECTED_HEADERS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Optional last column holding the time of each transaction
+pub const TIMESTAMP_HEADER: &str = "timestamp";
+
+/// Optional column after `TIMESTAMP_HEADER` numbering the transactions of each client
+pub const SEQ_HEADER: &str = "seq";
+
+/// First bytes of every gzip stream
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Byte order mark some Windows tools write at the start of UTF-8 files
+pub const UTF8_BOM: [u8; 3] = [0xef, 0xbb, 0xbf];
+
+/// Retries of a read failing with a transient error before `RetryingReader` gives up
+pub const READ_RETRIES: u32 = 5;
+
+/// Wait before the first retry of a failed read, doubled for every further retry
+pub const READ_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Records of a CSV transaction source, with its header already validated. A record the
+/// reader fails on, of the wrong width or spanning several lines is an error.
+pub type RecordStream<'r> = BoxStream<'r, Result<StringRecord, EngineError>>;
+
+/// Parsed data rows of a transaction source, whatever its `InputFormat`
+pub type RowStream<'r> = BoxStream<'r, InputRow>;
+
+/// A data row of a transaction source
+#[derive(Debug)]
+pub struct InputRow {
+    /// The transaction of the row, or why the row is not a valid one
+    pub transaction: Result<Transaction, EngineError>,
+    /// Bytes read from the source up to the end of the row, `None` when the row could not
+    /// be read at all
+    pub end: Option<u64>,
+    /// Cells of a row that failed to parse, as read, only kept for `ParseOptions::rejects`.
+    /// A JSON line is kept whole, as a single cell.
+    pub cells: Vec<String>,
+}
+
+/// Stream transactions from a CSV file without loading the entire file into memory.
+/// A gzip-compressed file is decompressed on the fly, whatever its name, see `decompressed`.
+pub async fn stream_transactions(file_path: &str) -> Result<RecordStream<'static>, EngineError> {
+    stream_transactions_with(file_path, &ParseOptions::default()).await
+}
+
+/// Same as `stream_transactions`, with non-default parse options
+pub async fn stream_transactions_with(
     file_path: &str,
-) -> Result<StringRecordsIntoStream<File>, EngineError> {
+    options: &ParseOptions,
+) -> Result<RecordStream<'static>, EngineError> {
     let file = File::open(file_path)
         .await
-        .map_err(|err| EngineError::IoError(err))?;
+        .map_err(|err| EngineError::file_open(file_path, err))?;
+
+    let source = BufReader::new(RetryingReader::new(file));
+    stream_records_with(decompressed(source).await?, options).await
+}
+
+/// Stream transactions from any tokio `AsyncRead` source, e.g. a socket or a network body,
+/// decompressing it like `stream_transactions`. `stream_records` takes a `futures::io`
+/// source instead.
+pub async fn stream_transactions_from_async_read<'r, R>(
+    reader: R,
+) -> Result<RecordStream<'r>, EngineError>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'r,
+{
+    stream_transactions_from_async_read_with(reader, &ParseOptions::default()).await
+}
+
+/// Same as `stream_transactions_from_async_read`, with non-default parse options
+pub async fn stream_transactions_from_async_read_with<'r, R>(
+    reader: R,
+    options: &ParseOptions,
+) -> Result<RecordStream<'r>, EngineError>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'r,
+{
+    let source = BufReader::new(reader.compat());
+    stream_records_with(decompressed(source).await?, options).await
+}
+
+/// Stream the parsed rows of a file in `options.input_format`, decompressing it like
+/// `stream_transactions`
+pub async fn stream_rows(
+    file_path: &str,
+    options: &ParseOptions,
+) -> Result<RowStream<'static>, EngineError> {
+    match options.input_format {
+        InputFormat::Csv => Ok(parse_records(
+            stream_transactions_with(file_path, options).await?,
+            options,
+        )),
+        InputFormat::Json => {
+            let file = File::open(file_path)
+                .await
+                .map_err(|err| EngineError::file_open(file_path, err))?;
+            let source = BufReader::new(RetryingReader::new(file));
+            stream_json_lines(decompressed(source).await?, options).await
+        }
+    }
+}
+
+/// Parse the records of a CSV source into rows. A record the reader fails on (e.g. invalid
+/// UTF-8) becomes a row with a `TransactionError` and no `end`, like a record rejected by
+/// `stream_records`.
+pub fn parse_records<'r>(records: RecordStream<'r>, options: &ParseOptions) -> RowStream<'r> {
+    let options = options.clone();
+    records
+        .map(move |record| match record {
+            Ok(record) => {
+                let transaction = parse_record(&record, &options);
+                InputRow {
+                    end: Some(record_end(&record)),
+                    cells: rejected_cells(&transaction, &options, || {
+                        record.iter().map(String::from).collect()
+                    }),
+                    transaction,
+                }
+            }
+            Err(EngineError::AsyncCsvError(err)) => InputRow {
+                transaction: Err(EngineError::TransactionError(err.to_string())),
+                end: None,
+                cells: Vec::new(),
+            },
+            Err(err) => InputRow {
+                transaction: Err(err),
+                end: None,
+                cells: Vec::new(),
+            },
+        })
+        .boxed()
+}
+
+/// Stream the transactions of a JSON Lines source, one object per line, see
+/// `parse_json_transaction`. Blank lines and lines starting with `options.comment` are
+/// skipped. A line that cannot be read ends the stream with a row holding the error.
+pub async fn stream_json_lines<'r, R>(
+    source: R,
+    options: &ParseOptions,
+) -> Result<RowStream<'r>, EngineError>
+where
+    R: AsyncRead + Unpin + Send + 'r,
+{
+    let mut source = BufReader::new(source);
+    skip_bom(&mut source).await?;
+
+    let lines = futures::stream::unfold(Some((source, 0)), |state| async move {
+        let (mut source, read) = state?;
+        let mut line = String::new();
+        match source.read_line(&mut line).await {
+            Ok(0) => None,
+            Ok(len) => {
+                let end = read + len as u64;
+                Some((Ok((end, line)), Some((source, end))))
+            }
+            Err(err) => Some((Err(err), None)),
+        }
+    });
+    let options = options.clone();
+    Ok(lines
+        .filter_map(move |line| {
+            let row = match line {
+                Ok((_, line))
+                    if line.trim().is_empty()
+                        || options
+                            .comment
+                            .is_some_and(|comment| line.as_bytes()[0] == comment) =>
+                {
+                    None
+                }
+                Ok((end, line)) => {
+                    let transaction = parse_json_transaction(&line, &options);
+                    Some(InputRow {
+                        cells: rejected_cells(&transaction, &options, || {
+                            vec![line.trim_end().to_string()]
+                        }),
+                        transaction,
+                        end: Some(end),
+                    })
+                }
+                Err(err) => Some(InputRow {
+                    transaction: Err(err.into()),
+                    end: None,
+                    cells: Vec::new(),
+                }),
+            };
+            futures::future::ready(row)
+        })
+        .boxed())
+}
+
+/// The cells of a row for `InputRow::cells`: only those of a rejected row, and only when
+/// rejects are collected
+fn rejected_cells(
+    transaction: &Result<Transaction, EngineError>,
+    options: &ParseOptions,
+    cells: impl FnOnce() -> Vec<String>,
+) -> Vec<String> {
+    if transaction.is_err() && options.rejects.is_some() {
+        cells()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Parse a JSON object such as `{"type":"deposit","client":1,"tx":1,"amount":"100.0"}`.
+///
+/// The amount is a string, to keep every digit, and may be missing, `null` or empty for
+/// disputes, resolves and chargebacks like an empty CSV cell. A `timestamp` is optional.
+/// Amounts are truncated and checked like those of CSV rows.
+pub fn parse_json_transaction(
+    line: &str,
+    options: &ParseOptions,
+) -> Result<Transaction, EngineError> {
+    let transaction: Transaction = serde_json::from_str(line).map_err(|err| {
+        EngineError::TransactionError(format!("Invalid JSON transaction: {}", err))
+    })?;
+    Ok(Transaction {
+        amount: validate_amount(transaction.tx_type, transaction.amount, options)?,
+        ..transaction
+    })
+}
 
-    let reader = AsyncReaderBuilder::new()
+/// Pick the decoder of a source by its content rather than its name: a source starting with
+/// `GZIP_MAGIC` is decompressed as it is read, anything else is read as is. Only the bytes
+/// already buffered are inspected, so nothing is consumed and the source stays streamed.
+pub async fn decompressed<'r, R>(
+    mut source: R,
+) -> Result<Box<dyn AsyncRead + Unpin + Send + 'r>, EngineError>
+where
+    R: AsyncBufRead + Unpin + Send + 'r,
+{
+    if source.fill_buf().await?.starts_with(&GZIP_MAGIC) {
+        let mut decoder = GzipDecoder::new(source);
+        // Like `gunzip`, read every member of a concatenated file
+        decoder.multiple_members(true);
+        Ok(Box::new(decoder))
+    } else {
+        Ok(Box::new(source))
+    }
+}
+
+/// Validate the header of a CSV transaction source and stream its records
+pub async fn stream_records<'r, R>(source: R) -> Result<RecordStream<'r>, EngineError>
+where
+    R: AsyncRead + Unpin + Send + 'r,
+{
+    stream_records_with(source, &ParseOptions::default()).await
+}
+
+/// Same as `stream_records`, with non-default parse options
+pub async fn stream_records_with<'r, R>(
+    source: R,
+    options: &ParseOptions,
+) -> Result<RecordStream<'r>, EngineError>
+where
+    R: AsyncRead + Unpin + Send + 'r,
+{
+    let mut source = BufReader::new(source);
+    skip_bom(&mut source).await?;
+    // The width of the rows is checked below rather than by the reader, so that a row
+    // swallowed by an unterminated quote is reported as such
+    let mut reader = AsyncReaderBuilder::new()
         .has_headers(true)
         .trim(Trim::All)
-        .create_reader(file)
-        .into_records();
+        .flexible(true)
+        .comment(options.comment)
+        .create_reader(source);
 
-    Ok(reader)
+    // The reader always takes the first row as the header, hand it back when it is data
+    let first_row = reader.headers().await?.clone();
+    let is_header = match options.has_header {
+        HeaderMode::Present => true,
+        HeaderMode::Absent => false,
+        HeaderMode::Auto => {
+            validate_headers(&first_row).is_ok()
+                || validate_and_parse_transaction_with(first_row.clone(), options).is_err()
+        }
+    };
+    if is_header {
+        validate_headers(&first_row)?;
+    }
+    let columns = first_row.len();
+    let data_row = (!is_header).then_some(Ok(first_row));
+
+    // Empty lines never reach the stream, rows of empty cells (e.g. `,,,`) are dropped here
+    let flexible = options.flexible;
+    Ok(futures::stream::iter(data_row)
+        .chain(reader.into_records())
+        .filter(|record| futures::future::ready(!record.as_ref().is_ok_and(is_blank)))
+        .map(move |record| {
+            let mut record = record?;
+            check_single_line(&record)?;
+            if flexible {
+                // Cells past the header are noise of a messy feed, not e.g. a timestamp
+                record.truncate(columns);
+            } else if record.len() != columns {
+                return Err(EngineError::TransactionError(format!(
+                    "Row with {} cells, expected {} like the first row",
+                    record.len(),
+                    columns
+                )));
+            }
+            Ok(record)
+        })
+        .boxed())
+}
+
+/// Reject a record with a line break in a cell. No field of a transaction holds one, such a
+/// record comes from an unterminated quote which made the reader take the following lines,
+/// up to the next quote or the end of the file, as part of the same cell.
+fn check_single_line(record: &StringRecord) -> Result<(), EngineError> {
+    if !record
+        .as_byte_record()
+        .as_slice()
+        .iter()
+        .any(|&byte| byte == b'\n' || byte == b'\r')
+    {
+        return Ok(());
+    }
+    let line = record.position().map_or(0, |position| position.line());
+    Err(EngineError::TransactionError(format!(
+        "Row starting on line {} spans several lines, a quoted cell is likely unterminated",
+        line
+    )))
 }
 
+/// Consume a leading `UTF8_BOM`, which would otherwise end up in the first cell
+/// (`\u{feff}type`) and fail the header check
+async fn skip_bom<R>(source: &mut R) -> Result<(), EngineError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    if source.fill_buf().await?.starts_with(&UTF8_BOM) {
+        source.consume_unpin(UTF8_BOM.len());
+    }
+    Ok(())
+}
+
+/// Whether a read error may go away on its own, e.g. an interrupted read on a network mount.
+/// Errors such as a missing file or a denied permission are permanent.
+pub fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Reader retrying a read that failed with a transient error (see `is_transient`) after
+/// `READ_RETRY_BACKOFF`, twice as long for each further retry. After `READ_RETRIES` retries
+/// in a row, or on a permanent error, the error is returned. A successful read resets the
+/// count, so a long file may go through several short outages.
+pub struct RetryingReader<R> {
+    inner: R,
+    retries: u32,
+    backoff: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> RetryingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            retries: 0,
+            backoff: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RetryingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if let Some(backoff) = &mut self.backoff {
+                ready!(backoff.as_mut().poll(cx));
+                self.backoff = None;
+            }
+            match ready!(Pin::new(&mut self.inner).poll_read(cx, buf)) {
+                Err(err) if is_transient(&err) && self.retries < READ_RETRIES => {
+                    let delay = READ_RETRY_BACKOFF * 2u32.pow(self.retries);
+                    self.retries += 1;
+                    log::warn!(
+                        "Transient read error, retry {} of {} in {:?}: {}",
+                        self.retries,
+                        READ_RETRIES,
+                        delay,
+                        err
+                    );
+                    self.backoff = Some(Box::pin(tokio::time::sleep(delay)));
+                }
+                result => {
+                    if result.is_ok() {
+                        self.retries = 0;
+                    }
+                    return Poll::Ready(result);
+                }
+            }
+        }
+    }
+}
+
+/// Read a list of client ids, one per line, e.g. the allowlist of `--accounts-filter`.
+/// Blank lines are ignored.
+pub async fn read_client_ids(file_path: &str) -> Result<HashSet<ClientId>, EngineError> {
+    let mut content = String::new();
+    File::open(file_path)
+        .await
+        .map_err(|err| EngineError::file_open(file_path, err))?
+        .read_to_string(&mut content)
+        .await?;
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(line_number, line)| {
+            line.parse().map_err(|_| {
+                EngineError::ConfigError(format!(
+                    "Invalid client id on line {} of {}: {}",
+                    line_number, file_path, line
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Whether every cell of a row is empty once trimmed, like a blank separator line
+fn is_blank(record: &StringRecord) -> bool {
+    record.iter().all(str::is_empty)
+}
+
+/// Reject files whose header does not match the expected schema before reading any rows
+pub fn validate_headers(headers: &StringRecord) -> Result<(), EngineError> {
+    let columns: Vec<String> = headers.iter().map(|h| h.to_lowercase()).collect();
+
+    let missing: Vec<&str> = EXPECTED_HEADERS
+        .iter()
+        .filter(|expected| !columns.iter().any(|column| column == *expected))
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        return Err(EngineError::HeaderError(format!(
+            "Missing required columns: {} (found: {})",
+            missing.join(", "),
+            columns.join(", ")
+        )));
+    }
+
+    // Records are parsed by position, so the columns must also be in the expected order
+    let optional_columns = [TIMESTAMP_HEADER, SEQ_HEADER];
+    let extra = &columns[EXPECTED_HEADERS.len().min(columns.len())..];
+    let optional_prefix = extra.len() <= optional_columns.len()
+        && extra
+            .iter()
+            .zip(optional_columns)
+            .all(|(column, optional)| column == optional);
+    if !optional_prefix
+        || columns
+            .iter()
+            .zip(EXPECTED_HEADERS.iter())
+            .any(|(a, b)| a != b)
+    {
+        return Err(EngineError::HeaderError(format!(
+            "Unexpected header: expected {}[,{}[,{}]], found {}",
+            EXPECTED_HEADERS.join(","),
+            TIMESTAMP_HEADER,
+            SEQ_HEADER,
+            columns.join(",")
+        )));
+    }
+
+    Ok(())
+}
+
+/// Whether the first row of an input file is a header, see `ParseOptions::has_header`
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum HeaderMode {
+    /// The first row must be the expected header
+    #[default]
+    Present,
+    /// Every row is data, including the first one
+    Absent,
+    /// The first row is data if it parses as a transaction, otherwise it must be the header
+    Auto,
+}
+
+impl FromStr for HeaderMode {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "true" => Ok(HeaderMode::Present),
+            "false" => Ok(HeaderMode::Absent),
+            "auto" => Ok(HeaderMode::Auto),
+            _ => Err(EngineError::ConfigError(format!(
+                "Invalid header mode: {} (expected true, false or auto)",
+                s
+            ))),
+        }
+    }
+}
+
+/// Format of input files, see `ParseOptions::input_format`
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum InputFormat {
+    /// CSV with a `type,client,tx,amount[,timestamp[,seq]]` header
+    #[default]
+    Csv,
+    /// JSON Lines, one transaction object per line, see `parse_json_transaction`
+    Json,
+}
+
+impl FromStr for InputFormat {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "json" => Ok(InputFormat::Json),
+            _ => Err(EngineError::ConfigError(format!(
+                "Invalid input format: {} (expected csv or json)",
+                s
+            ))),
+        }
+    }
+}
+
+/// Options changing how input files are read and their records parsed
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Thousands separator stripped from amounts before parsing, e.g. `,` for `"1,000.50"`
+    pub thousands_sep: Option<char>,
+    /// Abort a file once it has more data rows than this
+    pub max_rows: Option<u64>,
+    /// Abort a file once more bytes than this have been read from it
+    pub max_bytes: Option<u64>,
+    /// Pace the rows routed to the engine, shared by all files read concurrently
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Read files through a memory mapping (requires the `mmap` feature)
+    pub mmap: bool,
+    /// Accept rows with more or fewer cells than the header: extra trailing cells are
+    /// ignored and a missing amount is `None`
+    pub flexible: bool,
+    /// Accept deposits and withdrawals of zero, which some systems send as markers, instead
+    /// of requiring a positive amount
+    pub allow_zero_amount: bool,
+    /// Keep the amount of a dispute row, the part of its transaction to hold (see
+    /// `EngineOptions::dispute_amounts`). Without it the amount of a dispute is dropped.
+    pub dispute_amounts: bool,
+    /// Skip lines starting with this byte, e.g. `#` for operator annotations
+    pub comment: Option<u8>,
+    /// Whether input files start with a header row
+    pub has_header: HeaderMode,
+    /// Decimal places kept from input amounts, `None` keeps `MAX_DISPLAY_PRECISION`.
+    /// Should match `EngineOptions::hold_precision`.
+    pub hold_precision: Option<u32>,
+    /// Abort once too large a share of the rows is rejected, shared by all files read
+    pub error_rate: Option<Arc<ErrorRateLimit>>,
+    /// Format of the input files. `has_header`, `flexible` and `thousands_sep` only apply
+    /// to CSV.
+    pub input_format: InputFormat,
+    /// Time spent parsing and routing rows is added to this profile (`--profile`)
+    pub profile: Option<Arc<Profile>>,
+    /// Rows that fail to parse or to route are recorded here (`--rejects-output`)
+    pub rejects: Option<Arc<RejectLog>>,
+}
+
+impl ParseOptions {
+    /// Decimal places input amounts are truncated to
+    pub fn precision(&self) -> u32 {
+        self.hold_precision.unwrap_or(MAX_DISPLAY_PRECISION)
+    }
+
+    /// Fail with `InputLimitExceeded` once `record`, the `row`-th data row of `file_path`,
+    /// goes past the configured row or byte limit
+    pub fn check_limits(
+        &self,
+        file_path: &str,
+        row: u64,
+        record: &StringRecord,
+    ) -> Result<(), EngineError> {
+        self.check_row_limits(file_path, row, record_end(record))
+    }
+
+    /// Same as `check_limits`, for a row ending `end` bytes into the file
+    pub fn check_row_limits(&self, file_path: &str, row: u64, end: u64) -> Result<(), EngineError> {
+        if let Some(max_rows) = self.max_rows {
+            if row > max_rows {
+                return Err(EngineError::InputLimitExceeded(format!(
+                    "{} has more than {} rows",
+                    file_path, max_rows
+                )));
+            }
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            if end > max_bytes {
+                return Err(EngineError::InputLimitExceeded(format!(
+                    "{} is larger than {} bytes",
+                    file_path, max_bytes
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Offset of the end of a record, not counting delimiters and quotes
+fn record_end(record: &StringRecord) -> u64 {
+    record.position().map_or(0, |position| position.byte())
+        + record.as_byte_record().as_slice().len() as u64
+}
+
+/// Parse a record with the default options, see `TryFrom<StringRecord> for Transaction`
 pub fn validate_and_parse_transaction(record: StringRecord) -> Result<Transaction, EngineError> {
-    if record.len() != 4 {
+    Transaction::try_from(record)
+}
+
+/// Parse a `type,client,tx,amount[,timestamp[,seq]]` record with the default parse options, e.g.
+/// `Transaction::try_from(record)?`
+impl TryFrom<StringRecord> for Transaction {
+    type Error = EngineError;
+
+    fn try_from(record: StringRecord) -> Result<Self, Self::Error> {
+        validate_and_parse_transaction_with(record, &ParseOptions::default())
+    }
+}
+
+/// Same as `validate_and_parse_transaction`, with non-default parse options
+pub fn validate_and_parse_transaction_with(
+    record: StringRecord,
+    options: &ParseOptions,
+) -> Result<Transaction, EngineError> {
+    parse_record(&record, options)
+}
+
+fn parse_record(record: &StringRecord, options: &ParseOptions) -> Result<Transaction, EngineError> {
+    // A flexible row may omit the amount; its extra cells are already dropped when streamed
+    let min_len = if options.flexible { 3 } else { 4 };
+    if record.len() < min_len || record.len() > 6 {
         return Err(EngineError::TransactionError(
             "Insufficient data in transaction string".into(),
         ));
@@ -43,6 +685,18 @@ pub fn validate_and_parse_transaction(record: StringRecord) -> Result<Transactio
 
     let amount_str = record.get(3);
 
+    // An empty timestamp cell is allowed, like a missing column
+    let timestamp = match record.get(4) {
+        Some(timestamp_str) if !timestamp_str.is_empty() => {
+            Some(parse_field(timestamp_str, "timestamp")?)
+        }
+        _ => None,
+    };
+    let seq = match record.get(5) {
+        Some(seq_str) if !seq_str.is_empty() => Some(parse_field(seq_str, "seq")?),
+        _ => None,
+    };
+
     // Parse and validate transaction type
     let transaction_type = transaction_type_str
         .to_lowercase()
@@ -50,32 +704,31 @@ pub fn validate_and_parse_transaction(record: StringRecord) -> Result<Transactio
         .map_err(|_| EngineError::TransactionError("Invalid transaction type".into()))?;
 
     // Parse client_id
-    let client_id = client_id_str
-        .parse::<u16>()
-        .map_err(|_| EngineError::TransactionError("Invalid client ID".into()))?;
+    let client_id: ClientId = parse_id_field(client_id_str, "client ID")?;
 
     // Parse transaction_id
-    let transaction_id = transaction_id_str
-        .parse::<u32>()
-        .map_err(|_| EngineError::TransactionError("Invalid transaction ID".into()))?;
-
-    // Validate and parse amount for deposit and withdrawal
-    let amount = match transaction_type {
-        TransactionType::Deposit | TransactionType::Withdrawal => {
-            let amount_str =
-                amount_str.ok_or_else(|| EngineError::TransactionError("Missing amount".into()))?;
-            let amount = amount_str
-                .parse::<Decimal>()
-                .map_err(|_| EngineError::TransactionError("Invalid amount".into()))?;
-            if amount <= Decimal::ZERO {
-                return Err(EngineError::TransactionError(
-                    "Amount must be positive".into(),
-                ));
-            }
-            Some(amount.trunc_with_scale(MAX_DISPLAY_PRECISION))
+    let transaction_id: u32 = parse_id_field(transaction_id_str, "transaction ID")?;
+
+    // Parse the amount for deposit, withdrawal, fee and adjustment
+    let amount = match (transaction_type.is_recorded(), amount_str) {
+        (true, Some(amount_str)) => Some(match options.thousands_sep {
+            Some(sep) => parse_amount_field(&strip_thousands_sep(amount_str, sep)?)?,
+            None => parse_amount_field(amount_str)?,
+        }),
+        // An empty cell disputes the whole transaction
+        (false, Some(amount_str))
+            if options.dispute_amounts
+                && transaction_type == TransactionType::Dispute
+                && !amount_str.is_empty() =>
+        {
+            Some(match options.thousands_sep {
+                Some(sep) => parse_amount_field(&strip_thousands_sep(amount_str, sep)?)?,
+                None => parse_amount_field(amount_str)?,
+            })
         }
-        _ => None, // Dispute, Resolve, Chargeback don't require an amount
+        _ => None,
     };
+    let amount = validate_amount(transaction_type, amount, options)?;
 
     Ok(Transaction {
         tx_type: transaction_type,
@@ -83,19 +736,176 @@ pub fn validate_and_parse_transaction(record: StringRecord) -> Result<Transactio
         tx_id: transaction_id,
         amount,
         under_dispute: false,
+        timestamp,
+        seq,
+    })
+}
+
+/// Truncate the amount of a deposit, withdrawal, fee or adjustment to the hold precision and
+/// check its sign. Disputes, resolves and chargebacks have no amount, any given is dropped,
+/// except the optional amount of a dispute with `options.dispute_amounts`.
+fn validate_amount(
+    transaction_type: TransactionType,
+    amount: Option<Decimal>,
+    options: &ParseOptions,
+) -> Result<Option<Decimal>, EngineError> {
+    if !transaction_type.is_recorded() {
+        return match amount {
+            Some(amount)
+                if options.dispute_amounts && transaction_type == TransactionType::Dispute =>
+            {
+                let amount = amount.trunc_with_scale(options.precision());
+                if amount <= Decimal::ZERO {
+                    return Err(EngineError::TransactionError(
+                        "Disputed amount must be positive".into(),
+                    ));
+                }
+                Ok(Some(amount))
+            }
+            _ => Ok(None),
+        };
+    }
+    let amount = amount
+        .ok_or_else(|| EngineError::TransactionError("Missing amount".into()))?
+        .trunc_with_scale(options.precision());
+    // Adjustments are signed: a negative amount debits the account
+    if transaction_type == TransactionType::Adjustment {
+        if amount.is_zero() {
+            return Err(EngineError::TransactionError(
+                "Adjustment amount must not be zero".into(),
+            ));
+        }
+    } else if amount < Decimal::ZERO
+        || (amount.is_zero() && !allows_zero(transaction_type, options))
+    {
+        return Err(EngineError::TransactionError(
+            "Amount must be positive".into(),
+        ));
+    }
+    Ok(Some(amount))
+}
+
+/// Whether `options.allow_zero_amount` lets a transaction of this type have a zero amount
+fn allows_zero(transaction_type: TransactionType, options: &ParseOptions) -> bool {
+    options.allow_zero_amount
+        && matches!(
+            transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        )
+}
+
+/// Remove thousands separators from an amount, rejecting separators that are not between
+/// groups of three digits of the integer part (e.g. `1,00.5` or `1.000,5`)
+fn strip_thousands_sep(value: &str, sep: char) -> Result<String, EngineError> {
+    let (integer, fraction) = match value.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (value, None),
+    };
+    let digits = integer.trim_start_matches(['-', '+']);
+
+    let mut groups = digits.split(sep);
+    let first_is_valid = groups
+        .next()
+        .is_some_and(|group| (1..=3).contains(&group.len()) || !digits.contains(sep));
+    let clean = first_is_valid
+        && groups.all(|group| group.len() == 3)
+        && !fraction.is_some_and(|fraction| fraction.contains(sep));
+    if !clean {
+        return Err(EngineError::ParseField {
+            field: "amount",
+            source: Box::new(EngineError::TransactionError(format!(
+                "Misplaced thousands separator in {}",
+                value
+            ))),
+        });
+    }
+
+    Ok(value.replace(sep, ""))
+}
+
+/// Parse a client or transaction id, reporting a present but empty (e.g. quoted `""`) or
+/// whitespace-only cell separately from a malformed value
+fn parse_id_field<T>(value: &str, field: &'static str) -> Result<T, EngineError>
+where
+    T: FromStr,
+    EngineError: From<T::Err>,
+{
+    if value.is_empty() {
+        return Err(EngineError::TransactionError(format!("Empty {}", field)));
+    }
+    if value.trim().is_empty() {
+        return Err(EngineError::TransactionError(format!(
+            "Whitespace-only {}",
+            field
+        )));
+    }
+    parse_field(value, field)
+}
+
+/// Parse an amount, first checking its shape so a malformed one is reported with the reason:
+/// an empty cell, no digits (e.g. `.`), more than one decimal point, trailing characters
+/// after the number (`1.2abc`) or another unexpected character (`12x.5`)
+fn parse_amount_field(value: &str) -> Result<Decimal, EngineError> {
+    if value.is_empty() {
+        return Err(EngineError::TransactionError("Empty amount".into()));
+    }
+    if value.trim().is_empty() {
+        return Err(EngineError::TransactionError(
+            "Whitespace-only amount".into(),
+        ));
+    }
+    let unsigned = value.strip_prefix(['-', '+']).unwrap_or(value);
+    let shape_error = match unsigned.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(index) if !unsigned[index..].contains(|c: char| c.is_ascii_digit()) => Some(format!(
+            "Trailing characters {:?} after the number in {:?}",
+            &unsigned[index..],
+            value
+        )),
+        Some(index) => Some(format!(
+            "Unexpected character {:?} in {:?}",
+            unsigned[index..].chars().next().unwrap_or_default(),
+            value
+        )),
+        None if unsigned.matches('.').count() > 1 => {
+            Some(format!("More than one decimal point in {:?}", value))
+        }
+        None if !unsigned.contains(|c: char| c.is_ascii_digit()) => {
+            Some(format!("No digits in {:?}", value))
+        }
+        None => None,
+    };
+    if let Some(message) = shape_error {
+        return Err(EngineError::ParseField {
+            field: "amount",
+            source: Box::new(EngineError::TransactionError(message)),
+        });
+    }
+    parse_field(value, "amount")
+}
+
+/// Parse a single field, keeping both the field name and the underlying parse error
+fn parse_field<T>(value: &str, field: &'static str) -> Result<T, EngineError>
+where
+    T: FromStr,
+    EngineError: From<T::Err>,
+{
+    value.parse::<T>().map_err(|err| EngineError::ParseField {
+        field,
+        source: Box::new(err.into()),
     })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::engine::ShardedEngine;
     use csv_async::StringRecord;
     use futures::StreamExt;
     use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
     use std::fs;
     use std::fs::File;
     use std::io::Write;
-    use std::str::FromStr;
 
     fn create_temp_csv(file_path: &str, data: &str) -> String {
         let mut file =
@@ -126,6 +936,636 @@ mod tests {
         fs::remove_file(&file_path).expect("Failed to delete test file");
     }
 
+    async fn gzip(data: &str) -> Vec<u8> {
+        use async_compression::futures::bufread::GzipEncoder;
+        use futures::io::{AsyncReadExt, Cursor};
+
+        let mut compressed = Vec::new();
+        GzipEncoder::new(Cursor::new(data.as_bytes()))
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+        compressed
+    }
+
+    #[tokio::test]
+    async fn test_stream_from_tokio_async_read() {
+        let csv = "type,client,tx,amount\ndeposit,1,1,10.5\nwithdrawal,2,2,1.0\n";
+        let options = ParseOptions::default();
+        for data in [csv.as_bytes().to_vec(), gzip(csv).await] {
+            let records = stream_transactions_from_async_read(std::io::Cursor::new(data))
+                .await
+                .unwrap();
+            let transactions: Vec<_> = parse_records(records, &options)
+                .map(|row| row.transaction.unwrap())
+                .collect()
+                .await;
+
+            assert_eq!(
+                transactions,
+                vec![
+                    Transaction::deposit(1, 1, dec!(10.5)),
+                    Transaction::withdrawal(2, 2, dec!(1.0)),
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_input_file_names_the_path() {
+        for input_format in [InputFormat::Csv, InputFormat::Json] {
+            let options = ParseOptions {
+                input_format,
+                ..ParseOptions::default()
+            };
+            let err = match stream_rows("res/does_not_exist.csv", &options).await {
+                Err(err) => err,
+                Ok(_) => panic!("opened a missing file"),
+            };
+
+            assert!(matches!(
+                &err,
+                EngineError::FileOpenError { source, .. } if source.kind() == io::ErrorKind::NotFound
+            ));
+            assert!(err
+                .to_string()
+                .starts_with("FileOpenError: cannot open res/does_not_exist.csv: "));
+        }
+    }
+
+    /// Reader failing with `kind` the first `failures` times it is read
+    struct FlakyReader {
+        data: futures::io::Cursor<Vec<u8>>,
+        kind: io::ErrorKind,
+        failures: u32,
+    }
+
+    impl FlakyReader {
+        fn new(data: &str, kind: io::ErrorKind, failures: u32) -> Self {
+            Self {
+                data: futures::io::Cursor::new(data.as_bytes().to_vec()),
+                kind,
+                failures,
+            }
+        }
+    }
+
+    impl AsyncRead for FlakyReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Poll::Ready(Err(io::Error::new(self.kind, "flaky read")));
+            }
+            Pin::new(&mut self.data).poll_read(cx, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retrying_reader_continues_after_transient_errors() {
+        let csv_data = "type,client,tx,amount\ndeposit,1,1,1000.0\nwithdrawal,1,2,500.0\n";
+        let reader = RetryingReader::new(FlakyReader::new(csv_data, io::ErrorKind::Interrupted, 2));
+
+        let transactions: Vec<_> = stream_records(reader)
+            .await
+            .unwrap()
+            .map(|record| validate_and_parse_transaction(record.unwrap()).unwrap())
+            .collect()
+            .await;
+        assert_eq!(
+            transactions,
+            vec![
+                Transaction::deposit(1, 1, dec!(1000.0)),
+                Transaction::withdrawal(1, 2, dec!(500.0)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retrying_reader_fails_on_permanent_or_persistent_errors() {
+        use futures::io::AsyncReadExt;
+
+        let mut buf = Vec::new();
+        let mut denied =
+            RetryingReader::new(FlakyReader::new("data", io::ErrorKind::PermissionDenied, 1));
+        let err = denied.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(denied.retries, 0);
+
+        let mut timing_out = RetryingReader::new(FlakyReader::new(
+            "data",
+            io::ErrorKind::TimedOut,
+            READ_RETRIES + 1,
+        ));
+        let err = timing_out.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(timing_out.retries, READ_RETRIES);
+    }
+
+    async fn parse_file(file_path: &str) -> Vec<Transaction> {
+        stream_transactions(file_path)
+            .await
+            .unwrap()
+            .map(|record| validate_and_parse_transaction(record.unwrap()).unwrap())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_stream_transactions_detects_gzip_by_content() {
+        let csv_data = "type,client,tx,amount
+                        deposit,1,1,1000.0
+                        withdrawal,1,2,500.0
+";
+        let expected = vec![
+            Transaction::deposit(1, 1, dec!(1000.0)),
+            Transaction::withdrawal(1, 2, dec!(500.0)),
+        ];
+
+        // Compressed, without a `.gz` extension
+        let gzip_path = "res/test_stream_transactions_gzip.csv";
+        fs::write(gzip_path, gzip(csv_data).await).unwrap();
+        let from_gzip = parse_file(gzip_path).await;
+        fs::remove_file(gzip_path).expect("Failed to delete test file");
+        assert_eq!(from_gzip, expected);
+
+        // Plain text, with a misleading `.gz` extension
+        let plain_path = create_temp_csv("test_stream_transactions_plain.csv.gz", csv_data);
+        let from_plain = parse_file(&plain_path).await;
+        fs::remove_file(&plain_path).expect("Failed to delete test file");
+        assert_eq!(from_plain, expected);
+    }
+
+    async fn stream_file_rows(file_path: &str, input_format: InputFormat) -> Vec<InputRow> {
+        let options = ParseOptions {
+            input_format,
+            ..ParseOptions::default()
+        };
+        let rows = stream_rows(file_path, &options)
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        fs::remove_file(file_path).expect("Failed to delete test file");
+        rows
+    }
+
+    #[tokio::test]
+    async fn test_json_lines_parse_like_csv() {
+        let csv_path = create_temp_csv(
+            "test_json_lines_equivalent.csv",
+            "type,client,tx,amount,timestamp\n\
+             deposit,1,1,100.0,10\n\
+             withdrawal,1,2,40.123456,11\n\
+             dispute,1,1,,12\n\
+             adjustment,2,3,-1.5,\n\
+             resolve,1,1,,\n\
+             fee,2,4,0.25,\n",
+        );
+        // Keys in any order, a blank line, and the three forms of a missing amount
+        let json_path = create_temp_csv(
+            "test_json_lines_equivalent.jsonl",
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"100.0\",\"timestamp\":10}\n\
+             {\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":\"40.123456\",\"timestamp\":11}\n\
+             \n\
+             {\"client\":1,\"tx\":1,\"type\":\"dispute\",\"timestamp\":12}\n\
+             {\"type\":\"adjustment\",\"client\":2,\"tx\":3,\"amount\":\"-1.5\"}\n\
+             {\"type\":\"resolve\",\"client\":1,\"tx\":1,\"amount\":null}\n\
+             {\"type\":\"fee\",\"client\":2,\"tx\":4,\"amount\":\"\"}\n",
+        );
+
+        let from_csv: Vec<_> = stream_file_rows(&csv_path, InputFormat::Csv)
+            .await
+            .into_iter()
+            .map(|row| row.transaction.unwrap())
+            .collect();
+        let from_json = stream_file_rows(&json_path, InputFormat::Json).await;
+
+        assert_eq!(from_json.len(), 6);
+        let fee = &from_json[5].transaction;
+        assert!(
+            matches!(fee, Err(EngineError::TransactionError(msg)) if msg == "Missing amount"),
+            "{:?}",
+            fee
+        );
+        let from_json: Vec<_> = from_json[..5]
+            .iter()
+            .map(|row| *row.transaction.as_ref().unwrap())
+            .collect();
+        assert_eq!(from_json, from_csv[..5]);
+        // Equality ignores timestamps
+        let timestamps = |transactions: &[Transaction]| {
+            transactions
+                .iter()
+                .map(|transaction| transaction.timestamp)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timestamps(&from_json), timestamps(&from_csv[..5]));
+        assert_eq!(from_json[1].amount, Some(dec!(40.1234)));
+    }
+
+    #[tokio::test]
+    async fn test_json_lines_invalid_rows() {
+        let json_data = "# exported by the back office\n\
+                         {\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.0\"}\n\
+                         {\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":1.0}\n\
+                         {\"type\":\"withdrawal\",\"client\":1,\"tx\":3,\"amount\":\"-1.0\"}\n\
+                         {\"type\":\"Deposit\",\"client\":1,\"tx\":4,\"amount\":\"1.0\"}\n\
+                         deposit,1,5,1.0\n";
+        let options = ParseOptions {
+            input_format: InputFormat::Json,
+            comment: Some(b'#'),
+            ..ParseOptions::default()
+        };
+
+        let rows: Vec<_> = stream_json_lines(json_data.as_bytes(), &options)
+            .await
+            .unwrap()
+            .collect()
+            .await;
+
+        assert_eq!(rows.len(), 5);
+        assert!(rows[0].transaction.is_ok());
+        // Ends count every byte of the file, including the comment and newlines
+        let second_line_end: usize = json_data.lines().take(2).map(|line| line.len() + 1).sum();
+        assert_eq!(rows[0].end, Some(second_line_end as u64));
+        assert!(matches!(
+            &rows[2].transaction,
+            Err(EngineError::TransactionError(msg)) if msg == "Amount must be positive"
+        ));
+        // Numeric amounts, capitalized types and CSV rows are not valid objects
+        for row in [&rows[1], &rows[3], &rows[4]] {
+            assert!(
+                matches!(&row.transaction, Err(EngineError::TransactionError(msg))
+                    if msg.starts_with("Invalid JSON transaction")),
+                "{:?}",
+                row
+            );
+        }
+        assert_eq!(rows[4].end, Some(json_data.len() as u64));
+    }
+
+    #[tokio::test]
+    async fn test_leading_bom_skipped() {
+        let csv_data = "\u{feff}type,client,tx,amount\n\
+                        deposit,1,1,1000.0\n";
+        let file_path = create_temp_csv("test_leading_bom.csv", csv_data);
+        let result = parse_file(&file_path).await;
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+        assert_eq!(result, vec![Transaction::deposit(1, 1, dec!(1000.0))]);
+
+        // Without a header the BOM would land in the type of the first transaction
+        let options = ParseOptions {
+            has_header: HeaderMode::Absent,
+            ..ParseOptions::default()
+        };
+        let source = "\u{feff}deposit,2,2,5.0\n".as_bytes();
+        let records: Vec<_> = stream_records_with(source, &options)
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        let record = records[0].as_ref().unwrap();
+        assert_eq!(&record[0], "deposit");
+    }
+
+    #[tokio::test]
+    async fn test_concatenated_gzip_members_read_in_full() {
+        let mut data = gzip(
+            "type,client,tx,amount
+deposit,1,1,1.0
+",
+        )
+        .await;
+        data.extend(
+            gzip(
+                "deposit,2,2,2.0
+",
+            )
+            .await,
+        );
+
+        let source = decompressed(futures::io::Cursor::new(data)).await.unwrap();
+        let records: Vec<_> = stream_records(source).await.unwrap().collect().await;
+        assert_eq!(records.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stream_transactions_missing_amount_column() {
+        let csv_data = "type,client,tx\n\
+                        deposit,1,1\n\
+                        withdrawal,1,2\n";
+
+        let file_path = create_temp_csv("test_stream_transactions_missing_amount.csv", csv_data);
+        let result = stream_transactions(&file_path).await;
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+
+        match result {
+            Err(EngineError::HeaderError(msg)) => assert!(msg.contains("amount")),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Expected header validation to fail"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_and_malformed_client_id_messages() {
+        let csv_data = "type,client,tx,amount\n\
+                        deposit,\"\",1,1.0\n";
+        let file_path = create_temp_csv("test_stream_quoted_empty_client.csv", csv_data);
+        let mut records = stream_transactions(&file_path).await.unwrap();
+        let record = records.next().await.unwrap().unwrap();
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+
+        let quoted_empty = validate_and_parse_transaction(record).unwrap_err();
+        assert_eq!(
+            quoted_empty.to_string(),
+            "TransactionError: Empty client ID"
+        );
+
+        let record = StringRecord::from(vec!["deposit", "   ", "1", "1.0"]);
+        let whitespace = validate_and_parse_transaction(record).unwrap_err();
+        assert_eq!(
+            whitespace.to_string(),
+            "TransactionError: Whitespace-only client ID"
+        );
+
+        let record = StringRecord::from(vec!["deposit", "abc", "1", "1.0"]);
+        let malformed = validate_and_parse_transaction(record).unwrap_err();
+        assert_eq!(
+            malformed.to_string(),
+            "ParseField: Invalid client ID (ParseIntError: invalid digit found in string)"
+        );
+    }
+
+    #[test]
+    fn test_validate_headers() {
+        let headers = StringRecord::from(vec!["Type", "client", "TX", "amount"]);
+        assert!(validate_headers(&headers).is_ok());
+
+        let reordered = StringRecord::from(vec!["client", "type", "tx", "amount"]);
+        assert!(matches!(
+            validate_headers(&reordered),
+            Err(EngineError::HeaderError(_))
+        ));
+
+        let unrelated = StringRecord::from(vec!["name", "email"]);
+        assert!(matches!(
+            validate_headers(&unrelated),
+            Err(EngineError::HeaderError(_))
+        ));
+
+        let timestamped = StringRecord::from(vec!["type", "client", "tx", "amount", "Timestamp"]);
+        assert!(validate_headers(&timestamped).is_ok());
+
+        let extra = StringRecord::from(vec!["type", "client", "tx", "amount", "note"]);
+        assert!(matches!(
+            validate_headers(&extra),
+            Err(EngineError::HeaderError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream_records_with_and_without_timestamps() {
+        async fn parse(csv_data: &'static str) -> Vec<Transaction> {
+            stream_records(csv_data.as_bytes())
+                .await
+                .unwrap()
+                .map(|record| validate_and_parse_transaction(record.unwrap()).unwrap())
+                .collect()
+                .await
+        }
+
+        let timestamped = parse(
+            "type,client,tx,amount,timestamp\n\
+             deposit,1,1,10.0,1700000000000\n\
+             dispute,1,1,,1700000005000\n\
+             deposit,2,2,5.0,\n",
+        )
+        .await;
+        let timestamps: Vec<_> = timestamped.iter().map(|tx| tx.timestamp).collect();
+        assert_eq!(
+            timestamps,
+            vec![Some(1700000000000), Some(1700000005000), None]
+        );
+
+        let plain = parse(
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             dispute,1,1,\n",
+        )
+        .await;
+        assert_eq!(plain.len(), 2);
+        assert!(plain
+            .iter()
+            .all(|tx| tx.timestamp.is_none() && tx.seq.is_none()));
+
+        let numbered = parse(
+            "type,client,tx,amount,timestamp,seq\n\
+             deposit,1,1,10.0,,1\n\
+             deposit,1,2,5.0,1700000000000,2\n\
+             deposit,1,3,5.0,,\n",
+        )
+        .await;
+        let seqs: Vec<_> = numbered.iter().map(|tx| tx.seq).collect();
+        assert_eq!(seqs, vec![Some(1), Some(2), None]);
+        assert_eq!(numbered[1].timestamp, Some(1700000000000));
+    }
+
+    #[tokio::test]
+    async fn test_flexible_rows_with_extra_or_missing_columns() {
+        let csv_data = "type,client,tx,amount\n\
+                        deposit,1,1,10.0,\n\
+                        deposit,1,2,5.0,unexpected\n\
+                        dispute,1,1\n\
+                        withdrawal,1,3\n";
+
+        // By default the first row of the wrong width fails
+        let mut strict = stream_records(csv_data.as_bytes()).await.unwrap();
+        assert!(strict.next().await.unwrap().is_err());
+
+        let options = ParseOptions {
+            flexible: true,
+            ..ParseOptions::default()
+        };
+        let results: Vec<_> = stream_records_with(csv_data.as_bytes(), &options)
+            .await
+            .unwrap()
+            .map(|record| validate_and_parse_transaction_with(record.unwrap(), &options))
+            .collect()
+            .await;
+
+        let first = results[0].as_ref().unwrap();
+        assert_eq!(first.amount, Some(Decimal::from(10)));
+        assert_eq!(first.timestamp, None);
+        // The extra cell is not mistaken for a timestamp
+        assert_eq!(results[1].as_ref().unwrap().timestamp, None);
+        let dispute = results[2].as_ref().unwrap();
+        assert_eq!(dispute.tx_type, TransactionType::Dispute);
+        assert_eq!(dispute.amount, None);
+        // A withdrawal still needs its amount
+        assert!(matches!(
+            &results[3],
+            Err(EngineError::TransactionError(message)) if message == "Missing amount"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unterminated_quote_rejected_not_merged() {
+        let csv_data = "type,client,tx,amount\n\
+                        deposit,1,1,10.0\n\
+                        deposit,2,\"2,5.0\n\
+                        deposit,3,3,1.0\n\
+                        withdrawal,1,4,2.0\n";
+
+        for flexible in [false, true] {
+            let options = ParseOptions {
+                flexible,
+                ..ParseOptions::default()
+            };
+            let records = stream_records_with(csv_data.as_bytes(), &options)
+                .await
+                .unwrap();
+            let rows: Vec<_> = parse_records(records, &options).collect().await;
+
+            // The quote swallows the rest of the file into one row, which is rejected
+            assert_eq!(rows.len(), 2);
+            assert!(rows[0].transaction.is_ok());
+            assert!(matches!(
+                &rows[1].transaction,
+                Err(EngineError::TransactionError(message)) if message
+                    == "Row starting on line 3 spans several lines, a quoted cell is likely unterminated"
+            ));
+        }
+
+        // A quoted cell on a single line is fine, a row of the wrong width still is not
+        let csv_data = "type,client,tx,amount\n\
+                        deposit,\"1\",1,\"10.0\"\n\
+                        deposit,1,2,5.0,extra\n";
+        let results: Vec<_> = stream_records(csv_data.as_bytes())
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            &results[1],
+            Err(EngineError::TransactionError(message))
+                if message == "Row with 5 cells, expected 4 like the first row"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_comment_and_blank_lines_skipped() {
+        let csv_data = "# exported by the back office\n\
+                        type,client,tx,amount\n\
+                        deposit,1,1,10.0\n\
+                        \n\
+                        # second batch\n\
+                        ,,,\n\
+                        withdrawal,1,2,4.0\n";
+        let options = ParseOptions {
+            comment: Some(b'#'),
+            ..ParseOptions::default()
+        };
+
+        let results: Vec<_> = stream_records_with(csv_data.as_bytes(), &options)
+            .await
+            .unwrap()
+            .map(|record| validate_and_parse_transaction_with(record.unwrap(), &options))
+            .collect()
+            .await;
+
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(Result::is_ok));
+
+        // Without the option the comment is read as the header
+        let result = stream_records(csv_data.as_bytes()).await;
+        assert!(matches!(result, Err(EngineError::HeaderError(_))));
+    }
+
+    async fn parse_with_header_mode(
+        csv_data: &'static str,
+        has_header: HeaderMode,
+    ) -> Result<Vec<Transaction>, EngineError> {
+        let options = ParseOptions {
+            has_header,
+            ..ParseOptions::default()
+        };
+        let records = stream_records_with(csv_data.as_bytes(), &options).await?;
+        Ok(records
+            .map(|record| validate_and_parse_transaction(record.unwrap()).unwrap())
+            .collect()
+            .await)
+    }
+
+    #[tokio::test]
+    async fn test_header_modes() {
+        let headered = "type,client,tx,amount\n\
+                        deposit,1,1,100\n";
+        let headerless = "deposit,1,1,100\n\
+                          withdrawal,1,2,40\n";
+
+        for mode in [HeaderMode::Present, HeaderMode::Auto] {
+            let transactions = parse_with_header_mode(headered, mode).await.unwrap();
+            assert_eq!(transactions.len(), 1);
+        }
+        for mode in [HeaderMode::Absent, HeaderMode::Auto] {
+            let transactions = parse_with_header_mode(headerless, mode).await.unwrap();
+            assert_eq!(transactions.len(), 2);
+            assert_eq!(transactions[0].tx_type, TransactionType::Deposit);
+            assert_eq!(transactions[0].amount, Some(Decimal::from(100)));
+        }
+
+        // A headerless first row is never silently consumed as the header
+        assert!(matches!(
+            parse_with_header_mode(headerless, HeaderMode::Present).await,
+            Err(EngineError::HeaderError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_auto_header_with_ambiguous_first_row() {
+        // Neither the expected header nor a valid transaction: taken as a bad header
+        let malformed = "deposit,1,one,100\n\
+                         deposit,1,2,100\n";
+        assert!(matches!(
+            parse_with_header_mode(malformed, HeaderMode::Auto).await,
+            Err(EngineError::HeaderError(_))
+        ));
+
+        // With the header known to be absent, only the malformed row fails
+        let options = ParseOptions {
+            has_header: HeaderMode::Absent,
+            ..ParseOptions::default()
+        };
+        let results: Vec<_> = stream_records_with(malformed.as_bytes(), &options)
+            .await
+            .unwrap()
+            .map(|record| validate_and_parse_transaction(record.unwrap()))
+            .collect()
+            .await;
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+
+        assert_eq!("auto".parse::<HeaderMode>().unwrap(), HeaderMode::Auto);
+        assert!("yes".parse::<HeaderMode>().is_err());
+    }
+
+    #[test]
+    fn test_validate_and_parse_transaction_invalid_timestamp() {
+        let record = StringRecord::from(vec!["deposit", "1", "1", "1.0", "yesterday"]);
+        assert!(matches!(
+            validate_and_parse_transaction(record),
+            Err(EngineError::ParseField {
+                field: "timestamp",
+                ..
+            })
+        ));
+    }
+
     #[test]
     fn test_validate_and_parse_transaction_success() {
         let record = StringRecord::from(vec!["deposit", "1", "1001", "123.4567"]);
@@ -138,7 +1578,7 @@ mod tests {
             transaction.amount.unwrap(),
             Decimal::from_str("123.4567").unwrap()
         );
-        assert_eq!(transaction.under_dispute, false);
+        assert!(!transaction.under_dispute);
     }
 
     #[test]
@@ -165,6 +1605,161 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_validate_and_parse_transaction_invalid_client_id_message() {
+        let record = StringRecord::from(vec!["deposit", "5000000000", "1001", "123.4567"]);
+        let err = validate_and_parse_transaction(record).unwrap_err();
+
+        assert!(matches!(
+            err,
+            EngineError::ParseField {
+                field: "client ID",
+                ..
+            }
+        ));
+        let message = err.to_string();
+        assert!(message.contains("client ID"));
+        assert!(message.contains("number too large"));
+    }
+
+    #[cfg(feature = "wide-client-id")]
+    #[test]
+    fn test_validate_and_parse_transaction_wide_client_id() {
+        let record = StringRecord::from(vec!["deposit", "70001", "1001", "1.0"]);
+        let transaction = validate_and_parse_transaction(record).unwrap();
+
+        assert_eq!(transaction.client, 70_001);
+    }
+
+    #[test]
+    fn test_validate_and_parse_transaction_invalid_amount_message() {
+        let record = StringRecord::from(vec!["withdrawal", "1", "1001", "12x.5"]);
+        let err = validate_and_parse_transaction(record).unwrap_err();
+
+        let message = err.to_string();
+        assert!(message.contains("amount"));
+        assert!(message.contains("Unexpected character 'x'"));
+
+        // A well-formed amount the decimal type cannot hold is reported by its parser
+        let record = StringRecord::from(vec![
+            "withdrawal",
+            "1",
+            "1001",
+            "123456789012345678901234567890",
+        ]);
+        let err = validate_and_parse_transaction(record).unwrap_err();
+
+        let message = err.to_string();
+        assert!(message.contains("amount"));
+        assert!(message.contains("ParseDecimalError"));
+    }
+
+    #[test]
+    fn test_malformed_amount_messages() {
+        let cases = [
+            ("1.2.3", "Invalid amount (TransactionError: More than one decimal point in \"1.2.3\")"),
+            ("1.2abc", "Invalid amount (TransactionError: Trailing characters \"abc\" after the number in \"1.2abc\")"),
+            (".", "Invalid amount (TransactionError: No digits in \".\")"),
+            ("-", "Invalid amount (TransactionError: No digits in \"-\")"),
+        ];
+        for (amount, expected) in cases {
+            let record = StringRecord::from(vec!["deposit", "1", "1001", amount]);
+            let err = validate_and_parse_transaction(record).unwrap_err();
+            assert_eq!(err.to_string(), format!("ParseField: {}", expected));
+        }
+
+        let record = StringRecord::from(vec!["deposit", "1", "1001", ""]);
+        let err = validate_and_parse_transaction(record).unwrap_err();
+        assert!(matches!(err, EngineError::TransactionError(message) if message == "Empty amount"));
+
+        for valid in ["1.", ".5", "+2.25", "-0.5"] {
+            let record = StringRecord::from(vec!["adjustment", "1", "1001", valid]);
+            assert!(validate_and_parse_transaction(record).is_ok(), "{}", valid);
+        }
+    }
+
+    #[test]
+    fn test_validate_and_parse_transaction_thousands_separator() {
+        let options = ParseOptions {
+            thousands_sep: Some(','),
+            ..ParseOptions::default()
+        };
+        let record = StringRecord::from(vec!["deposit", "1", "1001", "1,000.50"]);
+        let transaction = validate_and_parse_transaction_with(record, &options).unwrap();
+        assert_eq!(
+            transaction.amount.unwrap(),
+            Decimal::from_str("1000.50").unwrap()
+        );
+
+        let record = StringRecord::from(vec!["deposit", "1", "1002", "12,345,678"]);
+        let transaction = validate_and_parse_transaction_with(record, &options).unwrap();
+        assert_eq!(transaction.amount.unwrap(), Decimal::from(12_345_678));
+
+        for misplaced in ["1,00.5", "1000,5", ",100", "1,000.5,0"] {
+            let record = StringRecord::from(vec!["deposit", "1", "1003", misplaced]);
+            let err = validate_and_parse_transaction_with(record, &options).unwrap_err();
+            assert!(matches!(
+                err,
+                EngineError::ParseField {
+                    field: "amount",
+                    ..
+                }
+            ));
+        }
+
+        // Without the option the separator is still rejected
+        let record = StringRecord::from(vec!["deposit", "1", "1004", "1,000.50"]);
+        assert!(validate_and_parse_transaction(record).is_err());
+    }
+
+    #[test]
+    fn test_check_limits() {
+        let options = ParseOptions {
+            max_rows: Some(2),
+            ..ParseOptions::default()
+        };
+        let record = StringRecord::from(vec!["deposit", "1", "1", "1.0"]);
+        assert!(options.check_limits("input.csv", 2, &record).is_ok());
+        assert!(matches!(
+            options.check_limits("input.csv", 3, &record),
+            Err(EngineError::InputLimitExceeded(_))
+        ));
+
+        let options = ParseOptions {
+            max_bytes: Some(10),
+            ..ParseOptions::default()
+        };
+        let small = StringRecord::from(vec!["deposit", "1"]);
+        assert!(options.check_limits("input.csv", 1, &small).is_ok());
+        assert!(matches!(
+            options.check_limits("input.csv", 1, &record),
+            Err(EngineError::InputLimitExceeded(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream_quoted_amount_with_thousands_separator() {
+        let csv_data = "type,client,tx,amount\n\
+                        deposit,1,1,\"1,000.50\"\n";
+
+        let file_path = create_temp_csv("test_stream_thousands_separator.csv", csv_data);
+        let mut transactions = stream_transactions(&file_path)
+            .await
+            .expect("Failed to stream transactions");
+        let record = transactions.next().await.unwrap().unwrap();
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+
+        let options = ParseOptions {
+            thousands_sep: Some(','),
+            ..ParseOptions::default()
+        };
+        let transaction = validate_and_parse_transaction_with(record, &options).unwrap();
+        assert_eq!(
+            transaction.amount.unwrap(),
+            Decimal::from_str("1000.50").unwrap()
+        );
+    }
+
     #[test]
     fn test_validate_and_parse_transaction_invalid_transaction_id() {
         let record = StringRecord::from(vec!["deposit", "1", "invalid_tx", "123.4567"]);
@@ -189,6 +1784,24 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_validate_and_parse_transaction_signed_adjustment() {
+        let record = StringRecord::from(vec!["adjustment", "1", "1001", "-12.5"]);
+        let transaction = validate_and_parse_transaction(record).unwrap();
+        assert_eq!(transaction.tx_type, TransactionType::Adjustment);
+        assert_eq!(transaction.amount, Some(Decimal::new(-125, 1)));
+
+        let record = StringRecord::from(vec!["adjustment", "1", "1002", "0.75"]);
+        let transaction = validate_and_parse_transaction(record).unwrap();
+        assert_eq!(transaction.amount, Some(Decimal::new(75, 2)));
+
+        let record = StringRecord::from(vec!["adjustment", "1", "1003", "0.00001"]);
+        assert!(validate_and_parse_transaction(record).is_err());
+
+        let record = StringRecord::from(vec!["adjustment", "1", "1004", ""]);
+        assert!(validate_and_parse_transaction(record).is_err());
+    }
+
     #[test]
     fn test_validate_and_parse_transaction_insufficient_data() {
         let record = StringRecord::from(vec!["deposit", "1"]);
@@ -197,6 +1810,114 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_read_client_ids() {
+        let file_path = create_temp_csv("test_read_client_ids.txt", "3\n 7 \n\n3\n");
+        let clients = read_client_ids(&file_path).await.unwrap();
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+        assert_eq!(clients, HashSet::from([3, 7]));
+
+        let file_path = create_temp_csv("test_read_client_ids_invalid.txt", "3\nseven\n");
+        let result = read_client_ids(&file_path).await;
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+        match result {
+            Err(EngineError::ConfigError(msg)) => assert!(msg.contains("line 2"), "{}", msg),
+            other => panic!("Expected a ConfigError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_try_from_record() {
+        let record = StringRecord::from(vec!["withdrawal", "7", "1002", "12.5"]);
+        let transaction = Transaction::try_from(record).unwrap();
+        assert_eq!(transaction, Transaction::withdrawal(7, 1002, dec!(12.5)));
+
+        let record = StringRecord::from(vec!["chargeback", "7", "1002", ""]);
+        let transaction: Transaction = record.try_into().unwrap();
+        assert_eq!(transaction, Transaction::chargeback(7, 1002));
+
+        let invalid = [
+            vec!["deposit", "1"],
+            vec!["refund", "1", "1", "1.0"],
+            vec!["deposit", "x", "1", "1.0"],
+            vec!["deposit", "1", "1", "-1.0"],
+            vec!["deposit", "1", "1", "1.0", "later"],
+        ];
+        for fields in invalid {
+            assert!(Transaction::try_from(StringRecord::from(fields)).is_err());
+        }
+        let record = StringRecord::from(vec!["deposit", "1", "1", "0"]);
+        assert!(matches!(
+            Transaction::try_from(record),
+            Err(EngineError::TransactionError(message)) if message == "Amount must be positive"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_zero_amount_follows_allow_zero_amount() {
+        let zero_deposit = || StringRecord::from(vec!["deposit", "1", "1", "0.0"]);
+        assert!(matches!(
+            validate_and_parse_transaction_with(zero_deposit(), &ParseOptions::default()),
+            Err(EngineError::TransactionError(message)) if message == "Amount must be positive"
+        ));
+
+        let options = ParseOptions {
+            allow_zero_amount: true,
+            ..ParseOptions::default()
+        };
+        let deposit = validate_and_parse_transaction_with(zero_deposit(), &options).unwrap();
+        assert_eq!(deposit.amount, Some(dec!(0)));
+        let withdrawal = StringRecord::from(vec!["withdrawal", "1", "2", "0"]);
+        assert!(validate_and_parse_transaction_with(withdrawal, &options).is_ok());
+        // Still positive only for fees, never negative
+        let fee = StringRecord::from(vec!["fee", "1", "3", "0"]);
+        assert!(validate_and_parse_transaction_with(fee, &options).is_err());
+        let negative = StringRecord::from(vec!["deposit", "1", "4", "-1.0"]);
+        assert!(validate_and_parse_transaction_with(negative, &options).is_err());
+
+        // The zero deposit changes no balance but is recorded, so it can be disputed
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![deposit, Transaction::dispute(1, 1)])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+        let account = engine.get_account(1).await.unwrap();
+        assert_eq!((account.available, account.held), (dec!(0), dec!(0)));
+        let summary = engine.run_summary().await;
+        assert_eq!((summary.deposits, summary.disputes), (1, 1));
+    }
+
+    #[test]
+    fn test_dispute_amount_follows_dispute_amounts() {
+        let dispute = || StringRecord::from(vec!["dispute", "1", "1", "4.00005"]);
+        let transaction = validate_and_parse_transaction(dispute()).unwrap();
+        assert_eq!(transaction.amount, None);
+
+        let options = ParseOptions {
+            dispute_amounts: true,
+            ..ParseOptions::default()
+        };
+        let transaction = validate_and_parse_transaction_with(dispute(), &options).unwrap();
+        assert_eq!(transaction.amount, Some(dec!(4.0000)));
+        let whole = StringRecord::from(vec!["dispute", "1", "1", ""]);
+        let transaction = validate_and_parse_transaction_with(whole, &options).unwrap();
+        assert_eq!(transaction.amount, None);
+        // Resolves and chargebacks settle what the dispute held
+        let resolve = StringRecord::from(vec!["resolve", "1", "1", "4.0"]);
+        let transaction = validate_and_parse_transaction_with(resolve, &options).unwrap();
+        assert_eq!(transaction.amount, None);
+
+        for invalid in ["0", "-1.0", "lots"] {
+            let record = StringRecord::from(vec!["dispute", "1", "1", invalid]);
+            assert!(
+                validate_and_parse_transaction_with(record, &options).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
     #[test]
     fn test_validate_and_parse_transaction_dispute_type_without_amount() {
         let record = StringRecord::from(vec!["dispute", "1", "1001", ","]);
@@ -206,6 +1927,6 @@ mod tests {
         assert_eq!(transaction.client, 1);
         assert_eq!(transaction.tx_id, 1001);
         assert!(transaction.amount.is_none());
-        assert_eq!(transaction.under_dispute, false);
+        assert!(!transaction.under_dispute);
     }
 }
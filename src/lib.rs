@@ -0,0 +1,27 @@
+pub mod amount;
+#[cfg(feature = "lock-free-reads")]
+pub mod atomic_account;
+pub mod checksum;
+pub mod cli;
+pub mod engine;
+pub mod error_rate;
+pub mod errors;
+pub mod io;
+pub mod logging;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod models;
+pub mod observer;
+pub mod profile;
+pub mod rate_limit;
+pub mod reconciliation;
+pub mod rejects;
+pub mod runner;
+pub mod single_threaded;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod statistics;
+pub mod verify;
@@ -0,0 +1,128 @@
+use crate::errors::EngineError;
+use log::kv::{Error as KvError, Key, Value, VisitSource};
+use log::Record;
+use serde_json::{Map, Value as JsonValue};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// Output format used by the logger
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(EngineError::ConfigError(format!(
+                "Invalid log format: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Initialize the global logger, keeping `RUST_LOG` overrides working for both formats
+pub fn init_logger(format: LogFormat) {
+    let mut builder = env_logger::Builder::new();
+    builder
+        .filter_level(log::LevelFilter::Info)
+        .parse_default_env();
+
+    if format == LogFormat::Json {
+        builder.format(write_json_record);
+    }
+
+    builder.init();
+}
+
+/// Write a log record as a single JSON line, flattening its key-values (tx_id, client, ...)
+pub fn write_json_record<W: Write>(writer: &mut W, record: &Record) -> io::Result<()> {
+    let mut fields = Map::new();
+    fields.insert("level".into(), record.level().as_str().into());
+    fields.insert("target".into(), record.target().into());
+    fields.insert("message".into(), record.args().to_string().into());
+
+    let mut visitor = JsonFields(&mut fields);
+    record
+        .key_values()
+        .visit(&mut visitor)
+        .map_err(io::Error::other)?;
+
+    serde_json::to_writer(&mut *writer, &fields)?;
+    writeln!(writer)
+}
+
+/// Collects structured log key-values into a JSON object
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+        let json = if let Some(num) = value.to_u64() {
+            JsonValue::from(num)
+        } else if let Some(num) = value.to_i64() {
+            JsonValue::from(num)
+        } else {
+            JsonValue::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), json);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_log_format_from_str() {
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_write_json_record_with_engine_fields() {
+        let kvs: [(&str, u64); 2] = [("tx_id", 42), ("client", 7)];
+        let record = Record::builder()
+            .level(Level::Error)
+            .target("payments_engine::engine")
+            .args(format_args!("TransactionNotFound: 42"))
+            .key_values(&kvs)
+            .build();
+
+        let mut buf = Vec::new();
+        write_json_record(&mut buf, &record).unwrap();
+
+        let line = String::from_utf8(buf).unwrap();
+        assert!(line.ends_with('\n'));
+        let json: JsonValue = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(json["level"], "ERROR");
+        assert_eq!(json["message"], "TransactionNotFound: 42");
+        assert_eq!(json["tx_id"], 42);
+        assert_eq!(json["client"], 7);
+    }
+
+    #[test]
+    fn test_write_json_record_without_key_values() {
+        let record = Record::builder()
+            .level(Level::Info)
+            .args(format_args!("plain \"quoted\" message"))
+            .build();
+
+        let mut buf = Vec::new();
+        write_json_record(&mut buf, &record).unwrap();
+
+        let json: JsonValue = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["message"], "plain \"quoted\" message");
+        assert!(json.get("tx_id").is_none());
+    }
+}
@@ -1,53 +1,320 @@
-mod engine;
-mod errors;
-mod io;
-mod models;
-
-use crate::engine::ShardedEngine;
-use crate::errors::EngineError;
-use futures::stream::StreamExt;
-use log::error;
+use async_std::io::stdout;
+use futures::io::{AsyncWrite, AsyncWriteExt};
+use payments_engine::cli::{Command, Config, ShardCount, USAGE};
+use payments_engine::engine::{EngineOptions, PaymentEngine, ShardedEngine};
+use payments_engine::error_rate::ErrorRateLimit;
+use payments_engine::errors::EngineError;
+use payments_engine::io::{self, ParseOptions};
+use payments_engine::observer::NoopObserver;
+use payments_engine::profile::{self, Phase, Profile};
+use payments_engine::rate_limit::RateLimiter;
+use payments_engine::rejects::RejectLog;
+use payments_engine::single_threaded::SingleThreadedEngine;
+use payments_engine::{logging, runner, verify};
 use std::env;
-use tokio::runtime::Runtime;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::runtime::{self, Runtime};
+use tokio_util::sync::CancellationToken;
 
 fn main() -> Result<(), EngineError> {
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Info)
-        .init();
+    let command = match Command::from_args(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    match command {
+        Command::Run(config) => run(*config),
+        Command::Verify { expected, actual } => verify(&expected, &actual),
+    }
+}
+
+/// Compare two account output files, exiting non-zero when they differ logically
+fn verify(expected: &str, actual: &str) -> Result<(), EngineError> {
+    logging::init_logger(Default::default());
 
     let runtime = Runtime::new()?;
+    let differences = runtime.block_on(verify::verify_files(expected, actual))?;
+    for difference in &differences {
+        println!("{}", difference);
+    }
+    if !differences.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run(config: Config) -> Result<(), EngineError> {
+    logging::init_logger(config.log_format);
+
+    // `--shards auto` skips the shard workers altogether for small inputs
+    let single_thread = config.single_thread
+        || (config.shards == ShardCount::Auto
+            && config.allows_auto_single_thread()
+            && runner::is_small_input(&config.input_files));
+    // The single-threaded engine applies transactions on the task reading the input
+    let runtime = if single_thread {
+        runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+    } else {
+        Runtime::new()?
+    };
     runtime.block_on(async {
-        let args: Vec<String> = env::args().collect();
-        if args.len() < 2 {
-            eprintln!("Usage: {} <input_file>", args[0]);
-            std::process::exit(1);
+        let profile = config.profile.then(|| Arc::new(Profile::new()));
+        let rejects = config
+            .rejects_output
+            .as_ref()
+            .map(|_| Arc::new(RejectLog::new()));
+        let parse_options = ParseOptions {
+            thousands_sep: config.thousands_sep,
+            max_rows: config.max_rows,
+            max_bytes: config.max_bytes,
+            rate_limiter: config.rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+            mmap: config.mmap,
+            flexible: config.flexible,
+            allow_zero_amount: config.allow_zero_amount,
+            dispute_amounts: config.dispute_amounts,
+            comment: config.comment_char,
+            has_header: config.has_header,
+            hold_precision: config.hold_precision,
+            error_rate: config
+                .max_error_rate
+                .map(|max_rate| Arc::new(ErrorRateLimit::new(max_rate))),
+            input_format: config.input_format,
+            profile: profile.clone(),
+            rejects: rejects.clone(),
+        };
+        if config.validate_schema_only {
+            return validate_schema(&config, &parse_options).await;
         }
+        let client_filter = match &config.accounts_filter {
+            Some(path) => Some(io::read_client_ids(path).await?),
+            None => None,
+        };
+        let options = EngineOptions {
+            dead_letter_retries: config.dead_letter_retries,
+            fee_policy: config.fee_policy,
+            dispute_policy: config.dispute_policy,
+            lock_policy: config.lock_policy,
+            auto_freeze_above: config.auto_freeze_above,
+            global_unique_tx: config.global_unique_tx,
+            strict_ordering: config.strict_ordering,
+            lenient_resolve: config.lenient_resolve,
+            dispute_amounts: config.dispute_amounts,
+            no_auto_create_on_withdrawal: config.no_auto_create_on_withdrawal,
+            max_clients: config.max_clients,
+            client_filter,
+            hold_precision: config.hold_precision,
+            output_precision: config.output_precision,
+            check_aggregate: config.check_aggregate || cfg!(debug_assertions),
+            lanes_per_shard: config.lanes_per_shard,
+            profile: profile.clone(),
+            rejects: rejects.clone(),
+            ..EngineOptions::default()
+        };
 
-        let num_shards = 4;
-        let mut engine = ShardedEngine::new(num_shards);
-        let mut stream = io::stream_transactions(&args[1]).await?;
-
-        // Process each transaction by routing it to the appropriate shard
-        while let Some(record_result) = stream.next().await {
-            let transaction = record_result
-                .map_err(|err| EngineError::TransactionError(err.to_string()))
-                .and_then(|record| io::validate_and_parse_transaction(record));
-
-            match transaction {
-                Ok(trans) => {
-                    if let Err(err) = engine.route_transaction(trans) {
-                        error!("Failed to route transaction: {}", err);
-                    }
-                }
-                Err(err) => {
-                    error!("{}", err);
-                }
+        if single_thread {
+            log::info!("Using the single-threaded engine");
+            let mut engine = SingleThreadedEngine::with_options(Arc::new(NoopObserver), options);
+            cancel_on_ctrl_c(engine.cancellation_token());
+            if config.merge_by_tx_id {
+                runner::route_files_merged(&engine, &config.input_files, &parse_options).await?;
+            } else {
+                runner::route_files(&engine, &config.input_files, &parse_options).await?;
             }
+            engine.finish().await;
+            runner::check_error_rate(&engine, &parse_options)?;
+            return report(&engine, &config, profile.as_deref(), rejects.as_deref()).await;
+        }
+
+        let num_shards = match config.shards {
+            ShardCount::Fixed(count) => count,
+            ShardCount::Auto => runner::auto_shards(&config.input_files, &parse_options).await?,
+        };
+        let mut engine = ShardedEngine::with_options(num_shards, Arc::new(NoopObserver), options);
+        cancel_on_ctrl_c(engine.cancellation_token());
+        #[cfg(feature = "prometheus")]
+        if let Some(addr) = &config.metrics_addr {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            log::info!(
+                "Serving metrics on http://{}/metrics",
+                listener.local_addr()?
+            );
+            tokio::spawn(payments_engine::metrics::serve(engine.stats(), listener));
+        }
+
+        if config.concurrent_inputs {
+            runner::route_files_concurrently(&engine, &config.input_files, &parse_options).await?;
+        } else if config.merge_by_tx_id {
+            runner::route_files_merged(&engine, &config.input_files, &parse_options).await?;
+        } else {
+            runner::route_files(&engine, &config.input_files, &parse_options).await?;
         }
 
         engine.shutdown();
         engine.wait_for_completion().await;
-        engine.write_accounts().await?;
-        Ok(())
+        engine.check_workers()?;
+        engine.ordering_report().await;
+        runner::check_error_rate(&engine, &parse_options)?;
+        if config.report_shards {
+            eprintln!("shard,clients,transactions");
+            for (shard, clients, transactions) in engine.shard_distribution().await {
+                eprintln!("{},{},{}", shard, clients, transactions);
+            }
+        }
+        report(&engine, &config, profile.as_deref(), rejects.as_deref()).await
     })
 }
+
+/// Print the valid and invalid row counts of the input, exiting non-zero when a row is
+/// invalid. No engine is created.
+async fn validate_schema(config: &Config, parse_options: &ParseOptions) -> Result<(), EngineError> {
+    let report = runner::validate_schema(&config.input_files, parse_options).await?;
+    if let (Some(path), Some(rejects)) = (&config.rejects_output, &parse_options.rejects) {
+        rejects.write_to(Path::new(path)).await?;
+    }
+    println!(
+        "metric,value\nvalid_rows,{}\ninvalid_rows,{}",
+        report.valid, report.invalid
+    );
+    if report.invalid > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Ctrl-C stops reading input and lets the engine finish its current transaction
+fn cancel_on_ctrl_c(cancel: CancellationToken) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            log::warn!("Interrupted, writing partial account state");
+            cancel.cancel();
+        }
+    });
+}
+
+/// Print and write everything the configuration asks for once all transactions are applied
+async fn report<E: PaymentEngine>(
+    engine: &E,
+    config: &Config,
+    profile: Option<&Profile>,
+    rejects: Option<&RejectLog>,
+) -> Result<(), EngineError> {
+    // Balances failing the aggregate check are not trustworthy enough to be written
+    engine.check_aggregate().await?;
+    if let Some(tx_id) = config.explain {
+        let explanations = engine.explain(tx_id).await;
+        if explanations.is_empty() {
+            eprintln!("tx {}: not recorded", tx_id);
+        }
+        for explanation in explanations {
+            eprintln!("{}", explanation);
+        }
+    }
+    if let Some(path) = &config.stats_output {
+        engine.run_summary().await.write_to(Path::new(path)).await?;
+    }
+    if let (Some(path), Some(rejects)) = (&config.rejects_output, rejects) {
+        rejects.write_to(Path::new(path)).await?;
+    }
+    profile::time_async(
+        profile,
+        Phase::Write,
+        write_output(engine, config, stdout()),
+    )
+    .await?;
+    if let Some(path) = &config.checksum_output {
+        let checksum = engine.checksum().await;
+        match path.as_str() {
+            "-" => eprintln!("sha256 {}", checksum),
+            path => async_std::fs::write(path, format!("{}\n", checksum)).await?,
+        }
+    }
+    if let Some(profile) = profile {
+        eprintln!("{}", profile);
+    }
+    if config.check_balance_nonnegative {
+        let clients = engine.negative_balances().await;
+        if !clients.is_empty() {
+            return Err(EngineError::NegativeBalance(clients));
+        }
+    }
+    Ok(())
+}
+
+/// Write the final balances where the configuration asks for them, or only the run summary
+/// with `--summary-only`
+async fn write_output<E, W>(engine: &E, config: &Config, mut stdout: W) -> Result<(), EngineError>
+where
+    E: PaymentEngine,
+    W: AsyncWrite + Unpin,
+{
+    if config.summary_only {
+        let summary = engine.run_summary().await.to_stats_csv();
+        return match stdout.write_all(summary.as_bytes()).await {
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => Ok(()),
+            result => Ok(result?),
+        };
+    }
+    if let Some(dir) = &config.output_dir {
+        engine.write_accounts_to_dir(Path::new(dir)).await?;
+        return Ok(());
+    }
+    match &config.sqlite_path {
+        #[cfg(feature = "sqlite")]
+        Some(path) => payments_engine::sqlite::write_accounts(engine, path).await?,
+        _ => engine.write_accounts_to_pipe(stdout).await?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use payments_engine::models::Transaction;
+    use rust_decimal_macros::dec;
+
+    async fn engine_with_one_account() -> ShardedEngine {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_transaction(Transaction::deposit(1, 1, dec!(10.0)))
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+        engine
+    }
+
+    #[tokio::test]
+    async fn test_summary_only_writes_no_accounts() {
+        let engine = engine_with_one_account().await;
+        let config = Config::from_args(["input.csv".into(), "--summary-only".into()]).unwrap();
+
+        let mut output = Vec::new();
+        write_output(&engine, &config, &mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.starts_with("metric,value\ndeposits,1\n"));
+        assert!(!output.contains("client"));
+        assert!(output.contains("amount.deposits,10.0\n"));
+        assert!(!output.contains("1,10.0,0.0000,10.0,false"));
+    }
+
+    #[tokio::test]
+    async fn test_accounts_written_by_default() {
+        let engine = engine_with_one_account().await;
+        let config = Config::from_args(["input.csv".into()]).unwrap();
+
+        let mut output = Vec::new();
+        write_output(&engine, &config, &mut output).await.unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,10.0,0.0000,10.0,false\n"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use crate::engine::EngineStats;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::io;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Content type of the OpenMetrics text format written by `render`
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+type Labels = [(&'static str, String); 1];
+
+/// The counters of the engine in the Prometheus text format, read when called:
+///
+/// - `payments_transactions_total{type}`: applied transactions, by type
+/// - `payments_rejections_total{reason}`: rejected transactions, by error kind
+/// - `payments_locked_accounts_total`: locked accounts, which are never unlocked
+/// - `payments_accounts`: client accounts
+/// - `payments_in_flight_transactions`: transactions routed but not yet taken by a shard
+pub fn render(stats: &EngineStats) -> String {
+    let summary = stats.run_summary();
+    let mut registry = Registry::default();
+
+    let transactions = Family::<Labels, Counter>::default();
+    for (tx_type, count) in [
+        ("deposit", summary.deposits),
+        ("withdrawal", summary.withdrawals),
+        ("dispute", summary.disputes),
+        ("resolve", summary.resolves),
+        ("chargeback", summary.chargebacks),
+        ("fee", summary.fees),
+        ("adjustment", summary.adjustments),
+    ] {
+        transactions
+            .get_or_create(&[("type", tx_type.to_string())])
+            .inc_by(count as u64);
+    }
+    registry.register(
+        "payments_transactions",
+        "Transactions applied, by type",
+        transactions,
+    );
+
+    let rejections = Family::<Labels, Counter>::default();
+    for (reason, count) in &summary.rejections {
+        rejections
+            .get_or_create(&[("reason", reason.clone())])
+            .inc_by(*count as u64);
+    }
+    registry.register(
+        "payments_rejections",
+        "Transactions rejected, by error kind",
+        rejections,
+    );
+
+    let locked_accounts = Counter::<u64>::default();
+    locked_accounts.inc_by(summary.locked_accounts as u64);
+    registry.register(
+        "payments_locked_accounts",
+        "Client accounts locked by a chargeback or a freeze",
+        locked_accounts,
+    );
+
+    let accounts = Gauge::<i64>::default();
+    accounts.set(stats.account_count() as i64);
+    registry.register("payments_accounts", "Client accounts", accounts);
+
+    let in_flight = Gauge::<i64>::default();
+    in_flight.set(stats.in_flight() as i64);
+    registry.register(
+        "payments_in_flight_transactions",
+        "Transactions routed and not yet taken by a shard",
+        in_flight,
+    );
+
+    let mut text = String::new();
+    encode(&mut text, &registry).expect("writing to a String cannot fail");
+    text
+}
+
+/// Answer `GET /metrics` with `render` on every connection accepted by `listener`, any other
+/// request with a 404, until the task is dropped. Each connection gets one response.
+pub async fn serve(stats: EngineStats, listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &stats).await {
+                log::warn!("Failed to answer a metrics request: {}", err);
+            }
+        });
+    }
+}
+
+async fn respond(stream: TcpStream, stats: &EngineStats) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    // The headers are not needed, but are read so the client is not reset mid-request
+    let mut header = String::new();
+    while stream.read_line(&mut header).await? > 2 {
+        header.clear();
+    }
+
+    let response = if request_line.starts_with("GET /metrics ") {
+        let body = render(stats);
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            CONTENT_TYPE,
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    let stream = stream.get_mut();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{PaymentEngine, ShardedEngine};
+    use crate::models::Transaction;
+    use rust_decimal_macros::dec;
+    use tokio::io::AsyncReadExt;
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_scrape_after_run() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.0)),
+                Transaction::deposit(2, 2, dec!(5.0)),
+                Transaction::withdrawal(2, 3, dec!(50.0)),
+                Transaction::dispute(1, 1),
+                Transaction::chargeback(1, 1),
+                Transaction::deposit(1, 4, dec!(1.0)),
+            ])
+            .unwrap();
+        engine.finish().await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(engine.stats(), listener));
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        for line in [
+            "payments_transactions_total{type=\"deposit\"} 2",
+            "payments_transactions_total{type=\"withdrawal\"} 0",
+            "payments_transactions_total{type=\"dispute\"} 1",
+            "payments_transactions_total{type=\"chargeback\"} 1",
+            "payments_rejections_total{reason=\"InsufficientFunds\"} 1",
+            "payments_rejections_total{reason=\"AccountLocked\"} 1",
+            "payments_locked_accounts_total 1",
+            "payments_accounts 2",
+            "payments_in_flight_transactions 0",
+        ] {
+            assert!(
+                response.lines().any(|l| l == line),
+                "{}\n{}",
+                line,
+                response
+            );
+        }
+        assert!(response.ends_with("# EOF\n"));
+
+        assert!(get(addr, "/")
+            .await
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+        server.abort();
+    }
+}
@@ -0,0 +1,128 @@
+use crate::errors::EngineError;
+use crate::io::{self, ParseOptions, RecordStream};
+use futures::io::Cursor;
+use memmap2::Mmap;
+use std::fs::File;
+use std::time::SystemTime;
+
+/// Records read from a memory-mapped transaction file
+pub type MappedRecords = RecordStream<'static>;
+
+/// Size and modification time of a file when it was mapped, to detect concurrent writers
+#[derive(Debug)]
+pub struct MappedFile {
+    path: String,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl MappedFile {
+    /// Fail if the file changed since it was mapped, in which case the records read from
+    /// the mapping may mix old and new content
+    pub fn check_unchanged(&self) -> Result<(), EngineError> {
+        let metadata = std::fs::metadata(&self.path)?;
+        if metadata.len() != self.len || metadata.modified().ok() != self.modified {
+            return Err(EngineError::InvalidOperation(format!(
+                "{} was modified while it was being processed",
+                self.path
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Map a CSV file into memory and stream its transactions, for multi-GB files on fast
+/// storage where this outperforms buffered reads.
+///
+/// The mapping is only valid while nobody else writes the file: call `check_unchanged` on
+/// the returned guard once all records have been read. Truncating the file while it is
+/// mapped can still crash the process (SIGBUS), so only use this mode for files that are
+/// complete and no longer written to.
+pub async fn stream_transactions_mmap(
+    file_path: &str,
+    options: &ParseOptions,
+) -> Result<(MappedRecords, MappedFile), EngineError> {
+    let file = File::open(file_path).map_err(|err| EngineError::file_open(file_path, err))?;
+    let metadata = file.metadata()?;
+    let guard = MappedFile {
+        path: file_path.to_string(),
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+    };
+
+    // SAFETY: the file is opened read-only and changes made by other processes are detected
+    // by `check_unchanged`; see the function documentation for the remaining truncation risk
+    let map = unsafe { Mmap::map(&file)? };
+    let source = io::decompressed(Cursor::new(map)).await?;
+    let records = io::stream_records_with(source, options).await?;
+
+    Ok((records, guard))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::fs;
+    use std::io::Write;
+
+    fn create_temp_csv(file_path: &str, data: &str) -> String {
+        let mut file =
+            File::create(format!("res/{}", file_path)).expect("Unable to create test file");
+        file.write_all(data.as_bytes())
+            .expect("Unable to write to test file");
+        format!("res/{}", file_path)
+    }
+
+    #[tokio::test]
+    async fn test_mmap_matches_buffered_reader() {
+        let mut data = String::from("type, client, tx, amount\n");
+        for tx in 0..500 {
+            data.push_str(&format!("deposit, {}, {}, {}.25\n", tx % 7, tx, tx));
+            data.push_str(&format!("dispute, {}, {},\n", tx % 7, tx));
+        }
+        let file_path = create_temp_csv("test_mmap_matches_buffered.csv", &data);
+
+        let buffered: Vec<_> = io::stream_transactions(&file_path)
+            .await
+            .unwrap()
+            .map(|record| io::validate_and_parse_transaction(record.unwrap()).unwrap())
+            .collect()
+            .await;
+        let (records, guard) = stream_transactions_mmap(&file_path, &ParseOptions::default())
+            .await
+            .unwrap();
+        let mapped: Vec<_> = records
+            .map(|record| io::validate_and_parse_transaction(record.unwrap()).unwrap())
+            .collect()
+            .await;
+        let unchanged = guard.check_unchanged();
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+
+        assert_eq!(mapped.len(), 1000);
+        assert_eq!(mapped, buffered);
+        assert!(unchanged.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_mmap_detects_modified_file() {
+        let file_path = create_temp_csv(
+            "test_mmap_modified.csv",
+            "type,client,tx,amount\n\
+             deposit,1,1,1.0\n",
+        );
+
+        let (_records, guard) = stream_transactions_mmap(&file_path, &ParseOptions::default())
+            .await
+            .unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&file_path)
+            .and_then(|mut file| file.write_all(b"deposit,1,2,1.0\n"))
+            .expect("Unable to append to test file");
+        let result = guard.check_unchanged();
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+
+        assert!(matches!(result, Err(EngineError::InvalidOperation(_))));
+    }
+}
@@ -1,12 +1,72 @@
+use crate::amount::Amount;
 use crate::errors::EngineError;
 use rust_decimal::Decimal;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 
 pub const MAX_DISPLAY_PRECISION: u32 = 4;
 
+/// Largest hold precision: the most decimal places a `Decimal` can carry
+pub const MAX_HOLD_PRECISION: u32 = 28;
+
+/// Most integer digits of an output amount. With at most `MAX_DISPLAY_PRECISION` places, an
+/// amount is written in at most 21 characters (`-999999999999999.9999`).
+pub const MAX_OUTPUT_DIGITS: u32 = 15;
+
+/// Amount as written to the output, truncated to `MAX_DISPLAY_PRECISION` places. Balances
+/// may be held at a higher precision (see `--hold-precision`), they are cut only here.
+pub fn output_amount(amount: Decimal) -> Decimal {
+    output_amount_at(amount, MAX_DISPLAY_PRECISION)
+}
+
+/// Same as `output_amount`, truncated to `places` decimal places
+pub fn output_amount_at(amount: Decimal, places: u32) -> Decimal {
+    if amount.scale() > places {
+        amount.trunc_with_scale(places)
+    } else {
+        amount
+    }
+}
+
+/// Fail with `ImplausibleAmount` when a balance of `client` has more than
+/// `MAX_OUTPUT_DIGITS` integer digits, a quadrillion or more either way. `Decimal` could
+/// carry 29 digits, but no account holds that much: such a balance comes from a bug, and is
+/// better reported than written.
+pub fn check_output_amount(client: ClientId, amount: Decimal) -> Result<(), EngineError> {
+    let limit = Decimal::from(10u64.pow(MAX_OUTPUT_DIGITS));
+    if amount.abs() >= limit {
+        return Err(EngineError::ImplausibleAmount { client, amount });
+    }
+    Ok(())
+}
+
+/// Format an output amount. Every zero, whatever its scale or sign after arithmetic,
+/// prints like the balance of a new account (`0.0000`).
+pub fn display_amount(amount: Decimal) -> String {
+    display_amount_at(amount, MAX_DISPLAY_PRECISION)
+}
+
+/// Same as `display_amount`, truncated to `places` decimal places, zero printing with
+/// `places` zeros (`0.00` for 2)
+pub fn display_amount_at(amount: Decimal, places: u32) -> String {
+    let amount = output_amount_at(amount, places);
+    if amount.is_zero() {
+        Decimal::new(0, places).to_string()
+    } else {
+        amount.to_string()
+    }
+}
+
+/// Identifier of a client account, widened to `u32` by the `wide-client-id` feature
+#[cfg(not(feature = "wide-client-id"))]
+pub type ClientId = u16;
+#[cfg(feature = "wide-client-id")]
+pub type ClientId = u32;
+
 /// Enum representing the types of transactions
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Deserialize)]
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -14,6 +74,43 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    Fee,
+    /// Signed correction such as an interest posting, credits or debits the account
+    Adjustment,
+}
+
+impl TransactionType {
+    /// Whether the transaction moves funds and is kept in the transaction log under its id
+    pub fn is_recorded(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Fee
+                | TransactionType::Adjustment
+        )
+    }
+
+    /// Whether a recorded transaction of this type can be disputed
+    pub fn is_disputable(&self) -> bool {
+        matches!(self, TransactionType::Deposit | TransactionType::Withdrawal)
+    }
+}
+
+/// Lowercase name, as in input files
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Fee => "fee",
+            TransactionType::Adjustment => "adjustment",
+        };
+        f.write_str(name)
+    }
 }
 
 impl FromStr for TransactionType {
@@ -26,6 +123,8 @@ impl FromStr for TransactionType {
             "dispute" => Ok(TransactionType::Dispute),
             "resolve" => Ok(TransactionType::Resolve),
             "chargeback" => Ok(TransactionType::Chargeback),
+            "fee" => Ok(TransactionType::Fee),
+            "adjustment" => Ok(TransactionType::Adjustment),
             _ => Err(EngineError::TransactionError(
                 "Invalid transaction type".into(),
             )),
@@ -34,16 +133,41 @@ impl FromStr for TransactionType {
 }
 
 /// Struct representing a single transaction
-#[derive(Debug, Copy, Clone, Hash, Deserialize)]
+#[derive(Debug, Copy, Clone, Deserialize)]
 pub struct Transaction {
     #[serde(rename = "type")]
     pub tx_type: TransactionType,
-    pub client: u16,
+    pub client: ClientId,
     #[serde(rename = "tx")]
     pub tx_id: u32,
+    #[serde(default, deserialize_with = "deserialize_optional_amount")]
     pub amount: Option<Decimal>,
     #[serde(skip_deserializing)]
     pub under_dispute: bool,
+    /// Time of the transaction as supplied by the input, in units chosen by the producer
+    /// (e.g. Unix milliseconds). `None` when the input has no `timestamp` column.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Position of the transaction among those of its client, numbered by the producer
+    /// without gaps, so a missing number points to a dropped transaction. `None` when the
+    /// input has no `seq` column.
+    #[serde(default)]
+    pub seq: Option<u64>,
+}
+
+/// Deserialize an amount cell, treating an empty or whitespace-only cell as `None` so
+/// dispute, resolve and chargeback rows without an amount stay parseable
+pub fn deserialize_optional_amount<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(amount) => Decimal::from_str(amount)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
 }
 
 impl PartialEq for Transaction {
@@ -57,89 +181,375 @@ impl PartialEq for Transaction {
 
 impl Eq for Transaction {}
 
-/// Struct representing a client's account
-#[derive(Debug)]
-pub struct ClientAccount {
-    pub available: Decimal,
-    pub held: Decimal,
-    pub total: Decimal,
+// Hashes the same fields `eq` compares: a replayed row is a duplicate whatever its timestamp
+// or sequence number
+impl Hash for Transaction {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.tx_type.hash(state);
+        self.client.hash(state);
+        self.tx_id.hash(state);
+        self.amount.hash(state);
+    }
+}
+
+impl Transaction {
+    /// Deposit of `amount` into `client`'s account
+    pub fn deposit(client: ClientId, tx_id: u32, amount: Decimal) -> Self {
+        Self::with_amount(TransactionType::Deposit, client, tx_id, Some(amount))
+    }
+
+    /// Withdrawal of `amount` from `client`'s account
+    pub fn withdrawal(client: ClientId, tx_id: u32, amount: Decimal) -> Self {
+        Self::with_amount(TransactionType::Withdrawal, client, tx_id, Some(amount))
+    }
+
+    /// Dispute of the transaction `tx_id` of `client`
+    pub fn dispute(client: ClientId, tx_id: u32) -> Self {
+        Self::with_amount(TransactionType::Dispute, client, tx_id, None)
+    }
+
+    /// Resolve of the disputed transaction `tx_id` of `client`
+    pub fn resolve(client: ClientId, tx_id: u32) -> Self {
+        Self::with_amount(TransactionType::Resolve, client, tx_id, None)
+    }
+
+    /// Chargeback of the disputed transaction `tx_id` of `client`
+    pub fn chargeback(client: ClientId, tx_id: u32) -> Self {
+        Self::with_amount(TransactionType::Chargeback, client, tx_id, None)
+    }
+
+    /// The same transaction, stamped with `timestamp`
+    pub fn at(self, timestamp: u64) -> Self {
+        Self {
+            timestamp: Some(timestamp),
+            ..self
+        }
+    }
+
+    /// The same transaction, numbered `seq` among those of its client
+    pub fn with_seq(self, seq: u64) -> Self {
+        Self {
+            seq: Some(seq),
+            ..self
+        }
+    }
+
+    /// Fee of `amount` charged to `client`'s account
+    pub fn fee(client: ClientId, tx_id: u32, amount: Decimal) -> Self {
+        Self::with_amount(TransactionType::Fee, client, tx_id, Some(amount))
+    }
+
+    /// Adjustment of `client`'s account, crediting a positive `amount` and debiting a negative one
+    pub fn adjustment(client: ClientId, tx_id: u32, amount: Decimal) -> Self {
+        Self::with_amount(TransactionType::Adjustment, client, tx_id, Some(amount))
+    }
+
+    fn with_amount(
+        tx_type: TransactionType,
+        client: ClientId,
+        tx_id: u32,
+        amount: Option<Decimal>,
+    ) -> Self {
+        Self {
+            tx_type,
+            client,
+            tx_id,
+            amount,
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        }
+    }
+}
+
+/// Balance operation applied to an account, independent of the transaction it came from.
+///
+/// The engine lowers every transaction into one of these, so a new transaction type only
+/// needs to decide which operation it maps to.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AccountOp<A = Decimal> {
+    /// Add to available and total funds
+    Credit(A),
+    /// Remove from available and total funds, if enough are available
+    Debit(A),
+    /// Move funds from available to held
+    Hold(A),
+    /// Move funds from held back to available
+    Release(A),
+    /// Remove held funds from the account and lock it
+    Reverse(A),
+    /// Remove from available and total funds, even if the balance goes negative
+    Charge(A),
+}
+
+impl<A: Copy> AccountOp<A> {
+    pub fn amount(&self) -> A {
+        match *self {
+            AccountOp::Credit(amount)
+            | AccountOp::Debit(amount)
+            | AccountOp::Hold(amount)
+            | AccountOp::Release(amount)
+            | AccountOp::Reverse(amount)
+            | AccountOp::Charge(amount) => amount,
+        }
+    }
+}
+
+/// What to do with a fee larger than the available funds
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum FeePolicy {
+    /// Reject the fee like a withdrawal with insufficient funds
+    #[default]
+    Reject,
+    /// Charge the fee anyway, leaving a negative balance
+    AllowNegative,
+}
+
+impl FromStr for FeePolicy {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(FeePolicy::Reject),
+            "allow-negative" => Ok(FeePolicy::AllowNegative),
+            _ => Err(EngineError::ConfigError(format!(
+                "Invalid fee policy: {} (expected reject or allow-negative)",
+                s
+            ))),
+        }
+    }
+}
+
+/// Which recorded transactions a dispute may target
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum DisputePolicy {
+    /// Only deposits can be disputed, a dispute of a withdrawal is rejected
+    DepositsOnly,
+    /// Deposits and withdrawals can both be disputed
+    #[default]
+    DepositsAndWithdrawals,
+}
+
+impl DisputePolicy {
+    /// Whether a dispute of a recorded `tx_type` transaction is allowed
+    pub fn allows(&self, tx_type: TransactionType) -> bool {
+        match self {
+            DisputePolicy::DepositsOnly => tx_type == TransactionType::Deposit,
+            DisputePolicy::DepositsAndWithdrawals => tx_type.is_disputable(),
+        }
+    }
+}
+
+impl FromStr for DisputePolicy {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposits-only" => Ok(DisputePolicy::DepositsOnly),
+            "deposits-and-withdrawals" => Ok(DisputePolicy::DepositsAndWithdrawals),
+            _ => Err(EngineError::ConfigError(format!(
+                "Invalid dispute policy: {} (expected deposits-only or deposits-and-withdrawals)",
+                s
+            ))),
+        }
+    }
+}
+
+/// Which operations a locked account still accepts. Reading an account is always possible.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum LockPolicy {
+    /// Reject every operation, the account stays as the chargeback left it
+    #[default]
+    FreezeAll,
+    /// Keep settling the transactions recorded before the lock: disputes, resolves and
+    /// chargebacks are applied, deposits, withdrawals, fees and adjustments are rejected
+    SettleDisputes,
+}
+
+impl LockPolicy {
+    /// Whether `op` is applied to a locked account
+    pub fn allows<A>(&self, op: &AccountOp<A>) -> bool {
+        match self {
+            LockPolicy::FreezeAll => false,
+            LockPolicy::SettleDisputes => matches!(
+                op,
+                AccountOp::Hold(_) | AccountOp::Release(_) | AccountOp::Reverse(_)
+            ),
+        }
+    }
+}
+
+impl FromStr for LockPolicy {
+    type Err = EngineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "freeze-all" => Ok(LockPolicy::FreezeAll),
+            "settle-disputes" => Ok(LockPolicy::SettleDisputes),
+            _ => Err(EngineError::ConfigError(format!(
+                "Invalid lock policy: {} (expected freeze-all or settle-disputes)",
+                s
+            ))),
+        }
+    }
+}
+
+/// `balance + amount`, failing with `AmountOverflow` when the sum does not fit `A`
+fn add<A: Amount>(balance: A, amount: A) -> Result<A, EngineError> {
+    balance.checked_add(amount).ok_or_else(|| overflow(amount))
+}
+
+/// `balance - amount`, failing with `AmountOverflow` when the difference does not fit `A`
+fn sub<A: Amount>(balance: A, amount: A) -> Result<A, EngineError> {
+    balance.checked_sub(amount).ok_or_else(|| overflow(amount))
+}
+
+fn overflow<A: Amount>(amount: A) -> EngineError {
+    EngineError::AmountOverflow {
+        client: ClientId::default(),
+        requested: amount.to_decimal(),
+    }
+}
+
+/// Struct representing a client's account, with balances kept as `Decimal` unless another
+/// `Amount` type is chosen
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientAccount<A = Decimal> {
+    pub available: A,
+    pub held: A,
+    pub total: A,
     pub locked: bool,
 }
 
-impl ClientAccount {
+impl<A: Amount> Default for ClientAccount<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Amount> ClientAccount<A> {
     pub fn new() -> Self {
         Self {
-            available: Decimal::new(0, MAX_DISPLAY_PRECISION),
-            held: Decimal::new(0, MAX_DISPLAY_PRECISION),
-            total: Decimal::new(0, MAX_DISPLAY_PRECISION),
+            available: A::zero(),
+            held: A::zero(),
+            total: A::zero(),
             locked: false,
         }
     }
 
+    /// Apply a balance operation lowered from a transaction
+    pub fn apply(&mut self, op: AccountOp<A>) -> Result<(), EngineError> {
+        match op {
+            AccountOp::Credit(amount) => self.deposit(amount),
+            AccountOp::Debit(amount) => self.withdraw(amount),
+            AccountOp::Hold(amount) => self.dispute(amount),
+            AccountOp::Release(amount) => self.resolve(amount),
+            AccountOp::Reverse(amount) => self.chargeback(amount),
+            AccountOp::Charge(amount) => self.charge(amount),
+        }
+    }
+
+    /// Same as `apply`, but a locked account also takes the operations `lock_policy` allows.
+    /// The account stays locked.
+    pub fn apply_with(
+        &mut self,
+        op: AccountOp<A>,
+        lock_policy: LockPolicy,
+    ) -> Result<(), EngineError> {
+        if !(self.locked && lock_policy.allows(&op)) {
+            return self.apply(op);
+        }
+        self.locked = false;
+        let result = self.apply(op);
+        self.locked = true;
+        result
+    }
+
     /// Handle a deposit by adding to available funds and total
-    pub fn deposit(&mut self, amount: Decimal) -> Result<(), EngineError> {
+    pub fn deposit(&mut self, amount: A) -> Result<(), EngineError> {
         if !self.locked {
-            self.available += amount;
-            self.total += amount;
+            (self.available, self.total) = (add(self.available, amount)?, add(self.total, amount)?);
             Ok(())
         } else {
-            Err(EngineError::InvalidOperation(
-                "Attempted to process invalid deposit".into(),
+            Err(EngineError::AccountLocked(
+                "Attempted to process deposit on a locked account".into(),
             ))
         }
     }
 
     /// Handle a withdrawal by subtracting from available funds
-    /// Returns an error if funds are insufficient
-    pub fn withdraw(&mut self, amount: Decimal) -> Result<(), EngineError> {
-        if !self.locked && self.available >= amount {
-            self.available -= amount;
-            self.total -= amount;
+    /// Returns `AccountLocked` if the account is locked, `InsufficientFunds` if funds are insufficient
+    pub fn withdraw(&mut self, amount: A) -> Result<(), EngineError> {
+        if self.locked {
+            Err(EngineError::AccountLocked(
+                "Attempted to process withdraw on a locked account".into(),
+            ))
+        } else if self.available >= amount {
+            (self.available, self.total) = (sub(self.available, amount)?, sub(self.total, amount)?);
             Ok(())
         } else {
-            Err(EngineError::InvalidOperation(
-                "Attempted to process invalid withdraw".into(),
+            Err(EngineError::InsufficientFunds {
+                client: ClientId::default(),
+                available: self.available.to_decimal(),
+                requested: amount.to_decimal(),
+            })
+        }
+    }
+
+    /// Handle a fee by subtracting from available funds, allowing a negative balance
+    pub fn charge(&mut self, amount: A) -> Result<(), EngineError> {
+        if !self.locked {
+            (self.available, self.total) = (sub(self.available, amount)?, sub(self.total, amount)?);
+            Ok(())
+        } else {
+            Err(EngineError::AccountLocked(
+                "Attempted to process fee on a locked account".into(),
             ))
         }
     }
 
     /// Handle a dispute by moving funds from available to held
-    pub fn dispute(&mut self, amount: Decimal) -> Result<(), EngineError> {
+    pub fn dispute(&mut self, amount: A) -> Result<(), EngineError> {
         if !self.locked {
-            self.available -= amount;
-            self.held += amount;
+            (self.available, self.held) = (sub(self.available, amount)?, add(self.held, amount)?);
             Ok(())
         } else {
-            Err(EngineError::InvalidOperation(
-                "Attempted to process invalid dispute".into(),
+            Err(EngineError::AccountLocked(
+                "Attempted to process dispute on a locked account".into(),
             ))
         }
     }
 
     /// Resolve a dispute by moving funds from held back to available
-    pub fn resolve(&mut self, amount: Decimal) -> Result<(), EngineError> {
+    pub fn resolve(&mut self, amount: A) -> Result<(), EngineError> {
         if !self.locked {
-            self.held -= amount;
-            self.available += amount;
+            (self.held, self.available) = (sub(self.held, amount)?, add(self.available, amount)?);
             Ok(())
         } else {
-            Err(EngineError::InvalidOperation(
-                "Attempted to process invalid resolve".into(),
+            Err(EngineError::AccountLocked(
+                "Attempted to process resolve on a locked account".into(),
             ))
         }
     }
 
-    /// Handle a chargeback by removing funds from held and total, and locking the account
-    pub fn chargeback(&mut self, amount: Decimal) -> Result<(), EngineError> {
-        if !self.locked {
-            self.held -= amount;
-            self.total -= amount;
+    /// Handle a chargeback by removing funds from held and total, and locking the account.
+    /// Charging back more than is held (e.g. after a restore from an inconsistent snapshot)
+    /// is rejected rather than driving held funds negative.
+    pub fn chargeback(&mut self, amount: A) -> Result<(), EngineError> {
+        if self.locked {
+            Err(EngineError::AccountLocked(
+                "Attempted to process chargeback on a locked account".into(),
+            ))
+        } else if self.held >= amount {
+            (self.held, self.total) = (sub(self.held, amount)?, sub(self.total, amount)?);
             self.locked = true;
             Ok(())
         } else {
-            Err(EngineError::InvalidOperation(
-                "Attempted to process invalid chargeback.".into(),
-            ))
+            Err(EngineError::InsufficientHeldFunds {
+                client: ClientId::default(),
+                held: self.held.to_decimal(),
+                requested: amount.to_decimal(),
+            })
         }
     }
 }
@@ -149,6 +559,71 @@ mod tests {
     use super::*;
     use rust_decimal_macros::dec;
 
+    #[test]
+    fn test_transaction_constructors() {
+        let literal = |tx_type, amount| Transaction {
+            tx_type,
+            client: 3,
+            tx_id: 42,
+            amount,
+            under_dispute: false,
+            timestamp: None,
+            seq: None,
+        };
+
+        let constructed = [
+            (
+                Transaction::deposit(3, 42, dec!(1.5)),
+                literal(TransactionType::Deposit, Some(dec!(1.5))),
+            ),
+            (
+                Transaction::withdrawal(3, 42, dec!(1.5)),
+                literal(TransactionType::Withdrawal, Some(dec!(1.5))),
+            ),
+            (
+                Transaction::dispute(3, 42),
+                literal(TransactionType::Dispute, None),
+            ),
+            (
+                Transaction::resolve(3, 42),
+                literal(TransactionType::Resolve, None),
+            ),
+            (
+                Transaction::chargeback(3, 42),
+                literal(TransactionType::Chargeback, None),
+            ),
+        ];
+        for (transaction, expected) in constructed {
+            assert_eq!(transaction, expected);
+            assert!(!transaction.under_dispute);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deserialize_optional_amount() {
+        use csv_async::{AsyncReaderBuilder, Trim};
+        use futures::StreamExt;
+
+        let data = "type,client,tx,amount\n\
+                    dispute,1,1,\n\
+                    resolve,1,1,   \n\
+                    deposit,1,2,12.5\n\
+                    withdrawal,1,3,abc\n";
+        let mut deserializer = AsyncReaderBuilder::new()
+            .trim(Trim::None)
+            .create_deserializer(data.as_bytes());
+        let rows: Vec<Result<Transaction, _>> = deserializer.deserialize().collect().await;
+
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0].as_ref().unwrap(), &Transaction::dispute(1, 1));
+        assert_eq!(rows[1].as_ref().unwrap(), &Transaction::resolve(1, 1));
+        assert_eq!(
+            rows[2].as_ref().unwrap(),
+            &Transaction::deposit(1, 2, dec!(12.5))
+        );
+        assert!(rows[3].is_err());
+    }
+
     #[test]
     fn test_deposit() {
         let mut account = ClientAccount::new();
@@ -157,7 +632,7 @@ mod tests {
         assert_eq!(account.available, dec!(1000.0));
         assert_eq!(account.total, dec!(1000.0));
         assert_eq!(account.held, dec!(0.0));
-        assert_eq!(account.locked, false);
+        assert!(!account.locked);
     }
 
     #[test]
@@ -178,12 +653,37 @@ mod tests {
         let _ = account.deposit(dec!(500.0));
         let result = account.withdraw(dec!(1000.0));
 
-        assert!(result.is_err());
+        match result {
+            Err(EngineError::InsufficientFunds {
+                available,
+                requested,
+                ..
+            }) => {
+                assert_eq!(available, dec!(500.0));
+                assert_eq!(requested, dec!(1000.0));
+            }
+            other => panic!("Expected InsufficientFunds, got {:?}", other),
+        }
         assert_eq!(account.available, dec!(500.0));
         assert_eq!(account.total, dec!(500.0));
         assert_eq!(account.held, dec!(0.0));
     }
 
+    #[test]
+    fn test_withdraw_on_locked_account() {
+        let mut account = ClientAccount::new();
+        account.deposit(dec!(500.0)).unwrap();
+        account.dispute(dec!(100.0)).unwrap();
+        account.chargeback(dec!(100.0)).unwrap();
+
+        // Enough funds, but the lock takes precedence
+        let result = account.withdraw(dec!(10.0));
+
+        assert!(matches!(result, Err(EngineError::AccountLocked(_))));
+        assert_eq!(account.available, dec!(400.0));
+        assert_eq!(account.total, dec!(400.0));
+    }
+
     #[test]
     fn test_dispute() {
         let mut account = ClientAccount::new();
@@ -218,7 +718,21 @@ mod tests {
         assert_eq!(account.available, dec!(500.0));
         assert_eq!(account.held, dec!(0.0));
         assert_eq!(account.total, dec!(500.0));
-        assert_eq!(account.locked, true);
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_dispute_on_locked_account() {
+        let mut account = ClientAccount::new();
+        let _ = account.deposit(dec!(1000.0));
+        let _ = account.dispute(dec!(500.0));
+        account.chargeback(dec!(500.0)).expect("Chargeback failed");
+        let result = account.dispute(dec!(200.0));
+
+        assert!(matches!(result, Err(EngineError::AccountLocked(_))));
+        assert_eq!(account.available, dec!(500.0));
+        assert_eq!(account.held, dec!(0.0));
+        assert_eq!(account.total, dec!(500.0));
     }
 
     #[test]
@@ -226,7 +740,7 @@ mod tests {
         let mut account = ClientAccount::new();
         let _ = account.deposit(dec!(1000.0));
         let _ = account.dispute(dec!(500.0));
-        let _ = account
+        account
             .chargeback(dec!(500.0))
             .expect("First chargeback failed");
         let result = account.chargeback(dec!(500.0));
@@ -235,6 +749,140 @@ mod tests {
         assert_eq!(account.available, dec!(500.0));
         assert_eq!(account.held, dec!(0.0));
         assert_eq!(account.total, dec!(500.0));
-        assert_eq!(account.locked, true);
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_display_amount_normalizes_zero() {
+        let mut negative_zero = dec!(0.0);
+        negative_zero.set_sign_negative(true);
+
+        assert_eq!(display_amount(dec!(0)), "0.0000");
+        assert_eq!(display_amount(dec!(0.00)), "0.0000");
+        assert_eq!(display_amount(negative_zero), "0.0000");
+        assert_eq!(display_amount(dec!(10.5)), "10.5");
+        assert_eq!(display_amount(dec!(-2.25)), "-2.25");
+    }
+
+    #[test]
+    fn test_display_amount_truncates_to_display_precision() {
+        assert_eq!(display_amount(dec!(0.10000000)), "0.1000");
+        assert_eq!(display_amount(dec!(1.23456789)), "1.2345");
+        assert_eq!(display_amount(dec!(-1.23456789)), "-1.2345");
+        assert_eq!(display_amount(dec!(0.00009)), "0.0000");
+    }
+
+    #[test]
+    fn test_apply_each_op() {
+        let mut account = ClientAccount::new();
+
+        account.apply(AccountOp::Credit(dec!(100.0))).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (dec!(100.0), dec!(0.0), dec!(100.0))
+        );
+
+        account.apply(AccountOp::Debit(dec!(30.0))).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (dec!(70.0), dec!(0.0), dec!(70.0))
+        );
+
+        account.apply(AccountOp::Hold(dec!(20.0))).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (dec!(50.0), dec!(20.0), dec!(70.0))
+        );
+
+        account.apply(AccountOp::Release(dec!(5.0))).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (dec!(55.0), dec!(15.0), dec!(70.0))
+        );
+
+        account.apply(AccountOp::Reverse(dec!(15.0))).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (dec!(55.0), dec!(0.0), dec!(55.0))
+        );
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_apply_debit_insufficient_funds() {
+        let mut account = ClientAccount::new();
+        account.apply(AccountOp::Credit(dec!(10.0))).unwrap();
+
+        let result = account.apply(AccountOp::Debit(dec!(10.5)));
+
+        assert!(matches!(result, Err(EngineError::InsufficientFunds { .. })));
+        assert_eq!(account.available, dec!(10.0));
+        assert_eq!(account.total, dec!(10.0));
+    }
+
+    #[test]
+    fn test_chargeback_exceeding_held_funds() {
+        let mut account = ClientAccount::new();
+        account.apply(AccountOp::Credit(dec!(10.0))).unwrap();
+        account.apply(AccountOp::Hold(dec!(4.0))).unwrap();
+
+        let result = account.apply(AccountOp::Reverse(dec!(10.0)));
+
+        assert!(matches!(
+            result,
+            Err(EngineError::InsufficientHeldFunds { .. })
+        ));
+        assert_eq!(account.held, dec!(4.0));
+        assert_eq!(account.total, dec!(10.0));
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn test_apply_with_lock_policy() {
+        let locked = || {
+            let mut account = ClientAccount::new();
+            account.apply(AccountOp::Credit(dec!(10.0))).unwrap();
+            account.apply(AccountOp::Hold(dec!(6.0))).unwrap();
+            account.apply(AccountOp::Reverse(dec!(2.0))).unwrap();
+            account
+        };
+        let ops = [
+            (AccountOp::Credit(dec!(1.0)), false),
+            (AccountOp::Debit(dec!(1.0)), false),
+            (AccountOp::Charge(dec!(1.0)), false),
+            (AccountOp::Hold(dec!(1.0)), true),
+            (AccountOp::Release(dec!(1.0)), true),
+            (AccountOp::Reverse(dec!(1.0)), true),
+        ];
+
+        for (op, settles) in ops {
+            let mut account = locked();
+            let result = account.apply_with(op, LockPolicy::FreezeAll);
+            assert!(
+                matches!(result, Err(EngineError::AccountLocked(_))),
+                "{:?}",
+                op
+            );
+            assert_eq!(account, locked());
+
+            let mut account = locked();
+            let result = account.apply_with(op, LockPolicy::SettleDisputes);
+            assert_eq!(result.is_ok(), settles, "{:?}", op);
+            assert_eq!(account != locked(), settles, "{:?}", op);
+            assert!(account.locked);
+        }
+    }
+
+    #[test]
+    fn test_apply_on_locked_account() {
+        let mut account = ClientAccount::new();
+        account.apply(AccountOp::Credit(dec!(10.0))).unwrap();
+        account.apply(AccountOp::Hold(dec!(10.0))).unwrap();
+        account.apply(AccountOp::Reverse(dec!(10.0))).unwrap();
+
+        let result = account.apply(AccountOp::Credit(dec!(1.0)));
+
+        assert!(matches!(result, Err(EngineError::AccountLocked(_))));
+        assert_eq!(account.total, dec!(0.0));
     }
 }
@@ -0,0 +1,54 @@
+use crate::models::{ClientAccount, ClientId, Transaction, TransactionType};
+
+/// Hooks invoked by a shard after a transaction has been successfully applied to an account.
+///
+/// Callbacks run on the shard worker (or one of its lanes, see
+/// `EngineOptions::lanes_per_shard`) once the account lock is released. They should still be
+/// cheap (increment a metric, push to a channel) and must not block: a blocking callback holds
+/// up every other client of its shard or lane.
+pub trait TransactionObserver: Send + Sync {
+    fn on_deposit(&self, _transaction: &Transaction, _account: &ClientAccount) {}
+    fn on_withdrawal(&self, _transaction: &Transaction, _account: &ClientAccount) {}
+    fn on_dispute(&self, _transaction: &Transaction, _account: &ClientAccount) {}
+    fn on_resolve(&self, _transaction: &Transaction, _account: &ClientAccount) {}
+    fn on_chargeback(&self, _transaction: &Transaction, _account: &ClientAccount) {}
+    fn on_fee(&self, _transaction: &Transaction, _account: &ClientAccount) {}
+    fn on_adjustment(&self, _transaction: &Transaction, _account: &ClientAccount) {}
+    /// The deposit `transaction` took the available funds past
+    /// `EngineOptions::auto_freeze_above` and the account was locked for review. Called after
+    /// `on_deposit`.
+    fn on_auto_freeze(&self, _transaction: &Transaction, _account: &ClientAccount) {}
+}
+
+/// Broadcast by the engine after each successfully applied transaction, see
+/// `ShardedEngine::subscribe`
+#[derive(Debug, Clone)]
+pub struct TransactionEvent {
+    pub client: ClientId,
+    pub tx_id: u32,
+    pub tx_type: TransactionType,
+    /// State of the account once the transaction was applied
+    pub account: ClientAccount,
+}
+
+/// Default observer doing nothing
+pub struct NoopObserver;
+
+impl TransactionObserver for NoopObserver {}
+
+/// Dispatch a processed transaction to the callback matching its type
+pub fn notify(
+    observer: &dyn TransactionObserver,
+    transaction: &Transaction,
+    account: &ClientAccount,
+) {
+    match transaction.tx_type {
+        TransactionType::Deposit => observer.on_deposit(transaction, account),
+        TransactionType::Withdrawal => observer.on_withdrawal(transaction, account),
+        TransactionType::Dispute => observer.on_dispute(transaction, account),
+        TransactionType::Resolve => observer.on_resolve(transaction, account),
+        TransactionType::Chargeback => observer.on_chargeback(transaction, account),
+        TransactionType::Fee => observer.on_fee(transaction, account),
+        TransactionType::Adjustment => observer.on_adjustment(transaction, account),
+    }
+}
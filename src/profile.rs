@@ -0,0 +1,168 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Stage of a run timed by a `Profile`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Phase {
+    /// Reading and parsing input rows, including waiting for the file
+    Parse,
+    /// Handing parsed transactions to the engine
+    Route,
+    /// Applying transactions in the shards
+    Process,
+    /// Writing the accounts output
+    Write,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 4] = [Phase::Parse, Phase::Route, Phase::Process, Phase::Write];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Phase::Parse => "parse",
+            Phase::Route => "route",
+            Phase::Process => "process",
+            Phase::Write => "write",
+        }
+    }
+}
+
+/// Time spent in each `Phase` of a run, shared by the readers, the shards and the writer.
+///
+/// The phases overlap: shards apply transactions while the input is still being parsed and
+/// routed, so the phase times do not add up to the wall time of the run. Each one is the sum
+/// of the time spent in that phase by every task doing it. Processing is summed over all
+/// shards (and lanes), so it may exceed the wall time. Parsing and routing happen on the
+/// reading task, or tasks with `--concurrent-inputs`. The single-threaded engine applies
+/// each transaction while routing it, so there routing includes processing.
+#[derive(Debug)]
+pub struct Profile {
+    started: Instant,
+    nanos: [AtomicU64; 4],
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            nanos: Default::default(),
+        }
+    }
+
+    pub fn record(&self, phase: Phase, elapsed: Duration) {
+        self.nanos[phase as usize].fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Time spent in `phase` so far
+    pub fn get(&self, phase: Phase) -> Duration {
+        Duration::from_nanos(self.nanos[phase as usize].load(Ordering::Relaxed))
+    }
+
+    /// Wall time since the profile was created
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `phase,seconds` CSV, one row per phase and a last `total` row with the wall time
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "phase,seconds")?;
+        for phase in Phase::ALL {
+            writeln!(f, "{},{:.6}", phase.name(), self.get(phase).as_secs_f64())?;
+        }
+        write!(f, "total,{:.6}", self.elapsed().as_secs_f64())
+    }
+}
+
+/// Run `f`, adding its duration to `phase` when profiling
+pub fn time<T>(profile: Option<&Profile>, phase: Phase, f: impl FnOnce() -> T) -> T {
+    let Some(profile) = profile else {
+        return f();
+    };
+    let started = Instant::now();
+    let result = f();
+    profile.record(phase, started.elapsed());
+    result
+}
+
+/// Await `future`, adding the time until it completes to `phase` when profiling
+pub async fn time_async<F: Future>(
+    profile: Option<&Profile>,
+    phase: Phase,
+    future: F,
+) -> F::Output {
+    let Some(profile) = profile else {
+        return future.await;
+    };
+    let started = Instant::now();
+    let result = future.await;
+    profile.record(phase, started.elapsed());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{EngineOptions, PaymentEngine, ShardedEngine};
+    use crate::io::ParseOptions;
+    use crate::observer::NoopObserver;
+    use crate::runner;
+    use std::fs;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_profile_populated_after_run() {
+        let file_path = "res/test_profile_populated.csv";
+        let mut csv = String::from("type,client,tx,amount\n");
+        for tx_id in 1..=2000 {
+            csv.push_str(&format!("deposit,{},{},1.5\n", tx_id % 50, tx_id));
+        }
+        fs::write(file_path, csv).unwrap();
+
+        let profile = Arc::new(Profile::new());
+        let parse_options = ParseOptions {
+            profile: Some(Arc::clone(&profile)),
+            ..ParseOptions::default()
+        };
+        let options = EngineOptions {
+            profile: Some(Arc::clone(&profile)),
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(4, Arc::new(NoopObserver), options);
+        let result = runner::route_file(&engine, file_path, &parse_options).await;
+        fs::remove_file(file_path).expect("Failed to delete test file");
+        result.unwrap();
+        engine.finish().await;
+
+        let mut output = Vec::new();
+        time_async(
+            Some(&profile),
+            Phase::Write,
+            engine.write_accounts_to(&mut output),
+        )
+        .await
+        .unwrap();
+
+        for phase in Phase::ALL {
+            assert!(profile.get(phase) > Duration::ZERO, "{:?}", phase);
+        }
+        let report = profile.to_string();
+        assert!(report.starts_with("phase,seconds\nparse,"), "{}", report);
+        assert_eq!(report.lines().count(), 6);
+        assert!(report.lines().last().unwrap().starts_with("total,"));
+    }
+
+    #[test]
+    fn test_time_without_profile_only_runs() {
+        assert_eq!(time(None, Phase::Route, || 42), 42);
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Paces callers to at most `per_second` acquisitions per second, shared by every reader.
+///
+/// Token bucket holding a single token and starting empty: the n-th call to `acquire`
+/// completes no earlier than n / `per_second` seconds after creation. After an idle period
+/// the bucket allows at most one second of catch-up burst.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / per_second.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait for the next slot
+    pub async fn acquire(&self) {
+        let now = Instant::now();
+        let deadline = {
+            let mut next = self.next.lock().unwrap();
+            if *next + Duration::from_secs(1) < now {
+                *next = now;
+            }
+            *next += self.interval;
+            *next
+        };
+        // Slots reserved in the past (e.g. after a sleep overshoot) are taken immediately
+        if deadline > now {
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use crate::errors::EngineError;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Cumulative money flows applied to the accounts of a shard (or of the whole engine)
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FlowTotals {
+    pub deposits: Decimal,
+    pub withdrawals: Decimal,
+    pub chargebacks: Decimal,
+    pub fees: Decimal,
+    /// Signed sum of adjustments
+    pub adjustments: Decimal,
+}
+
+impl FlowTotals {
+    /// Expected sum of all account totals given these flows.
+    /// Disputes and resolves only move funds between available and held.
+    pub fn net(&self) -> Decimal {
+        self.deposits - self.withdrawals - self.chargebacks - self.fees + self.adjustments
+    }
+
+    pub fn merge(&mut self, other: &FlowTotals) {
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.chargebacks += other.chargebacks;
+        self.fees += other.fees;
+        self.adjustments += other.adjustments;
+    }
+}
+
+/// Result of comparing the sum of all account totals against the tracked flows
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReconciliationReport {
+    pub total_balance: Decimal,
+    pub flows: FlowTotals,
+    pub discrepancy: Decimal,
+}
+
+impl ReconciliationReport {
+    pub fn new(total_balance: Decimal, flows: FlowTotals) -> Self {
+        Self {
+            total_balance,
+            flows,
+            discrepancy: total_balance - flows.net(),
+        }
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.discrepancy.is_zero()
+    }
+}
+
+/// Overflow-checked sum of money entering and leaving the accounts, kept as an `i128` count
+/// of `10^-scale` units. Unlike a `Decimal`, which tops out near 7.9e28, it can add up the
+/// balances of any number of accounts that each fit a `Decimal` (at the default scale).
+///
+/// An amount that cannot be added exactly, because the sum overflows or the amount has more
+/// decimal places than `scale`, leaves the aggregate unknown rather than wrong.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AggregateTotal {
+    scale: u32,
+    units: Option<i128>,
+}
+
+impl AggregateTotal {
+    pub fn new(scale: u32) -> Self {
+        Self {
+            scale,
+            units: Some(0),
+        }
+    }
+
+    pub fn add(&mut self, amount: Decimal) {
+        self.units = self
+            .units
+            .zip(self.units_of(amount))
+            .and_then(|(units, amount)| units.checked_add(amount));
+    }
+
+    pub fn sub(&mut self, amount: Decimal) {
+        self.units = self
+            .units
+            .zip(self.units_of(amount))
+            .and_then(|(units, amount)| units.checked_sub(amount));
+    }
+
+    /// Add the aggregate of another shard, kept at the same scale
+    pub fn merge(&mut self, other: &AggregateTotal) {
+        debug_assert_eq!(self.scale, other.scale);
+        self.units = self
+            .units
+            .zip(other.units)
+            .and_then(|(units, other)| units.checked_add(other));
+    }
+
+    /// Whether every amount so far was added exactly
+    pub fn is_exact(&self) -> bool {
+        self.units.is_some()
+    }
+
+    /// `amount` as a count of units, if it has at most `scale` decimal places and fits
+    fn units_of(&self, amount: Decimal) -> Option<i128> {
+        let shift = self.scale.checked_sub(amount.scale())?;
+        amount.mantissa().checked_mul(10i128.checked_pow(shift)?)
+    }
+
+    /// Fail with `AggregateMismatch` unless this aggregate of the flows is exact and equals
+    /// the sum of `totals`, the account totals, added up at the same scale
+    pub fn verify<I>(&self, totals: I) -> Result<(), EngineError>
+    where
+        I: IntoIterator<Item = Decimal>,
+    {
+        let mut accounts = AggregateTotal::new(self.scale);
+        for total in totals {
+            accounts.add(total);
+        }
+        match (self.units, accounts.units) {
+            (Some(flows), Some(balances)) if flows == balances => Ok(()),
+            (Some(_), Some(_)) => Err(EngineError::AggregateMismatch(format!(
+                "accounts total {}, flows add up to {}",
+                accounts, self
+            ))),
+            (None, _) => Err(EngineError::AggregateMismatch(
+                "the aggregate of the flows overflowed".into(),
+            )),
+            (_, None) => Err(EngineError::AggregateMismatch(
+                "the sum of the account totals overflowed".into(),
+            )),
+        }
+    }
+}
+
+/// Fixed point with `scale` decimal places, or `unknown` once an amount could not be added
+impl fmt::Display for AggregateTotal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(units) = self.units else {
+            return write!(f, "unknown");
+        };
+        let sign = if units < 0 { "-" } else { "" };
+        let units = units.unsigned_abs();
+        let one = 10u128.pow(self.scale);
+        if self.scale == 0 {
+            return write!(f, "{}{}", sign, units);
+        }
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            units / one,
+            units % one,
+            width = self.scale as usize
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_flow_totals_net_and_merge() {
+        let mut flows = FlowTotals {
+            deposits: dec!(100.0),
+            withdrawals: dec!(30.0),
+            chargebacks: dec!(20.0),
+            fees: dec!(5.0),
+            adjustments: dec!(-2.5),
+        };
+        assert_eq!(flows.net(), dec!(42.5));
+
+        flows.merge(&FlowTotals {
+            deposits: dec!(10.0),
+            fees: dec!(1.0),
+            ..FlowTotals::default()
+        });
+        assert_eq!(flows.deposits, dec!(110.0));
+        assert_eq!(flows.fees, dec!(6.0));
+        assert_eq!(flows.net(), dec!(51.5));
+    }
+
+    #[test]
+    fn test_report_discrepancy() {
+        let flows = FlowTotals {
+            deposits: dec!(100.0),
+            withdrawals: dec!(30.0),
+            chargebacks: dec!(0.0),
+            fees: dec!(0.0),
+            adjustments: dec!(0.0),
+        };
+
+        let balanced = ReconciliationReport::new(dec!(70.0), flows);
+        assert!(balanced.is_balanced());
+
+        let unbalanced = ReconciliationReport::new(dec!(75.0), flows);
+        assert!(!unbalanced.is_balanced());
+        assert_eq!(unbalanced.discrepancy, dec!(5.0));
+    }
+
+    #[test]
+    fn test_aggregate_total_goes_beyond_decimal_limit() {
+        let mut aggregate = AggregateTotal::new(4);
+        aggregate.add(Decimal::MAX);
+        aggregate.add(Decimal::MAX);
+        aggregate.sub(dec!(0.0001));
+        assert!(aggregate.is_exact());
+        assert_eq!(aggregate.to_string(), "158456325028528675187087900669.9999");
+
+        aggregate
+            .verify([Decimal::MAX, Decimal::MAX, dec!(-0.0001)])
+            .unwrap();
+        let result = aggregate.verify([Decimal::MAX]);
+        let expected = format!(
+            "accounts total {}.0000, flows add up to {}",
+            Decimal::MAX,
+            aggregate
+        );
+        assert!(
+            matches!(&result, Err(EngineError::AggregateMismatch(msg)) if *msg == expected),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_aggregate_total_unknown_when_inexact() {
+        let mut aggregate = AggregateTotal::new(4);
+        aggregate.add(dec!(1.00001));
+        assert!(!aggregate.is_exact());
+        assert_eq!(aggregate.to_string(), "unknown");
+        assert!(aggregate.verify([]).is_err());
+
+        let mut overflowed = AggregateTotal::new(4);
+        overflowed.add(Decimal::MAX);
+        overflowed.merge(&AggregateTotal {
+            scale: 4,
+            units: Some(i128::MAX),
+        });
+        assert!(!overflowed.is_exact());
+    }
+}
@@ -0,0 +1,143 @@
+use crate::errors::EngineError;
+use crate::models::Transaction;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A row left out of a run and why, see `RejectLog`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RejectedRow {
+    /// `type,client,tx,amount,timestamp` cells of the row, as read for a row that failed to
+    /// parse
+    pub cells: Vec<String>,
+    pub reason: String,
+}
+
+/// Rows rejected during a run, by the reader (a row that does not parse), the router (e.g. a
+/// duplicate id under `--global-unique-tx`) or a shard (e.g. insufficient funds), shared by
+/// all of them. Written with `--rejects-output` so operators can fix and resubmit only the
+/// failed rows.
+///
+/// Rows are kept in the order they were rejected. Shards reject concurrently with the reader,
+/// so this is not the order of the input.
+#[derive(Debug, Default)]
+pub struct RejectLog {
+    rows: Mutex<Vec<RejectedRow>>,
+}
+
+impl RejectLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a row that failed to parse, from its cells as read
+    pub fn record_row(&self, mut cells: Vec<String>, err: &EngineError) {
+        cells.resize(5, String::new());
+        self.push(RejectedRow {
+            cells,
+            reason: err.to_string(),
+        });
+    }
+
+    /// Record a transaction the engine did not apply
+    pub fn record_transaction(&self, transaction: &Transaction, err: &EngineError) {
+        let cells = vec![
+            transaction.tx_type.to_string(),
+            transaction.client.to_string(),
+            transaction.tx_id.to_string(),
+            transaction
+                .amount
+                .map_or_else(String::new, |a| a.to_string()),
+            transaction
+                .timestamp
+                .map_or_else(String::new, |t| t.to_string()),
+        ];
+        self.push(RejectedRow {
+            cells,
+            reason: err.to_string(),
+        });
+    }
+
+    fn push(&self, row: RejectedRow) {
+        self.rows.lock().expect("reject log poisoned").push(row);
+    }
+
+    /// Copy of the rows recorded so far
+    pub fn rows(&self) -> Vec<RejectedRow> {
+        self.rows.lock().expect("reject log poisoned").clone()
+    }
+
+    /// Write the rows as `type,client,tx,amount,timestamp,reason` CSV to `path`
+    pub async fn write_to(&self, path: &Path) -> Result<(), EngineError> {
+        let file = async_std::fs::File::create(path).await?;
+        let mut wtr = csv_async::AsyncWriter::from_writer(file);
+        wtr.write_record(&["type", "client", "tx", "amount", "timestamp", "reason"])
+            .await?;
+        for row in self.rows() {
+            wtr.write_record(row.cells.iter().chain([&row.reason]))
+                .await?;
+        }
+        wtr.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{EngineOptions, PaymentEngine, ShardedEngine};
+    use crate::io::ParseOptions;
+    use crate::observer::NoopObserver;
+    use crate::runner;
+    use std::fs;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_rejects_output_holds_failed_rows() {
+        let input_path = "res/test_rejects_input.csv";
+        let rejects_path = "res/test_rejects_output.csv";
+        fs::write(
+            input_path,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,1,two,5.0\n\
+             withdrawal,1,3,50.0\n\
+             withdrawal,1,4,2.5\n\
+             refund,2,5,1.0\n\
+             dispute,2,99,\n",
+        )
+        .unwrap();
+
+        let rejects = Arc::new(RejectLog::new());
+        let parse_options = ParseOptions {
+            rejects: Some(Arc::clone(&rejects)),
+            ..ParseOptions::default()
+        };
+        let options = EngineOptions {
+            rejects: Some(Arc::clone(&rejects)),
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+        let result = runner::route_file(&engine, input_path, &parse_options).await;
+        fs::remove_file(input_path).expect("Failed to delete test file");
+        result.unwrap();
+        engine.finish().await;
+
+        rejects.write_to(Path::new(rejects_path)).await.unwrap();
+        let output = fs::read_to_string(rejects_path).unwrap();
+        fs::remove_file(rejects_path).expect("Failed to delete test file");
+
+        let mut lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.remove(0), "type,client,tx,amount,timestamp,reason");
+        // Parse failures are recorded by the reader, the others as the shards reject them
+        lines.sort_unstable();
+        assert_eq!(
+            lines,
+            vec![
+                "deposit,1,two,5.0,,ParseField: Invalid transaction ID (ParseIntError: invalid digit found in string)",
+                "dispute,2,99,,,TransactionNotFound: 99",
+                "refund,2,5,1.0,,TransactionError: Invalid transaction type",
+                "withdrawal,1,3,50.0000,,\"InsufficientFunds: client 1 has 10.0000 available, 50.0000 requested\"",
+            ]
+        );
+    }
+}
@@ -0,0 +1,942 @@
+use crate::engine::{PaymentEngine, ShardedEngine};
+use crate::errors::EngineError;
+use crate::io::{self, InputRow, ParseOptions, RowStream};
+use crate::models::ClientId;
+use crate::profile::{self, Phase};
+use futures::stream::{Stream, StreamExt};
+use log::error;
+use std::collections::HashSet;
+use std::thread;
+
+/// Rows read from the start of the input by `estimate_distinct_clients`
+pub const AUTO_SHARD_SAMPLE_ROWS: usize = 10_000;
+
+/// Input size up to which `--shards auto` picks the single-threaded engine
+pub const SINGLE_THREAD_MAX_BYTES: u64 = 1024 * 1024;
+
+/// Stream a single CSV file into the engine, logging rows that fail to parse or route.
+///
+/// Stops with `InputLimitExceeded` as soon as the file goes past `max_rows` or `max_bytes`,
+/// and with `ErrorRateExceeded` once too many rows were rejected (`error_rate`); rows read
+/// before that point have already been routed. Stops with the routing error when
+/// a shard worker is gone (see `EngineError::is_fatal`).
+pub async fn route_file<E: PaymentEngine>(
+    engine: &E,
+    file_path: &str,
+    options: &ParseOptions,
+) -> Result<(), EngineError> {
+    #[cfg(feature = "mmap")]
+    if options.mmap {
+        let (records, mapped_file) =
+            crate::mmap::stream_transactions_mmap(file_path, options).await?;
+        route_rows(
+            engine,
+            file_path,
+            io::parse_records(records, options),
+            options,
+        )
+        .await?;
+        return mapped_file.check_unchanged();
+    }
+
+    let rows = io::stream_rows(file_path, options).await?;
+    route_rows(engine, file_path, rows, options).await
+}
+
+async fn route_rows<E, S>(
+    engine: &E,
+    file_path: &str,
+    mut stream: S,
+    options: &ParseOptions,
+) -> Result<(), EngineError>
+where
+    E: PaymentEngine,
+    S: Stream<Item = InputRow> + Unpin,
+{
+    let cancel = engine.cancellation_token();
+    let profile = options.profile.as_deref();
+    let mut rows = 0;
+
+    // Process each transaction by routing it to the appropriate shard, until cancelled
+    loop {
+        let row = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => {
+                log::info!("Processing of {} cancelled", file_path);
+                break;
+            }
+            next = profile::time_async(profile, Phase::Parse, stream.next()) => match next {
+                Some(row) => row,
+                None => break,
+            },
+        };
+        rows += 1;
+        if let Some(end) = row.end {
+            options.check_row_limits(file_path, rows, end)?;
+        }
+        route_row(engine, row, options).await?;
+    }
+
+    Ok(())
+}
+
+/// Route the transaction of a row, or log why the row is invalid. Only fails when the row
+/// is fatal to the run: the shard worker is gone or too many rows were rejected.
+async fn route_row<E: PaymentEngine>(
+    engine: &E,
+    row: InputRow,
+    options: &ParseOptions,
+) -> Result<(), EngineError> {
+    let profile = options.profile.as_deref();
+    let rejected = match row.transaction {
+        Ok(trans) => {
+            if let Some(rate_limiter) = &options.rate_limiter {
+                rate_limiter.acquire().await;
+            }
+            match profile::time(profile, Phase::Route, || engine.route_transaction(trans)) {
+                Err(err) if err.is_fatal() => {
+                    error!(
+                        tx_id = trans.tx_id,
+                        client = trans.client;
+                        "Shard of client {} stopped receiving transactions, aborting: {}",
+                        trans.client, err
+                    );
+                    return Err(err);
+                }
+                Err(err) => {
+                    error!(
+                        tx_id = trans.tx_id,
+                        client = trans.client;
+                        "Failed to route transaction: {}", err
+                    );
+                    if let Some(rejects) = &options.rejects {
+                        rejects.record_transaction(&trans, &err);
+                    }
+                    true
+                }
+                Ok(()) => false,
+            }
+        }
+        Err(err) => {
+            error!("{}", err);
+            if let Some(rejects) = &options.rejects {
+                rejects.record_row(row.cells, &err);
+            }
+            true
+        }
+    };
+    if let Some(error_rate) = &options.error_rate {
+        error_rate.record_row(rejected);
+        error_rate.check(engine.rejected_count())?;
+    }
+    Ok(())
+}
+
+/// Fail with `ErrorRateExceeded` when the rows rejected by the reader and by the engine add
+/// up to more than `options.error_rate` allows. Meant for once the engine has finished, when
+/// its count of rejections is complete.
+pub fn check_error_rate<E: PaymentEngine>(
+    engine: &E,
+    options: &ParseOptions,
+) -> Result<(), EngineError> {
+    match &options.error_rate {
+        Some(error_rate) => error_rate.check(engine.rejected_count()),
+        None => Ok(()),
+    }
+}
+
+/// Stream several files into the engine one after the other, in the given order
+pub async fn route_files<E: PaymentEngine>(
+    engine: &E,
+    file_paths: &[String],
+    options: &ParseOptions,
+) -> Result<(), EngineError> {
+    for file_path in file_paths {
+        route_file(engine, file_path, options).await?;
+    }
+    Ok(())
+}
+
+/// Stream independent files into the same engine concurrently, one reader task per file.
+///
+/// Shards still serialize all transactions of a client, but the relative order of rows coming
+/// from different files is not defined, so files must not depend on each other (e.g. a dispute
+/// in one file for a deposit in another). Transaction ids are expected to be unique across
+/// files: a deposit or withdrawal reusing an id already recorded for a client is rejected.
+pub async fn route_files_concurrently(
+    engine: &ShardedEngine,
+    file_paths: &[String],
+    options: &ParseOptions,
+) -> Result<(), EngineError> {
+    let handles: Vec<_> = file_paths
+        .iter()
+        .cloned()
+        .map(|file_path| {
+            let engine = engine.clone();
+            let options = options.clone();
+            tokio::spawn(async move { route_file(&engine, &file_path, &options).await })
+        })
+        .collect();
+
+    // Wait for every reader before reporting, so no task is left feeding the engine
+    let mut result = Ok(());
+    for handle in handles {
+        let outcome = handle.await.unwrap_or_else(|err| {
+            Err(EngineError::InvalidOperation(format!(
+                "Reader task failed: {}",
+                err
+            )))
+        });
+        if result.is_ok() {
+            result = outcome;
+        }
+    }
+    result
+}
+
+/// An input file of `route_files_merged` and its next row, read ahead
+struct MergedInput {
+    file_path: String,
+    rows: RowStream<'static>,
+    next: Option<InputRow>,
+    rows_read: u64,
+}
+
+/// Order of the next rows of the inputs in `route_files_merged`: a row that failed to parse
+/// has no id and goes first, and a deposit, withdrawal, fee or adjustment goes before the
+/// disputes, resolves and chargebacks referencing its id
+fn merge_key(row: &InputRow) -> Option<(u32, bool)> {
+    row.transaction
+        .as_ref()
+        .ok()
+        .map(|transaction| (transaction.tx_id, !transaction.tx_type.is_recorded()))
+}
+
+/// Stream several files into the engine as a single input ordered by tx id, for files each
+/// sorted by tx id but interleaved with one another, where reading them one after the other
+/// would put e.g. a dispute in the first file before its deposit in the second.
+///
+/// A k-way merge: every file is read ahead by one row, and the row with the lowest id among
+/// them is routed next, so memory stays at one row per file whatever their size. Ties go to
+/// the transaction being referenced, then to the earlier file. The rows of each file keep
+/// their order, so a file that is not sorted is still read in order, only interleaved less
+/// usefully with the others. Limits (`max_rows`, `max_bytes`) apply to each file as usual.
+pub async fn route_files_merged<E: PaymentEngine>(
+    engine: &E,
+    file_paths: &[String],
+    options: &ParseOptions,
+) -> Result<(), EngineError> {
+    let cancel = engine.cancellation_token();
+    let profile = options.profile.as_deref();
+
+    let mut inputs = Vec::with_capacity(file_paths.len());
+    for file_path in file_paths {
+        let mut rows = io::stream_rows(file_path, options).await?;
+        let next = profile::time_async(profile, Phase::Parse, rows.next()).await;
+        inputs.push(MergedInput {
+            file_path: file_path.clone(),
+            rows,
+            next,
+            rows_read: 0,
+        });
+    }
+
+    while !cancel.is_cancelled() {
+        let Some((_, input)) = inputs
+            .iter_mut()
+            .filter_map(|input| Some((merge_key(input.next.as_ref()?), input)))
+            .min_by_key(|(key, _)| *key)
+        else {
+            return Ok(());
+        };
+        let row = input
+            .next
+            .take()
+            .expect("only inputs with a next row are merged");
+        input.rows_read += 1;
+        if let Some(end) = row.end {
+            options.check_row_limits(&input.file_path, input.rows_read, end)?;
+        }
+        input.next = profile::time_async(profile, Phase::Parse, input.rows.next()).await;
+        route_row(engine, row, options).await?;
+    }
+
+    log::info!("Processing of the merged input files cancelled");
+    Ok(())
+}
+
+/// Rows counted by `validate_schema`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SchemaReport {
+    /// Rows parsing into a transaction
+    pub valid: usize,
+    /// Rows failing to parse, each logged like during a run
+    pub invalid: usize,
+}
+
+/// Parse every row of the input files without applying them: the `--validate-schema-only`
+/// pre-flight check. No engine is created and no account state is kept, so it only catches
+/// what the parser catches (a bad header, a malformed row, an invalid amount), not what the
+/// engine would reject, such as a withdrawal without funds.
+///
+/// Fails like a run on an unreadable file, a bad header or past `max_rows` or `max_bytes`.
+pub async fn validate_schema(
+    file_paths: &[String],
+    options: &ParseOptions,
+) -> Result<SchemaReport, EngineError> {
+    let mut report = SchemaReport::default();
+    for file_path in file_paths {
+        let mut stream = io::stream_rows(file_path, options).await?;
+        let mut rows = 0;
+        while let Some(row) = stream.next().await {
+            rows += 1;
+            if let Some(end) = row.end {
+                options.check_row_limits(file_path, rows, end)?;
+            }
+            match row.transaction {
+                Ok(_) => report.valid += 1,
+                Err(err) => {
+                    error!("{}", err);
+                    if let Some(rejects) = &options.rejects {
+                        rejects.record_row(row.cells, &err);
+                    }
+                    report.invalid += 1;
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Count the distinct client ids in the first `sample_rows` valid rows of the input files
+pub async fn estimate_distinct_clients(
+    file_paths: &[String],
+    sample_rows: usize,
+    options: &ParseOptions,
+) -> Result<usize, EngineError> {
+    let mut clients: HashSet<ClientId> = HashSet::new();
+    let mut rows = 0;
+
+    for file_path in file_paths {
+        let mut stream = io::stream_rows(file_path, options).await?;
+        while rows < sample_rows {
+            let Some(row) = stream.next().await else {
+                break;
+            };
+            // Malformed rows are reported during the real run, not while sampling
+            if let Ok(transaction) = row.transaction {
+                clients.insert(transaction.client);
+                rows += 1;
+            }
+        }
+        if rows >= sample_rows {
+            break;
+        }
+    }
+
+    Ok(clients.len())
+}
+
+/// Heuristic behind `--shards auto`.
+///
+/// A client is always handled by a single shard, so shards beyond the number of distinct
+/// clients would stay idle, and shards beyond the number of cores only add contention.
+/// The count is therefore `min(distinct clients, cores)`, and at least 1.
+pub fn auto_shard_count(distinct_clients: usize, cores: usize) -> usize {
+    distinct_clients.min(cores).max(1)
+}
+
+/// Whether the input files add up to at most `SINGLE_THREAD_MAX_BYTES` on disk, small
+/// enough that spawning and feeding shard workers costs more than applying the
+/// transactions inline. A file that cannot be inspected counts as large, so the real run
+/// reports the problem.
+pub fn is_small_input(file_paths: &[String]) -> bool {
+    let mut total: u64 = 0;
+    for file_path in file_paths {
+        match std::fs::metadata(file_path) {
+            Ok(metadata) => total += metadata.len(),
+            Err(_) => return false,
+        }
+    }
+    total <= SINGLE_THREAD_MAX_BYTES
+}
+
+/// Sample the input and pick a shard count for it, capped at the available cores
+pub async fn auto_shards(
+    file_paths: &[String],
+    options: &ParseOptions,
+) -> Result<usize, EngineError> {
+    let distinct_clients =
+        estimate_distinct_clients(file_paths, AUTO_SHARD_SAMPLE_ROWS, options).await?;
+    let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+    let shards = auto_shard_count(distinct_clients, cores);
+    log::info!(
+        "Using {} shards for {} sampled clients on {} cores",
+        shards,
+        distinct_clients,
+        cores
+    );
+    Ok(shards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineOptions;
+    use crate::error_rate::ErrorRateLimit;
+    use crate::models::{ClientAccount, Transaction};
+    use crate::observer::{NoopObserver, TransactionObserver};
+    use crate::rate_limit::RateLimiter;
+    use crate::single_threaded::SingleThreadedEngine;
+    use rust_decimal_macros::dec;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::sync::Notify;
+
+    fn create_temp_csv(file_path: &str, data: &str) -> String {
+        let mut file =
+            File::create(format!("res/{}", file_path)).expect("Unable to create test file");
+        file.write_all(data.as_bytes())
+            .expect("Unable to write to test file");
+        format!("res/{}", file_path)
+    }
+
+    #[tokio::test]
+    async fn test_accounts_filter_limits_processing_to_listed_clients() {
+        let input = create_temp_csv(
+            "test_accounts_filter_input.csv",
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,2,2,20.0\n\
+             deposit,3,3,30.0\n\
+             deposit,4,4,40.0\n\
+             deposit,5,5,50.0\n\
+             withdrawal,2,6,5.0\n\
+             dispute,3,3,\n\
+             dispute,4,4,\n\
+             chargeback,4,4,\n",
+        );
+        let filter = create_temp_csv("test_accounts_filter_clients.txt", "2\n\n4\n");
+        let clients = io::read_client_ids(&filter).await;
+        fs::remove_file(&filter).expect("Failed to delete test file");
+
+        let options = EngineOptions {
+            client_filter: Some(clients.unwrap()),
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+        let result = route_files(
+            &engine,
+            std::slice::from_ref(&input),
+            &ParseOptions::default(),
+        )
+        .await;
+        fs::remove_file(&input).expect("Failed to delete test file");
+        result.unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let mut output = Vec::new();
+        engine.write_accounts_to(&mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut rows: Vec<_> = output.lines().skip(1).collect();
+        rows.sort_unstable();
+        assert_eq!(
+            rows,
+            vec![
+                "2,15.0000,0.0000,15.0000,false",
+                "4,0.0000,0.0000,0.0000,true"
+            ]
+        );
+        // Filtered out transactions never reach a shard
+        assert_eq!(engine.run_summary().await.rejections.len(), 0);
+    }
+
+    /// Route `input` with amounts and balances held at `hold_precision` places, returning
+    /// client 1's account and the output rows
+    async fn run_with_hold_precision(
+        input: &str,
+        hold_precision: Option<u32>,
+    ) -> (Option<ClientAccount>, Vec<String>) {
+        let options = EngineOptions {
+            hold_precision,
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(2, Arc::new(NoopObserver), options);
+        let parse_options = ParseOptions {
+            hold_precision,
+            ..ParseOptions::default()
+        };
+        route_files(&engine, &[input.to_string()], &parse_options)
+            .await
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let mut output = Vec::new();
+        engine.write_accounts_to(&mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let rows = output.lines().skip(1).map(str::to_string).collect();
+        (engine.get_account(1).await, rows)
+    }
+
+    #[tokio::test]
+    async fn test_hold_precision_accumulates_tiny_deposits() {
+        let mut contents = String::from("type,client,tx,amount\n");
+        for tx in 1..=10_000 {
+            contents.push_str(&format!("deposit,1,{},0.00001\n", tx));
+        }
+        contents.push_str("withdrawal,1,10001,0.000015\n");
+        let input = create_temp_csv("test_hold_precision_input.csv", &contents);
+
+        let (held, held_rows) = run_with_hold_precision(&input, Some(8)).await;
+        let (truncated, truncated_rows) = run_with_hold_precision(&input, None).await;
+        fs::remove_file(&input).expect("Failed to delete test file");
+
+        // No loss internally, the output is cut to four places
+        let held = held.unwrap();
+        assert_eq!(held.total, dec!(0.099985));
+        assert_eq!(held.available, dec!(0.099985));
+        assert_eq!(held_rows, vec!["1,0.0999,0.0000,0.0999,false"]);
+        // At the output precision every deposit truncates to zero and is rejected
+        assert!(truncated.is_none());
+        assert!(truncated_rows.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_route_files_concurrently_into_one_engine() {
+        let first = create_temp_csv(
+            "test_route_concurrently_first.csv",
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,2,2,50.0\n\
+             withdrawal,1,3,30.0\n",
+        );
+        let second = create_temp_csv(
+            "test_route_concurrently_second.csv",
+            "type,client,tx,amount\n\
+             deposit,1,10,5.0\n\
+             deposit,3,11,70.0\n\
+             deposit,2,12,25.0\n",
+        );
+
+        let mut engine = ShardedEngine::new(4);
+        let result = route_files_concurrently(
+            &engine,
+            &[first.clone(), second.clone()],
+            &ParseOptions::default(),
+        )
+        .await;
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        fs::remove_file(&first).expect("Failed to delete test file");
+        fs::remove_file(&second).expect("Failed to delete test file");
+        assert!(result.is_ok());
+
+        let client_1 = engine.get_account(1).await.unwrap();
+        assert_eq!(client_1.available, dec!(75.0));
+        assert_eq!(client_1.total, dec!(75.0));
+
+        let client_2 = engine.get_account(2).await.unwrap();
+        assert_eq!(client_2.available, dec!(75.0));
+
+        let client_3 = engine.get_account(3).await.unwrap();
+        assert_eq!(client_3.available, dec!(70.0));
+    }
+
+    #[tokio::test]
+    async fn test_route_file_stops_when_cancelled() {
+        let file_path = create_temp_csv(
+            "test_route_file_cancelled.csv",
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,2,2,50.0\n",
+        );
+
+        let engine = ShardedEngine::new(2);
+        engine.cancellation_token().cancel();
+        let result = route_file(&engine, &file_path, &ParseOptions::default()).await;
+        engine.wait_for_completion().await;
+
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+        assert!(result.is_ok());
+        assert!(engine.get_account(1).await.is_none());
+        assert!(engine.get_account(2).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_route_files_concurrently_reports_missing_file() {
+        let present = create_temp_csv(
+            "test_route_concurrently_present.csv",
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n",
+        );
+
+        let mut engine = ShardedEngine::new(2);
+        let result = route_files_concurrently(
+            &engine,
+            &[present.clone(), "res/does_not_exist.csv".to_string()],
+            &ParseOptions::default(),
+        )
+        .await;
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        fs::remove_file(&present).expect("Failed to delete test file");
+        assert!(
+            matches!(&result, Err(EngineError::FileOpenError { path, .. })
+                if path == "res/does_not_exist.csv"),
+            "{:?}",
+            result
+        );
+
+        // The readable file is still fully processed
+        let client_1 = engine.get_account(1).await.unwrap();
+        assert_eq!(client_1.available, dec!(100.0));
+    }
+
+    #[tokio::test]
+    async fn test_route_files_merged_by_tx_id() {
+        // Each file is sorted by tx id, but the first disputes a deposit of the second
+        let first = create_temp_csv(
+            "test_route_merged_first.csv",
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             dispute,1,2,\n\
+             deposit,1,5,1.0\n",
+        );
+        let second = create_temp_csv(
+            "test_route_merged_second.csv",
+            "type,client,tx,amount\n\
+             deposit,1,2,30.0\n\
+             withdrawal,1,3,10.0\n",
+        );
+        let files = [first.clone(), second.clone()];
+
+        let mut concatenated = SingleThreadedEngine::new();
+        let concatenated_result =
+            route_files(&concatenated, &files, &ParseOptions::default()).await;
+        let mut merged = SingleThreadedEngine::new();
+        let merged_result = route_files_merged(&merged, &files, &ParseOptions::default()).await;
+        fs::remove_file(&first).expect("Failed to delete test file");
+        fs::remove_file(&second).expect("Failed to delete test file");
+        concatenated_result.unwrap();
+        merged_result.unwrap();
+        concatenated.finish().await;
+        merged.finish().await;
+
+        // In file order the dispute comes before its deposit and fails
+        let account = concatenated.get_account(1).await.unwrap();
+        assert_eq!(account.held, dec!(0.0));
+        assert_eq!(
+            concatenated
+                .run_summary()
+                .await
+                .rejections
+                .get("TransactionNotFound"),
+            Some(&1)
+        );
+
+        // Merged, it follows its deposit, and the withdrawal comes before deposit 5
+        let account = merged.get_account(1).await.unwrap();
+        assert_eq!(account.held, dec!(30.0));
+        assert_eq!(account.available, dec!(91.0));
+        assert!(merged.run_summary().await.rejections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_validate_schema_counts_rows() {
+        let input = create_temp_csv(
+            "test_validate_schema.csv",
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             withdrawal,1,2,lots\n\
+             deposit,2,3,5.0\n\
+             refund,2,4,1.0\n\
+             withdrawal,3,5,100.0\n\
+             dispute,3,99,\n",
+        );
+        // Takes no engine: the withdrawal without funds and the dispute of an unknown
+        // transaction are valid rows, only an engine would reject them
+        let result = validate_schema(std::slice::from_ref(&input), &ParseOptions::default()).await;
+        fs::remove_file(&input).expect("Failed to delete test file");
+
+        assert_eq!(
+            result.unwrap(),
+            SchemaReport {
+                valid: 4,
+                invalid: 2
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_schema_fails_on_bad_header() {
+        let input = create_temp_csv(
+            "test_validate_schema_header.csv",
+            "kind,client,tx,amount\ndeposit,1,1,10.0\n",
+        );
+        let result = validate_schema(std::slice::from_ref(&input), &ParseOptions::default()).await;
+        fs::remove_file(&input).expect("Failed to delete test file");
+
+        assert!(matches!(result, Err(EngineError::HeaderError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_auto_shards_for_skewed_clients() {
+        // Client 1 dominates the input, only a handful of others appear
+        let mut data = String::from("type,client,tx,amount\n");
+        for tx in 0..200 {
+            let client = if tx % 40 == 0 { 2 + tx / 40 } else { 1 };
+            data.push_str(&format!("deposit,{},{},1.0\n", client, tx));
+        }
+        let file_path = create_temp_csv("test_auto_shards_skewed.csv", &data);
+        let file_paths = [file_path.clone()];
+
+        let options = ParseOptions::default();
+        let distinct =
+            estimate_distinct_clients(&file_paths, AUTO_SHARD_SAMPLE_ROWS, &options).await;
+        let sampled = estimate_distinct_clients(&file_paths, 10, &options).await;
+        let shards = auto_shards(&file_paths, &options).await;
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+
+        assert_eq!(distinct.unwrap(), 6);
+        assert_eq!(sampled.unwrap(), 2);
+
+        let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+        let shards = shards.unwrap();
+        assert!((1..=6).contains(&shards));
+        assert!(shards <= cores);
+
+        assert_eq!(auto_shard_count(6, 4), 4);
+        assert_eq!(auto_shard_count(6, 16), 6);
+        assert_eq!(auto_shard_count(0, 8), 1);
+    }
+
+    /// Panics on a deposit of its client, after notifying the test
+    struct PanicOnClient(ClientId, Arc<Notify>);
+
+    impl TransactionObserver for PanicOnClient {
+        fn on_deposit(&self, transaction: &Transaction, _account: &ClientAccount) {
+            if transaction.client == self.0 {
+                self.1.notify_one();
+                panic!("simulated shard worker failure");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_file_aborts_when_shard_worker_died() {
+        let file_path = create_temp_csv(
+            "test_route_file_dead_shard.csv",
+            "type,client,tx,amount
+             deposit,2,2,50.0
+             deposit,1,3,10.0
+             deposit,2,4,50.0
+",
+        );
+
+        // The worker of client 1's shard panics on its first deposit, dropping its receiver
+        let panicked = Arc::new(Notify::new());
+        let engine =
+            ShardedEngine::with_observer(2, Arc::new(PanicOnClient(1, Arc::clone(&panicked))));
+        engine
+            .route_transaction(Transaction::deposit(1, 1, dec!(1.0)))
+            .unwrap();
+        // On the current-thread test runtime, the worker's task (and its receiver) is dropped
+        // in the same poll as the panic, before this test runs again
+        panicked.notified().await;
+
+        let result = route_file(&engine, &file_path, &ParseOptions::default()).await;
+
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+        assert!(matches!(result, Err(EngineError::SendError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_route_file_aborts_when_shard_lane_died() {
+        let file_path = create_temp_csv(
+            "test_route_file_dead_lane.csv",
+            "type,client,tx,amount\n\
+             deposit,2,2,50.0\n\
+             deposit,1,3,10.0\n\
+             deposit,2,4,50.0\n",
+        );
+
+        // A lane of client 1's shard panics on its first deposit, the worker keeps receiving
+        let panicked = Arc::new(Notify::new());
+        let options = EngineOptions {
+            lanes_per_shard: 2,
+            ..EngineOptions::default()
+        };
+        let mut engine = ShardedEngine::with_options(
+            2,
+            Arc::new(PanicOnClient(1, Arc::clone(&panicked))),
+            options,
+        );
+        engine
+            .route_transaction(Transaction::deposit(1, 1, dec!(1.0)))
+            .unwrap();
+        // The lane's guard marks the shard as failed in the same poll as the panic
+        panicked.notified().await;
+
+        let result = route_file(&engine, &file_path, &ParseOptions::default()).await;
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+        assert!(matches!(result, Err(EngineError::SendError(_))));
+        assert!(matches!(
+            engine.check_workers(),
+            Err(EngineError::WorkerFailed(1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_worker_died_on_last_transaction_fails_the_run() {
+        let file_path = create_temp_csv(
+            "test_route_file_dead_shard_last.csv",
+            "type,client,tx,amount\n\
+             deposit,2,1,50.0\n\
+             deposit,1,2,10.0\n",
+        );
+
+        // Every row is routed before the worker of client 1's shard panics
+        let mut engine =
+            ShardedEngine::with_observer(2, Arc::new(PanicOnClient(1, Arc::new(Notify::new()))));
+        route_file(&engine, &file_path, &ParseOptions::default())
+            .await
+            .unwrap();
+        engine.shutdown();
+        tokio::time::timeout(Duration::from_secs(10), engine.wait_for_completion())
+            .await
+            .expect("waited for the dead worker");
+
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+        assert!(matches!(
+            engine.check_workers(),
+            Err(EngineError::WorkerFailed(1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_route_file_aborts_past_row_limit() {
+        let file_path = create_temp_csv(
+            "test_route_file_row_limit.csv",
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,1,2,10.0\n\
+             deposit,1,3,10.0\n",
+        );
+        let options = ParseOptions {
+            max_rows: Some(2),
+            ..ParseOptions::default()
+        };
+
+        let mut engine = ShardedEngine::new(1);
+        let result = route_file(&engine, &file_path, &options).await;
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+        match result {
+            Err(EngineError::InputLimitExceeded(msg)) => assert!(msg.contains("2 rows")),
+            other => panic!("Expected the row limit error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_file_aborts_above_error_rate() {
+        // 3 of every 5 rows have an invalid tx id, starting with a bad one
+        let mut data = String::from("type,client,tx,amount\n");
+        for tx in 1..=200 {
+            match tx % 5 {
+                1..=3 => data.push_str("deposit,1,x,1.0\n"),
+                _ => data.push_str(&format!("deposit,1,{},1.0\n", tx)),
+            }
+        }
+        let file_path = create_temp_csv("test_route_file_error_rate.csv", &data);
+        let route_with_limit = |max_rate| {
+            let file_path = file_path.clone();
+            async move {
+                let options = ParseOptions {
+                    error_rate: Some(Arc::new(ErrorRateLimit::new(max_rate))),
+                    ..ParseOptions::default()
+                };
+                let mut engine = ShardedEngine::new(1);
+                let result = route_file(&engine, &file_path, &options).await;
+                engine.shutdown();
+                engine.wait_for_completion().await;
+                result.and_then(|()| check_error_rate(&engine, &options))
+            }
+        };
+
+        let aborted = route_with_limit(0.5).await;
+        let allowed = route_with_limit(0.7).await;
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+
+        // Not before the minimum sample, although the first row is already bad
+        match aborted {
+            Err(EngineError::ErrorRateExceeded(msg)) => {
+                assert!(msg.starts_with("60 of 100 rows rejected"), "{}", msg)
+            }
+            other => panic!("Expected the error rate error, got {:?}", other),
+        }
+        allowed.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_error_rate_counts_engine_rejections() {
+        // Every row parses, but each withdrawal exceeds the available funds
+        let mut data = String::from("type,client,tx,amount\n");
+        for tx in 1..=150 {
+            data.push_str(&format!("withdrawal,1,{},1.0\n", tx));
+        }
+        let file_path = create_temp_csv("test_error_rate_engine_rejections.csv", &data);
+        let options = ParseOptions {
+            error_rate: Some(Arc::new(ErrorRateLimit::new(0.5))),
+            ..ParseOptions::default()
+        };
+
+        let engine = SingleThreadedEngine::new();
+        let result = route_file(&engine, &file_path, &options).await;
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+
+        assert!(
+            matches!(&result, Err(EngineError::ErrorRateExceeded(msg))
+                if msg.starts_with("100 of 100 rows rejected")),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_file_respects_rate_limit() {
+        let mut data = String::from("type,client,tx,amount\n");
+        for tx in 1..=10 {
+            data.push_str(&format!("deposit,1,{},1.0\n", tx));
+        }
+        let file_path = create_temp_csv("test_route_file_rate_limit.csv", &data);
+        let rate = 50;
+        let started = Instant::now();
+        let options = ParseOptions {
+            rate_limiter: Some(Arc::new(RateLimiter::new(rate))),
+            ..ParseOptions::default()
+        };
+
+        let mut engine = ShardedEngine::new(1);
+        let result = route_file(&engine, &file_path, &options).await;
+        let elapsed = started.elapsed();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+        assert!(result.is_ok());
+        assert!(elapsed >= Duration::from_secs(10) / rate);
+        assert_eq!(engine.get_account(1).await.unwrap().total, dec!(10.0));
+    }
+}
@@ -0,0 +1,247 @@
+use crate::engine::{EngineOptions, Explanation, PaymentEngine, ShardState, ShardedEngine};
+use crate::errors::EngineError;
+use crate::models::{ClientAccount, ClientId, Transaction};
+use crate::observer::{NoopObserver, TransactionObserver};
+use crate::statistics::RunSummary;
+use futures::io::AsyncWrite;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Engine applying every transaction on the caller's task as it is routed, without shards,
+/// channels or worker tasks. For small inputs, where spawning and feeding shard workers
+/// costs more than the work itself.
+///
+/// Applies the same rules as `ShardedEngine` and writes the same output. Its single
+/// transaction log makes ids unique across all clients, as `EngineOptions::global_unique_tx`
+/// does for the sharded engine; `EngineOptions::event_capacity` is ignored.
+pub struct SingleThreadedEngine {
+    state: ShardState,
+    client_filter: Option<HashSet<ClientId>>,
+    finished: bool,
+    cancel: CancellationToken,
+    output_precision: u32,
+}
+
+impl SingleThreadedEngine {
+    pub fn new() -> Self {
+        Self::with_options(Arc::new(NoopObserver), EngineOptions::default())
+    }
+
+    /// Create an engine with an observer and non-default options
+    pub fn with_options(observer: Arc<dyn TransactionObserver>, options: EngineOptions) -> Self {
+        let mut state = ShardState::new(observer);
+        state.configure(&options);
+        Self {
+            state,
+            output_precision: options.output_precision(),
+            client_filter: options.client_filter,
+            finished: false,
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+impl Default for SingleThreadedEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PaymentEngine for SingleThreadedEngine {
+    fn route_transaction(&self, transaction: Transaction) -> Result<(), EngineError> {
+        if self.finished {
+            return Err(EngineError::ShutDownError(
+                "Engine is finished, no new transactions accepted.".into(),
+            ));
+        }
+        if self.cancel.is_cancelled() {
+            return Err(EngineError::ShutDownError(
+                "Engine processing was cancelled, no new transactions accepted.".into(),
+            ));
+        }
+        let routed = self
+            .client_filter
+            .as_ref()
+            .is_none_or(|clients| clients.contains(&transaction.client));
+        if routed {
+            self.state.receive(transaction);
+        }
+        Ok(())
+    }
+
+    fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    async fn finish(&mut self) {
+        self.finished = true;
+        // Like the shards, a cancelled engine drops its dead letters without a retry
+        if !self.cancel.is_cancelled() {
+            self.state.retry_dead_letters();
+        }
+    }
+
+    async fn get_account(&self, client: ClientId) -> Option<ClientAccount> {
+        self.state.account(client)
+    }
+
+    async fn accounts(&self) -> Vec<(ClientId, ClientAccount)> {
+        let mut accounts = self.state.accounts();
+        accounts.sort_by_key(|(client, _)| *client);
+        accounts
+    }
+
+    async fn run_summary(&self) -> RunSummary {
+        self.state.run_summary()
+    }
+
+    fn rejected_count(&self) -> usize {
+        self.state.rejected()
+    }
+
+    async fn check_aggregate(&self) -> Result<(), EngineError> {
+        match self.state.aggregate() {
+            Some(aggregate) => {
+                aggregate.verify(self.state.accounts().into_iter().map(|(_, a)| a.total))
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn explain(&self, tx_id: u32) -> Vec<Explanation> {
+        self.state.explain(tx_id).into_iter().collect()
+    }
+
+    async fn write_accounts_to<W>(&self, writer: W) -> Result<(), EngineError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let accounts = self.accounts().await;
+        ShardedEngine::write_accounts_csv(writer, &[accounts], self.output_precision).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::ParseOptions;
+    use crate::runner;
+    use rust_decimal_macros::dec;
+
+    fn input() -> Vec<Transaction> {
+        vec![
+            Transaction::deposit(1, 1, dec!(10.0)),
+            Transaction::deposit(2, 2, dec!(5.0)),
+            Transaction::withdrawal(1, 3, dec!(2.5)),
+            // Rejected: more than available
+            Transaction::withdrawal(2, 4, dec!(6.0)),
+            Transaction::dispute(2, 2),
+            Transaction::chargeback(2, 2),
+            // Rejected: locked
+            Transaction::deposit(2, 5, dec!(1.0)),
+        ]
+    }
+
+    /// Drive any engine through the trait only, like a consumer would
+    async fn run<E: PaymentEngine>(mut engine: E) -> (E, String) {
+        engine.route_batch(input()).unwrap();
+        engine
+            .route_transaction(Transaction::deposit(3, 6, dec!(0.75)))
+            .unwrap();
+        engine.finish().await;
+
+        let mut output = Vec::new();
+        engine.write_accounts_to(&mut output).await.unwrap();
+        (engine, String::from_utf8(output).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_engines_agree_through_trait() {
+        let (single, single_output) = run(SingleThreadedEngine::new()).await;
+        let (sharded, sharded_output) = run(ShardedEngine::new(3)).await;
+
+        assert_eq!(single.accounts().await, sharded.accounts().await);
+        let first = single.get_account(1).await.unwrap();
+        assert_eq!(first.available, dec!(7.5));
+        assert!(single.get_account(2).await.unwrap().locked);
+        assert!(single.get_account(4).await.is_none());
+
+        assert_eq!(single_output, sharded_output);
+        assert!(single_output.starts_with("client,available,held,total,locked\n1,7.5,"));
+    }
+
+    #[tokio::test]
+    async fn test_same_output_as_sharded_engine_from_file() {
+        let mut csv = String::from("type,client,tx,amount\n");
+        // Disputes, resolves and chargebacks reference an earlier deposit of the same client
+        let client = |tx: u32| tx % 23;
+        for tx in 10..=300u32 {
+            let row = match tx % 10 {
+                0 => format!("dispute,{},{},", client(tx - 1), tx - 1),
+                6 => format!("dispute,{},{},", client(tx - 2), tx - 2),
+                7 => format!("resolve,{},{},", client(tx - 3), tx - 3),
+                5 => format!("chargeback,{},{},", client(tx - 6), tx - 6),
+                3 => format!("withdrawal,{},{},{}.5", client(tx), tx, tx % 4),
+                _ => format!("deposit,{},{},{}.25", client(tx), tx, tx % 9),
+            };
+            csv.push_str(&row);
+            csv.push('\n');
+        }
+        let file_path = "res/test_single_threaded_equivalence.csv".to_string();
+        std::fs::write(&file_path, csv).unwrap();
+
+        let options = ParseOptions::default();
+        let mut single = SingleThreadedEngine::new();
+        let single_result =
+            runner::route_files(&single, std::slice::from_ref(&file_path), &options).await;
+        let mut sharded = ShardedEngine::new(4);
+        let sharded_result =
+            runner::route_files(&sharded, std::slice::from_ref(&file_path), &options).await;
+        std::fs::remove_file(&file_path).expect("Failed to delete test file");
+        single_result.unwrap();
+        sharded_result.unwrap();
+        single.finish().await;
+        PaymentEngine::finish(&mut sharded).await;
+
+        let output = write(&single).await;
+        assert_eq!(output, write(&sharded).await);
+        assert!(output.lines().count() > 20);
+        assert_eq!(single.checksum().await, sharded.checksum().await);
+        assert_eq!(single.run_summary().await, sharded.run_summary().await);
+        assert!(single.run_summary().await.locked_accounts > 0);
+    }
+
+    async fn write<E: PaymentEngine>(engine: &E) -> String {
+        let mut output = Vec::new();
+        engine.write_accounts_to(&mut output).await.unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_finished_engine_rejects_transactions() {
+        let mut engine = SingleThreadedEngine::new();
+        engine.finish().await;
+        let result = engine.route_transaction(Transaction::deposit(1, 1, dec!(1.0)));
+        assert!(matches!(result, Err(EngineError::ShutDownError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters_retried_on_finish() {
+        let options = EngineOptions {
+            dead_letter_retries: 1,
+            ..EngineOptions::default()
+        };
+        let mut engine = SingleThreadedEngine::with_options(Arc::new(NoopObserver), options);
+        engine
+            .route_batch(vec![
+                Transaction::dispute(1, 1),
+                Transaction::deposit(1, 1, dec!(4.0)),
+            ])
+            .unwrap();
+        engine.finish().await;
+
+        let account = engine.get_account(1).await.unwrap();
+        assert_eq!(account.held, dec!(4.0));
+    }
+}
@@ -0,0 +1,231 @@
+use crate::errors::EngineError;
+use crate::models::{ClientAccount, ClientId, Transaction, TransactionType};
+use crate::reconciliation::FlowTotals;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// State of an engine that can be saved after a run and restored into a new engine,
+/// see `ShardedEngine::snapshot` and `ShardedEngine::restore`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub accounts: Vec<AccountSnapshot>,
+    pub transactions: Vec<TransactionSnapshot>,
+    pub flows: FlowTotals,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountSnapshot {
+    pub client: ClientId,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// A recorded transaction. `Transaction` never reads `under_dispute` from its input, so a
+/// snapshot keeps its own copy of the flag: a dispute open when the snapshot was taken can
+/// still be resolved or charged back after a reload.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransactionSnapshot {
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub client: ClientId,
+    #[serde(rename = "tx")]
+    pub tx_id: u32,
+    pub amount: Option<Decimal>,
+    pub under_dispute: bool,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Amount held by an open dispute naming less than the whole transaction, see
+    /// `EngineOptions::dispute_amounts`
+    #[serde(default)]
+    pub held: Option<Decimal>,
+}
+
+impl AccountSnapshot {
+    pub fn new(client: ClientId, account: &ClientAccount) -> Self {
+        Self {
+            client,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        }
+    }
+
+    pub fn to_account(&self) -> ClientAccount {
+        ClientAccount {
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+        }
+    }
+}
+
+impl From<&Transaction> for TransactionSnapshot {
+    fn from(transaction: &Transaction) -> Self {
+        Self {
+            tx_type: transaction.tx_type,
+            client: transaction.client,
+            tx_id: transaction.tx_id,
+            amount: transaction.amount,
+            under_dispute: transaction.under_dispute,
+            timestamp: transaction.timestamp,
+            held: None,
+        }
+    }
+}
+
+impl From<&TransactionSnapshot> for Transaction {
+    fn from(snapshot: &TransactionSnapshot) -> Self {
+        Self {
+            tx_type: snapshot.tx_type,
+            client: snapshot.client,
+            tx_id: snapshot.tx_id,
+            amount: snapshot.amount,
+            under_dispute: snapshot.under_dispute,
+            timestamp: snapshot.timestamp,
+            seq: None,
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Snapshot is always serializable")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, EngineError> {
+        serde_json::from_str(json)
+            .map_err(|err| EngineError::InvalidOperation(format!("Invalid snapshot: {}", err)))
+    }
+
+    pub async fn write_to(&self, path: &Path) -> Result<(), EngineError> {
+        async_std::fs::write(path, self.to_json()).await?;
+        Ok(())
+    }
+
+    pub async fn read_from(path: &Path) -> Result<Self, EngineError> {
+        Self::from_json(&async_std::fs::read_to_string(path).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ShardedEngine;
+    use rust_decimal_macros::dec;
+
+    #[tokio::test]
+    async fn test_dispute_survives_snapshot_reload() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(100.0)),
+                Transaction::deposit(2, 2, dec!(30.0)),
+                Transaction::dispute(1, 1),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let json = engine.snapshot().await.to_json();
+        let snapshot = Snapshot::from_json(&json).unwrap();
+        assert!(snapshot
+            .transactions
+            .iter()
+            .any(|tx| tx.tx_id == 1 && tx.under_dispute));
+
+        // A different shard count moves clients around, the state must follow them
+        let mut reloaded = ShardedEngine::new(3);
+        reloaded.restore(snapshot).await;
+        reloaded
+            .route_batch(vec![
+                Transaction::resolve(1, 1),
+                Transaction::withdrawal(2, 3, dec!(10.0)),
+            ])
+            .unwrap();
+        reloaded.shutdown();
+        reloaded.wait_for_completion().await;
+
+        let first = reloaded.get_account(1).await.unwrap();
+        assert_eq!(first.available, dec!(100.0));
+        assert_eq!(first.held, dec!(0.0));
+        let second = reloaded.get_account(2).await.unwrap();
+        assert_eq!(second.available, dec!(20.0));
+        assert!(reloaded.run_summary().await.rejections.is_empty());
+        assert!(reloaded.reconcile().await.is_balanced());
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_larger_than_held_rejected_after_reload() {
+        let mut engine = ShardedEngine::new(1);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.0)),
+                Transaction::dispute(1, 1),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        // A manipulated snapshot holding less than the disputed amount
+        let mut snapshot = engine.snapshot().await;
+        snapshot.accounts[0].held = dec!(4.0);
+        snapshot.accounts[0].total = dec!(4.0);
+
+        let mut reloaded = ShardedEngine::new(1);
+        reloaded.restore(snapshot).await;
+        reloaded
+            .route_transaction(Transaction::chargeback(1, 1))
+            .unwrap();
+        reloaded.shutdown();
+        reloaded.wait_for_completion().await;
+
+        let account = reloaded.get_account(1).await.unwrap();
+        assert_eq!(account.held, dec!(4.0));
+        assert!(!account.locked);
+        assert_eq!(
+            reloaded
+                .run_summary()
+                .await
+                .rejections
+                .get("InsufficientHeldFunds"),
+            Some(&1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_transactions_in_timestamp_order() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(1.0)).at(300),
+                Transaction::deposit(2, 2, dec!(1.0)).at(100),
+                Transaction::deposit(3, 3, dec!(1.0)).at(200),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let snapshot = engine.snapshot().await;
+        let order: Vec<_> = snapshot.transactions.iter().map(|tx| tx.tx_id).collect();
+        assert_eq!(order, vec![2, 3, 1]);
+        // Older snapshots have no timestamps
+        let json = snapshot.to_json().replace(",\"timestamp\":100", "");
+        assert_eq!(
+            Snapshot::from_json(&json).unwrap().transactions[0].timestamp,
+            None
+        );
+    }
+
+    #[test]
+    fn test_invalid_snapshot_rejected() {
+        assert!(matches!(
+            Snapshot::from_json("{\"accounts\": 3}"),
+            Err(EngineError::InvalidOperation(_))
+        ));
+    }
+}
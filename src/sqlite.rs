@@ -0,0 +1,118 @@
+use crate::engine::PaymentEngine;
+use crate::errors::EngineError;
+use crate::models::{display_amount, ClientAccount, ClientId};
+use rusqlite::{params, Connection};
+
+const CREATE_ACCOUNTS_TABLE: &str = "CREATE TABLE IF NOT EXISTS accounts (
+    client INTEGER PRIMARY KEY,
+    available TEXT NOT NULL,
+    held TEXT NOT NULL,
+    total TEXT NOT NULL,
+    locked INTEGER NOT NULL
+)";
+
+/// Write the final state of every account into the SQLite database at `path`
+pub async fn write_accounts<E: PaymentEngine>(engine: &E, path: &str) -> Result<(), EngineError> {
+    let accounts = engine.accounts().await;
+    let path = path.to_string();
+
+    // rusqlite is blocking, keep it off the runtime workers
+    tokio::task::spawn_blocking(move || {
+        let mut conn = Connection::open(path)?;
+        write_accounts_to(&mut conn, &accounts)
+    })
+    .await
+    .map_err(|err| EngineError::InvalidOperation(format!("SQLite writer failed: {}", err)))?
+}
+
+/// Insert the accounts into the `accounts` table, creating it if absent.
+///
+/// Amounts are stored as TEXT so no precision is lost to floating point. All rows are
+/// inserted in a single transaction; a client already present in the table is replaced.
+pub fn write_accounts_to(
+    conn: &mut Connection,
+    accounts: &[(ClientId, ClientAccount)],
+) -> Result<(), EngineError> {
+    conn.execute(CREATE_ACCOUNTS_TABLE, [])?;
+
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT OR REPLACE INTO accounts (client, available, held, total, locked)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for (client, account) in accounts {
+            stmt.execute(params![
+                client,
+                display_amount(account.available),
+                display_amount(account.held),
+                display_amount(account.total),
+                account.locked,
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ShardedEngine;
+    use crate::models::{Transaction, TransactionType};
+    use rust_decimal_macros::dec;
+
+    #[tokio::test]
+    async fn test_write_accounts_to_in_memory_db() {
+        let mut engine = ShardedEngine::new(2);
+        let transactions = [
+            (TransactionType::Deposit, 1, 1, Some(dec!(10.5))),
+            (TransactionType::Deposit, 2, 2, Some(dec!(7.25))),
+            (TransactionType::Dispute, 2, 2, None),
+            (TransactionType::Chargeback, 2, 2, None),
+        ];
+        for (tx_type, client, tx_id, amount) in transactions {
+            engine
+                .route_transaction(Transaction {
+                    tx_type,
+                    client,
+                    tx_id,
+                    amount,
+                    under_dispute: false,
+                    timestamp: None,
+                    seq: None,
+                })
+                .unwrap();
+        }
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        write_accounts_to(&mut conn, &engine.accounts().await).unwrap();
+
+        let mut stmt = conn
+            .prepare("SELECT client, available, held, total, locked FROM accounts ORDER BY client")
+            .unwrap();
+        let rows: Vec<(i64, String, String, String, bool)> = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(
+            rows,
+            vec![
+                (1, "10.5".into(), "0.0000".into(), "10.5".into(), false),
+                (2, "0.0000".into(), "0.0000".into(), "0.0000".into(), true),
+            ]
+        );
+    }
+}
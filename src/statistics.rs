@@ -0,0 +1,326 @@
+use crate::errors::EngineError;
+use crate::models::TransactionType;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Health counters of a run, meant to be persisted next to the accounts output
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RunSummary {
+    /// Successfully applied transactions, by type
+    pub deposits: usize,
+    pub withdrawals: usize,
+    pub disputes: usize,
+    pub resolves: usize,
+    pub chargebacks: usize,
+    pub fees: usize,
+    pub adjustments: usize,
+    /// Sums of the amounts of the applied transactions, by type
+    pub amounts: AmountTotals,
+    /// Transactions finally rejected by the engine, by error kind (e.g. `InvalidOperation`)
+    pub rejections: BTreeMap<String, usize>,
+    /// Withdrawals among the `InsufficientFunds` rejections made on an account holding no
+    /// funds, likely received before their funding deposit rather than overdrawing
+    pub unfunded_withdrawals: usize,
+    /// Resolves of transactions not under dispute, accepted as no-ops with
+    /// `EngineOptions::lenient_resolve` and also counted in `resolves`
+    pub redundant_resolves: usize,
+    pub locked_accounts: usize,
+}
+
+impl RunSummary {
+    pub fn record_applied(&mut self, tx_type: TransactionType) {
+        match tx_type {
+            TransactionType::Deposit => self.deposits += 1,
+            TransactionType::Withdrawal => self.withdrawals += 1,
+            TransactionType::Dispute => self.disputes += 1,
+            TransactionType::Resolve => self.resolves += 1,
+            TransactionType::Chargeback => self.chargebacks += 1,
+            TransactionType::Fee => self.fees += 1,
+            TransactionType::Adjustment => self.adjustments += 1,
+        }
+    }
+
+    pub fn record_rejection(&mut self, err: &EngineError) {
+        *self.rejections.entry(err.kind().to_string()).or_default() += 1;
+    }
+
+    /// Add the counters of another shard's summary to this one
+    pub fn merge(&mut self, other: &RunSummary) {
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.disputes += other.disputes;
+        self.resolves += other.resolves;
+        self.chargebacks += other.chargebacks;
+        self.fees += other.fees;
+        self.adjustments += other.adjustments;
+        self.amounts.merge(&other.amounts);
+        for (reason, count) in &other.rejections {
+            *self.rejections.entry(reason.clone()).or_default() += count;
+        }
+        self.unfunded_withdrawals += other.unfunded_withdrawals;
+        self.redundant_resolves += other.redundant_resolves;
+        self.locked_accounts += other.locked_accounts;
+    }
+
+    /// Two-column `metric,value` CSV, one rejection reason per `rejected.<kind>` row
+    pub fn to_stats_csv(&self) -> String {
+        let mut csv = String::from("metric,value\n");
+        for (metric, value) in [
+            ("deposits", self.deposits),
+            ("withdrawals", self.withdrawals),
+            ("disputes", self.disputes),
+            ("resolves", self.resolves),
+            ("chargebacks", self.chargebacks),
+            ("fees", self.fees),
+            ("adjustments", self.adjustments),
+        ] {
+            csv.push_str(&format!("{},{}\n", metric, value));
+        }
+        for (metric, sum) in [
+            ("deposits", self.amounts.deposits),
+            ("withdrawals", self.amounts.withdrawals),
+            ("chargebacks", self.amounts.chargebacks),
+            ("fees", self.amounts.fees),
+            ("adjustments", self.amounts.adjustments),
+        ] {
+            match sum {
+                Some(sum) => csv.push_str(&format!("amount.{},{}\n", metric, sum)),
+                None => csv.push_str(&format!("amount.{},overflow\n", metric)),
+            }
+        }
+        for (reason, count) in &self.rejections {
+            csv.push_str(&format!("rejected.{},{}\n", reason, count));
+        }
+        csv.push_str(&format!(
+            "unfunded_withdrawals,{}\n",
+            self.unfunded_withdrawals
+        ));
+        csv.push_str(&format!("redundant_resolves,{}\n", self.redundant_resolves));
+        csv.push_str(&format!("locked_accounts,{}\n", self.locked_accounts));
+        csv
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("RunSummary is always serializable")
+    }
+
+    /// Write the summary to `path`, as JSON when it ends with `.json` and CSV otherwise
+    pub async fn write_to(&self, path: &Path) -> Result<(), EngineError> {
+        let content = if path.extension().is_some_and(|ext| ext == "json") {
+            self.to_json()
+        } else {
+            self.to_stats_csv()
+        };
+        async_std::fs::write(path, content).await?;
+        Ok(())
+    }
+}
+
+/// Overflow-checked sums of the amounts of applied transactions, by type. A sum that
+/// overflows a `Decimal` is `None` from then on, unknown rather than wrong.
+///
+/// Disputes and resolves only move funds between available and held and have no sum, only
+/// their count in `RunSummary`. A chargeback adds the amount of the transaction it reverses.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AmountTotals {
+    pub deposits: Option<Decimal>,
+    pub withdrawals: Option<Decimal>,
+    pub chargebacks: Option<Decimal>,
+    pub fees: Option<Decimal>,
+    /// Signed sum of adjustments
+    pub adjustments: Option<Decimal>,
+}
+
+impl Default for AmountTotals {
+    fn default() -> Self {
+        Self {
+            deposits: Some(Decimal::ZERO),
+            withdrawals: Some(Decimal::ZERO),
+            chargebacks: Some(Decimal::ZERO),
+            fees: Some(Decimal::ZERO),
+            adjustments: Some(Decimal::ZERO),
+        }
+    }
+}
+
+impl AmountTotals {
+    /// Sum of the amounts of `tx_type`, zero for disputes and resolves
+    pub fn get(&self, tx_type: TransactionType) -> Option<Decimal> {
+        match tx_type {
+            TransactionType::Deposit => self.deposits,
+            TransactionType::Withdrawal => self.withdrawals,
+            TransactionType::Chargeback => self.chargebacks,
+            TransactionType::Fee => self.fees,
+            TransactionType::Adjustment => self.adjustments,
+            TransactionType::Dispute | TransactionType::Resolve => Some(Decimal::ZERO),
+        }
+    }
+
+    /// Add the amount of an applied transaction of `tx_type`, negative for an adjustment
+    /// debiting the account. Amounts of disputes and resolves are ignored.
+    pub fn add(&mut self, tx_type: TransactionType, amount: Decimal) {
+        let sum = match tx_type {
+            TransactionType::Deposit => &mut self.deposits,
+            TransactionType::Withdrawal => &mut self.withdrawals,
+            TransactionType::Chargeback => &mut self.chargebacks,
+            TransactionType::Fee => &mut self.fees,
+            TransactionType::Adjustment => &mut self.adjustments,
+            TransactionType::Dispute | TransactionType::Resolve => return,
+        };
+        *sum = sum.and_then(|sum| sum.checked_add(amount));
+    }
+
+    pub fn merge(&mut self, other: &AmountTotals) {
+        for (sum, other) in [
+            (&mut self.deposits, other.deposits),
+            (&mut self.withdrawals, other.withdrawals),
+            (&mut self.chargebacks, other.chargebacks),
+            (&mut self.fees, other.fees),
+            (&mut self.adjustments, other.adjustments),
+        ] {
+            *sum = sum
+                .zip(other)
+                .and_then(|(sum, other)| sum.checked_add(other));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ShardedEngine;
+    use crate::models::Transaction;
+    use rust_decimal_macros::dec;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_stats_output_for_known_input() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(100.0)),
+                Transaction::deposit(2, 2, dec!(20.0)),
+                Transaction::withdrawal(1, 3, dec!(30.0)),
+                // Insufficient funds
+                Transaction::withdrawal(2, 4, dec!(50.0)),
+                Transaction::dispute(2, 2),
+                Transaction::chargeback(2, 2),
+                // Locked account
+                Transaction::deposit(2, 5, dec!(1.0)),
+                // Unknown transaction
+                Transaction::resolve(1, 42),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let summary = engine.run_summary().await;
+        let csv_path = Path::new("res/test_stats_output.csv");
+        let json_path = Path::new("res/test_stats_output.json");
+        summary.write_to(csv_path).await.unwrap();
+        summary.write_to(json_path).await.unwrap();
+        let csv = fs::read_to_string(csv_path).unwrap();
+        let json = fs::read_to_string(json_path).unwrap();
+        fs::remove_file(csv_path).expect("Failed to delete test file");
+        fs::remove_file(json_path).expect("Failed to delete test file");
+
+        assert_eq!(
+            csv,
+            "metric,value\n\
+             deposits,2\n\
+             withdrawals,1\n\
+             disputes,1\n\
+             resolves,0\n\
+             chargebacks,1\n\
+             fees,0\n\
+             adjustments,0\n\
+             amount.deposits,120.0\n\
+             amount.withdrawals,30.0\n\
+             amount.chargebacks,20.0000\n\
+             amount.fees,0\n\
+             amount.adjustments,0\n\
+             rejected.AccountLocked,1\n\
+             rejected.InsufficientFunds,1\n\
+             rejected.TransactionNotFound,1\n\
+             unfunded_withdrawals,0\n\
+             redundant_resolves,0\n\
+             locked_accounts,1\n"
+        );
+        assert_eq!(
+            json,
+            "{\"deposits\":2,\"withdrawals\":1,\"disputes\":1,\"resolves\":0,\"chargebacks\":1,\"fees\":0,\"adjustments\":0,\
+             \"amounts\":{\"deposits\":\"120.0\",\"withdrawals\":\"30.0\",\"chargebacks\":\"20.0000\",\"fees\":\"0\",\"adjustments\":\"0\"},\
+             \"rejections\":{\"AccountLocked\":1,\"InsufficientFunds\":1,\"TransactionNotFound\":1},\
+             \"unfunded_withdrawals\":0,\"redundant_resolves\":0,\"locked_accounts\":1}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unfunded_withdrawals_counted_apart() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                // Never funded: the deposit arrives after the withdrawal
+                Transaction::withdrawal(1, 1, dec!(10.0)),
+                Transaction::deposit(1, 2, dec!(10.0)),
+                // Funded, but not enough
+                Transaction::deposit(2, 3, dec!(5.0)),
+                Transaction::withdrawal(2, 4, dec!(50.0)),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let summary = engine.run_summary().await;
+        assert_eq!(summary.rejections.get("InsufficientFunds"), Some(&2));
+        assert_eq!(summary.unfunded_withdrawals, 1);
+    }
+
+    #[tokio::test]
+    async fn test_amount_totals_by_type() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(100.0)),
+                Transaction::deposit(2, 2, dec!(20.25)),
+                Transaction::deposit(3, 3, dec!(7.5)),
+                Transaction::withdrawal(1, 4, dec!(30.0)),
+                // Rejected, not summed
+                Transaction::withdrawal(2, 5, dec!(50.0)),
+                Transaction::fee(1, 6, dec!(1.5)),
+                Transaction::adjustment(3, 7, dec!(-2.5)),
+                Transaction::adjustment(1, 8, dec!(0.75)),
+                Transaction::dispute(2, 2),
+                Transaction::chargeback(2, 2),
+                Transaction::dispute(3, 3),
+                Transaction::resolve(3, 3),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let amounts = engine.run_summary().await.amounts;
+        assert_eq!(amounts.get(TransactionType::Deposit), Some(dec!(127.75)));
+        assert_eq!(amounts.get(TransactionType::Withdrawal), Some(dec!(30.0)));
+        assert_eq!(amounts.get(TransactionType::Chargeback), Some(dec!(20.25)));
+        assert_eq!(amounts.get(TransactionType::Fee), Some(dec!(1.5)));
+        assert_eq!(amounts.get(TransactionType::Adjustment), Some(dec!(-1.75)));
+        assert_eq!(amounts.get(TransactionType::Dispute), Some(dec!(0)));
+        assert_eq!(amounts.get(TransactionType::Resolve), Some(dec!(0)));
+    }
+
+    #[test]
+    fn test_amount_totals_overflow_unknown() {
+        let mut amounts = AmountTotals::default();
+        amounts.add(TransactionType::Deposit, Decimal::MAX);
+        amounts.add(TransactionType::Deposit, dec!(1));
+        assert_eq!(amounts.deposits, None);
+
+        let mut merged = AmountTotals::default();
+        merged.merge(&amounts);
+        assert_eq!(merged.deposits, None);
+        assert_eq!(merged.withdrawals, Some(dec!(0)));
+    }
+}
@@ -0,0 +1,384 @@
+use crate::errors::EngineError;
+use crate::models::{output_amount, ClientAccount, ClientId};
+use async_std::fs::File;
+use csv_async::{AsyncReaderBuilder, StringRecord, Trim};
+use futures::stream::StreamExt;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Columns of an account output file, as written by `write_accounts`
+pub const ACCOUNT_HEADERS: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+/// Balances of one client in an account output file
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountRow {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl From<&ClientAccount> for AccountRow {
+    /// The row written for an account, amounts cut to the output precision
+    fn from(account: &ClientAccount) -> Self {
+        Self {
+            available: output_amount(account.available),
+            held: output_amount(account.held),
+            total: output_amount(account.total),
+            locked: account.locked,
+        }
+    }
+}
+
+/// How the account of a client differs from the expected one, see `diff_accounts`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountDiff {
+    pub client: ClientId,
+    /// Expected balances, `None` when the client is not expected
+    pub expected: Option<AccountRow>,
+    /// Actual balances, `None` when the client has no account
+    pub actual: Option<AccountRow>,
+    /// Actual minus expected amounts, normalized (`0.5`, not `0.5000`) and zero where they
+    /// match. A missing side counts as zero balances.
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+impl fmt::Display for AccountDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.expected, &self.actual) {
+            (Some(expected), None) => {
+                write!(
+                    f,
+                    "client {}: missing, expected {}",
+                    self.client,
+                    describe(expected)
+                )
+            }
+            (None, Some(actual)) => {
+                write!(
+                    f,
+                    "client {}: unexpected, found {}",
+                    self.client,
+                    describe(actual)
+                )
+            }
+            _ => {
+                write!(
+                    f,
+                    "client {}: available {}, held {}, total {}",
+                    self.client,
+                    signed(self.available),
+                    signed(self.held),
+                    signed(self.total)
+                )?;
+                match (&self.expected, &self.actual) {
+                    (Some(expected), Some(actual)) if expected.locked != actual.locked => write!(
+                        f,
+                        ", locked {} instead of {}",
+                        actual.locked, expected.locked
+                    ),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// A difference with its sign, e.g. `+0.5` or `-2`, and `0` for none
+fn signed(delta: Decimal) -> String {
+    if delta.is_zero() {
+        "0".into()
+    } else {
+        format!("{:+}", delta)
+    }
+}
+
+/// Read an account output file, keyed by client so row order does not matter
+pub async fn read_accounts(file_path: &str) -> Result<BTreeMap<ClientId, AccountRow>, EngineError> {
+    let file = File::open(file_path)
+        .await
+        .map_err(|err| EngineError::file_open(file_path, err))?;
+    let mut reader = AsyncReaderBuilder::new()
+        .has_headers(true)
+        .trim(Trim::All)
+        .create_reader(file);
+
+    let headers: Vec<String> = reader
+        .headers()
+        .await?
+        .iter()
+        .map(|h| h.to_lowercase())
+        .collect();
+    if headers.iter().ne(ACCOUNT_HEADERS.iter()) {
+        return Err(EngineError::HeaderError(format!(
+            "Unexpected header in {}: expected {}, found {}",
+            file_path,
+            ACCOUNT_HEADERS.join(","),
+            headers.join(",")
+        )));
+    }
+
+    let mut accounts = BTreeMap::new();
+    let mut records = reader.records();
+    while let Some(record) = records.next().await {
+        let (client, row) = parse_account_row(&record?)?;
+        if accounts.insert(client, row).is_some() {
+            return Err(EngineError::InvalidOperation(format!(
+                "Duplicate client {} in {}",
+                client, file_path
+            )));
+        }
+    }
+    Ok(accounts)
+}
+
+fn parse_account_row(record: &StringRecord) -> Result<(ClientId, AccountRow), EngineError> {
+    if record.len() != ACCOUNT_HEADERS.len() {
+        return Err(EngineError::TransactionError(
+            "Insufficient data in account row".into(),
+        ));
+    }
+
+    let client = record[0].parse::<ClientId>()?;
+    let locked = record[4]
+        .parse::<bool>()
+        .map_err(|err| EngineError::ParseField {
+            field: "locked",
+            source: Box::new(EngineError::TransactionError(err.to_string())),
+        })?;
+
+    Ok((
+        client,
+        AccountRow {
+            available: record[1].parse()?,
+            held: record[2].parse()?,
+            total: record[3].parse()?,
+            locked,
+        },
+    ))
+}
+
+/// Compare two account output files logically and describe every real difference.
+///
+/// Rows are matched by client and amounts compared as decimals, so ordering and trailing
+/// zeros (`10.5` vs `10.5000`) are not reported. An empty result means the files match.
+pub async fn verify_files(expected: &str, actual: &str) -> Result<Vec<String>, EngineError> {
+    let expected_accounts = read_accounts(expected).await?;
+    let actual_accounts = read_accounts(actual).await?;
+    Ok(compare_accounts(&expected_accounts, &actual_accounts))
+}
+
+/// The differences found by `diff_accounts`, described one line per client, in client order.
+pub fn compare_accounts(
+    expected: &BTreeMap<ClientId, AccountRow>,
+    actual: &BTreeMap<ClientId, AccountRow>,
+) -> Vec<String> {
+    diff_accounts(expected, actual)
+        .into_iter()
+        .map(|diff| match (&diff.expected, &diff.actual) {
+            (Some(expected), Some(actual)) => format!(
+                "client {}: expected {}, found {}",
+                diff.client,
+                describe(expected),
+                describe(actual)
+            ),
+            (Some(_), None) => format!("client {}: missing", diff.client),
+            _ => format!("client {}: unexpected", diff.client),
+        })
+        .collect()
+}
+
+/// The clients whose actual account differs from the expected one, in client order, with
+/// how much each amount differs. Amounts are compared as decimals, so scale (`10.5` vs
+/// `10.5000`) makes no difference.
+pub fn diff_accounts(
+    expected: &BTreeMap<ClientId, AccountRow>,
+    actual: &BTreeMap<ClientId, AccountRow>,
+) -> Vec<AccountDiff> {
+    let clients: BTreeSet<_> = expected.keys().chain(actual.keys()).collect();
+    clients
+        .into_iter()
+        .filter_map(|client| {
+            let expected = expected.get(client);
+            let actual = actual.get(client);
+            if expected == actual {
+                return None;
+            }
+            let delta = |amount: fn(&AccountRow) -> Decimal| {
+                let expected = expected.map_or(Decimal::ZERO, amount);
+                let actual = actual.map_or(Decimal::ZERO, amount);
+                (actual - expected).normalize()
+            };
+            Some(AccountDiff {
+                client: *client,
+                expected: expected.cloned(),
+                actual: actual.cloned(),
+                available: delta(|row| row.available),
+                held: delta(|row| row.held),
+                total: delta(|row| row.total),
+            })
+        })
+        .collect()
+}
+
+fn describe(row: &AccountRow) -> String {
+    format!(
+        "available={} held={} total={} locked={}",
+        row.available.normalize(),
+        row.held.normalize(),
+        row.total.normalize(),
+        row.locked
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{PaymentEngine, ShardedEngine};
+    use crate::models::Transaction;
+    use rust_decimal_macros::dec;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+
+    fn create_temp_csv(file_path: &str, data: &str) -> String {
+        let mut file =
+            File::create(format!("res/{}", file_path)).expect("Unable to create test file");
+        file.write_all(data.as_bytes())
+            .expect("Unable to write to test file");
+        format!("res/{}", file_path)
+    }
+
+    #[tokio::test]
+    async fn test_verify_ignores_order_and_scale() {
+        let expected = create_temp_csv(
+            "test_verify_order_expected.csv",
+            "client,available,held,total,locked\n\
+             1,10.5,0.0000,10.5,false\n\
+             2,0.0000,0.0000,0.0000,true\n",
+        );
+        let actual = create_temp_csv(
+            "test_verify_order_actual.csv",
+            "client,available,held,total,locked\n\
+             2,0,0,0,true\n\
+             1,10.5000,0,10.50,false\n",
+        );
+
+        let result = verify_files(&expected, &actual).await;
+        fs::remove_file(&expected).expect("Failed to delete test file");
+        fs::remove_file(&actual).expect("Failed to delete test file");
+
+        assert!(result.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_verify_reports_real_differences() {
+        let expected = create_temp_csv(
+            "test_verify_diff_expected.csv",
+            "client,available,held,total,locked\n\
+             1,10.5,0,10.5,false\n\
+             2,1.0,0,1.0,false\n",
+        );
+        let actual = create_temp_csv(
+            "test_verify_diff_actual.csv",
+            "client,available,held,total,locked\n\
+             1,10.4,0,10.4,false\n\
+             3,1.0,0,1.0,false\n",
+        );
+
+        let result = verify_files(&expected, &actual).await;
+        fs::remove_file(&expected).expect("Failed to delete test file");
+        fs::remove_file(&actual).expect("Failed to delete test file");
+
+        assert_eq!(
+            result.unwrap(),
+            vec![
+                "client 1: expected available=10.5 held=0 total=10.5 locked=false, \
+                 found available=10.4 held=0 total=10.4 locked=false",
+                "client 2: missing",
+                "client 3: unexpected",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_diff_against_flags_perturbed_client() {
+        let mut engine = ShardedEngine::new(2);
+        engine
+            .route_batch(vec![
+                Transaction::deposit(1, 1, dec!(10.0)),
+                Transaction::deposit(2, 2, dec!(20.0)),
+                Transaction::withdrawal(2, 3, dec!(5.0)),
+                Transaction::deposit(3, 4, dec!(3.0)),
+            ])
+            .unwrap();
+        engine.shutdown();
+        engine.wait_for_completion().await;
+
+        let mut output = Vec::new();
+        engine.write_accounts_to(&mut output).await.unwrap();
+        let written = String::from_utf8(output).unwrap();
+        let file_path = create_temp_csv("test_diff_against.csv", &written);
+        assert!(engine.diff_against(&file_path).await.unwrap().is_empty());
+
+        // The ledger credits client 2 half a unit more than the engine did
+        let perturbed = written.replace("2,15.0,0.0000,15.0,false", "2,15.5,0.0000,15.5,false");
+        assert_ne!(perturbed, written);
+        let file_path = create_temp_csv("test_diff_against.csv", &perturbed);
+        let result = engine.diff_against(&file_path).await;
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+
+        let diffs = result.unwrap();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].client, 2);
+        assert_eq!(
+            (diffs[0].available, diffs[0].held, diffs[0].total),
+            (dec!(-0.5), dec!(0), dec!(-0.5))
+        );
+        assert_eq!(
+            diffs[0].to_string(),
+            "client 2: available -0.5, held 0, total -0.5"
+        );
+    }
+
+    #[test]
+    fn test_diff_accounts_missing_and_unexpected_clients() {
+        let row = |total| AccountRow {
+            available: total,
+            held: Decimal::ZERO,
+            total,
+            locked: false,
+        };
+        let expected = BTreeMap::from([(1, row(dec!(1.0))), (2, row(dec!(2.0)))]);
+        let actual = BTreeMap::from([(2, row(dec!(2.0000))), (3, row(dec!(3)))]);
+
+        let diffs: Vec<_> = diff_accounts(&expected, &actual)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            diffs,
+            vec![
+                "client 1: missing, expected available=1 held=0 total=1 locked=false",
+                "client 3: unexpected, found available=3 held=0 total=3 locked=false",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_rejects_unexpected_header() {
+        let file_path = create_temp_csv(
+            "test_verify_bad_header.csv",
+            "type,client,tx,amount\n\
+             deposit,1,1,1.0\n",
+        );
+
+        let result = read_accounts(&file_path).await;
+        fs::remove_file(&file_path).expect("Failed to delete test file");
+
+        assert!(matches!(result, Err(EngineError::HeaderError(_))));
+    }
+}